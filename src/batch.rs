@@ -0,0 +1,335 @@
+//! Batch allocation and deallocation.
+//!
+//! Object pools and deserializers allocate many objects of the same layout at once, and
+//! taking the lock for every one of them can cost more than the allocations themselves.
+//! [`MemAlloc::allocate_many`] takes it once for the whole batch and carves the blocks
+//! one after the other from the same free block, mapping a region big enough for the
+//! rest of the batch when it runs out:
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::MemAlloc;
+//!
+//! let allocator = MemAlloc::new();
+//! let layout = Layout::new::<[u64; 4]>();
+//!
+//! unsafe {
+//!     let objects = allocator.allocate_many(layout, 100);
+//!     assert_eq!(objects.len(), 100);
+//!
+//!     for ptr in objects {
+//!         allocator.deallocate(ptr, layout);
+//!     }
+//! }
+//! ```
+//!
+//! [`MemAlloc::deallocate_many`] does the opposite: it frees a batch of allocations in
+//! address order taking the lock once, so neighbours are merged as they are freed, and
+//! only checks whether a region is empty once all of its blocks in the batch are free,
+//! instead of after every block.
+//!
+//! The `Vec`s are allocated before taking the lock, so the allocator can be the global
+//! one. Allocations that don't go through the blocks (large objects, hardened
+//! allocations and zero-sized ones) are made one by one like [`MemAlloc::allocate`] does.
+
+use std::{alloc::Layout, cmp, mem, ptr::NonNull};
+
+use lock_api::RawMutex;
+
+use crate::{
+    block::{BLOCK_FOOTER_SIZE, BLOCK_HEADER_SIZE, Block},
+    corruption::CorruptionKind,
+    heap::StatsCounters,
+    kernel::Kernel,
+    list::Node,
+    memalloc::{MemAlloc, MIN_BLOCK_SIZE},
+    region::{Region, RegionKind},
+    sync,
+    utils::align,
+};
+
+impl Kernel {
+    /// Allocates blocks of `layout` into `ptrs` until it holds `count` of them, going
+    /// over the quota or running out of memory. `layout` must be aligned to the minimum
+    /// alignment already and must not be large.
+    ///
+    /// Every block is taken from the last block of the free list when it fits, which is
+    /// what is left of the previous one after splitting it, so the batch is carved from
+    /// consecutive memory.
+    #[track_caller]
+    fn allocate_batch(&mut self, stats: &StatsCounters, layout: Layout, count: usize, quota: Option<usize>, ptrs: &mut Vec<*mut u8>) {
+        let node = self.preferred_node();
+
+        // Bytes of the region used by every block, if they don't need any padding.
+        let payload = cmp::max(align(layout.size(), mem::size_of::<usize>()) + BLOCK_FOOTER_SIZE, MIN_BLOCK_SIZE);
+        let stride = align(BLOCK_HEADER_SIZE + payload, self.config.min_align.max(mem::size_of::<usize>()));
+
+        while ptrs.len() < count {
+            if self.failures.fails(layout) || !self.fits_quota(stats, layout.size(), quota) {
+                return;
+            }
+
+            let mut block = self
+                .free_list
+                .find_last_free_block(layout)
+                .or_else(|| self.find_free_block(layout, node));
+
+            if block.is_none() {
+                // A region for the rest of the batch, as long as it doesn't make a large object.
+                let rest = (stride + layout.align()).saturating_mul(count - ptrs.len());
+                let size = rest.min(self.config.large_object_threshold).max(layout.size());
+                let Ok(region_layout) = Layout::from_size_align(size, layout.align()) else {
+                    return;
+                };
+
+                if self.allocate_new_region(region_layout).is_err() {
+                    return;
+                }
+
+                block = self.free_list.find_last_free_block(layout);
+            }
+
+            let Some(block) = block else {
+                return;
+            };
+
+            unsafe {
+                let ptr = self.take_from_block(block, layout);
+                self.record_allocation(stats, ptr, layout.size());
+                ptrs.push(ptr);
+            }
+        }
+    }
+
+    /// Returns `region` to the OS if all of its blocks are free, which means that they
+    /// have been merged into a single one.
+    ///
+    /// # Safety
+    ///
+    /// `region` must be part of [`Kernel::regions`].
+    unsafe fn release_if_empty(&mut self, mut region: NonNull<Node<Region>>) {
+        unsafe {
+            let blocks = &region.as_ref().data.blocks;
+
+            if let Some(block) = blocks.first()
+                && blocks.len() == 1
+                && block.as_ref().data.is_free()
+            {
+                self.check_region_removal(&mut region, block);
+            }
+        }
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Allocates `count` blocks of `layout` taking the lock once. See the `batch` module.
+    ///
+    /// The returned pointers are the same [`MemAlloc::allocate`] would return, and each
+    /// one must be freed with [`MemAlloc::deallocate`] on its own. If the memory runs
+    /// out, fewer than `count` pointers are returned.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`MemAlloc::allocate`].
+    #[track_caller]
+    pub unsafe fn allocate_many(&self, layout: Layout, count: usize) -> Vec<*mut u8> {
+        let mut ptrs = Vec::with_capacity(count);
+
+        if count > 0 && layout.size() > 0 && !self.config.hardened && !self.config.electric_fence && !sync::is_acquiring() {
+            Self::check_forbidden(layout);
+
+            let mut kernel = self.lock_draining();
+
+            if let Ok(aligned) = layout.align_to(self.config.min_align)
+                && !kernel.is_large(aligned)
+            {
+                kernel.allocate_batch(&self.stats, aligned, count, self.config.quota, &mut ptrs);
+            }
+
+            drop(kernel);
+
+            #[cfg(feature = "trace")]
+            for &ptr in &ptrs {
+                self.trace_allocation(layout, ptr);
+            }
+        }
+
+        // The rest go one by one, so the hook of `MemAlloc::set_oom_hook` gets a chance
+        // to release memory too.
+        while ptrs.len() < count {
+            let ptr = unsafe { self.allocate(layout) };
+
+            if ptr.is_null() {
+                break;
+            }
+
+            ptrs.push(ptr);
+        }
+
+        ptrs
+    }
+
+    /// Deallocates every allocation of `allocations` taking the lock once. See the
+    /// `batch` module.
+    ///
+    /// # Safety
+    ///
+    /// Every allocation must satisfy the requirements of [`MemAlloc::deallocate`], and
+    /// none of them may appear twice.
+    #[track_caller]
+    pub unsafe fn deallocate_many(&self, allocations: &[(*mut u8, Layout)]) {
+        // Hardened allocations have no blocks to merge, and foreign pointers are told
+        // apart one by one, see the `foreign` module.
+        if self.config.hardened || self.config.forward_foreign_frees {
+            for &(ptr, layout) in allocations {
+                unsafe { self.deallocate(ptr, layout) };
+            }
+
+            return;
+        }
+
+        // Zero-sized allocations are dangling pointers, see `MemAlloc::allocate`.
+        let mut sorted: Vec<_> = allocations
+            .iter()
+            .copied()
+            .filter(|&(ptr, layout)| !ptr.is_null() && layout.size() != 0)
+            .collect();
+
+        // Blocks of the same region are next to each other in memory, so this groups
+        // them by region too.
+        sorted.sort_unstable_by_key(|&(ptr, _)| ptr.addr());
+
+        #[cfg(feature = "trace")]
+        for &(ptr, layout) in &sorted {
+            self.trace_deallocation(ptr, layout);
+        }
+
+        let mut kernel = self.lock_draining();
+
+        // Region of the last freed block, which may be empty once we leave it.
+        let mut current = None;
+
+        for &(ptr, layout) in &sorted {
+            unsafe {
+                let block = Block::from_payload(ptr);
+
+                if !Block::is_intact(block) {
+                    kernel.found_corruption(CorruptionKind::CorruptedHeader, ptr.addr());
+                    continue;
+                }
+
+                let region = block.as_ref().data.region;
+
+                if region.as_ref().data.kind == RegionKind::Detached {
+                    Kernel::deallocate_detached(region);
+                    continue;
+                }
+
+                if kernel.release_block(&self.stats, block, ptr, layout.size()).is_some()
+                    && let Some(previous) = current.replace(region)
+                    && previous != region
+                {
+                    kernel.release_if_empty(previous);
+                }
+            }
+        }
+
+        if let Some(region) = current {
+            unsafe { kernel.release_if_empty(region) };
+        }
+
+        if let Some(corruption) = kernel.corruption.take() {
+            drop(kernel);
+            let _ = self.report_corruption(corruption);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, HeapSummary};
+
+    #[test]
+    fn batches_are_carved_from_consecutive_memory() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::from_size_align(48, 8).unwrap();
+
+        unsafe {
+            let ptrs = allocator.allocate_many(layout, 200);
+            assert_eq!(ptrs.len(), 200);
+            assert_eq!(allocator.stats().allocations, 200);
+            assert_eq!(allocator.summary().regions, 1);
+
+            // Every block starts right where the previous one ends.
+            let stride = ptrs[1] as usize - ptrs[0] as usize;
+            assert!(ptrs.windows(2).all(|pair| pair[1] as usize - pair[0] as usize == stride));
+
+            for &ptr in &ptrs {
+                ptr.write_bytes(0xAB, layout.size());
+            }
+
+            for ptr in ptrs {
+                allocator.deallocate(ptr, layout);
+            }
+
+            assert_eq!(allocator.stats().allocations, 0);
+        }
+    }
+
+    #[test]
+    fn batches_are_freed_under_one_lock() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::from_size_align(48, 8).unwrap();
+        let large = Layout::from_size_align(Config::new().large_object_threshold, 8).unwrap();
+
+        unsafe {
+            let mut allocations: Vec<_> = allocator.allocate_many(layout, 100).into_iter().map(|ptr| (ptr, layout)).collect();
+            allocations.push((allocator.allocate(large), large));
+            allocations.push((allocator.allocate(Layout::new::<()>()), Layout::new::<()>()));
+            allocations.reverse();
+
+            // Freeing half of them merges every other block with its neighbours. The
+            // zero-sized allocation doesn't count in the stats.
+            let (odd, even): (Vec<_>, Vec<_>) = allocations.iter().enumerate().partition(|(index, _)| index % 2 == 1);
+            let odd: Vec<_> = odd.into_iter().map(|(_, &allocation)| allocation).collect();
+            let even: Vec<_> = even.into_iter().map(|(_, &allocation)| allocation).collect();
+
+            allocator.deallocate_many(&odd);
+            assert_eq!(allocator.stats().allocations, 50);
+            assert_eq!(allocator.summary().regions, 1);
+
+            // Freeing the rest empties the region, which is returned to the OS.
+            allocator.deallocate_many(&even);
+            assert_eq!(allocator.stats().allocations, 0);
+            assert_eq!(allocator.summary(), HeapSummary::default());
+        }
+    }
+
+    #[test]
+    fn batches_stop_at_the_quota() {
+        let allocator = MemAlloc::with_config(Config::new().quota(1000));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        unsafe {
+            let ptrs = allocator.allocate_many(layout, 20);
+            assert_eq!(ptrs.len(), 10);
+
+            for ptr in ptrs {
+                allocator.deallocate(ptr, layout);
+            }
+
+            // Zero-sized and large allocations don't go through the blocks.
+            let large = Layout::from_size_align(Config::new().large_object_threshold, 8).unwrap();
+            let unlimited = MemAlloc::new();
+            let ptrs = unlimited.allocate_many(large, 2);
+            assert_eq!(ptrs.len(), 2);
+            assert_eq!(unlimited.summary().large_objects, 2);
+            assert_eq!(unlimited.allocate_many(Layout::new::<()>(), 3).len(), 3);
+
+            for ptr in ptrs {
+                unlimited.deallocate(ptr, large);
+            }
+        }
+    }
+}
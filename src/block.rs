@@ -1,11 +1,76 @@
 use std::{ptr::NonNull, mem};
-use crate::{list::Node, region::Region};
+#[cfg(feature = "call-sites")]
+use std::panic::Location;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::{config::MIN_ALIGN, list::Node, region::{REGION_HEADER_SIZE, Region, RegionKind}, utils::align};
 
 
 /// Header size of a block. We need to add the overhead introduced by our 
 /// [`Node`] structure since we always use our `Block` as a node of our linked list.
 pub(crate) const BLOCK_HEADER_SIZE: usize = mem::size_of::<Node<Block>>();
 
+/// Words of the fields of [`Node<Block>`]: the links, the region, the size and the tag,
+/// call site, allocation time and checksum if they are enabled.
+const HEADER_WORDS: usize = 4
+    + cfg!(feature = "tagging") as usize
+    + cfg!(feature = "call-sites") as usize
+    + cfg!(feature = "dhat") as usize
+    + cfg!(feature = "checksums") as usize;
+
+/// Words added to the header so that its size is a multiple of [`MIN_ALIGN`], which keeps
+/// payloads that start right after it aligned without any padding.
+const PADDING_WORDS: usize = align(HEADER_WORDS * mem::size_of::<usize>(), MIN_ALIGN) / mem::size_of::<usize>() - HEADER_WORDS;
+
+/// Size of the boundary tag stored in the last word of every block. See
+/// [`Block::write_footer`] for more detail.
+pub(crate) const BLOCK_FOOTER_SIZE: usize = mem::size_of::<usize>();
+
+/// Bit of the size word that tells whether the block is free. Block sizes are
+/// always word-aligned, so the lowest bits of the size are always zero and we can
+/// use them as flags.
+const FREE_BIT: usize = 0b01;
+
+/// Bit used to tag the word stored just before a padded payload. See
+/// [`Block::from_payload`] for more detail. It is also set on the size word of padded
+/// blocks, which is never the word before their payload, but never on their footer.
+const PADDED_BIT: usize = 0b10;
+
+/// Bit of the footer of a used block that tells whether its pages are sealed. See
+/// [`crate::kernel::Kernel::seal`]. Footers are never read as reflection words, so
+/// this can share the bit with `PADDED_BIT`.
+const SEALED_BIT: usize = 0b10;
+
+/// Bit of the footer of a used block that tells whether its deallocation is queued. See
+/// the `deferred` module. Footers of used blocks never have `FREE_BIT` set, so this can
+/// share the bit with it, which keeps it in the footer on targets whose block sizes only
+/// leave two flag bits free.
+const QUEUED_BIT: usize = 0b01;
+
+/// Mask with every flag bit of the size word.
+const FLAGS_MASK: usize = FREE_BIT | PADDED_BIT;
+
+/// Secret mixed into the checksum of every header, or 0 until [`cookie`] picks it.
+#[cfg(feature = "checksums")]
+static COOKIE: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the secret mixed into the checksum of every header, picking a random one the
+/// first time. It is the same for every heap of the process. See [`Block::checksum`].
+#[cfg(feature = "checksums")]
+#[inline]
+fn cookie() -> usize {
+    match COOKIE.load(Ordering::Relaxed) {
+        0 => {
+            let cookie = crate::placement::random() as usize | 1;
+
+            match COOKIE.compare_exchange(0, cookie, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => cookie,
+                Err(current) => current,
+            }
+        }
+        cookie => cookie,
+    }
+}
+
 /// This is the structure of a block. The fields of the block are it's metadata,
 /// content is placed after this header.
 /// 
@@ -14,11 +79,9 @@ pub(crate) const BLOCK_HEADER_SIZE: usize = mem::size_of::<Node<Block>>();
 /// 
 /// ```text
 /// +---------------------+ <------+        
-/// |        size         |        |
-/// +---------------------+        |
-/// |    is_free (1b)     |        | -> Header
-/// +---------------------+        |
 /// |       region        |        |
+/// +---------------------+        | -> Header
+/// |  size | is_free(1b) |        |
 /// +---------------------+ <------+
 /// |     Additional      |
 /// |      metadata       |
@@ -29,7 +92,17 @@ pub(crate) const BLOCK_HEADER_SIZE: usize = mem::size_of::<Node<Block>>();
 /// |         ...         |        |
 /// |                     |        |
 /// +---------------------+ <------+
+/// |  Footer (size|free) |
+/// +---------------------+
 /// ```
+///
+/// Since sizes are always word-aligned, the free flag is packed in the lowest bit
+/// of the size word, so the header doesn't waste a whole word on a `bool`. Use
+/// [`Block::size`] and [`Block::is_free`] to read them.
+///
+/// The footer is a boundary tag: a copy of the size word placed in the last word
+/// of the block. It is counted inside `size`, so the actual addressable content is
+/// `size - BLOCK_FOOTER_SIZE` bytes.
 /// 
 /// As always take we need to take into account that every memory address needs to be
 /// aligned for CPU efficiency. The reason behind this is that processors fetch data in
@@ -46,12 +119,433 @@ pub(crate) const BLOCK_HEADER_SIZE: usize = mem::size_of::<Node<Block>>();
 /// we will used a little bit of space just before the block's content to store a pointer to
 /// the header's address. By doing that, we can always locate the `header` by using that information.
 /// However that is also what can cause Undefined Behaviour since that pointer can have a memory address
-/// that isn't actually a `header`.
+/// that isn't actually a `header`. See [`Block::from_payload`].
+///
+/// The header is `repr(C)` because we rely on the size word being the last word of
+/// the [`Node<Block>`], just before the content.
+#[repr(C)]
 pub(crate) struct Block {
-    /// Size of the block.
-    pub size: usize, 
-    /// Flag to tell whether the block is free or not.
-    pub is_free: bool,
     /// Region which the block belongs to
     pub region: NonNull<Node<Region>>,
-}
\ No newline at end of file
+    /// Allocation tag of the used block. See the `tag` module.
+    #[cfg(feature = "tagging")]
+    pub tag: u32,
+    /// Source location that allocated the used block. See the `call_site` module.
+    #[cfg(feature = "call-sites")]
+    pub call_site: Option<&'static Location<'static>>,
+    /// When the used block was allocated, in microseconds. See the `dhat` module.
+    #[cfg(feature = "dhat")]
+    pub allocated_at: usize,
+    /// Checksum of the region and the size word. See [`Block::checksum`].
+    #[cfg(feature = "checksums")]
+    checksum: usize,
+    /// Unused, see `PADDING_WORDS`.
+    _padding: [usize; PADDING_WORDS],
+    /// Size of the block with the free flag packed in the lowest bit.
+    size: usize,
+}
+
+impl Block {
+    /// Creates a new block header of the given `size`.
+    #[inline]
+    pub(crate) fn new(size: usize, is_free: bool, region: NonNull<Node<Region>>) -> Self {
+        debug_assert_eq!(size & FLAGS_MASK, 0, "block sizes must be word-aligned");
+
+        let mut block = Self {
+            region,
+            #[cfg(feature = "tagging")]
+            tag: 0,
+            #[cfg(feature = "call-sites")]
+            call_site: None,
+            #[cfg(feature = "dhat")]
+            allocated_at: 0,
+            #[cfg(feature = "checksums")]
+            checksum: 0,
+            _padding: [0; PADDING_WORDS],
+            size: size | if is_free { FREE_BIT } else { 0 },
+        };
+
+        block.update_checksum();
+        block
+    }
+
+    /// Returns the checksum of the header: the size word and the region XORed with a
+    /// random cookie. It is stored in the header by [`Block::update_checksum`] every time
+    /// any of them changes, and checked by [`Block::is_intact`].
+    ///
+    /// A buffer overflow or a use after free that reaches a header can't write a valid
+    /// checksum without knowing the cookie, so it is caught the next time the block is
+    /// freed or merged, instead of making the free list or the block list write through
+    /// the corrupted fields.
+    #[cfg(feature = "checksums")]
+    #[inline]
+    fn checksum(&self) -> usize {
+        self.size ^ self.region.as_ptr().addr() ^ cookie()
+    }
+
+    /// Stores the checksum of the header after changing its size word or its region.
+    /// Does nothing without the `checksums` feature.
+    #[inline]
+    pub(crate) fn update_checksum(&mut self) {
+        #[cfg(feature = "checksums")]
+        {
+            self.checksum = self.checksum();
+        }
+    }
+
+    /// Whether the header of `node` matches its checksum. If it doesn't, it has been
+    /// overwritten, see the `corruption` module. Always `true` without the `checksums`
+    /// feature.
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to readable memory.
+    #[inline]
+    pub(crate) unsafe fn is_intact(node: NonNull<Node<Block>>) -> bool {
+        #[cfg(feature = "checksums")]
+        unsafe {
+            let block = &node.as_ref().data;
+            block.checksum == block.checksum()
+        }
+
+        #[cfg(not(feature = "checksums"))]
+        {
+            let _ = node;
+            true
+        }
+    }
+
+    /// Size of the block.
+    #[inline]
+    pub(crate) fn size(&self) -> usize {
+        self.size & !FLAGS_MASK
+    }
+
+    /// Sets the size of the block keeping its flags.
+    #[inline]
+    pub(crate) fn set_size(&mut self, size: usize) {
+        debug_assert_eq!(size & FLAGS_MASK, 0, "block sizes must be word-aligned");
+        self.size = size | (self.size & FLAGS_MASK);
+        self.update_checksum();
+    }
+
+    /// Flag to tell whether the block is free or not.
+    #[inline]
+    pub(crate) fn is_free(&self) -> bool {
+        self.size & FREE_BIT != 0
+    }
+
+    /// Whether the payload of the used block doesn't start right after the header.
+    /// See [`Block::reflect`].
+    #[inline]
+    pub(crate) fn is_padded(&self) -> bool {
+        self.size & PADDED_BIT != 0
+    }
+
+    /// Marks the block as free or used.
+    #[inline]
+    pub(crate) fn set_free(&mut self, is_free: bool) {
+        if is_free {
+            // Free blocks have no payload, so they are not padded either.
+            self.size = (self.size & !PADDED_BIT) | FREE_BIT;
+        } else {
+            self.size &= !FREE_BIT;
+        }
+
+        self.update_checksum();
+    }
+
+    /// Returns a pointer to the footer of the given block `node`.
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to a valid block header.
+    #[inline]
+    unsafe fn footer(node: NonNull<Node<Block>>) -> *mut usize {
+        unsafe {
+            let size = node.as_ref().data.size();
+            (node.as_ptr() as *mut u8).add(BLOCK_HEADER_SIZE + size - BLOCK_FOOTER_SIZE) as *mut usize
+        }
+    }
+
+    /// Writes the boundary tag of the given block `node` by copying its size word.
+    /// It must be called every time the size or the free flag changes.
+    ///
+    /// ```text
+    /// +--------+-----------------------+--------+--------+-----------------------+--------+
+    /// | Header |        Content        | Footer | Header |        Content        | Footer |
+    /// +--------+-----------------------+--------+--------+-----------------------+--------+
+    ///                                      ^        ^
+    ///                                      |        |
+    ///                     Previous block tag        Current block
+    /// ```
+    ///
+    /// By doing this, the block placed right after `node` can locate `node`'s header
+    /// by reading the word that precedes its own header. See [`Block::prev_in_region`].
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to a valid block header whose size covers the footer.
+    #[inline]
+    pub(crate) unsafe fn write_footer(node: NonNull<Node<Block>>) {
+        unsafe {
+            Self::footer(node).write(node.as_ref().data.size & !PADDED_BIT);
+        }
+    }
+
+    /// Returns whether the pages of the used block `node` are sealed.
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to a valid block header.
+    #[inline]
+    pub(crate) unsafe fn is_sealed(node: NonNull<Node<Block>>) -> bool {
+        unsafe {
+            // Fenced blocks have no footer, and they are never sealed. See `fence`.
+            if node.as_ref().data.region.as_ref().data.kind == RegionKind::Fenced {
+                return false;
+            }
+
+            Self::footer(node).read() & SEALED_BIT != 0
+        }
+    }
+
+    /// Records in the footer of the used block `node` whether its pages are sealed.
+    /// The flag is dropped as soon as the footer is written again, so it must be
+    /// cleared before the block is freed.
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to a valid used block header.
+    #[inline]
+    pub(crate) unsafe fn set_sealed(node: NonNull<Node<Block>>, sealed: bool) {
+        unsafe {
+            let footer = Self::footer(node);
+
+            if sealed {
+                footer.write(footer.read() | SEALED_BIT);
+            } else {
+                footer.write(footer.read() & !SEALED_BIT);
+            }
+        }
+    }
+
+    /// Returns the footer of the used block `node` as an atomic. Threads that don't hold
+    /// the lock mark their blocks as queued in it while the lock holder may be reading it
+    /// to find the next block's neighbour. See [`Block::prev_in_region`].
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to a valid used block header of a region with footers.
+    #[inline]
+    unsafe fn atomic_footer<'a>(node: NonNull<Node<Block>>) -> &'a AtomicUsize {
+        unsafe { AtomicUsize::from_ptr(Self::footer(node)) }
+    }
+
+    /// Returns whether the deallocation of the used block `node` is queued. See the
+    /// `deferred` module.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`Block::atomic_footer`].
+    #[inline]
+    pub(crate) unsafe fn is_queued(node: NonNull<Node<Block>>) -> bool {
+        unsafe { Self::atomic_footer(node).load(Ordering::Relaxed) & QUEUED_BIT != 0 }
+    }
+
+    /// Marks the deallocation of the used block `node` as queued, or not. Marking it
+    /// returns whether it already was, so if two threads free the same block at once only
+    /// one of them queues it. The flag is dropped as soon as the footer is written again.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`Block::atomic_footer`].
+    #[inline]
+    pub(crate) unsafe fn set_queued(node: NonNull<Node<Block>>, queued: bool) -> bool {
+        unsafe {
+            let footer = Self::atomic_footer(node);
+
+            let old = if queued {
+                footer.fetch_or(QUEUED_BIT, Ordering::Relaxed)
+            } else {
+                footer.fetch_and(!QUEUED_BIT, Ordering::Relaxed)
+            };
+
+            old & QUEUED_BIT != 0
+        }
+    }
+
+    /// Returns the block that is placed just before `node` in memory, or `None`
+    /// if `node` is the first block of its region.
+    ///
+    /// This is done in constant time by reading the boundary tag of the previous block,
+    /// so it does not depend on the [`Region::blocks`] list.
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to a valid block header whose neighbours have valid footers.
+    #[inline]
+    pub(crate) unsafe fn prev_in_region(node: NonNull<Node<Block>>) -> Option<NonNull<Node<Block>>> {
+        unsafe {
+            let region = node.as_ref().data.region;
+            let first_block = (region.as_ptr() as *mut u8).add(REGION_HEADER_SIZE);
+            let addr = node.as_ptr() as *mut u8;
+
+            if addr == first_block {
+                return None;
+            }
+
+            // The footer of a used block may be marked as queued at the same time, see
+            // `Block::set_queued`.
+            let tag = AtomicUsize::from_ptr((addr as *mut usize).sub(1)).load(Ordering::Relaxed);
+            let prev_size = tag & !FLAGS_MASK;
+
+            Some(NonNull::new_unchecked(addr.sub(BLOCK_HEADER_SIZE + prev_size)).cast())
+        }
+    }
+
+    /// Returns the block that is placed just after `node` in memory, or `None`
+    /// if `node` is the last block of its region.
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to a valid block header.
+    #[inline]
+    pub(crate) unsafe fn next_in_region(node: NonNull<Node<Block>>) -> Option<NonNull<Node<Block>>> {
+        unsafe {
+            let block = &node.as_ref().data;
+            let region = block.region;
+            let region_end = (region.as_ptr() as *mut u8).add(REGION_HEADER_SIZE + region.as_ref().data.size);
+            let next = (node.as_ptr() as *mut u8).add(BLOCK_HEADER_SIZE + block.size());
+
+            if next >= region_end {
+                return None;
+            }
+
+            Some(NonNull::new_unchecked(next).cast())
+        }
+    }
+
+    /// Stores the information needed to find the header of `node` from the
+    /// `payload` pointer given to the user and marks the block as padded if needed.
+    /// See [`Block::from_payload`].
+    ///
+    /// # Safety
+    ///
+    /// `payload` must be a word-aligned address inside `node`'s content.
+    #[inline]
+    pub(crate) unsafe fn reflect(node: NonNull<Node<Block>>, payload: *mut u8) {
+        unsafe {
+            let payload_start = (node.as_ptr() as *mut u8).add(BLOCK_HEADER_SIZE);
+
+            // Without padding, the word before the payload is already the size word
+            // of the header, so there is nothing to store.
+            if payload != payload_start {
+                (payload as *mut usize).sub(1).write(node.as_ptr().addr() | PADDED_BIT);
+                (*node.as_ptr()).data.size |= PADDED_BIT;
+                (*node.as_ptr()).data.update_checksum();
+            }
+        }
+    }
+
+    /// Points the reflection word of the padded used block `node` back to `node` after
+    /// its bytes have been copied from `old_node`. See [`crate::Snapshot`].
+    ///
+    /// We don't know where the payload starts, so every word of the first `window` bytes
+    /// of the content that reflects `old_node` is rewritten. Stale reflection words of
+    /// previous allocations can only be in the padding, and user data is very unlikely to
+    /// hold one since they are not valid pointers.
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to a valid used block header.
+    pub(crate) unsafe fn relocate_reflection(node: NonNull<Node<Block>>, old_node: usize, window: usize) {
+        unsafe {
+            let content = (node.as_ptr() as *mut u8).add(BLOCK_HEADER_SIZE) as *mut usize;
+            let words = node.as_ref().data.size().min(window) / mem::size_of::<usize>();
+
+            for index in 0..words {
+                if content.add(index).read() == old_node | PADDED_BIT {
+                    content.add(index).write(node.as_ptr().addr() | PADDED_BIT);
+                }
+            }
+        }
+    }
+
+    /// Returns the header of the block that contains the given `payload`.
+    ///
+    /// The word that precedes the payload is either:
+    /// - The size word of the header, when the payload starts right after it.
+    /// - A pointer to the header tagged with `PADDED_BIT`, when there is padding.
+    ///
+    /// ```text
+    /// [ Node<Block> ] [ User Data (ptr) ]
+    ///            ^
+    ///            size word (PADDED_BIT never set)
+    ///
+    /// [ Node<Block> ] [ ... Padding ... ] [ Ptr to Node | PADDED_BIT ] [ User Data (ptr) ]
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// `payload` must have been returned by [`crate::kernel::Kernel::take_from_block`],
+    /// otherwise the returned header is garbage.
+    #[inline]
+    pub(crate) unsafe fn from_payload(payload: *mut u8) -> NonNull<Node<Block>> {
+        unsafe {
+            let word = (payload as *mut usize).sub(1).read();
+
+            let header = if word & PADDED_BIT != 0 {
+                payload.with_addr(word & !FLAGS_MASK).cast::<Node<Block>>()
+            } else {
+                payload.sub(BLOCK_HEADER_SIZE) as *mut Node<Block>
+            };
+
+            NonNull::new_unchecked(header)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "checksums"))]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::*;
+    use crate::MemAlloc;
+
+    #[test]
+    fn checksums_follow_every_header_change() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let padded = Layout::from_size_align(100, 64).unwrap();
+
+        unsafe {
+            let ptrs = [layout, padded, layout, layout].map(|layout| allocator.allocate(layout));
+
+            // Splitting, padding and merging blocks keep their checksums up to date.
+            allocator.deallocate(ptrs[2], layout);
+            allocator.deallocate(ptrs[1], padded);
+
+            {
+                let kernel = allocator.allocator.lock();
+                let region = kernel.regions.first().unwrap();
+                let mut block = region.as_ref().data.blocks.first();
+
+                while let Some(node) = block {
+                    let data = &node.as_ref().data;
+                    assert_eq!(data.checksum, data.checksum());
+                    block = node.as_ref().next;
+                }
+            }
+
+            // An overflow that reaches the next header doesn't match it anymore.
+            let mut node = Block::from_payload(ptrs[3]);
+            node.as_mut().data.size += 64;
+            assert_ne!(node.as_ref().data.checksum, node.as_ref().data.checksum());
+
+            node.as_mut().data.size -= 64;
+            allocator.deallocate(ptrs[3], layout);
+            allocator.deallocate(ptrs[0], layout);
+        }
+    }
+}
@@ -0,0 +1,526 @@
+//! Owning smart pointers over a [`MemAlloc`] instance.
+//!
+//! [`MemAlloc::allocate`] and [`MemAlloc::deallocate`] are unsafe: the caller has to
+//! remember the layout, drop the value and free the memory exactly once. [`AllocBox`]
+//! does all of that, the same way [`Box`] does for the global allocator:
+//!
+//! ```rust
+//! use memalloc::{AllocBox, MemAlloc};
+//!
+//! let allocator = MemAlloc::new();
+//!
+//! let mut numbers = AllocBox::new_in(vec![1, 2, 3], &allocator);
+//! numbers.push(4);
+//!
+//! assert_eq!(*numbers, [1, 2, 3, 4]);
+//! ```
+//!
+//! Slices and strings can be copied into the allocator too, which is handy for parsers
+//! and deserializers that build their output from borrowed input:
+//!
+//! ```rust
+//! use memalloc::MemAlloc;
+//!
+//! let allocator = MemAlloc::new();
+//!
+//! let name = allocator.alloc_str("memalloc");
+//! let bytes = allocator.alloc_slice_copy(name.as_bytes());
+//! let zeros = allocator.alloc_slice_fill_default::<u32>(4);
+//!
+//! assert_eq!(&*name, "memalloc");
+//! assert_eq!(bytes.len(), 8);
+//! assert_eq!(*zeros, [0; 4]);
+//! ```
+//!
+//! Code that doesn't want to write `unsafe` at all can use a [`Heap`], which owns its
+//! allocator and only hands out boxes:
+//!
+//! ```rust
+//! use memalloc::Heap;
+//!
+//! let heap = Heap::new();
+//!
+//! let point = heap.alloc_value((1, 2));
+//! let names = heap.alloc_slice(&[String::from("a"), String::from("b")]);
+//! let squares = heap.alloc_slice_fill_with(4, |i| i * i);
+//!
+//! assert_eq!(*point, (1, 2));
+//! assert_eq!(names[1], "b");
+//! assert_eq!(*squares, [0, 1, 4, 9]);
+//! assert_eq!(heap.allocator().stats().allocations, 3);
+//! ```
+
+use std::{
+    alloc::{self, Layout},
+    fmt,
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+use lock_api::RawMutex;
+
+use crate::{config::Config, memalloc::MemAlloc, sync::{self, DefaultRawMutex}};
+
+/// A pointer type that owns a value of type `T` allocated by a [`MemAlloc`]. The value
+/// is dropped and its memory deallocated when the `AllocBox` goes out of scope.
+///
+/// `T` can also be a slice or a `str`, see [`MemAlloc::alloc_slice_copy`].
+pub struct AllocBox<'a, T: ?Sized, R: RawMutex = DefaultRawMutex> {
+    ptr: NonNull<T>,
+    allocator: &'a MemAlloc<R>,
+    /// We own a `T`, see the drop check section of [`PhantomData`].
+    _owns: PhantomData<T>,
+}
+
+impl<'a, T, R: RawMutex> AllocBox<'a, T, R> {
+    /// Moves `value` into memory taken from `allocator`.
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn new_in(value: T, allocator: &'a MemAlloc<R>) -> Self {
+        let layout = Layout::new::<T>();
+
+        // Zero sized values don't need any memory.
+        let ptr: NonNull<T> = if layout.size() == 0 {
+            NonNull::dangling()
+        } else {
+            let ptr = unsafe { allocator.allocate(layout) };
+            NonNull::new(ptr.cast()).unwrap_or_else(|| alloc::handle_alloc_error(layout))
+        };
+
+        unsafe { ptr.as_ptr().write(value) };
+
+        unsafe { Self::from_raw_in(ptr, allocator) }
+    }
+
+    /// Moves the value out of the box and deallocates its memory.
+    pub fn into_inner(this: Self) -> T {
+        let this = ManuallyDrop::new(this);
+
+        unsafe {
+            let value = this.ptr.as_ptr().read();
+            this.free(Layout::new::<T>());
+            value
+        }
+    }
+
+}
+
+impl<'a, T: ?Sized, R: RawMutex> AllocBox<'a, T, R> {
+    /// Takes ownership of the value at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to an initialized value whose memory was allocated by `allocator`
+    /// with the layout of the value, or be dangling if the value is zero sized.
+    pub(crate) unsafe fn from_raw_in(ptr: NonNull<T>, allocator: &'a MemAlloc<R>) -> Self {
+        Self { ptr, allocator, _owns: PhantomData }
+    }
+
+    /// Returns the allocator that owns the memory of the box.
+    pub fn allocator(this: &Self) -> &'a MemAlloc<R> {
+        this.allocator
+    }
+
+    /// Deallocates the memory of the given `layout` without dropping the value.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called once, and the value must not be used afterwards.
+    unsafe fn free(&self, layout: Layout) {
+        if layout.size() != 0 {
+            unsafe { self.allocator.deallocate(self.ptr.as_ptr().cast(), layout) };
+        }
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Copies `src` into memory taken from this allocator.
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn alloc_slice_copy<T: Copy>(&self, src: &[T]) -> AllocBox<'_, [T], R> {
+        unsafe {
+            let ptr = self.allocate_array::<T>(src.len());
+            ptr::copy_nonoverlapping(src.as_ptr(), ptr.as_ptr(), src.len());
+
+            AllocBox::from_raw_in(NonNull::slice_from_raw_parts(ptr, src.len()), self)
+        }
+    }
+
+    /// Allocates a slice of `len` elements initialized with [`Default::default`].
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn alloc_slice_fill_default<T: Default>(&self, len: usize) -> AllocBox<'_, [T], R> {
+        self.alloc_slice_fill_with(len, |_| T::default())
+    }
+
+    /// Clones the elements of `src` into memory taken from this allocator.
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn alloc_slice_clone<T: Clone>(&self, src: &[T]) -> AllocBox<'_, [T], R> {
+        self.alloc_slice_fill_with(src.len(), |i| src[i].clone())
+    }
+
+    /// Allocates a slice of `len` elements where the element at index `i` is `f(i)`. If
+    /// `f` panics, the elements created so far are dropped and the memory is freed.
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn alloc_slice_fill_with<T>(&self, len: usize, mut f: impl FnMut(usize) -> T) -> AllocBox<'_, [T], R> {
+        let mut slice = PartialSlice { ptr: self.allocate_array::<T>(len), len: 0, capacity: len, allocator: self };
+
+        while slice.len < len {
+            unsafe { slice.ptr.as_ptr().add(slice.len).write(f(slice.len)) };
+            slice.len += 1;
+        }
+
+        let slice = ManuallyDrop::new(slice);
+
+        unsafe { AllocBox::from_raw_in(NonNull::slice_from_raw_parts(slice.ptr, len), self) }
+    }
+
+    /// Copies the string `src` into memory taken from this allocator.
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn alloc_str(&self, src: &str) -> AllocBox<'_, str, R> {
+        let bytes = ManuallyDrop::new(self.alloc_slice_copy(src.as_bytes()));
+
+        // `str` has the same layout as `[u8]` and the bytes come from a `str`.
+        unsafe { AllocBox::from_raw_in(NonNull::new_unchecked(bytes.ptr.as_ptr() as *mut str), self) }
+    }
+
+    /// Allocates uninitialized memory for `len` values of type `T`. Returns a dangling
+    /// pointer if no memory is needed.
+    #[track_caller]
+    fn allocate_array<T>(&self, len: usize) -> NonNull<T> {
+        let Ok(layout) = Layout::array::<T>(len) else {
+            panic!("capacity overflow");
+        };
+
+        if layout.size() == 0 {
+            return NonNull::dangling();
+        }
+
+        let ptr = unsafe { self.allocate(layout) };
+        NonNull::new(ptr.cast()).unwrap_or_else(|| alloc::handle_alloc_error(layout))
+    }
+}
+
+/// A heap with its own [`MemAlloc`] that can only be used through safe methods, which
+/// return [`AllocBox`]es that free their memory when they are dropped. See the `boxed`
+/// module.
+///
+/// The allocator is dropped with the heap, so the boxes borrow it and can't outlive it.
+pub struct Heap<R: RawMutex = DefaultRawMutex> {
+    allocator: MemAlloc<R>,
+}
+
+impl Heap {
+    sync::const_fn! {
+        /// Creates a heap with the default configuration.
+        pub const fn new() -> Self {
+            Self::with_config(Config::new())
+        }
+    }
+
+    sync::const_fn! {
+        /// Creates a heap that uses the given `config`. See [`Config`].
+        pub const fn with_config(config: Config) -> Self {
+            Self::with_lock(config)
+        }
+    }
+}
+
+impl<R: RawMutex> Heap<R> {
+    sync::const_fn! {
+        /// Creates a heap that uses the given `config` and the raw mutex `R`. See
+        /// [`MemAlloc::with_lock`].
+        pub const fn with_lock(config: Config) -> Self {
+            Self { allocator: MemAlloc::with_lock(config) }
+        }
+    }
+
+    /// Returns the allocator of the heap, whose safe methods tell how it is doing, like
+    /// [`MemAlloc::stats`], or give memory back, like [`MemAlloc::trim`].
+    pub fn allocator(&self) -> &MemAlloc<R> {
+        &self.allocator
+    }
+
+    /// Moves `value` into the heap. See [`AllocBox::new_in`].
+    #[track_caller]
+    pub fn alloc_value<T>(&self, value: T) -> AllocBox<'_, T, R> {
+        AllocBox::new_in(value, &self.allocator)
+    }
+
+    /// Clones the elements of `src` into the heap. See [`MemAlloc::alloc_slice_clone`].
+    #[track_caller]
+    pub fn alloc_slice<T: Clone>(&self, src: &[T]) -> AllocBox<'_, [T], R> {
+        self.allocator.alloc_slice_clone(src)
+    }
+
+    /// Copies `src` into the heap. See [`MemAlloc::alloc_slice_copy`].
+    #[track_caller]
+    pub fn alloc_slice_copy<T: Copy>(&self, src: &[T]) -> AllocBox<'_, [T], R> {
+        self.allocator.alloc_slice_copy(src)
+    }
+
+    /// Allocates a slice of `len` elements made by `f` from their index. See
+    /// [`MemAlloc::alloc_slice_fill_with`].
+    #[track_caller]
+    pub fn alloc_slice_fill_with<T>(&self, len: usize, f: impl FnMut(usize) -> T) -> AllocBox<'_, [T], R> {
+        self.allocator.alloc_slice_fill_with(len, f)
+    }
+
+    /// Allocates a slice of `len` default elements. See
+    /// [`MemAlloc::alloc_slice_fill_default`].
+    #[track_caller]
+    pub fn alloc_slice_fill_default<T: Default>(&self, len: usize) -> AllocBox<'_, [T], R> {
+        self.allocator.alloc_slice_fill_default(len)
+    }
+
+    /// Copies the string `src` into the heap. See [`MemAlloc::alloc_str`].
+    #[track_caller]
+    pub fn alloc_str(&self, src: &str) -> AllocBox<'_, str, R> {
+        self.allocator.alloc_str(src)
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: RawMutex> fmt::Debug for Heap<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Heap").field("allocator", &self.allocator).finish()
+    }
+}
+
+/// Slice being filled by [`MemAlloc::alloc_slice_fill_with`]. If filling it panics, the
+/// elements written so far are dropped and the memory is freed.
+struct PartialSlice<'a, T, R: RawMutex> {
+    ptr: NonNull<T>,
+    /// Number of elements written.
+    len: usize,
+    /// Number of elements the memory was allocated for.
+    capacity: usize,
+    allocator: &'a MemAlloc<R>,
+}
+
+impl<T, R: RawMutex> Drop for PartialSlice<'_, T, R> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(self.ptr.as_ptr(), self.len));
+
+            let layout = Layout::array::<T>(self.capacity).unwrap_unchecked();
+
+            if layout.size() != 0 {
+                self.allocator.deallocate(self.ptr.as_ptr().cast(), layout);
+            }
+        }
+    }
+}
+
+impl<T: ?Sized, R: RawMutex> Drop for AllocBox<'_, T, R> {
+    fn drop(&mut self) {
+        unsafe {
+            // The value can't be used to compute its layout once it is dropped.
+            let layout = Layout::for_value(self.ptr.as_ref());
+
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.free(layout);
+        }
+    }
+}
+
+impl<T: ?Sized, R: RawMutex> Deref for AllocBox<'_, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: ?Sized, R: RawMutex> DerefMut for AllocBox<'_, T, R> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: ?Sized, R: RawMutex> AsRef<T> for AllocBox<'_, T, R> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized, R: RawMutex> AsMut<T> for AllocBox<'_, T, R> {
+    fn as_mut(&mut self) -> &mut T {
+        self
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, R: RawMutex> fmt::Debug for AllocBox<'_, T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display, R: RawMutex> fmt::Display for AllocBox<'_, T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+// Same as `Box`: the box can be sent or shared if the value can, and the allocator
+// reference can be shared between threads.
+unsafe impl<T: ?Sized + Send, R: RawMutex + Sync> Send for AllocBox<'_, T, R> {}
+unsafe impl<T: ?Sized + Sync, R: RawMutex + Sync> Sync for AllocBox<'_, T, R> {}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+
+    /// Counts how many times it has been dropped.
+    struct DropCounter(Rc<Cell<usize>>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn value_is_dropped_and_freed() {
+        let allocator = MemAlloc::new();
+        let drops = Rc::new(Cell::new(0));
+
+        {
+            let value = AllocBox::new_in(DropCounter(drops.clone()), &allocator);
+            assert!(allocator.owns_allocation(&*value as *const DropCounter as *const u8));
+        }
+
+        assert_eq!(drops.get(), 1);
+        assert!(allocator.allocator.lock().regions.is_empty());
+    }
+
+    #[test]
+    fn into_inner_moves_the_value_out() {
+        let allocator = MemAlloc::new();
+        let drops = Rc::new(Cell::new(0));
+
+        let value = AllocBox::new_in(DropCounter(drops.clone()), &allocator);
+        let inner = AllocBox::into_inner(value);
+
+        assert_eq!(drops.get(), 0);
+        assert!(allocator.allocator.lock().regions.is_empty());
+
+        drop(inner);
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn zero_sized_and_over_aligned_values() {
+        #[repr(align(4096))]
+        struct Page([u8; 4096]);
+
+        let allocator = MemAlloc::new();
+
+        let unit = AllocBox::new_in((), &allocator);
+        assert_eq!(*unit, ());
+
+        let mut page = AllocBox::new_in(Page([0; 4096]), &allocator);
+        page.0[4095] = 1;
+        assert_eq!(&*page as *const Page as usize % 4096, 0);
+        assert_eq!(page.0[4095], 1);
+    }
+
+    #[test]
+    fn slices_and_strings_are_copied() {
+        let allocator = MemAlloc::new();
+
+        let numbers = allocator.alloc_slice_copy(&[1u64, 2, 3]);
+        assert_eq!(*numbers, [1, 2, 3]);
+        assert!(allocator.owns_allocation(numbers.as_ptr().cast()));
+
+        let text = allocator.alloc_str("hello");
+        assert_eq!(&*text, "hello");
+
+        let empty = allocator.alloc_str("");
+        assert!(empty.is_empty());
+
+        let strings = allocator.alloc_slice_fill_default::<String>(3);
+        assert!(strings.iter().all(String::is_empty));
+
+        drop((numbers, text, empty, strings));
+        assert!(allocator.allocator.lock().regions.is_empty());
+    }
+
+    #[test]
+    fn slice_elements_are_dropped() {
+        let allocator = MemAlloc::new();
+        let drops = Rc::new(Cell::new(0));
+
+        #[derive(Default)]
+        struct Counted(Option<DropCounter>);
+
+        let mut slice = allocator.alloc_slice_fill_default::<Counted>(4);
+        for counted in slice.iter_mut() {
+            counted.0 = Some(DropCounter(drops.clone()));
+        }
+
+        drop(slice);
+        assert_eq!(drops.get(), 4);
+    }
+
+    #[test]
+    fn panics_while_filling_drop_what_was_made() {
+        let heap = Heap::new();
+        let drops = Rc::new(Cell::new(0));
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            heap.alloc_slice_fill_with(4, |i| match i {
+                3 => panic!("fourth element"),
+                _ => DropCounter(drops.clone()),
+            })
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(drops.get(), 3);
+        assert_eq!(heap.allocator().stats().allocations, 0);
+    }
+
+    #[test]
+    fn heaps_hand_out_boxes() {
+        let heap = Heap::new();
+
+        let value = heap.alloc_value(7u32);
+        let cloned = heap.alloc_slice(&[String::from("x"), String::from("y")]);
+        let text = heap.alloc_str("heap");
+        assert_eq!((*value, &cloned[..], &*text), (7, &["x".to_string(), "y".to_string()][..], "heap"));
+        assert_eq!(heap.allocator().stats().allocations, 3);
+
+        drop((value, cloned, text));
+        assert_eq!(heap.allocator().stats().allocations, 0);
+    }
+}
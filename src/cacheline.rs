@@ -0,0 +1,180 @@
+//! Cache-line aligned allocations.
+//!
+//! Two values written by different threads that share a cache line make the cores fight
+//! over it even though they never touch the same bytes, which is known as false sharing.
+//! [`MemAlloc::allocate_cacheline`] aligns an allocation to [`CACHE_LINE_SIZE`] and pads
+//! it to a whole number of lines, so no other allocation shares its lines, and
+//! [`CachePadded`] does the same for a value inside another structure:
+//!
+//! ```rust
+//! use std::{alloc::Layout, sync::atomic::AtomicUsize};
+//! use memalloc::{CachePadded, MemAlloc, CACHE_LINE_SIZE};
+//!
+//! let allocator = MemAlloc::new();
+//!
+//! // Each counter is written by a different thread.
+//! let counters = allocator.alloc_slice_fill_default::<CachePadded<AtomicUsize>>(4);
+//! assert_eq!(counters.as_ptr() as usize % CACHE_LINE_SIZE, 0);
+//!
+//! unsafe {
+//!     let layout = Layout::from_size_align(24, 8).unwrap();
+//!     let ptr = allocator.allocate_cacheline(layout);
+//!
+//!     assert_eq!(ptr as usize % CACHE_LINE_SIZE, 0);
+//!     allocator.deallocate_cacheline(ptr, layout);
+//! }
+//! ```
+//!
+//! Like in `crossbeam`, lines are taken to be 128 bytes on x86-64, AArch64 and PowerPC 64,
+//! whose prefetchers pull pairs of 64-byte lines or whose lines are that big, and 64 bytes
+//! anywhere else.
+
+use std::{
+    alloc::Layout,
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+use lock_api::RawMutex;
+
+use crate::memalloc::MemAlloc;
+
+/// Size of the cache lines [`MemAlloc::allocate_cacheline`] and [`CachePadded`] align to.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "powerpc64"))]
+pub const CACHE_LINE_SIZE: usize = 128;
+
+/// Size of the cache lines [`MemAlloc::allocate_cacheline`] and [`CachePadded`] align to.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "powerpc64")))]
+pub const CACHE_LINE_SIZE: usize = 64;
+
+/// Aligns and pads `T` to [`CACHE_LINE_SIZE`], so it never shares a cache line with
+/// anything else. See the `cacheline` module.
+#[cfg_attr(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "powerpc64"), repr(align(128)))]
+#[cfg_attr(not(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "powerpc64")), repr(align(64)))]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CachePadded<T> {
+    value: T,
+}
+
+impl<T> CachePadded<T> {
+    /// Pads `value` to a cache line.
+    pub const fn new(value: T) -> Self {
+        Self { value }
+    }
+
+    /// Returns the padded value.
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T> From<T> for CachePadded<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for CachePadded<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CachePadded").field(&self.value).finish()
+    }
+}
+
+/// Returns `layout` aligned to at least [`CACHE_LINE_SIZE`] and padded to a multiple of
+/// its alignment, or `None` if the size overflows.
+fn cacheline_layout(layout: Layout) -> Option<Layout> {
+    Some(layout.align_to(CACHE_LINE_SIZE).ok()?.pad_to_align())
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Same as [`MemAlloc::allocate`], but the memory is aligned to at least
+    /// [`CACHE_LINE_SIZE`] and its size is rounded up to a multiple of the alignment, so
+    /// no other allocation shares its cache lines. Returns null if the rounded size
+    /// doesn't fit in a layout.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`MemAlloc::allocate`]. The memory must be freed with
+    /// [`MemAlloc::deallocate_cacheline`] and the same `layout`.
+    #[track_caller]
+    pub unsafe fn allocate_cacheline(&self, layout: Layout) -> *mut u8 {
+        match cacheline_layout(layout) {
+            Some(layout) => unsafe { self.allocate(layout) },
+            None => std::ptr::null_mut(),
+        }
+    }
+
+    /// Frees memory returned by [`MemAlloc::allocate_cacheline`].
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by [`MemAlloc::allocate_cacheline`] with `layout`.
+    #[track_caller]
+    pub unsafe fn deallocate_cacheline(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(layout) = cacheline_layout(layout) {
+            unsafe { self.deallocate(ptr, layout) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{mem, sync::atomic::AtomicU8};
+
+    use super::*;
+
+    #[test]
+    fn allocations_take_whole_cache_lines() {
+        let allocator = MemAlloc::new();
+
+        unsafe {
+            for (size, align) in [(1, 1), (CACHE_LINE_SIZE + 1, 8), (8, 4 * CACHE_LINE_SIZE)] {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptrs = [(); 2].map(|_| allocator.allocate_cacheline(layout));
+
+                for ptr in ptrs {
+                    assert_eq!(ptr as usize % layout.align().max(CACHE_LINE_SIZE), 0);
+                }
+
+                // Neither allocation reaches into the lines of the other one.
+                let lines = ptrs.map(|ptr| ptr as usize / CACHE_LINE_SIZE);
+                let len = size.div_ceil(CACHE_LINE_SIZE);
+                assert!(lines[0] + len <= lines[1] || lines[1] + len <= lines[0]);
+
+                for ptr in ptrs {
+                    allocator.deallocate_cacheline(ptr, layout);
+                }
+            }
+
+            assert_eq!(allocator.stats().allocations, 0);
+
+            let overflow = Layout::from_size_align(isize::MAX as usize - 1, 1).unwrap();
+            assert!(allocator.allocate_cacheline(overflow).is_null());
+        }
+    }
+
+    #[test]
+    fn padded_values_fill_cache_lines() {
+        assert_eq!(mem::align_of::<CachePadded<AtomicU8>>(), CACHE_LINE_SIZE);
+        assert_eq!(mem::size_of::<CachePadded<AtomicU8>>(), CACHE_LINE_SIZE);
+        assert_eq!(mem::size_of::<CachePadded<[u8; CACHE_LINE_SIZE + 1]>>(), 2 * CACHE_LINE_SIZE);
+
+        let mut padded = CachePadded::new(1);
+        *padded += 1;
+        assert_eq!(padded.into_inner(), 2);
+    }
+}
@@ -0,0 +1,89 @@
+//! Call-site attribution.
+//!
+//! [`MemAlloc::allocate`] and the rest of allocating methods are `#[track_caller]`, so
+//! with the `call-sites` feature the source location that called them is recorded in the
+//! header of every block. This tells which line allocated a block without capturing a
+//! backtrace, which would be way too expensive (and would need to allocate). Leak reports
+//! include it too, see [`crate::Leak`] when the `leak-scanner` feature is enabled.
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::MemAlloc;
+//!
+//! let allocator = MemAlloc::new();
+//!
+//! unsafe {
+//!     let ptr = allocator.allocate(Layout::new::<u64>());
+//!     let line = line!() - 1;
+//!
+//!     assert_eq!(allocator.call_site(ptr).unwrap().line(), line);
+//!
+//!     allocator.deallocate(ptr, Layout::new::<u64>());
+//! }
+//! ```
+//!
+//! Only the outermost `#[track_caller]` function is recorded, so wrappers around the
+//! allocator should be annotated too. Allocations made through [`std::alloc::GlobalAlloc`]
+//! always point to the implementation of the trait, since the standard library calls it
+//! from functions that don't track their callers.
+
+use std::panic::Location;
+
+use lock_api::RawMutex;
+
+use crate::{memalloc::MemAlloc, sync};
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Returns the source location that allocated `ptr`, or `None` if `ptr` is not a
+    /// live allocation of this allocator.
+    pub fn call_site(&self, ptr: *const u8) -> Option<&'static Location<'static>> {
+        let kernel = sync::lock(&self.allocator);
+        let block = kernel.find_used_block(ptr)?;
+
+        unsafe { block.as_ref().data.call_site }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::*;
+    use crate::block::{BLOCK_FOOTER_SIZE, BLOCK_HEADER_SIZE};
+
+    #[track_caller]
+    fn allocate(allocator: &MemAlloc, layout: Layout) -> *mut u8 {
+        unsafe { allocator.allocate(layout) }
+    }
+
+    #[test]
+    fn call_sites_are_recorded() {
+        let allocator = MemAlloc::new();
+
+        // Every block takes 256 bytes, so moving them keeps their alignment.
+        let layout = Layout::from_size_align(256 - BLOCK_HEADER_SIZE - BLOCK_FOOTER_SIZE, 8).unwrap();
+
+        let (boxed, boxed_line) = (allocator.alloc_str("memalloc"), line!());
+        assert_eq!(allocator.call_site(boxed.as_ptr()).unwrap().line(), boxed_line);
+        drop(boxed);
+
+        let (ptr, line) = (allocate(&allocator, layout), line!());
+        let location = allocator.call_site(ptr).unwrap();
+        assert_eq!((location.file(), location.line()), (file!(), line));
+
+        // Moving a block keeps its call site.
+        unsafe {
+            let (next, next_line) = (allocator.allocate(layout), line!());
+            allocator.deallocate(ptr, layout);
+
+            let mut moved = next;
+            assert_eq!(allocator.compact(|old, new, _| if old == next { moved = new }), 1);
+
+            assert_eq!(moved, ptr);
+            assert_eq!(allocator.call_site(moved).unwrap().line(), next_line);
+            assert_eq!(allocator.call_site(next), None);
+
+            allocator.deallocate(moved, layout);
+        }
+    }
+}
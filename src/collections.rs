@@ -0,0 +1,821 @@
+//! Collections over a [`MemAlloc`] instance.
+//!
+//! The collections of the standard library can only use another allocator through the
+//! unstable `allocator_api`, so on stable a [`MemAlloc`] has to be the global allocator
+//! to hold them. [`AllocVec`], [`AllocString`] and [`AllocHashMap`] work like [`Vec`],
+//! [`String`] and [`HashMap`](std::collections::HashMap) but take their memory from the
+//! allocator they borrow, so every subsystem can have its own heap:
+//!
+//! ```rust
+//! use memalloc::{AllocHashMap, AllocString, AllocVec, MemAlloc};
+//!
+//! let allocator = MemAlloc::new();
+//!
+//! let mut numbers = AllocVec::new_in(&allocator);
+//! numbers.extend([1, 2, 3]);
+//! assert_eq!(*numbers, [1, 2, 3]);
+//!
+//! let mut name = AllocString::from_str_in("mem", &allocator);
+//! name.push_str("alloc");
+//! assert_eq!(name, "memalloc");
+//!
+//! let mut ages = AllocHashMap::new_in(&allocator);
+//! ages.insert("alice", 30);
+//! assert_eq!(ages.get("alice"), Some(&30));
+//! ```
+//!
+//! Only the most common methods are provided. Slices and strings get the rest of theirs
+//! through [`Deref`].
+
+use std::{
+    alloc::{self, Layout},
+    borrow::Borrow,
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hash},
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+use lock_api::RawMutex;
+
+use crate::{memalloc::MemAlloc, sync::DefaultRawMutex};
+
+/// A growable array like [`Vec`] whose memory is taken from a [`MemAlloc`].
+pub struct AllocVec<'a, T, R: RawMutex = DefaultRawMutex> {
+    ptr: NonNull<T>,
+    len: usize,
+    capacity: usize,
+    allocator: &'a MemAlloc<R>,
+    /// We own the elements, see the drop check section of [`PhantomData`].
+    _owns: PhantomData<T>,
+}
+
+impl<'a, T, R: RawMutex> AllocVec<'a, T, R> {
+    /// Creates an empty vector that doesn't allocate until elements are pushed.
+    pub const fn new_in(allocator: &'a MemAlloc<R>) -> Self {
+        // Zero sized elements never need any memory.
+        let capacity = if mem::size_of::<T>() == 0 { usize::MAX } else { 0 };
+
+        Self { ptr: NonNull::dangling(), len: 0, capacity, allocator, _owns: PhantomData }
+    }
+
+    /// Creates an empty vector with room for at least `capacity` elements.
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn with_capacity_in(capacity: usize, allocator: &'a MemAlloc<R>) -> Self {
+        let mut vec = Self::new_in(allocator);
+        vec.reserve(capacity);
+
+        vec
+    }
+
+    /// Returns the allocator that owns the memory of the vector.
+    pub fn allocator(&self) -> &'a MemAlloc<R> {
+        self.allocator
+    }
+
+    /// Number of elements the vector can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Makes room for at least `additional` more elements.
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn reserve(&mut self, additional: usize) {
+        let Some(needed) = self.len.checked_add(additional) else {
+            panic!("capacity overflow");
+        };
+
+        if needed <= self.capacity {
+            return;
+        }
+
+        // Doubling keeps pushes amortized constant time.
+        let capacity = needed.max(self.capacity * 2).max(4);
+        let Ok(layout) = Layout::array::<T>(capacity) else {
+            panic!("capacity overflow");
+        };
+
+        let ptr = unsafe {
+            if self.capacity == 0 {
+                self.allocator.allocate(layout)
+            } else {
+                let old_layout = Layout::array::<T>(self.capacity).unwrap();
+                self.allocator.reallocate(self.ptr.as_ptr().cast(), old_layout, layout.size())
+            }
+        };
+
+        self.ptr = NonNull::new(ptr.cast()).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        self.capacity = capacity;
+    }
+
+    /// Appends `value` to the end of the vector.
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn push(&mut self, value: T) {
+        if self.len == self.capacity {
+            self.reserve(1);
+        }
+
+        unsafe { self.ptr.as_ptr().add(self.len).write(value) };
+        self.len += 1;
+    }
+
+    /// Removes the last element and returns it, or `None` if the vector is empty.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        self.len -= 1;
+        Some(unsafe { self.ptr.as_ptr().add(self.len).read() })
+    }
+
+    /// Inserts `value` at `index`, shifting the elements after it to the right.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > len`, and calls [`alloc::handle_alloc_error`] if the
+    /// allocation fails.
+    #[track_caller]
+    pub fn insert(&mut self, index: usize, value: T) {
+        assert!(index <= self.len, "insertion index {index} is out of bounds (len {})", self.len);
+
+        if self.len == self.capacity {
+            self.reserve(1);
+        }
+
+        unsafe {
+            let slot = self.ptr.as_ptr().add(index);
+            ptr::copy(slot, slot.add(1), self.len - index);
+            slot.write(value);
+        }
+
+        self.len += 1;
+    }
+
+    /// Removes and returns the element at `index`, shifting the elements after it to the
+    /// left.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index >= len`.
+    #[track_caller]
+    pub fn remove(&mut self, index: usize) -> T {
+        assert!(index < self.len, "removal index {index} is out of bounds (len {})", self.len);
+
+        unsafe {
+            let slot = self.ptr.as_ptr().add(index);
+            let value = slot.read();
+            ptr::copy(slot.add(1), slot, self.len - index - 1);
+            self.len -= 1;
+
+            value
+        }
+    }
+
+    /// Removes and returns the element at `index`, replacing it with the last one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index >= len`.
+    #[track_caller]
+    pub fn swap_remove(&mut self, index: usize) -> T {
+        assert!(index < self.len, "removal index {index} is out of bounds (len {})", self.len);
+
+        let last = self.len - 1;
+        self.swap(index, last);
+        self.pop().unwrap()
+    }
+
+    /// Drops the elements from `len` on, keeping the capacity.
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+
+        let tail = ptr::slice_from_raw_parts_mut(unsafe { self.ptr.as_ptr().add(len) }, self.len - len);
+
+        // The length goes first, so a panicking destructor doesn't drop them twice.
+        self.len = len;
+        unsafe { ptr::drop_in_place(tail) };
+    }
+
+    /// Drops every element, keeping the capacity.
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+}
+
+impl<T: Clone, R: RawMutex> AllocVec<'_, T, R> {
+    /// Clones and appends every element of `values`.
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn extend_from_slice(&mut self, values: &[T]) {
+        self.reserve(values.len());
+        self.extend(values.iter().cloned());
+    }
+}
+
+impl<T, R: RawMutex> Drop for AllocVec<'_, T, R> {
+    fn drop(&mut self) {
+        self.clear();
+
+        if mem::size_of::<T>() != 0 && self.capacity != 0 {
+            unsafe {
+                let layout = Layout::array::<T>(self.capacity).unwrap();
+                self.allocator.deallocate(self.ptr.as_ptr().cast(), layout);
+            }
+        }
+    }
+}
+
+impl<T, R: RawMutex> Deref for AllocVec<'_, T, R> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { NonNull::slice_from_raw_parts(self.ptr, self.len).as_ref() }
+    }
+}
+
+impl<T, R: RawMutex> DerefMut for AllocVec<'_, T, R> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { NonNull::slice_from_raw_parts(self.ptr, self.len).as_mut() }
+    }
+}
+
+impl<T, R: RawMutex> Extend<T> for AllocVec<'_, T, R> {
+    #[track_caller]
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+
+        for value in iter {
+            self.push(value);
+        }
+    }
+}
+
+impl<'v, T, R: RawMutex> IntoIterator for &'v AllocVec<'_, T, R> {
+    type Item = &'v T;
+    type IntoIter = std::slice::Iter<'v, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'v, T, R: RawMutex> IntoIterator for &'v mut AllocVec<'_, T, R> {
+    type Item = &'v mut T;
+    type IntoIter = std::slice::IterMut<'v, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+impl<'a, T: Clone, R: RawMutex> Clone for AllocVec<'a, T, R> {
+    fn clone(&self) -> Self {
+        let mut vec = Self::with_capacity_in(self.len, self.allocator);
+        vec.extend_from_slice(self);
+
+        vec
+    }
+}
+
+impl<T: PartialEq, R: RawMutex> PartialEq for AllocVec<'_, T, R> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Eq, R: RawMutex> Eq for AllocVec<'_, T, R> {}
+
+impl<T: fmt::Debug, R: RawMutex> fmt::Debug for AllocVec<'_, T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+// Same as `Vec`: the vector can be sent or shared if the elements can, and the
+// allocator reference can be shared between threads.
+unsafe impl<T: Send, R: RawMutex + Sync> Send for AllocVec<'_, T, R> {}
+unsafe impl<T: Sync, R: RawMutex + Sync> Sync for AllocVec<'_, T, R> {}
+
+/// A growable UTF-8 string like [`String`] whose memory is taken from a [`MemAlloc`].
+pub struct AllocString<'a, R: RawMutex = DefaultRawMutex> {
+    bytes: AllocVec<'a, u8, R>,
+}
+
+impl<'a, R: RawMutex> AllocString<'a, R> {
+    /// Creates an empty string that doesn't allocate until text is pushed.
+    pub const fn new_in(allocator: &'a MemAlloc<R>) -> Self {
+        Self { bytes: AllocVec::new_in(allocator) }
+    }
+
+    /// Creates an empty string with room for at least `capacity` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn with_capacity_in(capacity: usize, allocator: &'a MemAlloc<R>) -> Self {
+        Self { bytes: AllocVec::with_capacity_in(capacity, allocator) }
+    }
+
+    /// Copies `text` into a new string.
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn from_str_in(text: &str, allocator: &'a MemAlloc<R>) -> Self {
+        let mut string = Self::with_capacity_in(text.len(), allocator);
+        string.push_str(text);
+
+        string
+    }
+
+    /// Returns the allocator that owns the memory of the string.
+    pub fn allocator(&self) -> &'a MemAlloc<R> {
+        self.bytes.allocator()
+    }
+
+    /// Number of bytes the string can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.bytes.capacity()
+    }
+
+    /// Returns the string as a `&str`.
+    pub fn as_str(&self) -> &str {
+        // Only whole strings and characters are ever pushed.
+        unsafe { std::str::from_utf8_unchecked(&self.bytes) }
+    }
+
+    /// Makes room for at least `additional` more bytes.
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn reserve(&mut self, additional: usize) {
+        self.bytes.reserve(additional);
+    }
+
+    /// Appends `text` to the end of the string.
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn push_str(&mut self, text: &str) {
+        self.bytes.extend_from_slice(text.as_bytes());
+    }
+
+    /// Appends `c` to the end of the string.
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn push(&mut self, c: char) {
+        self.push_str(c.encode_utf8(&mut [0; 4]));
+    }
+
+    /// Removes the last character and returns it, or `None` if the string is empty.
+    pub fn pop(&mut self) -> Option<char> {
+        let c = self.as_str().chars().next_back()?;
+        self.bytes.truncate(self.bytes.len - c.len_utf8());
+
+        Some(c)
+    }
+
+    /// Shortens the string to `len` bytes, keeping the capacity.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `len` doesn't lie on a character boundary.
+    #[track_caller]
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.bytes.len {
+            assert!(self.is_char_boundary(len), "new length {len} is not a char boundary");
+            self.bytes.truncate(len);
+        }
+    }
+
+    /// Removes all the text, keeping the capacity.
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+    }
+}
+
+impl<R: RawMutex> Deref for AllocString<'_, R> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<R: RawMutex> DerefMut for AllocString<'_, R> {
+    fn deref_mut(&mut self) -> &mut str {
+        unsafe { std::str::from_utf8_unchecked_mut(&mut self.bytes) }
+    }
+}
+
+impl<R: RawMutex> fmt::Write for AllocString<'_, R> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        self.push_str(text);
+        Ok(())
+    }
+}
+
+impl<R: RawMutex> Clone for AllocString<'_, R> {
+    fn clone(&self) -> Self {
+        Self { bytes: self.bytes.clone() }
+    }
+}
+
+impl<R: RawMutex> PartialEq for AllocString<'_, R> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl<R: RawMutex> Eq for AllocString<'_, R> {}
+
+impl<R: RawMutex> PartialEq<str> for AllocString<'_, R> {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl<R: RawMutex> PartialEq<&str> for AllocString<'_, R> {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl<R: RawMutex> Hash for AllocString<'_, R> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state);
+    }
+}
+
+impl<R: RawMutex> Borrow<str> for AllocString<'_, R> {
+    fn borrow(&self) -> &str {
+        self
+    }
+}
+
+impl<R: RawMutex> fmt::Debug for AllocString<'_, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl<R: RawMutex> fmt::Display for AllocString<'_, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+/// A hash map like [`HashMap`](std::collections::HashMap) whose memory is taken from a
+/// [`MemAlloc`].
+///
+/// The entries are kept in a single table with linear probing, which is grown to twice
+/// its size when it is three quarters full. Removed entries shift the ones after them
+/// back, so there are no tombstones.
+pub struct AllocHashMap<'a, K, V, R: RawMutex = DefaultRawMutex, S = RandomState> {
+    /// Power of two number of slots, or none until the first insertion.
+    slots: AllocVec<'a, Option<(K, V)>, R>,
+    len: usize,
+    hasher: S,
+}
+
+impl<'a, K, V, R: RawMutex> AllocHashMap<'a, K, V, R> {
+    /// Creates an empty map that doesn't allocate until entries are inserted.
+    pub fn new_in(allocator: &'a MemAlloc<R>) -> Self {
+        Self::with_hasher_in(RandomState::new(), allocator)
+    }
+}
+
+impl<'a, K, V, R: RawMutex, S> AllocHashMap<'a, K, V, R, S> {
+    /// Creates an empty map that hashes its keys with `hasher`.
+    pub const fn with_hasher_in(hasher: S, allocator: &'a MemAlloc<R>) -> Self {
+        Self { slots: AllocVec::new_in(allocator), len: 0, hasher }
+    }
+
+    /// Returns the allocator that owns the memory of the map.
+    pub fn allocator(&self) -> &'a MemAlloc<R> {
+        self.slots.allocator()
+    }
+
+    /// Number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the map has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Removes every entry, keeping the table.
+    pub fn clear(&mut self) {
+        self.slots.iter_mut().for_each(|slot| *slot = None);
+        self.len = 0;
+    }
+
+    /// Iterates over the entries in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.slots.iter().filter_map(|slot| slot.as_ref().map(|(key, value)| (key, value)))
+    }
+
+    /// Iterates over the entries in no particular order, with mutable values.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+        self.slots.iter_mut().filter_map(|slot| slot.as_mut().map(|(key, value)| (&*key, value)))
+    }
+
+    /// Iterates over the keys in no particular order.
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    /// Iterates over the values in no particular order.
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+}
+
+impl<K: Hash + Eq, V, R: RawMutex, S: BuildHasher> AllocHashMap<'_, K, V, R, S> {
+    /// Slot where the probe for `key` starts.
+    fn home<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        self.hasher.hash_one(key) as usize & (self.slots.len() - 1)
+    }
+
+    /// Slot holding `key`, if any.
+    fn find<Q>(&self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.len == 0 {
+            return None;
+        }
+
+        let mask = self.slots.len() - 1;
+        let mut index = self.home(key);
+
+        // The table is never full, so there is always an empty slot to stop at.
+        while let Some((existing, _)) = &self.slots[index] {
+            if existing.borrow() == key {
+                return Some(index);
+            }
+
+            index = (index + 1) & mask;
+        }
+
+        None
+    }
+
+    /// Returns the value of `key`, if any.
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let index = self.find(key)?;
+        self.slots[index].as_ref().map(|(_, value)| value)
+    }
+
+    /// Returns the value of `key` mutably, if any.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let index = self.find(key)?;
+        self.slots[index].as_mut().map(|(_, value)| value)
+    }
+
+    /// Whether the map has an entry for `key`.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find(key).is_some()
+    }
+
+    /// Inserts `value` for `key`, returning the value it replaces, if any.
+    ///
+    /// # Panics
+    ///
+    /// Calls [`alloc::handle_alloc_error`] if the allocation fails.
+    #[track_caller]
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(index) = self.find(&key) {
+            let (_, old) = self.slots[index].as_mut().unwrap();
+            return Some(mem::replace(old, value));
+        }
+
+        if (self.len + 1) * 4 > self.slots.len() * 3 {
+            self.grow();
+        }
+
+        let mask = self.slots.len() - 1;
+        let mut index = self.home(&key);
+
+        while self.slots[index].is_some() {
+            index = (index + 1) & mask;
+        }
+
+        self.slots[index] = Some((key, value));
+        self.len += 1;
+
+        None
+    }
+
+    /// Removes the entry of `key`, returning its value, if any.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut hole = self.find(key)?;
+        let (_, value) = self.slots[hole].take().unwrap();
+        self.len -= 1;
+
+        // Shift back the entries that probed past the hole, so lookups don't stop at it.
+        let mask = self.slots.len() - 1;
+        let mut index = (hole + 1) & mask;
+
+        while let Some((existing, _)) = &self.slots[index] {
+            let home = self.home(existing);
+
+            if (index.wrapping_sub(home) & mask) >= (index.wrapping_sub(hole) & mask) {
+                self.slots[hole] = self.slots[index].take();
+                hole = index;
+            }
+
+            index = (index + 1) & mask;
+        }
+
+        Some(value)
+    }
+
+    /// Doubles the number of slots and inserts every entry again.
+    #[track_caller]
+    fn grow(&mut self) {
+        let slots = (self.slots.len() * 2).max(8);
+        let allocator = self.slots.allocator();
+        let mut old = mem::replace(&mut self.slots, AllocVec::with_capacity_in(slots, allocator));
+        self.slots.extend((0..slots).map(|_| None));
+
+        let mask = slots - 1;
+
+        for (key, value) in old.iter_mut().filter_map(Option::take) {
+            let mut index = self.home(&key);
+
+            while self.slots[index].is_some() {
+                index = (index + 1) & mask;
+            }
+
+            self.slots[index] = Some((key, value));
+        }
+    }
+}
+
+impl<K: Hash + Eq, V, R: RawMutex, S: BuildHasher> Extend<(K, V)> for AllocHashMap<'_, K, V, R, S> {
+    #[track_caller]
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, R: RawMutex, S> fmt::Debug for AllocHashMap<'_, K, V, R, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, fmt::Write, rc::Rc};
+
+    use super::*;
+
+    /// Counts how many times it has been dropped.
+    struct DropCounter(Rc<Cell<usize>>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn vectors_grow_and_drop_their_elements() {
+        let allocator = MemAlloc::new();
+        let drops = Rc::new(Cell::new(0));
+
+        {
+            let mut vec = AllocVec::new_in(&allocator);
+            for _ in 0..100 {
+                vec.push(DropCounter(drops.clone()));
+            }
+
+            assert_eq!(vec.len(), 100);
+            assert!(allocator.owns_allocation(vec.as_ptr().cast()));
+
+            drop(vec.remove(10));
+            drop(vec.swap_remove(0));
+            vec.truncate(50);
+            assert_eq!(drops.get(), 50);
+        }
+
+        assert_eq!(drops.get(), 100);
+        assert_eq!(allocator.stats().allocations, 0);
+
+        let mut numbers = AllocVec::with_capacity_in(2, &allocator);
+        numbers.extend_from_slice(&[1, 3]);
+        numbers.insert(1, 2);
+        assert_eq!(*numbers, [1, 2, 3]);
+        assert_eq!(numbers.clone(), numbers);
+        assert_eq!(numbers.pop(), Some(3));
+
+        let mut units = AllocVec::new_in(&allocator);
+        units.extend([(); 10]);
+        assert_eq!(units.len(), 10);
+    }
+
+    #[test]
+    fn strings_are_built_in_place() {
+        let allocator = MemAlloc::new();
+
+        let mut text = AllocString::new_in(&allocator);
+        write!(text, "{}-{}", 1, 2.5).unwrap();
+        text.push('ñ');
+        assert_eq!(text, "1-2.5ñ");
+        assert_eq!(text.pop(), Some('ñ'));
+
+        text.truncate(1);
+        assert_eq!(text.as_str(), "1");
+        assert!(allocator.owns_allocation(text.as_ptr()));
+
+        drop(text);
+        assert_eq!(allocator.stats().allocations, 0);
+    }
+
+    #[test]
+    fn maps_insert_find_and_remove() {
+        let allocator = MemAlloc::new();
+        let mut map = AllocHashMap::new_in(&allocator);
+
+        for i in 0..1000 {
+            assert_eq!(map.insert(i, i * 2), None);
+        }
+
+        assert_eq!(map.insert(7, 0), Some(14));
+        assert_eq!(map.len(), 1000);
+
+        // Removing every other key shifts the rest back, and they can still be found.
+        for i in (0..1000).step_by(2) {
+            assert_eq!(map.remove(&i), Some(i * 2));
+        }
+
+        assert_eq!(map.len(), 500);
+        assert!((1..1000).step_by(2).all(|i| map.get(&i) == Some(&(if i == 7 { 0 } else { i * 2 }))));
+        assert!(!map.contains_key(&2));
+
+        *map.get_mut(&1).unwrap() = 100;
+        assert_eq!(map.values().filter(|&&value| value == 100).count(), 1);
+
+        // Keys can be looked up through `Borrow`, like with `HashMap`.
+        let mut names = AllocHashMap::new_in(&allocator);
+        names.insert(AllocString::from_str_in("memalloc", &allocator), 1);
+        assert_eq!(names.get("memalloc"), Some(&1));
+
+        drop((map, names));
+        assert_eq!(allocator.stats().allocations, 0);
+    }
+}
@@ -0,0 +1,345 @@
+//! Compaction of the regions.
+//!
+//! Long-running programs that allocate and free objects of many different sizes end up
+//! with regions full of small free blocks that can't hold new allocations. Compacting
+//! slides the used blocks of every region towards its start, so the free space is merged
+//! into a single block at the end:
+//!
+//! ```text
+//! Before:
+//! +--------+------+-------+------+-------+------+-------+
+//! | Region | Free | Block | Free | Block | Free | Block |
+//! +--------+------+-------+------+-------+------+-------+
+//!
+//! After:
+//! +--------+-------+-------+-------+--------------------+
+//! | Region | Block | Block | Block |        Free        |
+//! +--------+-------+-------+-------+--------------------+
+//! ```
+//!
+//! The allocator can't know where the program keeps its pointers, so every move is
+//! reported to a callback that must update them. See [`MemAlloc::compact`].
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    ptr::{self, NonNull},
+};
+
+use lock_api::RawMutex;
+
+use crate::{
+    block::{BLOCK_FOOTER_SIZE, BLOCK_HEADER_SIZE, Block},
+    kernel::Kernel,
+    list::Node,
+    memalloc::MemAlloc,
+    region::Region,
+};
+
+impl Kernel {
+    /// Compacts every region of blocks, moving the used blocks whose payload is accepted
+    /// by `movable` and calling `relocate` for every block moved. Returns the number of
+    /// blocks moved.
+    pub(crate) unsafe fn compact(
+        &mut self,
+        movable: &mut impl FnMut(*mut u8) -> bool,
+        relocate: &mut impl FnMut(*mut u8, *mut u8, usize),
+    ) -> usize {
+        let mut moved = 0;
+        let mut region = self.regions.first();
+
+        while let Some(current) = region {
+            unsafe {
+                // Regions without free blocks have no holes to move blocks into.
+                if current.as_ref().data.free_bytes > 0 {
+                    moved += self.compact_region(current, movable, relocate);
+                }
+
+                region = current.as_ref().next;
+            }
+        }
+
+        moved
+    }
+
+    /// Moves the used blocks of `region` into the free blocks placed before them.
+    unsafe fn compact_region(
+        &mut self,
+        mut region: NonNull<Node<Region>>,
+        movable: &mut impl FnMut(*mut u8) -> bool,
+        relocate: &mut impl FnMut(*mut u8, *mut u8, usize),
+    ) -> usize {
+        let mut moved = 0;
+
+        unsafe {
+            let mut current = region.as_ref().data.blocks.first();
+
+            while let Some(mut hole) = current {
+                if !hole.as_ref().data.is_free() {
+                    current = hole.as_ref().next;
+                    continue;
+                }
+
+                // The contents of the hole are going to change, so it leaves the free list
+                // until we are done with it.
+                self.free_list.remove_free_block(hole);
+
+                while let Some(next) = hole.as_ref().next {
+                    if next.as_ref().data.is_free() {
+                        if let Err(neighbour) = region.as_mut().data.merge_with_next(&mut hole, &mut self.free_list) {
+                            self.found_corrupted_header(neighbour);
+                            break;
+                        }
+                    } else if Self::can_move(next, hole) && movable(next.as_ptr().cast::<u8>().add(BLOCK_HEADER_SIZE)) {
+                        let (old, new, size) = Self::swap_with_hole(region, hole, next);
+                        hole = Block::next_in_region(hole).unwrap_unchecked();
+
+                        // The lock is released if `relocate` panics, so the hole goes back
+                        // to the free list first. See the `sync` module.
+                        if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| relocate(old, new, size))) {
+                            self.free_list.insert_free_block(hole, free_node_addr(hole));
+                            panic::resume_unwind(panic);
+                        }
+
+                        moved += 1;
+                    } else {
+                        break;
+                    }
+                }
+
+                self.free_list.insert_free_block(hole, free_node_addr(hole));
+
+                current = hole.as_ref().next;
+            }
+        }
+
+        moved
+    }
+
+    /// Whether the used `block` can be moved to the start of the free block `hole` that
+    /// precedes it.
+    ///
+    /// We don't know the alignment each block was requested with, so the new address must
+    /// be at least as aligned as the current one (up to a page, which is the maximum
+    /// alignment of a block in a shared region). Padded blocks store the address of their
+    /// header before the payload and sealed ones are read-only, so they are not moved.
+    unsafe fn can_move(block: NonNull<Node<Block>>, hole: NonNull<Node<Block>>) -> bool {
+        unsafe {
+            if block.as_ref().data.is_padded() || Block::is_sealed(block) {
+                return false;
+            }
+
+            let old = block.as_ptr() as usize + BLOCK_HEADER_SIZE;
+            let new = hole.as_ptr() as usize + BLOCK_HEADER_SIZE;
+            let alignment = 1usize << old.trailing_zeros().min(crate::kernel::page_size().trailing_zeros());
+
+            new.is_multiple_of(alignment)
+        }
+    }
+
+    /// Moves the used `block` to the start of the free `hole` right before it, leaving
+    /// the hole right after the block. Returns the old and new payload and its size.
+    ///
+    /// ```text
+    /// +---------------+----------------------+      +----------------------+---------------+
+    /// |     Hole      |        Block         | ---> |        Block         |     Hole      |
+    /// +---------------+----------------------+      +----------------------+---------------+
+    /// ```
+    unsafe fn swap_with_hole(
+        mut region: NonNull<Node<Region>>,
+        mut hole: NonNull<Node<Block>>,
+        block: NonNull<Node<Block>>,
+    ) -> (*mut u8, *mut u8, usize) {
+        unsafe {
+            let hole_size = hole.as_ref().data.size();
+            let block_size = block.as_ref().data.size();
+            let payload_size = block_size - BLOCK_FOOTER_SIZE;
+
+            // The header moves with the block, together with its tag and call site.
+            let header = ptr::read(&block.as_ref().data);
+
+            let old = block.as_ptr().cast::<u8>().add(BLOCK_HEADER_SIZE);
+            let new = hole.as_ptr().cast::<u8>().add(BLOCK_HEADER_SIZE);
+
+            // Unlink the block while its header is still intact. The payload may overwrite
+            // it when the hole is smaller than the block.
+            let blocks = &mut region.as_mut().data.blocks;
+            blocks.remove(block);
+
+            ptr::copy(old, new, payload_size);
+
+            // The header of the hole becomes the header of the block.
+            ptr::write(&mut hole.as_mut().data, header);
+            Block::write_footer(hole);
+
+            let new_hole_addr = NonNull::new_unchecked(new.add(block_size)).cast();
+            let new_hole = blocks.insert_after(hole, Block::new(hole_size, true, region), new_hole_addr);
+            Block::write_footer(new_hole);
+
+            (old, new, payload_size)
+        }
+    }
+}
+
+/// Address of the free list node of the free block `hole`.
+///
+/// # Safety
+///
+/// `hole` must point to a valid block header.
+unsafe fn free_node_addr(hole: NonNull<Node<Block>>) -> NonNull<u8> {
+    unsafe { NonNull::new_unchecked(hole.as_ptr().cast::<u8>().add(BLOCK_HEADER_SIZE)) }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Slides the used blocks of every region towards the start of the region, so the
+    /// free space in between them is merged and can hold bigger allocations. Returns the
+    /// number of blocks moved.
+    ///
+    /// `relocate` is called with the old address, the new address and the size of every
+    /// block moved, once its contents have been copied. Pointers into
+    /// `old..old + size` must be updated to the same offset from `new`. If it panics, the
+    /// panic goes on once the heap is back in order, and the blocks moved so far, the one
+    /// being reported included, stay moved.
+    ///
+    /// Large objects and executable memory are never moved, and neither are blocks
+    /// allocated with an alignment that required padding or sealed blocks.
+    ///
+    /// # Safety
+    ///
+    /// Any pointer to a moved block that is not updated by `relocate` is dangling. No
+    /// other thread may access the allocations while compacting, and `relocate` must not
+    /// use this allocator since the lock is held while it runs.
+    ///
+    /// ```rust
+    /// use std::alloc::Layout;
+    /// use memalloc::MemAlloc;
+    ///
+    /// let allocator = MemAlloc::new();
+    /// let layout = Layout::new::<[u64; 8]>();
+    ///
+    /// unsafe {
+    ///     let hole = allocator.allocate(layout);
+    ///     let mut live = allocator.allocate(layout);
+    ///     live.write(42);
+    ///
+    ///     allocator.deallocate(hole, layout);
+    ///
+    ///     allocator.compact(|old, new, _| {
+    ///         if old == live {
+    ///             live = new;
+    ///         }
+    ///     });
+    ///
+    ///     assert_eq!(live.read(), 42);
+    ///     allocator.deallocate(live, layout);
+    /// }
+    /// ```
+    pub unsafe fn compact(&self, mut relocate: impl FnMut(*mut u8, *mut u8, usize)) -> usize {
+        unsafe { self.lock_draining().compact(&mut |_| true, &mut relocate) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::*;
+
+    #[test]
+    fn used_blocks_slide_into_holes() {
+        let allocator = MemAlloc::new();
+
+        // Every block takes 256 bytes, so moving them keeps their alignment.
+        let layout = Layout::from_size_align(256 - BLOCK_HEADER_SIZE - BLOCK_FOOTER_SIZE, 8).unwrap();
+
+        unsafe {
+            let [a, b, c, d] = [(); 4].map(|_| allocator.allocate(layout));
+
+            for (ptr, byte) in [(b, 0xB), (d, 0xD)] {
+                ptr.write_bytes(byte, layout.size());
+            }
+
+            allocator.deallocate(a, layout);
+            allocator.deallocate(c, layout);
+
+            let mut moves = Vec::new();
+            let moved = allocator.compact(|old, new, size| moves.push((old, new, size)));
+
+            assert_eq!(moved, 2);
+            assert_eq!(moves, [(b, a, layout.size()), (d, b, layout.size())]);
+
+            for (ptr, byte) in [(a, 0xB), (b, 0xD)] {
+                assert!(std::slice::from_raw_parts(ptr, layout.size()).iter().all(|&x| x == byte));
+                assert!(allocator.owns_allocation(ptr));
+            }
+
+            assert!(!allocator.owns_allocation(d));
+
+            // The free space after the moved blocks is a single block again.
+            {
+                let kernel = allocator.allocator.lock();
+                let region = kernel.regions.first().unwrap();
+                assert_eq!(region.as_ref().data.blocks.len(), 3);
+                assert_eq!(kernel.free_list.items.len(), 1);
+            }
+
+            allocator.deallocate(a, layout);
+            allocator.deallocate(b, layout);
+            assert!(allocator.allocator.lock().regions.is_empty());
+        }
+    }
+
+    #[test]
+    fn panicking_callbacks_leave_the_heap_usable() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::from_size_align(256 - BLOCK_HEADER_SIZE - BLOCK_FOOTER_SIZE, 8).unwrap();
+
+        unsafe {
+            let [a, mut b, c] = [(); 3].map(|_| allocator.allocate(layout));
+            allocator.deallocate(a, layout);
+
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                allocator.compact(|old, new, _| {
+                    if old == b {
+                        b = new;
+                    }
+
+                    panic!("relocation failed");
+                })
+            }));
+            assert!(result.is_err());
+
+            // The block was moved before the callback panicked, and the hole it left is in
+            // the free list again.
+            assert_eq!(b, a);
+            let summary = allocator.summary();
+            assert_eq!((summary.used_blocks, summary.free_blocks, summary.free_list_len), (2, 2, 2));
+
+            let ptr = allocator.allocate(layout);
+            assert!(allocator.owns_allocation(ptr));
+
+            for ptr in [ptr, b, c] {
+                allocator.deallocate(ptr, layout);
+            }
+
+            assert!(allocator.allocator.lock().regions.is_empty());
+        }
+    }
+
+    #[test]
+    fn padded_blocks_stay_in_place() {
+        let allocator = MemAlloc::new();
+        let small = Layout::from_size_align(64, 8).unwrap();
+        let aligned = Layout::from_size_align(64, 256).unwrap();
+
+        unsafe {
+            let hole = allocator.allocate(small);
+            let ptr = allocator.allocate(aligned);
+            allocator.deallocate(hole, small);
+
+            assert_eq!(allocator.compact(|_, _, _| panic!("nothing can move")), 0);
+            assert!(allocator.owns_allocation(ptr));
+
+            allocator.deallocate(ptr, aligned);
+        }
+    }
+}
@@ -0,0 +1,534 @@
+//! Tunable parameters of the allocator.
+//!
+//! Every option has a sensible default, so [`Config::new`] gives the same behaviour
+//! as [`crate::MemAlloc::new`]. Options are set using `const` builder methods so that
+//! a configured allocator can still be used as a `#[global_allocator]`:
+//!
+//! ```rust
+//! use memalloc::{Config, MemAlloc};
+//!
+//! static ALLOCATOR: MemAlloc = MemAlloc::with_config(
+//!     Config::new().large_object_threshold(4 * 1024 * 1024)
+//! );
+//! ```
+
+use std::time::Duration;
+
+use crate::corruption::CorruptionPolicy;
+use crate::placement::{Placement, PlacementStrategy};
+
+/// Default size from which allocations bypass the regions and get their own mapping.
+pub const LARGE_OBJECT_THRESHOLD: usize = 1024 * 1024;
+
+/// Default minimum alignment of the allocations, the one `malloc` guarantees on 64-bit
+/// platforms.
+pub const MIN_ALIGN: usize = 16;
+
+/// How the physical memory of cached regions is given back to the OS. See
+/// [`Config::decommit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decommit {
+    /// Cached regions keep their physical memory.
+    Never,
+    /// The physical memory is released straight away using `MADV_DONTNEED`
+    /// (`MEM_DECOMMIT` on Windows), so reusing the region takes a page fault for every
+    /// page touched again.
+    Eager,
+    /// The physical memory is only reclaimed by the OS under memory pressure using
+    /// `MADV_FREE` (`MEM_RESET` on Windows, undone with `MEM_RESET_UNDO` on reuse), so
+    /// reusing the region soon after is nearly free. Falls back to [`Decommit::Eager`]
+    /// where `MADV_FREE` isn't supported.
+    Lazy,
+}
+
+/// Whether the regions are backed by transparent huge pages. See [`Config::huge_pages`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePages {
+    /// Leave it to the system wide configuration of the OS.
+    Default,
+    /// Ask the OS to back every region of at least [`HUGE_PAGE_SIZE`] with huge pages
+    /// using `MADV_HUGEPAGE`. On Windows, such regions are mapped with large pages instead
+    /// if the user holds the "Lock pages in memory" privilege (`SeLockMemoryPrivilege`),
+    /// which is enabled for the process the first time. Otherwise they get regular pages.
+    /// On FreeBSD, they are mapped with `MAP_ALIGNED_SUPER` so the kernel can promote them
+    /// to superpages.
+    Enabled,
+    /// Never back the regions with huge pages using `MADV_NOHUGEPAGE`. Useful for
+    /// latency-sensitive programs, since the OS may stall to compact memory for them.
+    Disabled,
+}
+
+/// When new regions and large objects are charged against the commit limit of the OS,
+/// the amount of memory it promises to back with RAM or swap. See [`Config::commit_charge`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitCharge {
+    /// Mappings are charged when they are created (`MEM_COMMIT` on Windows) and their
+    /// pages are faulted in when they are touched for the first time. On Linux, whether
+    /// running out of memory makes the mapping fail or kills the program later on depends
+    /// on the overcommit policy of the system.
+    Default,
+    /// Every page is also touched as soon as the mapping is created, like with
+    /// [`Config::prefault`], so running out of memory shows up while allocating instead
+    /// of at a random write later on.
+    Eager,
+    /// Mappings are created with `MAP_NORESERVE`, so they are not charged and the OS can
+    /// overcommit them unless its accounting policy is strict. Useful for huge, sparsely
+    /// used allocations, at the risk of being killed when touching a page if the memory
+    /// runs out. Windows can't touch memory before committing it and other platforms
+    /// don't support it, so there it is the same as [`CommitCharge::Default`].
+    Lazy,
+}
+
+/// Size of a transparent huge page on x86_64 and most aarch64 systems.
+pub const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// How a free block is picked among all the ones that can hold an allocation.
+/// See [`Config::fit_policy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitPolicy {
+    /// Take the first block of the free list that fits. This is the fastest one.
+    FirstFit,
+    /// Take the smallest block that fits, which leaves bigger blocks for bigger
+    /// allocations at the cost of walking the whole free list.
+    BestFit,
+    /// Like [`FitPolicy::FirstFit`], but each search starts where the previous one
+    /// ended, which spreads allocations across the free list.
+    NextFit,
+    /// Take a random block among the ones that fit, so where an allocation lands can't
+    /// be predicted from the previous ones. This is a hardening option against heap
+    /// spraying and grooming, which rely on placing allocations next to each other. It
+    /// walks the whole free list like [`FitPolicy::BestFit`].
+    Random,
+}
+
+impl FitPolicy {
+    /// Name of the environment variable that overrides [`Config::fit_policy`].
+    pub const ENV_VAR: &str = "MEMALLOC_FIT_POLICY";
+
+    /// Parses the value of [`FitPolicy::ENV_VAR`].
+    fn parse(value: &[u8]) -> Option<Self> {
+        match value {
+            b"first-fit" | b"first" => Some(Self::FirstFit),
+            b"best-fit" | b"best" => Some(Self::BestFit),
+            b"next-fit" | b"next" => Some(Self::NextFit),
+            b"random" => Some(Self::Random),
+            _ => None,
+        }
+    }
+
+    /// Reads the policy from [`FitPolicy::ENV_VAR`], if it is set to a valid value.
+    /// This can't use [`std::env`] since it allocates.
+    pub(crate) fn from_env() -> Option<Self> {
+        #[cfg(unix)]
+        unsafe {
+            let value = libc::getenv(c"MEMALLOC_FIT_POLICY".as_ptr());
+
+            if value.is_null() {
+                return None;
+            }
+
+            Self::parse(std::ffi::CStr::from_ptr(value).to_bytes())
+        }
+
+        #[cfg(windows)]
+        unsafe {
+            let mut buffer = [0u8; 16];
+            let len = windows::Win32::System::Environment::GetEnvironmentVariableA(
+                windows::core::s!("MEMALLOC_FIT_POLICY"),
+                Some(&mut buffer),
+            ) as usize;
+
+            // A length bigger than the buffer means that it didn't fit.
+            buffer.get(..len).and_then(Self::parse)
+        }
+
+        #[cfg(not(any(unix, windows)))]
+        None
+    }
+}
+
+/// Configuration of a [`crate::MemAlloc`] instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Allocations of at least this size are mapped directly. See [`Config::large_object_threshold`].
+    pub(crate) large_object_threshold: usize,
+    /// Maximum number of empty regions kept mapped. See [`Config::cached_regions`].
+    pub(crate) cached_regions: usize,
+    /// Whether regions are bound to the NUMA node of the thread. See [`Config::numa_aware`].
+    pub(crate) numa_aware: bool,
+    /// What happens to the physical memory of cached regions. See [`Config::decommit`].
+    pub(crate) decommit: Decommit,
+    /// Time it takes for cached regions to be unmapped. See [`Config::decay`].
+    pub(crate) decay: Duration,
+    /// Number of empty regions kept inaccessible. See [`Config::quarantine_regions`].
+    pub(crate) quarantine_regions: usize,
+    /// Transparent huge page advice given for new regions. See [`Config::huge_pages`].
+    pub(crate) huge_pages: HugePages,
+    /// Whether new regions are populated as soon as they are mapped. See [`Config::prefault`].
+    pub(crate) prefault: bool,
+    /// When new mappings are charged against the commit limit. See [`Config::commit_charge`].
+    pub(crate) commit_charge: CommitCharge,
+    /// Whether every mapping is placed below 4 GiB. See [`Config::low_address`].
+    pub(crate) low_address: bool,
+    /// How free blocks are picked. See [`Config::placement_strategy`].
+    pub(crate) placement: Placement,
+    /// Maximum number of free list nodes visited per search. See [`Config::search_limit`].
+    pub(crate) search_limit: usize,
+    /// Smallest remainder worth splitting off a block. See [`Config::split_threshold`].
+    pub(crate) split_threshold: usize,
+    /// Whether remainders of split blocks go to the front of the free list. See
+    /// [`Config::move_to_front`].
+    pub(crate) move_to_front: bool,
+    /// Smallest size of a new region. See [`Config::min_region_size`].
+    pub(crate) min_region_size: usize,
+    /// Bytes of address space reserved for regions. See [`Config::reserve`].
+    pub(crate) reserve: usize,
+    /// Name of the heap. See [`Config::name`].
+    pub(crate) name: Option<&'static str>,
+    /// Maximum number of bytes allocated at the same time. See [`Config::quota`].
+    pub(crate) quota: Option<usize>,
+    /// Address where the first region is mapped. See [`Config::address_hint`].
+    pub(crate) address_hint: usize,
+    /// Whether the metadata is kept apart from the allocations. See [`Config::hardened`].
+    pub(crate) hardened: bool,
+    /// Whether every allocation gets its own guarded region. See [`Config::electric_fence`].
+    pub(crate) electric_fence: bool,
+    /// Whether the metadata pages are read-only between operations. See [`Config::protect_metadata`].
+    pub(crate) protect_metadata: bool,
+    /// Minimum alignment of every allocation. See [`Config::min_align`].
+    pub(crate) min_align: usize,
+    /// What to do when corruption is found. See [`Config::on_corruption`].
+    pub(crate) on_corruption: CorruptionPolicy,
+    /// Whether pointers we didn't allocate are given to the system `free`. See
+    /// [`Config::forward_foreign_frees`].
+    pub(crate) forward_foreign_frees: bool,
+}
+
+impl Config {
+    /// Creates the default configuration.
+    pub const fn new() -> Self {
+        Self {
+            large_object_threshold: LARGE_OBJECT_THRESHOLD,
+            cached_regions: 0,
+            numa_aware: false,
+            decommit: Decommit::Never,
+            decay: Duration::ZERO,
+            quarantine_regions: 0,
+            huge_pages: HugePages::Default,
+            prefault: false,
+            commit_charge: CommitCharge::Default,
+            low_address: false,
+            placement: Placement(FitPolicy::FirstFit.as_strategy()),
+            search_limit: 0,
+            split_threshold: 0,
+            move_to_front: false,
+            min_region_size: 0,
+            reserve: 0,
+            name: None,
+            quota: None,
+            address_hint: 0,
+            hardened: false,
+            electric_fence: false,
+            protect_metadata: false,
+            min_align: MIN_ALIGN,
+            on_corruption: CorruptionPolicy::Abort,
+            forward_foreign_frees: false,
+        }
+    }
+
+    /// Allocations whose size is at least `bytes` bypass the blocks and the free list
+    /// entirely: each one of them gets a dedicated mapping which is returned to the OS
+    /// as soon as it is deallocated. Defaults to [`LARGE_OBJECT_THRESHOLD`].
+    pub const fn large_object_threshold(mut self, bytes: usize) -> Self {
+        self.large_object_threshold = bytes;
+        self
+    }
+
+    /// Instead of returning every empty region to the OS straight away, keep up to
+    /// `count` of them mapped so that the next allocations can reuse them without a
+    /// syscall. Cached regions can be released on demand with [`crate::MemAlloc::trim`],
+    /// and they are released automatically when the OS refuses to map more memory,
+    /// before the mapping is tried again. Defaults to `0`, which means that empty regions
+    /// are always unmapped.
+    pub const fn cached_regions(mut self, count: usize) -> Self {
+        self.cached_regions = count;
+        self
+    }
+
+    /// Bind every new region to the NUMA node of the thread that maps it, and prefer
+    /// free blocks from regions of the current thread's node when allocating. This
+    /// avoids cross-node memory traffic on multi-socket machines.
+    ///
+    /// It only has effect on Linux (using `getcpu` and `mbind`), on other platforms
+    /// it is ignored. Defaults to `false`.
+    pub const fn numa_aware(mut self, enabled: bool) -> Self {
+        self.numa_aware = enabled;
+        self
+    }
+
+    /// Give the physical memory of the regions kept by [`Config::cached_regions`] back
+    /// to the OS while keeping them mapped, so an idle cache doesn't count towards the
+    /// resident memory of the process. Large objects are always unmapped when they are
+    /// deallocated, so this doesn't apply to them.
+    ///
+    /// On Windows both modes use `MEM_RESET`, which behaves like [`Decommit::Lazy`].
+    /// Defaults to [`Decommit::Never`].
+    pub const fn decommit(mut self, mode: Decommit) -> Self {
+        self.decommit = mode;
+        self
+    }
+
+    /// Unmap the regions kept by [`Config::cached_regions`] gradually as they age, like
+    /// the `dirty_decay_ms` of jemalloc: the bytes that entered the cache may stay in it
+    /// for up to `time`, and less of them the older they get. See the `decay` module.
+    ///
+    /// Defaults to zero, which keeps cached regions until they are trimmed.
+    pub const fn decay(mut self, time: Duration) -> Self {
+        self.decay = time;
+        self
+    }
+
+    /// Instead of unmapping empty regions straight away, make them inaccessible and keep
+    /// the last `count` of them, so a dangling pointer into one of them faults at the
+    /// first access instead of landing in whatever the OS maps there later. Their
+    /// physical memory is given back, so they only take address space. Regions kept by
+    /// [`Config::cached_regions`] are not quarantined.
+    ///
+    /// At most [`crate::MAX_QUARANTINED_REGIONS`] regions are kept, and larger values
+    /// behave the same. See the `quarantine` module. Defaults to `0`, which means that
+    /// empty regions are unmapped.
+    pub const fn quarantine_regions(mut self, count: usize) -> Self {
+        self.quarantine_regions = count;
+        self
+    }
+
+    /// Advise the OS on whether to back new regions and large objects with transparent
+    /// huge pages. With [`HugePages::Enabled`], only mappings that span at least one
+    /// whole [`HUGE_PAGE_SIZE`] aligned range are advised, since smaller ones can't use
+    /// huge pages anyway. Combine it with a bigger [`Config::large_object_threshold`]
+    /// to make the heap benefit from them.
+    ///
+    /// It only has effect on Linux and, for regions, on Windows and FreeBSD. On other
+    /// platforms it is ignored. Defaults to [`HugePages::Default`].
+    pub const fn huge_pages(mut self, mode: HugePages) -> Self {
+        self.huge_pages = mode;
+        self
+    }
+
+    /// Populate the physical pages of every new region and large object as soon as it is
+    /// mapped, so latency-critical programs don't take a page fault the first time they
+    /// touch freshly mapped heap memory. The cost is paid upfront by the allocation that
+    /// maps the region, and memory that is never used still counts as resident.
+    ///
+    /// Defaults to `false`.
+    pub const fn prefault(mut self, enabled: bool) -> Self {
+        self.prefault = enabled;
+        self
+    }
+
+    /// Choose between failing fast when the system runs out of memory and overcommitting,
+    /// for every new region and large object. See [`CommitCharge`].
+    ///
+    /// [`CommitCharge::Lazy`] only has effect on Linux, and regions of the reservation of
+    /// [`Config::reserve`] and mappings below 4 GiB are always charged as usual. Defaults
+    /// to [`CommitCharge::Default`].
+    pub const fn commit_charge(mut self, mode: CommitCharge) -> Self {
+        self.commit_charge = mode;
+        self
+    }
+
+    /// Place every region below 4 GiB, so that pointers returned by the allocator fit in
+    /// 32 bits. This is useful when pointers are shared with code that stores them in
+    /// 32-bit slots. Allocations fail once the low address space is exhausted.
+    ///
+    /// On x86_64 Linux this uses `MAP_32BIT`, which only covers the first 2 GiB. Otherwise
+    /// (and once those 2 GiB are full) addresses are probed one by one, which makes
+    /// mapping new regions slower. Defaults to `false`.
+    pub const fn low_address(mut self, enabled: bool) -> Self {
+        self.low_address = enabled;
+        self
+    }
+
+    /// Choose how a free block is picked among all the ones that can hold an allocation.
+    /// See [`FitPolicy`].
+    ///
+    /// The [`FitPolicy::ENV_VAR`] environment variable (`first-fit`, `best-fit`,
+    /// `next-fit` or `random`) takes precedence over this option, so policies can be compared on a
+    /// workload without recompiling it. Defaults to [`FitPolicy::FirstFit`].
+    pub const fn fit_policy(mut self, policy: FitPolicy) -> Self {
+        self.placement = Placement(policy.as_strategy());
+        self
+    }
+
+    /// Plug a custom [`PlacementStrategy`] to pick free blocks, replacing the
+    /// [`FitPolicy`]. The strategy is shared by every thread and called with the
+    /// allocator lock held. The [`FitPolicy::ENV_VAR`] environment variable takes
+    /// precedence over this option too.
+    pub const fn placement_strategy(mut self, strategy: &'static dyn PlacementStrategy) -> Self {
+        self.placement = Placement(strategy);
+        self
+    }
+
+    /// Stop looking for a free block after visiting `nodes` nodes of the free list and
+    /// map a new region instead. Under fragmentation the free list gets long and walking
+    /// it dominates the time of an allocation, so this bounds that time at the cost of
+    /// some memory. Strategies only choose among the blocks visited, so best fit picks
+    /// the smallest of them. Defaults to 0, which searches the whole list.
+    pub const fn search_limit(mut self, nodes: usize) -> Self {
+        self.search_limit = nodes;
+        self
+    }
+
+    /// Only split a free block to serve an allocation if at least `bytes` would be left
+    /// for the new free block, counting its footer but not its header. Otherwise the
+    /// allocation takes the whole block. Higher values split less, so the heap doesn't
+    /// fill with tiny free blocks that can't serve anything, at the cost of the slack
+    /// handed out with the allocations. Defaults to 0, which splits whenever the rest can
+    /// hold a free block at all, and smaller values than that behave the same.
+    pub const fn split_threshold(mut self, bytes: usize) -> Self {
+        self.split_threshold = bytes;
+        self
+    }
+
+    /// Put what is left of a free block after splitting an allocation off it at the
+    /// front of the free list instead of at the back. Programs tend to make bursts of
+    /// similar allocations, and the next one of the burst then finds its block at the
+    /// first node visited, like a bump allocator, instead of walking past every hole the
+    /// previous ones couldn't use. The holes drift to the back of the list, so first fit
+    /// reuses them less. Defaults to `false`.
+    pub const fn move_to_front(mut self, enabled: bool) -> Self {
+        self.move_to_front = enabled;
+        self
+    }
+
+    /// Map regions of at least `bytes`, rounded up to the page size, even if the
+    /// allocation that needs a new region is smaller. The rest of the region serves the
+    /// next allocations, so a program making many small allocations maps memory in big
+    /// steps instead of one page at a time. Large objects still get a mapping of their
+    /// own size. Defaults to 0, which maps just the pages the allocation needs.
+    pub const fn min_region_size(mut self, bytes: usize) -> Self {
+        self.min_region_size = bytes;
+        self
+    }
+
+    /// Reserve `bytes` of contiguous address space the first time memory is needed and
+    /// carve the regions out of it, committing their pages as they are created. All the
+    /// regions are then next to each other, and finding the region of a pointer is just
+    /// arithmetic. Reserving doesn't use any physical memory, so this can be generous.
+    ///
+    /// Regions that don't fit in the reservation, large objects and executable memory
+    /// are mapped as usual. Not supported by the `sbrk` and `system-backend` backends
+    /// nor together with [`Config::low_address`]. Defaults to 0, which disables it.
+    pub const fn reserve(mut self, bytes: usize) -> Self {
+        self.reserve = bytes;
+        self
+    }
+
+    /// Give the heap a name, so it can be registered with [`crate::MemAlloc::register`]
+    /// and listed by [`crate::heaps`]. Defaults to no name.
+    pub const fn name(mut self, name: &'static str) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Refuse allocations once the live allocations of the heap add up to more than
+    /// `bytes`, counting the sizes requested by their layouts. Allocations over the quota
+    /// return null and are counted in [`crate::Stats::quota_failures`]. Defaults to no quota.
+    pub const fn quota(mut self, bytes: usize) -> Self {
+        self.quota = Some(bytes);
+        self
+    }
+
+    /// Map the regions at predictable addresses: the first one at `addr` (rounded up to
+    /// 64 KiB) and each of the next ones right after the previous one. The same program
+    /// then gets the same pointers on every run, which makes tests that print pointers
+    /// and record/replay debugging reproducible.
+    ///
+    /// This is best-effort: existing mappings are never replaced, so a region whose range
+    /// is in use is mapped wherever the OS wants. Regions of the reservation of
+    /// [`Config::reserve`], large objects and executable memory are mapped as usual. Not
+    /// supported by the `sbrk` and `system-backend` backends nor together with
+    /// [`Config::low_address`]. Defaults to 0, which disables it.
+    pub const fn address_hint(mut self, addr: usize) -> Self {
+        self.address_hint = addr;
+        self
+    }
+
+    /// Keep every piece of allocator metadata in dedicated pages instead of next to the
+    /// allocations, so a heap buffer overflow can only corrupt other allocations and not
+    /// the allocator itself. Allocations are rounded up to size classes, which uses more
+    /// memory than the regular blocks.
+    ///
+    /// Hardened allocations are not made of blocks, so sealing, compaction, the leak
+    /// scanner, tags, call sites and the options that apply to regions don't work with
+    /// them. See the `hardened` module for more detail. Defaults to `false`.
+    pub const fn hardened(mut self, enabled: bool) -> Self {
+        self.hardened = enabled;
+        self
+    }
+
+    /// Give every allocation its own region, with the payload right before an
+    /// inaccessible guard page, and make the region inaccessible when it is freed, so
+    /// overruns and uses after free crash at the faulting instruction. Freed regions
+    /// stay in the quarantine of [`Config::quarantine_regions`], which holds
+    /// [`crate::MAX_QUARANTINED_REGIONS`] of them if it isn't set.
+    ///
+    /// Every allocation takes at least two pages and a syscall, so this is meant for
+    /// debugging only. Allocations aligned to more than a page, hardened mode and
+    /// snapshots don't work with it. See the `fence` module. Defaults to `false`.
+    pub const fn electric_fence(mut self, enabled: bool) -> Self {
+        self.electric_fence = enabled;
+        self
+    }
+
+    /// In the [`Config::hardened`] mode, keep the metadata pages read-only while no
+    /// allocation or deallocation is in progress, so a stray write into them crashes the
+    /// program right away. Every allocation and deallocation then makes two more syscalls
+    /// to change the protection. Not supported by the `system-backend` backend. Defaults
+    /// to `false`.
+    pub const fn protect_metadata(mut self, enabled: bool) -> Self {
+        self.protect_metadata = enabled;
+        self
+    }
+
+    /// Every allocation is aligned to at least `align` bytes, even if its layout asks for
+    /// less. C code commonly assumes the alignment of `malloc`, so it defaults to
+    /// [`MIN_ALIGN`]. Lower it to the word size to save the padding of small allocations.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `align` is not a power of two.
+    pub const fn min_align(mut self, align: usize) -> Self {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        self.min_align = align;
+        self
+    }
+
+    /// What to do when a double free or a corrupted block header is found: abort the
+    /// process, leak the block and report it from [`crate::MemAlloc::checked_deallocate`],
+    /// or call a function too. See the `corruption` module. Defaults to
+    /// [`CorruptionPolicy::Abort`].
+    pub const fn on_corruption(mut self, policy: CorruptionPolicy) -> Self {
+        self.on_corruption = policy;
+        self
+    }
+
+    /// Check that every pointer given to [`crate::MemAlloc::deallocate`] and
+    /// [`crate::MemAlloc::reallocate`] is inside one of our regions before reading its
+    /// header, and give the ones that aren't to the `free` of the C library instead. This
+    /// is meant for interposing `malloc` with `LD_PRELOAD`, where pointers allocated by
+    /// the C library before we were loaded end up freed by us. See the `foreign` module.
+    ///
+    /// Every deallocation takes the lock one more time and walks the regions. It only has
+    /// effect on Unix, on other platforms it is ignored. Defaults to `false`.
+    pub const fn forward_foreign_frees(mut self, enabled: bool) -> Self {
+        self.forward_foreign_frees = enabled;
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
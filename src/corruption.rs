@@ -0,0 +1,367 @@
+//! Responses to heap corruption.
+//!
+//! Freeing an allocation twice, or freeing a block whose header has been overwritten,
+//! would make the allocator link the same memory twice or write through garbage. The
+//! allocator checks for these before going on:
+//!
+//! - A block that is already free is a double free.
+//! - A layout bigger than the block it frees doesn't belong to that allocation.
+//! - With the `checksums` feature, a header that doesn't match its checksum has been
+//!   overwritten. Headers are checked when their block is freed and when a neighbour
+//!   is merged with it.
+//!
+//! What happens then is up to [`crate::Config::on_corruption`]. By default the process
+//! is aborted with a message, since the heap can't be trusted anymore. Deployments that
+//! would rather keep running can leak the offending block instead, and learn about it
+//! from [`MemAlloc::checked_deallocate`] or a callback:
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::{Config, Corruption, CorruptionKind, CorruptionPolicy, DeallocError, MemAlloc};
+//!
+//! let allocator = MemAlloc::with_config(Config::new().on_corruption(CorruptionPolicy::Report));
+//! let layout = Layout::new::<[u64; 4]>();
+//!
+//! unsafe {
+//!     let ptr = allocator.allocate(layout);
+//!     let neighbour = allocator.allocate(layout);
+//!     assert_eq!(allocator.checked_deallocate(ptr, layout), Ok(()));
+//!
+//!     let error = allocator.checked_deallocate(ptr, layout).unwrap_err();
+//!     let corruption = Corruption { kind: CorruptionKind::DoubleFree, addr: ptr.addr() };
+//!     assert_eq!(error, DeallocError::Corruption(corruption));
+//!
+//!     allocator.deallocate(neighbour, layout);
+//! }
+//! ```
+//!
+//! Double frees are only found while the memory of the block is still mapped, which is
+//! why the example keeps a neighbour alive. Corruption found while freeing the allocations queued by other threads (see the
+//! `deferred` module) is reported by the next deallocation that takes the lock. Links
+//! of the free list that don't decode (see [`crate::freelist::FreeList`]) always abort
+//! the process, since the list can't be walked past them.
+
+use std::{
+    alloc::Layout,
+    error::Error,
+    fmt, mem,
+    ptr::{self, NonNull},
+};
+
+use lock_api::RawMutex;
+
+use crate::{
+    block::{BLOCK_FOOTER_SIZE, BLOCK_HEADER_SIZE, Block},
+    kernel::Kernel,
+    list::Node,
+    memalloc::MemAlloc,
+    utils::abort_with,
+};
+
+/// What kind of corruption was found. See [`Corruption`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CorruptionKind {
+    /// The header of the block doesn't match its checksum. Only found with the
+    /// `checksums` feature.
+    CorruptedHeader,
+    /// The allocation was already free.
+    DoubleFree,
+    /// The layout the allocation was freed with is bigger than the allocation.
+    LayoutMismatch,
+}
+
+impl CorruptionKind {
+    /// Message written before aborting the process, which can't be formatted since that
+    /// would allocate.
+    fn abort_message(self) -> &'static [u8] {
+        match self {
+            Self::CorruptedHeader => b"memalloc: corrupted block header\n",
+            Self::DoubleFree => b"memalloc: double free\n",
+            Self::LayoutMismatch => b"memalloc: free with a layout bigger than the allocation\n",
+        }
+    }
+}
+
+/// Corruption found by the allocator. See the `corruption` module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Corruption {
+    /// What was found.
+    pub kind: CorruptionKind,
+    /// Address of the allocation being freed or, for a neighbour found while merging,
+    /// of the payload of the block whose header is corrupted.
+    pub addr: usize,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            CorruptionKind::CorruptedHeader => write!(f, "corrupted block header at {:#x}", self.addr),
+            CorruptionKind::DoubleFree => write!(f, "double free of {:#x}", self.addr),
+            CorruptionKind::LayoutMismatch => write!(f, "free of {:#x} with a layout bigger than the allocation", self.addr),
+        }
+    }
+}
+
+impl Error for Corruption {}
+
+/// Why [`MemAlloc::checked_deallocate`] refused to free a pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DeallocError {
+    /// The pointer is not inside any memory handed out by the allocator.
+    UnknownPointer,
+    /// The pointer is inside memory of the allocator, but it is not the start of an
+    /// allocation.
+    NotAnAllocation,
+    /// The allocation is smaller than the size of the layout, or not aligned to it.
+    LayoutMismatch,
+    /// The allocation was already free, or its header is corrupted. It is reported
+    /// according to [`crate::Config::on_corruption`] first.
+    Corruption(Corruption),
+}
+
+impl fmt::Display for DeallocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownPointer => f.write_str("pointer not allocated by this allocator"),
+            Self::NotAnAllocation => f.write_str("pointer is not the start of an allocation"),
+            Self::LayoutMismatch => f.write_str("layout doesn't match the allocation"),
+            Self::Corruption(corruption) => corruption.fmt(f),
+        }
+    }
+}
+
+impl Error for DeallocError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Corruption(corruption) => Some(corruption),
+            _ => None,
+        }
+    }
+}
+
+impl From<Corruption> for DeallocError {
+    fn from(corruption: Corruption) -> Self {
+        Self::Corruption(corruption)
+    }
+}
+
+/// What the allocator does when it finds corruption. See [`crate::Config::on_corruption`].
+/// Two callbacks are equal if they are the same function.
+#[derive(Debug, Clone, Copy, Eq)]
+pub enum CorruptionPolicy {
+    /// Write a message to the standard error and abort the process.
+    Abort,
+    /// Leave the offending block alone, leaking it, and return the [`Corruption`] from
+    /// [`MemAlloc::checked_deallocate`]. [`MemAlloc::deallocate`] ignores it.
+    Report,
+    /// Call the function with the [`Corruption`], without the lock held, and then do
+    /// the same as [`CorruptionPolicy::Report`]. It must not unwind, since the allocator
+    /// may be called by the global allocator.
+    Callback(fn(Corruption)),
+}
+
+impl PartialEq for CorruptionPolicy {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Callback(a), Self::Callback(b)) => ptr::fn_addr_eq(*a, *b),
+            _ => mem::discriminant(self) == mem::discriminant(other),
+        }
+    }
+}
+
+impl Kernel {
+    /// Records that `kind` of corruption was found at `addr`, aborting right away if
+    /// that is the policy. Otherwise it is kept until it is taken from
+    /// [`Kernel::corruption`], and only the first one is.
+    pub(crate) fn found_corruption(&mut self, kind: CorruptionKind, addr: usize) {
+        if matches!(self.config.on_corruption, CorruptionPolicy::Abort) {
+            abort_with(kind.abort_message());
+        }
+
+        self.corruption.get_or_insert(Corruption { kind, addr });
+    }
+
+    /// Records that the header of `node` doesn't match its checksum. See
+    /// [`Kernel::found_corruption`].
+    pub(crate) fn found_corrupted_header(&mut self, node: NonNull<Node<Block>>) {
+        let payload = node.as_ptr().addr() + BLOCK_HEADER_SIZE;
+        self.found_corruption(CorruptionKind::CorruptedHeader, payload);
+    }
+}
+
+impl Kernel {
+    /// Checks that the allocation `ptr` can be freed with `layout`. See
+    /// [`MemAlloc::checked_deallocate`]. Nothing is dereferenced before the header is
+    /// found among the blocks of the region that contains `ptr`.
+    pub(crate) fn validate_deallocation(&self, ptr: *mut u8, layout: Layout) -> Result<(), DeallocError> {
+        let addr = ptr.addr();
+        let region = self.block_region_of(addr).ok_or(DeallocError::UnknownPointer)?;
+
+        unsafe {
+            let block = Kernel::find_block(region, ptr).ok_or(DeallocError::NotAnAllocation)?;
+
+            if !Block::is_intact(block) {
+                return Err(Corruption { kind: CorruptionKind::CorruptedHeader, addr }.into());
+            }
+
+            if block.as_ref().data.is_free() {
+                return Err(Corruption { kind: CorruptionKind::DoubleFree, addr }.into());
+            }
+
+            let start = block.as_ptr().addr() + BLOCK_HEADER_SIZE;
+            let end = start + block.as_ref().data.size() - BLOCK_FOOTER_SIZE;
+
+            if !(start..end).contains(&addr) {
+                return Err(DeallocError::NotAnAllocation);
+            }
+
+            if !addr.is_multiple_of(layout.align()) || layout.size() > end - addr {
+                return Err(DeallocError::LayoutMismatch);
+            }
+
+            Ok(())
+        }
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Responds to `corruption` according to [`crate::Config::on_corruption`]. Returns
+    /// it as an error unless the process is aborted. Must be called without the lock.
+    #[cold]
+    pub(crate) fn report_corruption(&self, corruption: Corruption) -> Result<(), Corruption> {
+        #[cfg(feature = "log")]
+        if self.config.on_corruption != CorruptionPolicy::Abort {
+            crate::diagnostics::corruption_found(corruption);
+        }
+
+        match self.config.on_corruption {
+            CorruptionPolicy::Abort => abort_with(corruption.kind.abort_message()),
+            CorruptionPolicy::Report => {}
+            CorruptionPolicy::Callback(callback) => callback(corruption),
+        }
+
+        Err(corruption)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::Layout,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::Config;
+
+    static REPORTED: AtomicUsize = AtomicUsize::new(0);
+
+    fn record(corruption: Corruption) {
+        assert_eq!(corruption.kind, CorruptionKind::DoubleFree);
+        REPORTED.store(corruption.addr, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn double_frees_are_reported_and_leaked() {
+        let allocator = MemAlloc::with_config(Config::new().on_corruption(CorruptionPolicy::Callback(record)));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        unsafe {
+            let [a, b] = [(); 2].map(|_| allocator.allocate(layout));
+
+            allocator.deallocate(a, layout);
+            allocator.deallocate(a, layout);
+            assert_eq!(REPORTED.load(Ordering::Relaxed), a.addr());
+
+            let error = allocator.checked_deallocate(a, layout).unwrap_err();
+            assert_eq!(error.to_string(), format!("double free of {:#x}", a.addr()));
+
+            // Batches report it too, and the rest of the batch is freed.
+            REPORTED.store(0, Ordering::Relaxed);
+            allocator.deallocate_many(&[(a, layout), (b, layout)]);
+            assert_eq!(REPORTED.load(Ordering::Relaxed), a.addr());
+
+            assert_eq!(allocator.stats().allocations, 0);
+            assert_eq!(allocator.summary().regions, 0);
+        }
+    }
+
+    #[test]
+    fn frees_with_a_bigger_layout_are_reported_and_leaked() {
+        static MISMATCHED: AtomicUsize = AtomicUsize::new(0);
+
+        fn record_mismatch(corruption: Corruption) {
+            assert_eq!(corruption.to_string(), format!("free of {:#x} with a layout bigger than the allocation", corruption.addr));
+            MISMATCHED.store(corruption.addr, Ordering::Relaxed);
+        }
+
+        let allocator = MemAlloc::with_config(Config::new().on_corruption(CorruptionPolicy::Callback(record_mismatch)));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        unsafe {
+            let ptr = allocator.allocate(layout);
+
+            allocator.deallocate(ptr, Layout::from_size_align(4096, 8).unwrap());
+            assert_eq!(MISMATCHED.load(Ordering::Relaxed), ptr.addr());
+            assert_eq!(allocator.stats().allocations, 1);
+
+            // The block was left alone, so it can still be freed with the right layout.
+            allocator.deallocate(ptr, layout);
+            assert_eq!(allocator.stats().allocations, 0);
+        }
+    }
+
+    #[test]
+    fn untrusted_pointers_are_rejected_before_freeing() {
+        let allocator = MemAlloc::with_config(Config::new().on_corruption(CorruptionPolicy::Report));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let mut local = [0u64; 4];
+
+        unsafe {
+            let ptr = allocator.allocate(layout);
+
+            let error = allocator.checked_deallocate(local.as_mut_ptr().cast(), layout);
+            assert_eq!(error, Err(DeallocError::UnknownPointer));
+            assert_eq!(allocator.checked_deallocate(ptr.add(8), layout), Err(DeallocError::NotAnAllocation));
+
+            let bigger = Layout::from_size_align(4096, 8).unwrap();
+            assert_eq!(allocator.checked_deallocate(ptr, bigger), Err(DeallocError::LayoutMismatch));
+            let aligned = Layout::from_size_align(100, 4096).unwrap();
+            assert_eq!(allocator.checked_deallocate(ptr, aligned), Err(DeallocError::LayoutMismatch));
+
+            assert_eq!(allocator.stats().allocations, 1);
+            assert_eq!(allocator.checked_deallocate(ptr, layout), Ok(()));
+            assert_eq!(allocator.stats().allocations, 0);
+
+            // Once the region is gone, the pointer is unknown again.
+            assert_eq!(allocator.checked_deallocate(ptr, layout), Err(DeallocError::UnknownPointer));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "checksums")]
+    fn overwritten_headers_are_reported() {
+        let allocator = MemAlloc::with_config(Config::new().on_corruption(CorruptionPolicy::Report));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        unsafe {
+            let [a, b] = [(); 2].map(|_| allocator.allocate(layout));
+
+            // The word before an unpadded payload is the size word of its header.
+            let size_word = b.cast::<usize>().sub(1);
+            size_word.write(size_word.read() + 64);
+
+            let corruption = Corruption { kind: CorruptionKind::CorruptedHeader, addr: b.addr() };
+            assert_eq!(allocator.checked_deallocate(b, layout), Err(DeallocError::Corruption(corruption)));
+
+            // Neighbours are checked before they are merged.
+            assert_eq!(allocator.checked_deallocate(a, layout), Err(DeallocError::Corruption(corruption)));
+
+            size_word.write(size_word.read() - 64);
+            allocator.deallocate(b, layout);
+            assert_eq!(allocator.stats().allocations, 0);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Decay of the region cache.
+//!
+//! A cache of empty regions that is only emptied by [`MemAlloc::trim`] keeps the memory
+//! of the last peak forever, and one that is emptied as soon as it is idle makes the
+//! next burst of allocations pay a syscall for every region again. With
+//! [`crate::Config::decay`], like with the `dirty_decay_ms` of jemalloc, cached bytes
+//! are released gradually instead: the bytes that entered the cache during the last
+//! decay time may stay in it, and less of them the older they get, following a
+//! smoothstep curve:
+//!
+//! ```text
+//!  allowed
+//!  100% |‾‾‾‾‾‾‾---..
+//!       |             `-.
+//!       |                `-.
+//!       |                   `--..
+//!    0% +-------------------------‾‾‾‾--> age
+//!       0                            decay time
+//! ```
+//!
+//! The decay time is split into [`DECAY_EPOCHS`] epochs, and only the number of bytes
+//! that entered the cache in each of them is remembered. Whenever the cache holds more
+//! than the curve allows, its oldest regions are unmapped until it doesn't. A peak that
+//! leaves many regions behind is thus returned over the whole decay time, a few regions
+//! at a time, rather than in a storm of syscalls, and a cache that keeps being reused
+//! stays mapped.
+//!
+//! The cache decays whenever a region enters it or a new region is mapped. Idle heaps
+//! don't do either, so the thread of [`MemAlloc::start_background_unmapping`] also
+//! makes it decay once per epoch, and unmaps the regions itself.
+
+use std::time::{Duration, Instant};
+
+use crate::{kernel::Kernel, region::REGION_HEADER_SIZE};
+#[cfg(doc)]
+use crate::MemAlloc;
+
+/// Number of epochs a decay time is split into.
+pub(crate) const DECAY_EPOCHS: usize = 20;
+
+/// Fixed-point scale of [`WEIGHTS`].
+const SCALE: u64 = 1 << 16;
+
+/// Fraction of the bytes that entered the cache `i` epochs ago that may still be in it,
+/// out of [`SCALE`]: one minus the smoothstep of `i / DECAY_EPOCHS`.
+const WEIGHTS: [u64; DECAY_EPOCHS] = {
+    let mut weights = [0; DECAY_EPOCHS];
+    let mut i = 0;
+
+    while i < DECAY_EPOCHS {
+        let x = i as u64 * SCALE / DECAY_EPOCHS as u64;
+        let smoothstep = (3 * x * x * SCALE - 2 * x * x * x) / (SCALE * SCALE);
+        weights[i] = SCALE - smoothstep;
+        i += 1;
+    }
+
+    weights
+};
+
+/// Bytes that entered the cache during the last decay time. See the module docs.
+pub(crate) struct Decay {
+    /// Start of the current epoch, or `None` until the cache decays for the first time.
+    epoch: Option<Instant>,
+    /// Bytes that entered the cache in each epoch, from the current one back.
+    backlog: [usize; DECAY_EPOCHS],
+    /// Bytes cached after the last time the cache decayed.
+    cached: usize,
+}
+
+impl Decay {
+    pub(crate) const fn new() -> Self {
+        Self { epoch: None, backlog: [0; DECAY_EPOCHS], cached: 0 }
+    }
+
+    /// Moves on to the epoch of `now` and records the growth of the cache up to `cached`
+    /// bytes. Returns how many bytes the cache may hold after decaying for `time`.
+    fn limit(&mut self, time: Duration, now: Instant, cached: usize) -> usize {
+        let epoch_len = (time / DECAY_EPOCHS as u32).max(Duration::from_nanos(1));
+        let start = *self.epoch.get_or_insert(now);
+        let elapsed = now.saturating_duration_since(start).as_nanos() / epoch_len.as_nanos();
+
+        if elapsed >= DECAY_EPOCHS as u128 {
+            self.backlog = [0; DECAY_EPOCHS];
+            self.epoch = Some(now);
+        } else if elapsed > 0 {
+            let elapsed = elapsed as usize;
+            self.backlog.copy_within(..DECAY_EPOCHS - elapsed, elapsed);
+            self.backlog[..elapsed].fill(0);
+            self.epoch = Some(start + epoch_len * elapsed as u32);
+        }
+
+        self.backlog[0] += cached.saturating_sub(self.cached);
+
+        let limit = self.backlog.iter().zip(WEIGHTS).map(|(&bytes, weight)| bytes as u128 * weight as u128).sum::<u128>();
+
+        (limit / SCALE as u128) as usize
+    }
+}
+
+impl Kernel {
+    /// Unmaps the oldest cached regions until the cache holds no more bytes than
+    /// [`crate::Config::decay`] allows. Returns the number of bytes released. See the
+    /// `decay` module.
+    pub(crate) fn decay_cache(&mut self) -> usize {
+        if self.config.decay.is_zero() {
+            return 0;
+        }
+
+        let mut cached: usize = self.cache.iter().map(|region| region.size + REGION_HEADER_SIZE).sum();
+        let limit = self.decay.limit(self.config.decay, Instant::now(), cached);
+        let mut released = 0;
+
+        while cached > limit
+            && let Some(region) = self.cache.first()
+        {
+            unsafe {
+                let total_region_size = region.as_ref().data.size + REGION_HEADER_SIZE;
+
+                self.cache.remove(region);
+                self.unmap_region(region.as_ptr() as *mut u8, total_region_size);
+
+                cached -= total_region_size;
+                released += total_region_size;
+            }
+        }
+
+        self.decay.cached = cached;
+
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{alloc::Layout, thread};
+
+    use super::*;
+    use crate::{Config, MemAlloc};
+
+    #[test]
+    fn cached_bytes_decay_along_the_curve() {
+        let mut decay = Decay::new();
+        let time = Duration::from_secs(20);
+        let start = Instant::now();
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+
+        assert_eq!(decay.limit(time, at(0), 1000), 1000);
+        decay.cached = 1000;
+
+        // Nothing new enters the cache, so what may stay shrinks slowly, then fast,
+        // then slowly again.
+        let limits: Vec<_> = (1..=20).map(|second| decay.limit(time, at(second), 1000)).collect();
+        assert!(limits.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(limits[0] > 990 && limits[9] < 600 && limits[9] > 400 && limits[18] < 10);
+        assert_eq!(limits[19], 0);
+
+        // Bytes that enter the cache later decay on their own schedule.
+        decay.cached = 0;
+        assert_eq!(decay.limit(time, at(30), 500), 500);
+    }
+
+    #[test]
+    fn idle_caches_are_unmapped_after_the_decay_time() {
+        let allocator = MemAlloc::with_config(Config::new().cached_regions(4).decay(Duration::from_millis(40)));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        unsafe {
+            allocator.deallocate(allocator.allocate(layout), layout);
+            assert_eq!(allocator.summary().cached_regions, 1);
+
+            // Right after entering the cache, the region may stay.
+            assert_eq!(allocator.allocator.lock().decay_cache(), 0);
+
+            thread::sleep(Duration::from_millis(50));
+            assert!(allocator.allocator.lock().decay_cache() > 0);
+            assert_eq!(allocator.summary().cached_regions, 0);
+        }
+    }
+}
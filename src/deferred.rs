@@ -0,0 +1,347 @@
+//! Deferred frees.
+//!
+//! A thread that deallocates while another one holds the lock doesn't wait for it.
+//! Instead, it pushes the allocation onto a lock-free queue and returns right away, and
+//! whoever takes the lock next frees every queued allocation before doing anything else:
+//!
+//! ```text
+//!  Thread A (holds the lock)          Thread B                    Thread C
+//!            |                           |                           |
+//!            |                   deallocate(x): lock busy    deallocate(y): lock busy
+//!            |                       push x                      push y
+//!            v                           |                           |
+//!   unlock ... allocate: lock,      head -> y -> x -> null <---------+
+//!        free y and x, allocate
+//! ```
+//!
+//! This borrows the "remote free" queues of allocators with thread-local arenas, where
+//! threads that release memory owned by another arena push it onto that arena's queue
+//! and its owner frees it later. This allocator has no thread arenas: every
+//! [`MemAlloc`] is a single arena behind a single lock, so it has a single queue, and
+//! it is drained by whichever thread takes the lock next rather than by an owner
+//! thread. It is only used when the lock is contended, which is exactly when waiting
+//! for it would hurt.
+//!
+//! The queue is a multi-producer single-consumer stack linked through the payloads of
+//! the queued allocations: the first word holds the next one and the second word the
+//! size of the layout, which the stats need. The consumer takes the whole stack at once
+//! with a swap, so it doesn't suffer from the ABA problem of popping one node at a time.
+//! Allocations that can't hold the two words, sealed ones and executable ones are freed
+//! with the lock as usual, since their payload can't be written, and so are fenced ones,
+//! which have no footer to mark them in.
+//!
+//! Queued allocations are marked in the footer of their block, see
+//! [`Block::set_queued`]. An allocation that is already free or still queued is never
+//! pushed again, since that would link it twice. Its deallocation waits for the lock
+//! instead, which frees the queue first and then reports the double free like any other.
+//!
+//! Queued allocations still count as live in the stats until they are freed.
+
+use std::ptr;
+
+use lock_api::{MutexGuard, RawMutex};
+
+use crate::{
+    block::{BLOCK_FOOTER_SIZE, BLOCK_HEADER_SIZE, Block},
+    heap::StatsCounters,
+    kernel::Kernel,
+    list::Node,
+    memalloc::MemAlloc,
+    region::RegionKind,
+    sync::{
+        self,
+        atomic::{AtomicPtr, Ordering},
+    },
+};
+
+/// Words written to the payload of a queued allocation.
+struct Link {
+    /// Allocation queued before this one, or null.
+    next: *mut u8,
+    /// Size of the layout of this allocation.
+    size: usize,
+}
+
+/// Bytes of the payload overwritten by the [`Link`].
+const LINK_SIZE: usize = size_of::<Link>();
+
+/// Queue of allocations whose deallocation is waiting for the lock. See the module docs.
+pub(crate) struct DeferredFrees {
+    /// Last allocation queued, or null.
+    head: AtomicPtr<u8>,
+}
+
+impl DeferredFrees {
+    sync::const_fn! {
+        pub(crate) const fn new() -> Self {
+            Self { head: AtomicPtr::new(ptr::null_mut()) }
+        }
+    }
+
+    /// Queues the allocation `ptr` of `size` bytes.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a live allocation whose payload is writable and holds at least
+    /// [`LINK_SIZE`] bytes, and it must not be used anymore.
+    unsafe fn push(&self, ptr: *mut u8, size: usize) {
+        let link = ptr.cast::<Link>();
+        let mut head = self.head.load(Ordering::Relaxed);
+
+        loop {
+            unsafe { link.write_unaligned(Link { next: head, size }) };
+
+            match self.head.compare_exchange_weak(head, ptr, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Takes every queued allocation, leaving the queue empty.
+    fn take(&self) -> *mut u8 {
+        // Cheap check, so the common case doesn't write the cache line.
+        if self.head.load(Ordering::Relaxed).is_null() {
+            return ptr::null_mut();
+        }
+
+        self.head.swap(ptr::null_mut(), Ordering::Acquire)
+    }
+}
+
+impl Kernel {
+    /// Frees every allocation queued in `deferred`, removing them from `stats`.
+    pub(crate) fn free_deferred(&mut self, deferred: &DeferredFrees, stats: &StatsCounters) {
+        let mut ptr = deferred.take();
+
+        while !ptr.is_null() {
+            unsafe {
+                let Link { next, size } = ptr.cast::<Link>().read_unaligned();
+                let node = Block::from_payload(ptr);
+
+                // Freeing the block rewrites the footer, but it may find corruption and
+                // leak the block instead.
+                Block::set_queued(node, false);
+
+                self.free_block(stats, node, ptr, size);
+                ptr = next;
+            }
+        }
+    }
+}
+
+/// Whether the payload of the block `node` can hold the link of the queue from `ptr` on
+/// and its footer the queued flag. Blocks that are free or already queued can't, since
+/// freeing them is a double free that only the lock holder can report.
+///
+/// # Safety
+///
+/// `node` must point to a valid block header and `ptr` to its payload.
+unsafe fn can_queue(node: std::ptr::NonNull<Node<Block>>, ptr: *mut u8) -> bool {
+    unsafe {
+        let data = &node.as_ref().data;
+        let end = node.as_ptr() as usize + BLOCK_HEADER_SIZE + data.size() - BLOCK_FOOTER_SIZE;
+
+        end - ptr as usize >= LINK_SIZE
+            && !data.is_free()
+            && !matches!(data.region.as_ref().data.kind, RegionKind::Executable | RegionKind::Fenced)
+            && !Block::is_sealed(node)
+            && !Block::is_queued(node)
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Takes the lock and frees the queued allocations.
+    pub(crate) fn lock_draining(&self) -> MutexGuard<'_, R, Kernel> {
+        let mut kernel = sync::lock(&self.allocator);
+        kernel.free_deferred(&self.deferred, &self.stats);
+
+        kernel
+    }
+
+    /// Takes the lock for the deallocation of `ptr` of `size` bytes, or queues it and
+    /// returns `None` if the lock is busy.
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to the block header of the allocation `ptr`.
+    pub(crate) unsafe fn lock_or_defer(&self, node: std::ptr::NonNull<Node<Block>>, ptr: *mut u8, size: usize) -> Option<MutexGuard<'_, R, Kernel>> {
+        // The profiles of the `dhat` feature tell blocks that are never written from
+        // their contents, which the link would overwrite.
+        if !cfg!(feature = "dhat") && unsafe { can_queue(node, ptr) } {
+            match self.allocator.try_lock() {
+                Some(mut kernel) => {
+                    kernel.free_deferred(&self.deferred, &self.stats);
+                    return Some(kernel);
+                }
+                // Another thread may be queueing the same block right now, only the one
+                // that marks it first links it.
+                None if unsafe { !Block::set_queued(node, true) } => {
+                    unsafe { self.deferred.push(ptr, size) };
+                    return None;
+                }
+                None => {}
+            }
+        }
+
+        Some(self.lock_draining())
+    }
+}
+
+impl<R: RawMutex> Drop for MemAlloc<R> {
+    fn drop(&mut self) {
+        // The kernel unmaps every region once the queue is empty, see `Kernel::drop`.
+        self.allocator.get_mut().free_deferred(&self.deferred, &self.stats);
+    }
+}
+
+// With `dhat` frees are never deferred, so the test would wait for the lock forever.
+#[cfg(all(test, not(feature = "dhat")))]
+mod tests {
+    use std::{
+        alloc::Layout,
+        sync::{
+            Barrier,
+            atomic::{AtomicUsize, Ordering},
+        },
+        thread,
+    };
+
+    use super::*;
+    use crate::{Config, Corruption, CorruptionKind, CorruptionPolicy};
+
+    #[test]
+    fn contended_frees_are_deferred() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        unsafe {
+            let ptrs: Vec<_> = (0..100).map(|_| allocator.allocate(layout).expose_provenance()).collect();
+            let tiny = Layout::new::<u8>();
+            let small = allocator.allocate(tiny);
+
+            let kernel = sync::lock(&allocator.allocator);
+            let barrier = Barrier::new(2);
+
+            // The lock is held, so the frees are queued instead of waiting for it.
+            thread::scope(|scope| {
+                scope.spawn(|| {
+                    for &ptr in &ptrs {
+                        allocator.deallocate(ptr::with_exposed_provenance_mut(ptr), layout);
+                    }
+
+                    barrier.wait();
+                });
+
+                barrier.wait();
+            });
+
+            assert!(!allocator.deferred.head.load(Ordering::Relaxed).is_null());
+            drop(kernel);
+
+            assert_eq!(allocator.stats().allocations, 101);
+
+            // The next allocation frees them first.
+            let ptr = allocator.allocate(layout);
+            assert!(allocator.deferred.head.load(Ordering::Relaxed).is_null());
+            assert_eq!(allocator.stats().allocations, 2);
+
+            allocator.deallocate(ptr, layout);
+            allocator.deallocate(small, tiny);
+            assert_eq!(allocator.stats().allocations, 0);
+        }
+    }
+
+    #[test]
+    fn contended_double_frees_are_reported() {
+        static REPORTED: AtomicUsize = AtomicUsize::new(0);
+
+        fn record(corruption: Corruption) {
+            assert_eq!(corruption.kind, CorruptionKind::DoubleFree);
+            REPORTED.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let allocator = MemAlloc::with_config(Config::new().on_corruption(CorruptionPolicy::Callback(record)));
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        unsafe {
+            // The neighbour keeps the region mapped, so the second frees find the blocks.
+            let [queued, freed, neighbour] = [(); 3].map(|_| allocator.allocate(layout).expose_provenance());
+            allocator.deallocate(ptr::with_exposed_provenance_mut(freed), layout);
+
+            let kernel = sync::lock(&allocator.allocator);
+
+            thread::scope(|scope| {
+                let thread = scope.spawn(|| {
+                    // Freed twice while queued, and once more after it was freed. The
+                    // second free of each waits for the lock.
+                    for ptr in [queued, queued, freed] {
+                        allocator.deallocate(ptr::with_exposed_provenance_mut(ptr), layout);
+                    }
+                });
+
+                while allocator.deferred.head.load(Ordering::Relaxed).is_null() {
+                    thread::yield_now();
+                }
+
+                drop(kernel);
+                thread.join().unwrap();
+            });
+
+            assert_eq!(REPORTED.load(Ordering::Relaxed), 2);
+            assert!(allocator.deferred.head.load(Ordering::Relaxed).is_null());
+            assert_eq!(allocator.stats().allocations, 1);
+
+            allocator.deallocate(ptr::with_exposed_provenance_mut(neighbour), layout);
+            assert_eq!(allocator.stats().allocations, 0);
+        }
+    }
+
+    /// Sizes of the allocations of the queue taken from `deferred`.
+    #[cfg(loom)]
+    fn take_sizes(deferred: &DeferredFrees) -> Vec<usize> {
+        let mut sizes = Vec::new();
+        let mut ptr = deferred.take();
+
+        while !ptr.is_null() {
+            let Link { next, size } = unsafe { ptr.cast::<Link>().read_unaligned() };
+            sizes.push(size);
+            ptr = next;
+        }
+
+        sizes
+    }
+
+    #[test]
+    #[cfg(loom)]
+    fn loom_queued_frees_are_taken_once() {
+        loom::model(|| {
+            let deferred = loom::sync::Arc::new(DeferredFrees::new());
+            let payloads: Vec<usize> = (0..2).map(|_| Box::into_raw(Box::new([0usize; 2])).expose_provenance()).collect();
+
+            let threads: Vec<_> = payloads
+                .iter()
+                .enumerate()
+                .map(|(index, &payload)| {
+                    let deferred = loom::sync::Arc::clone(&deferred);
+                    loom::thread::spawn(move || unsafe { deferred.push(ptr::with_exposed_provenance_mut(payload), index + 1) })
+                })
+                .collect();
+
+            // Taking the queue while the pushes are in flight must not lose nor repeat any.
+            let mut sizes = take_sizes(&deferred);
+
+            for thread in threads {
+                thread.join().unwrap();
+            }
+
+            sizes.extend(take_sizes(&deferred));
+            sizes.sort_unstable();
+            assert_eq!(sizes, [1, 2]);
+
+            for payload in payloads {
+                drop(unsafe { Box::from_raw(ptr::with_exposed_provenance_mut::<[usize; 2]>(payload)) });
+            }
+        });
+    }
+}
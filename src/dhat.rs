@@ -0,0 +1,401 @@
+//! DHAT-style allocation profiles.
+//!
+//! With the `dhat` feature, every allocator keeps a profile of the allocations made by
+//! each call site (see [`MemAlloc::call_site`]): how many blocks and bytes it allocated,
+//! how long they lived and how many of them were never written. [`MemAlloc::write_dhat`]
+//! writes it in the JSON format of Valgrind's DHAT, which can be opened with its viewer
+//! (`dh_view.html`) to find short-lived and oversized allocations:
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::MemAlloc;
+//!
+//! let allocator = MemAlloc::new();
+//! let layout = Layout::from_size_align(1000, 8).unwrap();
+//!
+//! unsafe {
+//!     for _ in 0..10 {
+//!         // Allocated but never written.
+//!         allocator.deallocate(allocator.allocate(layout), layout);
+//!     }
+//! }
+//!
+//! let site = allocator.dhat_sites().next().unwrap();
+//! assert_eq!((site.blocks, site.unwritten_blocks), (10, 10));
+//!
+//! let mut profile = Vec::new();
+//! allocator.write_dhat("my-program", &mut profile).unwrap();
+//! // std::fs::write("dhat-heap.json", profile).unwrap();
+//! ```
+//!
+//! DHAT finds the blocks that are never written by instrumenting every memory access.
+//! We can't do that, so new allocations are filled with a pattern instead, and blocks
+//! that still hold it when they are deallocated count as never written. Blocks written
+//! with the pattern itself are counted too. Filling and checking the contents takes time
+//! proportional to the size of every allocation, and it commits the pages of large ones.
+//!
+//! Each allocator profiles up to [`MAX_DHAT_SITES`] call sites, allocations made by any
+//! other site are not profiled. Hardened allocations have no call site and are not
+//! profiled either.
+
+use std::{
+    io::{self, Write},
+    panic::Location,
+    ptr::NonNull,
+    slice,
+    time::{Duration, Instant},
+};
+
+use lock_api::RawMutex;
+
+use crate::{block::Block, kernel::Kernel, list::Node, memalloc::MemAlloc, sync};
+
+/// Maximum number of call sites profiled by an allocator.
+pub const MAX_DHAT_SITES: usize = 256;
+
+/// Byte new allocations are filled with, to tell whether they are written.
+const UNWRITTEN: u8 = 0xDB;
+
+/// Profile of the allocations of a call site, returned by [`MemAlloc::dhat_sites`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DhatSite {
+    /// Source location that made the allocations.
+    pub call_site: &'static Location<'static>,
+    /// Number of blocks allocated.
+    pub blocks: usize,
+    /// Bytes allocated.
+    pub bytes: usize,
+    /// Sum of the lifetimes of the blocks. Live blocks count until now.
+    pub lifetime: Duration,
+    /// Maximum number of blocks that have been live at the same time.
+    pub max_blocks: usize,
+    /// Maximum number of bytes that have been live at the same time.
+    pub max_bytes: usize,
+    /// Blocks that were live when the profiled bytes of the allocator peaked.
+    pub peak_blocks: usize,
+    /// Bytes that were live when the profiled bytes of the allocator peaked.
+    pub peak_bytes: usize,
+    /// Blocks that are live now.
+    pub live_blocks: usize,
+    /// Bytes that are live now.
+    pub live_bytes: usize,
+    /// Deallocated blocks that were never written. See the `dhat` module.
+    pub unwritten_blocks: usize,
+}
+
+/// Profile of a call site while it is being recorded.
+#[derive(Clone, Copy)]
+struct Record {
+    site: DhatSite,
+    /// Microseconds lived by the deallocated blocks.
+    lifetime: usize,
+    /// Sum of the allocation times of the live blocks, so their lifetime can be added
+    /// when the profile is read.
+    live_since: usize,
+}
+
+/// Profiles of the call sites of an allocator. Records are found by hashing the line
+/// and column of their call site.
+pub(crate) struct DhatTable {
+    records: [Option<Record>; MAX_DHAT_SITES],
+    /// When the first allocation was profiled. Times are microseconds since then.
+    start: Option<Instant>,
+    /// Bytes of every live profiled block.
+    live: usize,
+    /// Maximum of `live`.
+    peak: usize,
+    /// When `live` reached `peak`.
+    peak_at: usize,
+}
+
+impl DhatTable {
+    pub(crate) const fn new() -> Self {
+        Self { records: [None; MAX_DHAT_SITES], start: None, live: 0, peak: 0, peak_at: 0 }
+    }
+
+    /// Microseconds since the first allocation was profiled.
+    fn now(&mut self) -> usize {
+        self.start.get_or_insert_with(Instant::now).elapsed().as_micros() as usize
+    }
+
+    /// Returns the record of `site`, creating it if `create` is set and there is room.
+    fn record(&mut self, site: &'static Location<'static>, create: bool) -> Option<&mut Record> {
+        let hash = (site.line() as usize).wrapping_mul(31).wrapping_add(site.column() as usize);
+        let mut index = hash % MAX_DHAT_SITES;
+
+        for _ in 0..MAX_DHAT_SITES {
+            match self.records[index] {
+                Some(record) if *record.site.call_site == *site => break,
+                Some(_) => index = (index + 1) % MAX_DHAT_SITES,
+                None if create => {
+                    self.records[index] = Some(Record {
+                        site: DhatSite {
+                            call_site: site,
+                            blocks: 0,
+                            bytes: 0,
+                            lifetime: Duration::ZERO,
+                            max_blocks: 0,
+                            max_bytes: 0,
+                            peak_blocks: 0,
+                            peak_bytes: 0,
+                            live_blocks: 0,
+                            live_bytes: 0,
+                            unwritten_blocks: 0,
+                        },
+                        lifetime: 0,
+                        live_since: 0,
+                    });
+                    break;
+                }
+                None => return None,
+            }
+        }
+
+        self.records[index].as_mut().filter(|record| *record.site.call_site == *site)
+    }
+
+    /// Profiles of every call site, with the lifetime of the live blocks up to now, and
+    /// the current time.
+    fn sites(&mut self) -> ([Option<DhatSite>; MAX_DHAT_SITES], usize) {
+        let now = self.now();
+
+        let sites = self.records.map(|record| {
+            record.map(|Record { mut site, lifetime, live_since }| {
+                let lifetime = lifetime + site.live_blocks * now - live_since;
+                site.lifetime = Duration::from_micros(lifetime as u64);
+                site
+            })
+        });
+
+        (sites, now)
+    }
+}
+
+impl Kernel {
+    /// Profiles the allocation `ptr` of `size` bytes of the used block `node`, filling
+    /// it with [`UNWRITTEN`] except for the first `kept` bytes, which already hold data.
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to a valid used block header with its call site recorded, and
+    /// `ptr` to its payload.
+    pub(crate) unsafe fn profile_allocation(&mut self, mut node: NonNull<Node<Block>>, ptr: *mut u8, size: usize, kept: usize) {
+        let now = self.dhat.now();
+
+        unsafe { node.as_mut().data.allocated_at = now };
+
+        let Some(call_site) = (unsafe { node.as_ref().data.call_site }) else {
+            return;
+        };
+
+        let Some(record) = self.dhat.record(call_site, true) else {
+            return;
+        };
+
+        let site = &mut record.site;
+        site.blocks += 1;
+        site.bytes += size;
+        site.live_blocks += 1;
+        site.live_bytes += size;
+        site.max_blocks = site.max_blocks.max(site.live_blocks);
+        site.max_bytes = site.max_bytes.max(site.live_bytes);
+        record.live_since += now;
+
+        if size > kept {
+            unsafe { ptr.add(kept).write_bytes(UNWRITTEN, size - kept) };
+        }
+
+        let table = &mut self.dhat;
+        table.live += size;
+
+        if table.live > table.peak {
+            table.peak = table.live;
+            table.peak_at = now;
+
+            for record in table.records.iter_mut().flatten() {
+                record.site.peak_blocks = record.site.live_blocks;
+                record.site.peak_bytes = record.site.live_bytes;
+            }
+        }
+    }
+
+    /// Removes the allocation `ptr` of `size` bytes of the used block `node` from the
+    /// live blocks of its call site.
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to a valid used block header and `ptr` to its payload.
+    pub(crate) unsafe fn profile_deallocation(&mut self, node: NonNull<Node<Block>>, ptr: *mut u8, size: usize) {
+        let now = self.dhat.now();
+        let (call_site, allocated_at) = unsafe { (node.as_ref().data.call_site, node.as_ref().data.allocated_at) };
+
+        // Blocks restored from a snapshot have no call site and were never profiled.
+        let Some(record) = call_site.and_then(|site| self.dhat.record(site, false)) else {
+            return;
+        };
+
+        if record.site.live_blocks == 0 {
+            return;
+        }
+
+        let written = unsafe { slice::from_raw_parts(ptr, size) }.iter().any(|&byte| byte != UNWRITTEN);
+
+        let site = &mut record.site;
+        site.live_blocks -= 1;
+        site.live_bytes -= size;
+        site.unwritten_blocks += !written as usize;
+        record.lifetime += now - allocated_at;
+        record.live_since -= allocated_at;
+
+        self.dhat.live -= size;
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Returns the profile of every call site that allocated with this allocator. See
+    /// the `dhat` module.
+    pub fn dhat_sites(&self) -> impl Iterator<Item = DhatSite> {
+        let (sites, _) = sync::lock(&self.allocator).dhat.sites();
+
+        sites.into_iter().flatten()
+    }
+
+    /// Writes the profile of every call site to `out` in the JSON format of DHAT. `cmd`
+    /// is the command shown by the viewer. See the `dhat` module.
+    ///
+    /// The viewer doesn't know about blocks that are never written, so their number is
+    /// written in an extra `uwbk` field of every site that it ignores.
+    pub fn write_dhat(&self, cmd: &str, out: &mut impl Write) -> io::Result<()> {
+        // The profile is copied so nothing is written with the lock held.
+        let ((sites, now), peak_at) = {
+            let mut kernel = sync::lock(&self.allocator);
+            (kernel.dhat.sites(), kernel.dhat.peak_at)
+        };
+
+        write!(out, "{{\"dhatFileVersion\":2,\"mode\":\"rust-heap\",\"verb\":\"Allocated\",")?;
+        write!(out, "\"bklt\":true,\"bkacc\":false,\"tu\":\"µs\",\"Mtu\":\"s\",\"tuth\":10,\"cmd\":")?;
+        write_json_string(cmd, out)?;
+        write!(out, ",\"pid\":{},\"tg\":{peak_at},\"te\":{now},\"pps\":[", std::process::id())?;
+
+        // Frame 0 is the root, the one of every site follows.
+        for (index, site) in sites.iter().flatten().enumerate() {
+            if index > 0 {
+                out.write_all(b",")?;
+            }
+
+            write!(
+                out,
+                "{{\"tb\":{},\"tbk\":{},\"tl\":{},\"mb\":{},\"mbk\":{},\"gb\":{},\"gbk\":{},\"eb\":{},\"ebk\":{},\"uwbk\":{},\"fs\":[{}]}}",
+                site.bytes,
+                site.blocks,
+                site.lifetime.as_micros(),
+                site.max_bytes,
+                site.max_blocks,
+                site.peak_bytes,
+                site.peak_blocks,
+                site.live_bytes,
+                site.live_blocks,
+                site.unwritten_blocks,
+                index + 1,
+            )?;
+        }
+
+        write!(out, "],\"ftbl\":[\"[root]\"")?;
+
+        for site in sites.iter().flatten() {
+            let location = site.call_site;
+
+            out.write_all(b",")?;
+            write_json_string(&format!("0x0: ??? ({}:{}:{})", location.file(), location.line(), location.column()), out)?;
+        }
+
+        writeln!(out, "]}}")
+    }
+}
+
+/// Writes `value` as a JSON string.
+fn write_json_string(value: &str, out: &mut impl Write) -> io::Result<()> {
+    out.write_all(b"\"")?;
+
+    for c in value.chars() {
+        match c {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{c}")?,
+        }
+    }
+
+    out.write_all(b"\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{alloc::Layout, ptr};
+
+    use super::*;
+
+    #[test]
+    fn sites_are_profiled() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        unsafe {
+            let (written, line) = ((0..3).map(|_| allocator.allocate(layout)).collect::<Vec<_>>(), line!());
+            let (unwritten, unwritten_line) = (allocator.allocate(layout), line!());
+
+            for &ptr in &written {
+                ptr::write_bytes(ptr, 0, 1);
+            }
+
+            allocator.deallocate(written[0], layout);
+            allocator.deallocate(unwritten, layout);
+
+            let sites: Vec<_> = allocator.dhat_sites().collect();
+            assert_eq!(sites.len(), 2);
+
+            let site = sites.iter().find(|site| site.call_site.line() == line).unwrap();
+            assert_eq!((site.blocks, site.bytes, site.max_blocks, site.peak_blocks), (3, 300, 3, 3));
+            assert_eq!((site.live_blocks, site.live_bytes, site.unwritten_blocks), (2, 200, 0));
+
+            let site = sites.iter().find(|site| site.call_site.line() == unwritten_line).unwrap();
+            assert_eq!((site.blocks, site.peak_blocks, site.live_blocks, site.unwritten_blocks), (1, 1, 0, 1));
+
+            let mut profile = Vec::new();
+            allocator.write_dhat("test \"quoted\"", &mut profile).unwrap();
+            let profile = String::from_utf8(profile).unwrap();
+
+            assert!(profile.starts_with("{\"dhatFileVersion\":2,"));
+            assert!(profile.contains("\"cmd\":\"test \\\"quoted\\\"\""));
+            assert!(profile.contains("\"tb\":300,\"tbk\":3,"));
+            assert!(profile.contains(&format!("\"0x0: ??? ({}:{line}:", file!())));
+            assert!(profile.ends_with("]}\n"));
+
+            for &ptr in &written[1..] {
+                allocator.deallocate(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn resized_allocations_keep_their_contents() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::from_size_align(2 * 1024 * 1024, 8).unwrap();
+
+        unsafe {
+            let ptr = allocator.allocate(layout);
+            ptr.write(42);
+
+            let ptr = allocator.reallocate(ptr, layout, 4 * 1024 * 1024);
+            assert_eq!(ptr.read(), 42);
+            assert_eq!(ptr.add(3 * 1024 * 1024).read(), UNWRITTEN);
+
+            allocator.deallocate(ptr, Layout::from_size_align(4 * 1024 * 1024, 8).unwrap());
+
+            // The resized block counts as a new one, like in DHAT.
+            let sites: Vec<_> = allocator.dhat_sites().collect();
+            assert_eq!(sites.iter().map(|site| site.blocks).sum::<usize>(), 2);
+            assert!(sites.iter().all(|site| site.live_blocks == 0 && site.unwritten_blocks == 0));
+        }
+    }
+}
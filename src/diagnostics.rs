@@ -0,0 +1,115 @@
+//! Diagnostics through the `log` crate.
+//!
+//! With the `log` feature, the allocator emits records that tell operators about the
+//! problems it would otherwise only report through null pointers or hooks:
+//!
+//! - An error when an allocation fails because memory can't be mapped.
+//! - A warning when an allocation goes over [`crate::Config::quota`].
+//! - An error when heap corruption is found, unless [`crate::Config::on_corruption`]
+//!   aborts the process, since the logger can't be trusted to work on a corrupted heap.
+//!
+//! Records are emitted without the allocator lock held, so a logger can allocate with the
+//! same allocator, even if it is the global one. If the logger itself runs out of memory
+//! on the same thread, that failure is not logged again.
+
+use std::{alloc::Layout, cell::Cell};
+
+use crate::{corruption::Corruption, error::AllocError};
+
+thread_local! {
+    /// Whether a record is being emitted on the current thread.
+    static LOGGING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `emit` unless the current thread is already emitting a record, so a logger whose
+/// allocations fail doesn't recurse.
+fn guarded(emit: impl FnOnce()) {
+    if LOGGING.try_with(|logging| logging.replace(true)) != Ok(false) {
+        return;
+    }
+
+    emit();
+
+    let _ = LOGGING.try_with(|logging| logging.set(false));
+}
+
+/// Logs that the allocation of `layout` failed with `error`.
+#[cold]
+pub(crate) fn allocation_failed(layout: Layout, error: AllocError) {
+    guarded(|| match error {
+        AllocError::LimitExceeded => log::warn!("allocation of {} bytes goes over the quota", layout.size()),
+        error => log::error!("allocation of {} bytes failed: {error}", layout.size()),
+    });
+}
+
+/// Logs that `corruption` was found.
+#[cold]
+pub(crate) fn corruption_found(corruption: Corruption) {
+    guarded(|| log::error!("heap corruption found: {corruption}"));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        fmt::Write,
+    };
+
+    use log::{Level, Log, Metadata, Record};
+
+    use super::*;
+    use crate::{Config, CorruptionPolicy, MemAlloc};
+
+    thread_local! {
+        /// Records emitted on the current thread, so other tests don't interfere.
+        static RECORDS: RefCell<Vec<(Level, String)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    struct ThreadLogger;
+
+    impl Log for ThreadLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            let mut message = String::new();
+            let _ = write!(message, "{}", record.args());
+
+            RECORDS.with_borrow_mut(|records| records.push((record.level(), message)));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend"), ignore = "needs OS memory")]
+    fn failures_and_corruption_are_logged() {
+        let _ = log::set_logger(&ThreadLogger);
+        log::set_max_level(log::LevelFilter::Warn);
+
+        let limited = MemAlloc::with_config(Config::new().quota(4096).on_corruption(CorruptionPolicy::Report));
+
+        unsafe {
+            let big = Layout::from_size_align(8192, 8).unwrap();
+            assert!(limited.allocate(big).is_null());
+
+            let layout = Layout::from_size_align(100, 8).unwrap();
+            let [a, b] = [(); 2].map(|_| limited.allocate(layout));
+            limited.deallocate(a, layout);
+            limited.deallocate(a, layout);
+            limited.deallocate(b, layout);
+
+            // Asking for more address space than there is.
+            let huge = Layout::from_size_align(isize::MAX as usize / 2, 8).unwrap();
+            assert!(MemAlloc::new().allocate(huge).is_null());
+        }
+
+        let records = RECORDS.take();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0], (Level::Warn, "allocation of 8192 bytes goes over the quota".into()));
+        assert_eq!(records[1].0, Level::Error);
+        assert!(records[1].1.starts_with("heap corruption found: double free"));
+        assert_eq!(records[2].0, Level::Error);
+    }
+}
@@ -0,0 +1,80 @@
+//! Errors of the fallible APIs.
+//!
+//! Everything that can fail because memory couldn't be mapped, protected or accounted
+//! for returns an [`AllocError`], so callers can tell running out of address space from
+//! a refused system call or from a limit set in the [`crate::Config`]:
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::{AllocError, Config, MemAlloc};
+//!
+//! let allocator = MemAlloc::with_config(Config::new().quota(4096));
+//!
+//! unsafe {
+//!     let error = allocator.try_allocate(Layout::from_size_align(8192, 8).unwrap());
+//!     assert_eq!(error, Err(AllocError::LimitExceeded));
+//! }
+//! ```
+//!
+//! [`MemAlloc::allocate`] still returns null on failure, as [`std::alloc::GlobalAlloc`]
+//! requires, and [`MemAlloc::try_allocate`] returns the reason instead. Errors that are
+//! not about memory, like malformed snapshots and traces, handles in the wrong state,
+//! heaps registered twice or threads that can't be started, are still described by a
+//! message.
+//!
+//! [`MemAlloc::allocate`]: crate::MemAlloc::allocate
+//! [`MemAlloc::try_allocate`]: crate::MemAlloc::try_allocate
+
+use std::{error::Error, fmt, io};
+
+/// Why a fallible operation of the allocator failed. See the `error` module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AllocError {
+    /// The OS has no memory or address space left for the mapping.
+    ExhaustedAddressSpace,
+    /// A system call failed with this error code (`errno` on Unix, `GetLastError` on
+    /// Windows).
+    OsError(i32),
+    /// The operation would go over a limit: [`crate::Config::quota`], the capacity of a
+    /// fixed-size table or the reservation of a [`crate::VirtualVec`].
+    LimitExceeded,
+    /// The layout can't be allocated, for example because its size overflows once
+    /// aligned.
+    InvalidLayout,
+    /// The pointer or handle was not returned by this allocator, or not by the method
+    /// the operation expects.
+    InvalidPointer,
+    /// The operation is not available for this allocation, mode or platform.
+    Unsupported,
+}
+
+impl AllocError {
+    /// Error of a system call that just failed, read from the last OS error of the
+    /// thread. Backends that don't set it, or set it to out of memory, give
+    /// [`AllocError::ExhaustedAddressSpace`].
+    pub(crate) fn last_os_error() -> Self {
+        let error = io::Error::last_os_error();
+
+        match error.raw_os_error() {
+            Some(code) if code != 0 && error.kind() != io::ErrorKind::OutOfMemory => Self::OsError(code),
+            _ => Self::ExhaustedAddressSpace,
+        }
+    }
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ExhaustedAddressSpace => f.write_str("out of memory or address space"),
+            Self::OsError(code) => write!(f, "system call failed: {}", io::Error::from_raw_os_error(*code)),
+            Self::LimitExceeded => f.write_str("memory limit exceeded"),
+            Self::InvalidLayout => f.write_str("invalid layout"),
+            Self::InvalidPointer => f.write_str("pointer not allocated by this allocator"),
+            Self::Unsupported => f.write_str("operation not supported"),
+        }
+    }
+}
+
+impl Error for AllocError {}
+
@@ -0,0 +1,91 @@
+//! Executable memory for JIT compilers.
+//!
+//! Regular regions are readable and writable and they are shared by many blocks, so we
+//! can't change their protection. Executable allocations get a dedicated region where
+//! the code starts at a page boundary:
+//!
+//! ```text
+//! +-------------------------------+----------------------------------------------+
+//! | Node<Region> | Node<Block> |  |                Code (page-aligned)           |
+//! +-------------------------------+----------------------------------------------+
+//! ^                               ^
+//! |                               |
+//! Always read-write               Read-write or read-execute
+//! ```
+//!
+//! This way, the allocator metadata lives in its own page, which is always writable, and
+//! only the code pages are flipped between read-write and read-execute.
+
+use std::{alloc::Layout, cmp, ptr::NonNull};
+
+use crate::{
+    config::CommitCharge,
+    error::AllocError,
+    kernel::{Kernel, Protection, protect},
+    list::Node,
+    region::{REGION_HEADER_SIZE, Region, RegionKind},
+    syscalls::Syscall,
+};
+
+impl Kernel {
+    /// Maps a dedicated region for executable code and records it in [`Kernel::executable`].
+    /// The returned pointer is page-aligned and the memory is readable and writable.
+    pub(crate) fn allocate_executable(&mut self, layout: Layout) -> Result<*mut u8, AllocError> {
+        self.init();
+
+        // Aligning the code to a page boundary keeps the headers out of the code pages.
+        let layout = Layout::from_size_align(layout.size(), cmp::max(layout.align(), self.page_size))
+            .map_err(|_| AllocError::InvalidLayout)?;
+
+        unsafe {
+            let node = self.preferred_node();
+            let (region, ptr) = self
+                .map_or_trim(|kernel| {
+                    kernel.count_map(Self::map_single(layout, kernel.page_size, RegionKind::Executable, node, kernel.config.low_address, CommitCharge::Default))
+                })
+                .ok_or_else(AllocError::last_os_error)?;
+
+            self.executable.append_node(region);
+
+            Ok(ptr)
+        }
+    }
+
+    /// Changes the protection of the code pages of the executable allocation `ptr`.
+    pub(crate) fn protect_executable(&self, ptr: *mut u8, protection: Protection) -> Result<(), AllocError> {
+        let region = Self::find_region(&self.executable, ptr as usize).ok_or(AllocError::InvalidPointer)?;
+
+        unsafe {
+            let (start, len) = Self::code_pages(region);
+
+            if start != ptr {
+                return Err(AllocError::InvalidPointer);
+            }
+
+            self.syscalls.count(Syscall::Protect);
+
+            if protect(start, len, protection) {
+                Ok(())
+            } else {
+                Err(AllocError::last_os_error())
+            }
+        }
+    }
+
+    /// Returns the start and the length of the pages of `region` that hold code.
+    ///
+    /// # Safety
+    ///
+    /// `region` must be a [`RegionKind::Executable`] region.
+    unsafe fn code_pages(region: NonNull<Node<Region>>) -> (*mut u8, usize) {
+        unsafe {
+            let region_start = region.as_ptr() as *mut u8;
+            let region_end = region_start.add(REGION_HEADER_SIZE + region.as_ref().data.size);
+
+            // Headers never fill a whole page, so the code starts at the second one.
+            let start = region_start.add(crate::kernel::page_size());
+
+            (start, region_end.offset_from(start) as usize)
+        }
+    }
+}
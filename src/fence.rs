@@ -0,0 +1,165 @@
+//! Electric-fence mode.
+//!
+//! Heap overruns and uses after free usually corrupt some other allocation and crash
+//! much later, far from the bug. With [`crate::Config::electric_fence`], like with the
+//! Electric Fence library, every allocation gets a dedicated region whose payload ends
+//! right at a page boundary, followed by an inaccessible guard page:
+//!
+//! ```text
+//! +---------------------------------------------+-----------------+
+//! | Node<Region> | Node<Block> |  |   Payload   |   Guard page    |
+//! +---------------------------------------------+-----------------+
+//!                                               ^
+//!                                               |
+//!                                               Page boundary, PROT_NONE from here on
+//! ```
+//!
+//! Reading or writing even one byte past the end of the payload faults at the offending
+//! instruction. When the allocation is freed, its whole region is made inaccessible and
+//! put in quarantine (see the `quarantine` module), so uses after free fault too until
+//! the region is pushed out of it. If [`crate::Config::quarantine_regions`] isn't set,
+//! the quarantine holds [`crate::MAX_QUARANTINED_REGIONS`] of them.
+//!
+//! Payloads are aligned to the alignment of their layout, so there can be a few bytes
+//! between the end of an allocation whose size isn't a multiple of it and the guard
+//! page. Every allocation takes at least two pages and a syscall, so this is only meant
+//! for debugging.
+//!
+//! The footer of a fenced block would be the first word of the guard page, so it is
+//! never written, and fenced allocations can't be sealed. Platforms that can't change
+//! the protection of their memory, like the `system-backend` one, get no guard pages.
+
+use std::{alloc::Layout, mem, ptr::NonNull};
+
+use crate::{
+    block::{BLOCK_FOOTER_SIZE, BLOCK_HEADER_SIZE, Block},
+    error::AllocError,
+    kernel::{Kernel, Protection, name_memory, protect, request_memory},
+    list::Node,
+    region::{REGION_HEADER_SIZE, Region, RegionKind},
+    syscalls::Syscall,
+    utils::align,
+};
+
+impl Kernel {
+    /// Maps a dedicated region for `layout` whose payload is followed by a guard page,
+    /// and records it in [`Kernel::large_objects`]. See the `fence` module.
+    ///
+    /// The alignment of `layout` must not be bigger than the page size.
+    pub(crate) fn allocate_fenced(&mut self, layout: Layout) -> Result<*mut u8, AllocError> {
+        self.init();
+
+        let page_size = self.page_size;
+
+        // The payload starts after the headers and the reflection word, and ends at the
+        // first page boundary after it.
+        let headers = align(REGION_HEADER_SIZE + BLOCK_HEADER_SIZE + mem::size_of::<usize>(), layout.align());
+        let payload_end = align(headers + layout.size(), page_size);
+        let payload_start = (payload_end - layout.size()) & !(layout.align() - 1);
+        let len = payload_end + page_size;
+
+        unsafe {
+            let config = self.config;
+            let addr = self
+                .map_or_trim(|kernel| kernel.count_map(request_memory(len, config.low_address)))
+                .ok_or_else(AllocError::last_os_error)?;
+
+            let kind = RegionKind::Fenced;
+            name_memory(addr.as_ptr(), len, kind.name());
+
+            // Without it the allocation is still usable, just not guarded.
+            self.syscalls.count(Syscall::Protect);
+            protect(addr.as_ptr().add(payload_end), page_size, Protection::Inaccessible);
+
+            let mut region = addr.cast::<Node<Region>>();
+            region.as_ptr().write(Node {
+                next: None,
+                prev: None,
+                data: Region::new(len - REGION_HEADER_SIZE, kind, None),
+            });
+
+            // The footer is never written, see the module docs.
+            let block_addr = NonNull::new_unchecked(region.as_ptr().offset(1)).cast();
+            let block_size = payload_end + BLOCK_FOOTER_SIZE - REGION_HEADER_SIZE - BLOCK_HEADER_SIZE;
+            let block = region.as_mut().data.blocks.append(Block::new(block_size, false, region), block_addr);
+
+            let ptr = addr.as_ptr().add(payload_start);
+            Block::reflect(block, ptr);
+
+            self.large_objects.append_node(region);
+
+            Ok(ptr)
+        }
+    }
+
+    /// Makes the fenced `region` inaccessible and puts it in quarantine, or unmaps it if
+    /// it can't be.
+    ///
+    /// # Safety
+    ///
+    /// `region` must be a [`RegionKind::Fenced`] region removed from
+    /// [`Kernel::large_objects`].
+    pub(crate) unsafe fn deallocate_fenced(&mut self, region: NonNull<Node<Region>>) {
+        unsafe {
+            let (start, len) = (region.as_ptr() as *mut u8, region.as_ref().data.size + REGION_HEADER_SIZE);
+
+            if !self.quarantine_region(start, len) {
+                self.unmap_region(start, len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, MemAlloc, kernel::page_size};
+
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend"), ignore = "needs OS memory")]
+    fn payloads_end_at_a_guard_page() {
+        let allocator = MemAlloc::with_config(Config::new().electric_fence(true));
+        let page_size = page_size();
+
+        unsafe {
+            let layouts = [(1, 1), (100, 16), (page_size, 8), (3 * page_size + 5, 1)];
+
+            let ptrs: Vec<_> = layouts
+                .iter()
+                .map(|&(size, alignment)| {
+                    let layout = Layout::from_size_align(size, alignment).unwrap();
+                    let ptr = allocator.allocate(layout);
+                    ptr.write_bytes(0xAB, size);
+
+                    // Only the padding needed for the alignment is left before the guard page.
+                    let end = ptr.addr() + size;
+                    assert!(align(end, page_size) - end < layout.align().max(Config::new().min_align));
+
+                    #[cfg(target_os = "linux")]
+                    {
+                        assert_eq!(crate::utils::tests::permissions_of(ptr), "rw-p");
+                        assert_eq!(crate::utils::tests::permissions_of(ptr.with_addr(align(end, page_size))), "---p");
+                    }
+
+                    (ptr, layout)
+                })
+                .collect();
+
+            assert_eq!(allocator.summary().large_objects, layouts.len());
+            assert!(ptrs.iter().all(|&(ptr, _)| allocator.owns_allocation(ptr)));
+            assert!(allocator.seal(ptrs[2].0).is_err());
+
+            for (ptr, layout) in ptrs {
+                allocator.deallocate(ptr, layout);
+
+                // Freed allocations can't be accessed anymore.
+                #[cfg(target_os = "linux")]
+                assert_eq!(crate::utils::tests::permissions_of(ptr), "---p");
+            }
+
+            assert_eq!(allocator.stats().allocations, 0);
+            assert_eq!(allocator.summary().large_objects, 0);
+            assert_eq!(allocator.summary().quarantined_regions, layouts.len());
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Allocation-free sections.
+//!
+//! Real-time code, like audio callbacks, must not allocate since the allocator may block
+//! or take an unbounded time. [`MemAlloc::forbid_alloc`] returns a guard and, while it is
+//! alive, any allocation made by the current thread with any [`MemAlloc`] is reported,
+//! which proves that a hot path doesn't allocate:
+//!
+//! ```rust
+//! use memalloc::MemAlloc;
+//!
+//! fn process(samples: &mut [f32]) {
+//!     let _guard = MemAlloc::forbid_alloc();
+//!
+//!     for sample in samples {
+//!         *sample *= 0.5;
+//!     }
+//! }
+//!
+//! process(&mut [1.0; 64]);
+//! ```
+//!
+//! Unwinding out of a global allocator is undefined behaviour, so we can't panic there.
+//! By default the process is aborted with a message instead. [`MemAlloc::set_forbidden_alloc_hook`]
+//! replaces that with a custom hook, for example one that only logs the allocation.
+
+use std::{
+    alloc::Layout,
+    cell::Cell,
+    io::{self, Write},
+    marker::PhantomData,
+    mem, process,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use lock_api::RawMutex;
+
+use crate::memalloc::MemAlloc;
+
+thread_local! {
+    /// Number of live [`ForbidAllocGuard`] of the current thread.
+    static FORBIDDEN: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Hook set with [`MemAlloc::set_forbidden_alloc_hook`], or null for the default one.
+static HOOK: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Guard returned by [`MemAlloc::forbid_alloc`]. It must be dropped by the thread that
+/// created it.
+#[must_use = "allocations are allowed again as soon as the guard is dropped"]
+pub struct ForbidAllocGuard {
+    _thread: PhantomData<*const ()>,
+}
+
+impl Drop for ForbidAllocGuard {
+    fn drop(&mut self) {
+        FORBIDDEN.set(FORBIDDEN.get() - 1);
+    }
+}
+
+impl MemAlloc {
+    /// Forbids the current thread to allocate until the returned guard is dropped.
+    /// Guards can be nested. See the `forbid` module for what happens when it does.
+    pub fn forbid_alloc() -> ForbidAllocGuard {
+        FORBIDDEN.set(FORBIDDEN.get() + 1);
+
+        ForbidAllocGuard { _thread: PhantomData }
+    }
+
+    /// Calls `hook` with the layout of every allocation made while a guard of
+    /// [`MemAlloc::forbid_alloc`] is alive, instead of aborting. The allocation goes on
+    /// once the hook returns. Allocations made by the hook itself are allowed.
+    ///
+    /// The hook must not unwind, since the allocator may be called by the global allocator.
+    pub fn set_forbidden_alloc_hook(hook: fn(Layout)) {
+        HOOK.store(hook as *mut (), Ordering::Release);
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Reports `layout` if allocations are forbidden on the current thread.
+    #[inline]
+    pub(crate) fn check_forbidden(layout: Layout) {
+        if FORBIDDEN.get() > 0 {
+            forbidden(layout);
+        }
+    }
+}
+
+#[cold]
+fn forbidden(layout: Layout) {
+    let hook = HOOK.load(Ordering::Acquire);
+
+    if hook.is_null() {
+        // Formatting the size would allocate.
+        let _ = io::stderr().write_all(b"memalloc: allocation in a section where allocations are forbidden\n");
+        process::abort();
+    }
+
+    // The hook can allocate, so allocations are allowed while it runs.
+    let depth = FORBIDDEN.replace(0);
+    let hook = unsafe { mem::transmute::<*mut (), fn(Layout)>(hook) };
+
+    hook(layout);
+
+    FORBIDDEN.set(depth);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::*;
+
+    static VIOLATIONS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(layout: Layout) {
+        VIOLATIONS.fetch_add(layout.size(), Ordering::Relaxed);
+    }
+
+    #[test]
+    fn forbidden_allocations_are_reported() {
+        MemAlloc::set_forbidden_alloc_hook(count);
+
+        let allocator = MemAlloc::new();
+        let layout = Layout::new::<u64>();
+
+        unsafe {
+            let allowed = allocator.allocate(layout);
+            assert_eq!(VIOLATIONS.load(Ordering::Relaxed), 0);
+
+            let outer = MemAlloc::forbid_alloc();
+            let inner = MemAlloc::forbid_alloc();
+            drop(inner);
+
+            let forbidden = allocator.allocate(layout);
+            assert!(!forbidden.is_null());
+            assert_eq!(VIOLATIONS.load(Ordering::Relaxed), 8);
+
+            // The guard only affects its own thread, and deallocating is fine.
+            thread::scope(|scope| {
+                scope.spawn(|| allocator.deallocate(allocator.allocate(layout), layout));
+            });
+            allocator.deallocate(forbidden, layout);
+            assert_eq!(VIOLATIONS.load(Ordering::Relaxed), 8);
+
+            drop(outer);
+            allocator.deallocate(allocator.allocate(layout), layout);
+            assert_eq!(VIOLATIONS.load(Ordering::Relaxed), 8);
+
+            allocator.deallocate(allowed, layout);
+        }
+    }
+}
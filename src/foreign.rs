@@ -0,0 +1,128 @@
+//! Foreign pointers.
+//!
+//! When `malloc` is interposed with `LD_PRELOAD`, the C library and the dynamic loader
+//! may have allocated memory before we were loaded, and that memory ends up freed by
+//! us. Its header would be read from whatever is in front of it. With
+//! [`crate::Config::forward_foreign_frees`], deallocations check first that the pointer
+//! is inside one of our regions (see [`MemAlloc::owns`]) and give the ones that aren't
+//! to the `free` that comes after us in the lookup order of the dynamic loader, which is
+//! the one of the C library.
+//!
+//! ```text
+//!  free(ptr) --> owns(ptr)? --yes--> deallocate
+//!                   |
+//!                   no
+//!                   v
+//!              dlsym(RTLD_NEXT, "free")(ptr)
+//! ```
+//!
+//! Reallocating a foreign pointer copies it into a new allocation of ours. The
+//! allocations the lock makes for itself (see `sync::lock`) are not recorded in any
+//! region, so with this option they are taken from the `posix_memalign` of the C library
+//! instead, and freed the same way.
+
+use std::{
+    alloc::Layout,
+    ffi::{CStr, c_void},
+    mem, ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use lock_api::RawMutex;
+
+use crate::memalloc::MemAlloc;
+
+type Free = unsafe extern "C" fn(*mut c_void);
+type PosixMemalign = unsafe extern "C" fn(*mut *mut c_void, usize, usize) -> i32;
+
+/// `free` of the C library, or null until it is looked up.
+static FREE: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+/// `posix_memalign` of the C library, or null until it is looked up.
+static POSIX_MEMALIGN: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+/// Returns the next definition of `name` after ours, looking it up the first time.
+fn next_symbol(cache: &AtomicPtr<c_void>, name: &CStr) -> Option<*mut c_void> {
+    let mut symbol = cache.load(Ordering::Relaxed);
+
+    if symbol.is_null() {
+        symbol = unsafe { libc::dlsym(libc::RTLD_NEXT, name.as_ptr()) };
+        cache.store(symbol, Ordering::Relaxed);
+    }
+
+    (!symbol.is_null()).then_some(symbol)
+}
+
+/// Gives `ptr` to the `free` of the C library. The memory is leaked if there is none.
+///
+/// # Safety
+///
+/// `ptr` must have been allocated by the C library.
+pub(crate) unsafe fn free(ptr: *mut u8) {
+    if let Some(symbol) = next_symbol(&FREE, c"free") {
+        unsafe { mem::transmute::<*mut c_void, Free>(symbol)(ptr.cast()) };
+    }
+}
+
+/// Allocates `layout` with the `posix_memalign` of the C library. Returns null if it
+/// fails or there is none.
+pub(crate) fn allocate(layout: Layout) -> *mut u8 {
+    let Some(symbol) = next_symbol(&POSIX_MEMALIGN, c"posix_memalign") else {
+        return ptr::null_mut();
+    };
+
+    let mut ptr = ptr::null_mut();
+    let align = layout.align().max(mem::size_of::<usize>());
+
+    match unsafe { mem::transmute::<*mut c_void, PosixMemalign>(symbol)(&mut ptr, align, layout.size()) } {
+        0 => ptr.cast(),
+        _ => ptr::null_mut(),
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Whether `ptr` has to be given to the C library. See the `foreign` module.
+    #[inline]
+    pub(crate) fn is_foreign(&self, ptr: *mut u8) -> bool {
+        self.config.forward_foreign_frees && !self.owns(ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, sync};
+
+    #[test]
+    fn pointers_of_the_c_library_are_freed_by_it() {
+        let allocator = MemAlloc::with_config(Config::new().forward_foreign_frees(true));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        unsafe {
+            let ours = allocator.allocate(layout);
+            let theirs = libc::malloc(layout.size()).cast::<u8>();
+            theirs.write_bytes(0xAB, layout.size());
+
+            assert!(!allocator.is_foreign(ours));
+            assert!(allocator.is_foreign(theirs));
+
+            // Reallocating copies it into one of ours.
+            let moved = allocator.reallocate(theirs, layout, 200);
+            assert!(allocator.owns(moved));
+            assert_eq!(moved.add(layout.size() - 1).read(), 0xAB);
+
+            allocator.deallocate(moved, Layout::from_size_align(200, 8).unwrap());
+            allocator.deallocate(libc::malloc(layout.size()).cast(), layout);
+            allocator.deallocate(ours, layout);
+            assert_eq!(allocator.stats().allocations, 0);
+
+            // The allocations of the lock come from the C library too.
+            sync::set_acquiring(true);
+            let detached = allocator.allocate(layout);
+            sync::set_acquiring(false);
+
+            assert!(allocator.is_foreign(detached));
+            allocator.deallocate(detached, layout);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Fork safety on Unix.
+//!
+//! When a multi-threaded process calls `fork`, only the calling thread survives in the
+//! child. If any other thread was holding the allocator lock at that moment, the child
+//! inherits a locked mutex that nobody will ever unlock, so its first allocation deadlocks.
+//!
+//! To avoid that, [`crate::MemAlloc::register_fork_handlers`] installs `pthread_atfork`
+//! handlers that:
+//!
+//! - `prepare`: acquire the lock of every registered allocator before forking.
+//! - `parent`: release them in the parent once the fork is done.
+//! - `child`: release them in the child, which leaves the lock usable again.
+//!
+//! Since the handlers can't receive any context, registered allocators are stored in a
+//! fixed-size static table. We can't use a `Vec` here because we are the allocator.
+//! As [`crate::MemAlloc`] is generic over its raw mutex, the table stores them as
+//! [`ForkLock`] trait objects.
+
+use std::{
+    cell::UnsafeCell,
+    hint, ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use lock_api::RawMutex;
+
+use crate::MemAlloc;
+
+/// Maximum number of allocators that can register fork handlers.
+pub const MAX_FORK_HANDLERS: usize = 16;
+
+/// Lock of an allocator that can be held across `fork` without a guard.
+trait ForkLock: Sync {
+    /// Acquires the lock.
+    fn lock(&self);
+
+    /// Releases the lock acquired by [`ForkLock::lock`].
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held by the current thread (or by its copy in the child).
+    unsafe fn unlock(&self);
+}
+
+impl<R: RawMutex + Sync> ForkLock for MemAlloc<R> {
+    fn lock(&self) {
+        unsafe { self.allocator.raw().lock() }
+    }
+
+    unsafe fn unlock(&self) {
+        unsafe { self.allocator.force_unlock() }
+    }
+}
+
+/// Allocators registered with [`register`].
+struct Table(UnsafeCell<[Option<&'static dyn ForkLock>; MAX_FORK_HANDLERS]>);
+
+/// The table is only accessed while holding [`TABLE_LOCK`].
+unsafe impl Sync for Table {}
+
+static TABLE: Table = Table(UnsafeCell::new([None; MAX_FORK_HANDLERS]));
+
+/// Spin lock that protects [`TABLE`]. It is held from [`prepare`] until the fork is
+/// done, so no allocator can be registered in the middle of a fork.
+static TABLE_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// Whether `pthread_atfork` has already been called.
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+fn lock_table() {
+    while TABLE_LOCK
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        hint::spin_loop();
+    }
+}
+
+fn unlock_table() {
+    TABLE_LOCK.store(false, Ordering::Release);
+}
+
+/// Registers `allocator` so that its lock is held across `fork`. Returns `false`
+/// if the table of allocators is already full.
+pub(crate) fn register<R: RawMutex + Sync>(allocator: &'static MemAlloc<R>) -> bool {
+    let allocator: &'static dyn ForkLock = allocator;
+
+    lock_table();
+
+    let table = unsafe { &mut *TABLE.0.get() };
+
+    let registered = table
+        .iter()
+        .flatten()
+        .any(|registered| ptr::addr_eq(*registered, allocator));
+
+    let success = registered || match table.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(allocator);
+            true
+        }
+        None => false,
+    };
+
+    unlock_table();
+
+    if success && !INSTALLED.swap(true, Ordering::AcqRel) {
+        unsafe {
+            libc::pthread_atfork(Some(prepare), Some(parent), Some(child));
+        }
+    }
+
+    success
+}
+
+/// Locks every registered allocator, in registration order.
+unsafe extern "C" fn prepare() {
+    lock_table();
+
+    let table = unsafe { &*TABLE.0.get() };
+
+    for allocator in table.iter().flatten() {
+        allocator.lock();
+    }
+}
+
+/// Unlocks every allocator locked in [`prepare`], in reverse order.
+unsafe extern "C" fn release() {
+    let table = unsafe { &*TABLE.0.get() };
+
+    for allocator in table.iter().rev().flatten() {
+        unsafe { allocator.unlock() };
+    }
+
+    unlock_table();
+}
+
+/// Runs in the parent after `fork`.
+unsafe extern "C" fn parent() {
+    unsafe { release() }
+}
+
+/// Runs in the child after `fork`. The child only has the forking thread, which is
+/// the one that took the locks, so releasing them leaves the allocator ready to use.
+unsafe extern "C" fn child() {
+    unsafe { release() }
+}
+
+// Allocators can't be statics under loom, see the `sync` module.
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::{alloc::Layout, sync::atomic::AtomicBool, thread, time::Duration};
+
+    static ALLOCATOR: MemAlloc = MemAlloc::new();
+
+    #[test]
+    fn child_can_allocate_after_fork() {
+        assert!(ALLOCATOR.register_fork_handlers());
+        // Registering twice doesn't take another slot
+        assert!(ALLOCATOR.register_fork_handlers());
+
+        let stop = AtomicBool::new(false);
+        let layout = Layout::new::<[u64; 4]>();
+
+        thread::scope(|scope| {
+            // Keep the lock busy while we fork
+            scope.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    unsafe {
+                        let ptr = ALLOCATOR.allocate(layout);
+                        ALLOCATOR.deallocate(ptr, layout);
+                    }
+                }
+            });
+
+            thread::sleep(Duration::from_millis(10));
+
+            for _ in 0..10 {
+                unsafe {
+                    let pid = libc::fork();
+                    assert!(pid >= 0);
+
+                    if pid == 0 {
+                        // If the lock was left locked, SIGALRM kills the child.
+                        libc::alarm(5);
+                        let ptr = ALLOCATOR.allocate(layout);
+                        ALLOCATOR.deallocate(ptr, layout);
+                        libc::_exit(0);
+                    }
+
+                    let mut status = 0;
+                    libc::waitpid(pid, &mut status, 0);
+
+                    assert!(libc::WIFEXITED(status), "child did not exit normally");
+                    assert_eq!(libc::WEXITSTATUS(status), 0);
+                }
+            }
+
+            stop.store(true, Ordering::Relaxed);
+        });
+    }
+}
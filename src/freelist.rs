@@ -1,12 +1,67 @@
-use std::{alloc::Layout, mem, ptr::NonNull};
+use std::{alloc::Layout, cell::Cell, ptr::NonNull};
+
+use lock_api::RawMutex;
 
 use crate::{
-    block::Block,
+    block::{BLOCK_HEADER_SIZE, Block},
     list::{Link, List, Node},
-    memalloc::MIN_BLOCK_SIZE,
-    utils::align,
+    memalloc::MemAlloc,
+    placement::{FreeBlocks, NodeFilter, PlacementStrategy, random},
+    sync,
 };
 
+/// Number of buckets of [`SearchStats::histogram`].
+pub const SEARCH_BUCKETS: usize = 16;
+
+/// Statistics of the searches of the free list, returned by [`MemAlloc::search_stats`].
+///
+/// Long searches are the cost of keeping a single free list: if most searches visit
+/// many nodes, or fail after visiting them, a [`crate::Config::search_limit`] or
+/// segregated lists (see [`crate::Config::hardened`]) will serve allocations faster.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SearchStats {
+    /// Number of searches for a free block.
+    pub searches: u64,
+    /// Number of searches that didn't find any block, so a new region had to be mapped.
+    pub misses: u64,
+    /// Number of free list nodes visited by all the searches.
+    pub visited: u64,
+    /// Most nodes visited by a single search.
+    pub max_visited: usize,
+    /// Number of searches by the nodes they visited, on a log scale: bucket 0 counts the
+    /// searches that visited none, and bucket `i` those that visited from `2^(i - 1)` up
+    /// to `2^i` nodes. The last bucket also counts any longer search.
+    pub histogram: [u64; SEARCH_BUCKETS],
+}
+
+impl SearchStats {
+    pub(crate) const fn new() -> Self {
+        Self { searches: 0, misses: 0, visited: 0, max_visited: 0, histogram: [0; SEARCH_BUCKETS] }
+    }
+
+    /// Average number of nodes visited per search.
+    pub fn mean_visited(&self) -> f64 {
+        match self.searches {
+            0 => 0.0,
+            searches => self.visited as f64 / searches as f64,
+        }
+    }
+
+    /// Records a search that visited `visited` nodes and found a block if `found`.
+    pub(crate) fn record(&mut self, visited: usize, found: bool) {
+        self.searches += 1;
+        self.misses += u64::from(!found);
+        self.visited = self.visited.saturating_add(visited as u64);
+        self.max_visited = self.max_visited.max(visited);
+
+        let bucket = (usize::BITS - visited.leading_zeros()) as usize;
+        self.histogram[bucket.min(SEARCH_BUCKETS - 1)] += 1;
+    }
+}
+
+/// Node of the [`FreeList`], stored in the payload of the free block it points to.
+pub(crate) type FreeNode = NonNull<Node<NonNull<Node<Block>>>>;
+
 /// Linked list to keep track of free [`Block`].
 ///
 /// This list only stores pointers to the actual [`Region`] blocks. The reason behind
@@ -55,8 +110,8 @@ use crate::{
 /// |       Node<Block>      |          |
 /// +------------------------+          |
 /// |       Block.data:      |          |-------> Block Header
-/// |        - size          |          |
-/// |        - is_free       |          |
+/// |        - region        |          |
+/// |        - size | free   |          |
 /// +------------------------+ <--------+
 /// |                        |
 /// |      Free Payload      |
@@ -66,15 +121,45 @@ use crate::{
 /// |          ...           |
 /// +------------------------+
 /// ```
+///
+/// Since the links between nodes live in memory the user has access to, an overflow or
+/// a use after free can overwrite them and make the next unlink write wherever the
+/// attacker wants. Like glibc's safe-linking, once [`FreeList::randomize_links`] is
+/// called they are stored XORed with a random key, and links that don't decode to an
+/// aligned address abort the process. See [`List::append_node_keyed`].
 pub(crate) struct FreeList {
     /// Nodes of the list (Pointers to <Node<Block>>)
     pub items: List<NonNull<Node<Block>>>,
+    /// How [`FreeList::find_free_block`] picks a block. See [`PlacementStrategy`].
+    pub strategy: &'static dyn PlacementStrategy,
+    /// Node of the list picked last time, where the next search starts if the
+    /// strategy resumes its searches. See [`PlacementStrategy::resume_search`].
+    cursor: Option<FreeNode>,
+    /// Maximum number of nodes visited per search, or 0 for all of them. See
+    /// [`crate::Config::search_limit`].
+    limit: usize,
+    /// Key the links of the nodes are XORed with, or 0 until
+    /// [`FreeList::randomize_links`] is called.
+    key: usize,
+    /// No block in the list is bigger than this. It is raised when a bigger block is
+    /// inserted and only lowered by [`crate::kernel::Kernel::find_free_block`] after a
+    /// search misses, so it may be bigger than the actual largest block.
+    pub largest: usize,
+    /// See [`MemAlloc::search_stats`].
+    pub stats: SearchStats,
 }
 
 impl FreeList {
     /// Creates a new empty List
-    pub const fn new() -> Self {
-        return Self { items: List::new() };
+    pub const fn new(strategy: &'static dyn PlacementStrategy, limit: usize) -> Self {
+        Self { items: List::new(), strategy, cursor: None, limit, key: 0, largest: 0, stats: SearchStats::new() }
+    }
+
+    /// Makes the list store its links XORed with a new random key. The list must be
+    /// empty.
+    pub fn randomize_links(&mut self) {
+        debug_assert!(self.is_empty());
+        self.key = random() as usize | 1;
     }
 
     /// It tells whether the FreeList is empty or not.
@@ -84,20 +169,50 @@ impl FreeList {
 
     /// Inserts an existing `block` into the FreeList.
     /// Because this [`FreeList`] is an abstraction built over [`List`] we
-    /// need to give this method the `addr` where the node is going to be written.
+    /// need to give this method the `addr` where the node is going to be written, which
+    /// must be the start of the payload of `block`. See [`FreeList::unlink_free_block`].
     ///
     /// For more information about this decision see [`List::append`]
     pub fn insert_free_block(
         &mut self,
-        mut block: NonNull<Node<Block>>,
+        block: NonNull<Node<Block>>,
         addr: NonNull<u8>,
-    ) -> NonNull<Node<NonNull<Node<Block>>>> {
+    ) -> FreeNode {
+        self.insert(block, addr, false)
+    }
+
+    /// Same as [`FreeList::insert_free_block`], but the block is inserted at the front of
+    /// the list, so it is the first one the next search visits. See
+    /// [`crate::Config::move_to_front`].
+    pub fn insert_free_block_first(&mut self, block: NonNull<Node<Block>>, addr: NonNull<u8>) -> FreeNode {
+        self.insert(block, addr, true)
+    }
+
+    /// Does the work of [`FreeList::insert_free_block`], at the front of the list if
+    /// `first`.
+    fn insert(&mut self, mut block: NonNull<Node<Block>>, addr: NonNull<u8>, first: bool) -> FreeNode {
         unsafe {
+            debug_assert_eq!(addr.addr().get(), block.addr().get() + BLOCK_HEADER_SIZE);
+
             // Mark the block as free to use
-            block.as_mut().data.is_free = true;
+            block.as_mut().data.set_free(true);
+            Block::write_footer(block);
+
+            let mut region = block.as_ref().data.region;
+            region.as_mut().data.track_free(block.as_ref().data.size());
+            self.largest = self.largest.max(block.as_ref().data.size());
 
             // Add the block from the list
-            self.items.append(block, addr)
+            let node = addr.cast::<Node<NonNull<Node<Block>>>>();
+            node.as_ptr().write(Node { next: None, prev: None, data: block });
+
+            if first {
+                self.items.prepend_node_keyed(node, self.key);
+            } else {
+                self.items.append_node_keyed(node, self.key);
+            }
+
+            node
         }
     }
 
@@ -110,53 +225,206 @@ impl FreeList {
     ///
     /// See [`List::remove`] for more detail about how the actual removal works.
     pub fn remove_free_block(&mut self, node: NonNull<Node<Block>>) {
-        let mut current = self.items.first();
-
-        while let Some(free_node) = current {
+        if self.unlink_free_block(node) {
             unsafe {
-                if free_node.as_ref().data == node {
-                    // We found the block in the FreeList so we remove it
-                    self.items.remove(free_node);
+                let mut region = node.as_ref().data.region;
+                region.as_mut().data.untrack_free(node.as_ref().data.size());
+            }
+        }
+    }
 
-                    return;
-                }
+    /// Same as [`FreeList::remove_free_block`], but the free bytes of the region of `node`
+    /// are left for the caller to update, since it may be borrowing the region. Returns
+    /// whether `node` was in the list.
+    ///
+    /// The list node of a free block is always written at the start of its payload (see
+    /// [`FreeList::insert_free_block`]), so it is unlinked right there in constant time,
+    /// without walking the list. This is what makes merging with the neighbours found
+    /// through the footers constant time too.
+    pub fn unlink_free_block(&mut self, node: NonNull<Node<Block>>) -> bool {
+        unsafe {
+            if !node.as_ref().data.is_free() {
+                return false;
+            }
+
+            let mut free_node = node.cast::<u8>().add(BLOCK_HEADER_SIZE).cast::<Node<NonNull<Node<Block>>>>();
+
+            // Free blocks taken out of the list for a while, like the holes of a
+            // compaction, no longer point back to their header.
+            if free_node.as_ref().data != node {
+                return false;
+            }
 
-                current = free_node.as_ref().next;
+            // The next search can't start from a node that is gone.
+            if self.cursor == Some(free_node) {
+                self.cursor = self.items.next_keyed(free_node, self.key);
             }
+
+            self.items.remove_keyed(free_node, self.key);
+            free_node.as_mut().data = NonNull::dangling();
+
+            true
         }
     }
 
     /// Returns a pointer to the [`Block`] where we can allocate `layout`.
     /// This is done by iterating through the [`FreeList`] and searching for
-    /// a block that can allocate enough `size`.
+    /// blocks that can allocate enough `size`. Which one of them we pick is up
+    /// to the [`PlacementStrategy`], see [`crate::FitPolicy`] for the built-in ones.
     ///
-    /// This implementation of the method uses the first-fit algorithm, it returns
-    /// the first block on the [`FreeList`] that we can use.
-    pub fn find_free_block(&self, layout: Layout) -> Link<Node<Block>> {
+    /// If a NUMA `node` is given, blocks from regions bound to that node are preferred:
+    /// we only give the strategy blocks from any other node if it doesn't pick any of
+    /// the local ones.
+    ///
+    /// Only the first [`crate::Config::search_limit`] nodes from where the search starts
+    /// are visited, if there is a limit. Every search is counted in [`SearchStats`].
+    pub fn find_free_block(&mut self, layout: Layout, node: Option<u32>) -> Link<Node<Block>> {
+        let visited = Cell::new(0);
+        let block = self.search(layout, node, &visited);
+
+        self.stats.record(visited.get(), block.is_some());
+
+        block
+    }
+
+    /// Does the work of [`FreeList::find_free_block`], counting the nodes visited.
+    fn search(&mut self, layout: Layout, node: Option<u32>, visited: &Cell<usize>) -> Link<Node<Block>> {
         if self.is_empty() {
             // We have no regions created yet.
             return None;
         }
 
-        // This is the size we need, including aligment
-        let layout_size = align(layout.size(), mem::size_of::<usize>());
+        let head = self.items.first();
+        let start = if self.strategy.resume_search() { self.cursor.or(head) } else { head };
 
-        // The minimun block size we can give to the user is `MIN_BLOCK_SIZE`. If we
-        // didn't do this, we wouldn't be able to store our allocator's metadata on
-        // small memory requests.
-        let needed_size = std::cmp::max(layout_size, MIN_BLOCK_SIZE);
+        let filters: &[NodeFilter] = match node {
+            None => &[NodeFilter::Any],
+            Some(_) => &[NodeFilter::Local(node), NodeFilter::Remote(node)],
+        };
 
-        // We check in our free_list if there exists any node that can fit `needed_size`
-        for node in &self.items {
-            unsafe {
-                if node.as_ref().data.size >= needed_size {
-                    // We found a node that we can use
-                    return Some(*node);
-                }
+        let len = match self.limit {
+            0 => self.items.len(),
+            limit => limit.min(self.items.len()),
+        };
+
+        for &filter in filters {
+            let blocks = FreeBlocks::new(&self.items, self.key, start, len, layout, filter, visited);
+
+            if let Some(block) = self.strategy.select(layout, blocks) {
+                self.cursor = Some(block.node);
+
+                return unsafe { Some(block.node.as_ref().data) };
             }
         }
 
         // There is no free block we can use
         None
     }
+
+    /// Returns the last block of the list if it can hold `layout` and the strategy picks
+    /// it. Blocks of new regions are appended, so this finds them right after mapping one
+    /// without walking the whole list again, which may not even reach them if the search
+    /// is limited.
+    pub fn find_last_free_block(&mut self, layout: Layout) -> Link<Node<Block>> {
+        let last = self.items.last()?;
+
+        let visited = Cell::new(0);
+        let blocks = FreeBlocks::new(&self.items, self.key, Some(last), 1, layout, NodeFilter::Any, &visited);
+        let block = self.strategy.select(layout, blocks)?;
+
+        self.cursor = Some(block.node);
+
+        unsafe { Some(block.node.as_ref().data) }
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Returns the statistics of the searches of the free list. See [`SearchStats`].
+    pub fn search_stats(&self) -> SearchStats {
+        sync::lock(&self.allocator).free_list.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::*;
+
+    #[test]
+    fn searches_count_the_nodes_they_visit() {
+        let allocator = MemAlloc::with_config(crate::Config::new().fit_policy(crate::FitPolicy::BestFit));
+        let small = Layout::from_size_align(32, 8).unwrap();
+
+        unsafe {
+            // The first search finds no region, and the second one the rest of it.
+            let first = allocator.allocate(small);
+            let second = allocator.allocate(small);
+
+            let stats = allocator.search_stats();
+            assert_eq!((stats.searches, stats.misses), (2, 1));
+            assert_eq!(stats.histogram[0], 1);
+            assert_eq!(stats.histogram[1], 1);
+
+            // Freeing the first block leaves a hole too small for this, but best fit still
+            // visits it besides the rest of the region.
+            allocator.deallocate(first, small);
+            let big = Layout::from_size_align(256, 8).unwrap();
+            let third = allocator.allocate(big);
+
+            let stats = allocator.search_stats();
+            assert_eq!((stats.searches, stats.misses), (3, 1));
+            assert_eq!((stats.visited, stats.max_visited), (3, 2));
+            assert_eq!(stats.histogram[2], 1);
+            assert_eq!(stats.mean_visited(), 1.0);
+
+            allocator.deallocate(second, small);
+            allocator.deallocate(third, big);
+        }
+    }
+
+    #[test]
+    fn free_blocks_are_unlinked_in_place() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        unsafe {
+            let [a, b, c] = [(); 3].map(|_| allocator.allocate(layout));
+            allocator.deallocate(a, layout);
+
+            let mut kernel = allocator.allocator.lock();
+            let block = Block::from_payload(a);
+            let len = kernel.free_list.items.len();
+
+            assert!(kernel.free_list.unlink_free_block(block));
+            assert_eq!(kernel.free_list.items.len(), len - 1);
+
+            // The node no longer points back to the block, so it isn't unlinked twice.
+            assert!(!kernel.free_list.unlink_free_block(block));
+            assert_eq!(kernel.free_list.items.len(), len - 1);
+
+            // Used blocks are never in the list.
+            assert!(!kernel.free_list.unlink_free_block(Block::from_payload(b)));
+
+            let mut region = block.as_ref().data.region;
+            region.as_mut().data.untrack_free(block.as_ref().data.size());
+            kernel.free_list.insert_free_block(block, NonNull::new_unchecked(a));
+            drop(kernel);
+
+            allocator.deallocate(b, layout);
+            allocator.deallocate(c, layout);
+            assert_eq!(allocator.summary().regions, 0);
+        }
+    }
+
+    #[test]
+    fn long_searches_fall_in_the_last_bucket() {
+        let mut stats = SearchStats::new();
+        stats.record(usize::MAX, false);
+        stats.record(1 << 20, true);
+
+        assert_eq!(stats.histogram[SEARCH_BUCKETS - 1], 2);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.max_visited, usize::MAX);
+    }
 }
@@ -0,0 +1,447 @@
+//! Relocatable allocations.
+//!
+//! Raw pointers pin allocations to their address forever, so [`MemAlloc::compact`] needs
+//! the program to fix its pointers. Handles are the alternative: the program keeps an
+//! opaque [`Handle`] and only gets the address of the allocation while it is pinned.
+//! Unpinned allocations can be moved at any time by [`MemAlloc::compact_handles`], which
+//! updates the handles by itself.
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::MemAlloc;
+//!
+//! let allocator = MemAlloc::new();
+//! let handle = allocator.allocate_handle(Layout::new::<u64>()).unwrap();
+//!
+//! unsafe {
+//!     let ptr = allocator.pin(handle).unwrap() as *mut u64;
+//!     ptr.write(42);
+//!     allocator.unpin(handle).unwrap();
+//! }
+//!
+//! allocator.compact_handles();
+//!
+//! unsafe {
+//!     // The allocation may have moved, so the old pointer can't be used anymore.
+//!     let ptr = allocator.pin(handle).unwrap() as *mut u64;
+//!     assert_eq!(ptr.read(), 42);
+//!     allocator.unpin(handle).unwrap();
+//! }
+//!
+//! allocator.deallocate_handle(handle).unwrap();
+//! ```
+//!
+//! The handle table can't be allocated from the allocator itself, so it lives in its own
+//! mapping, which is replaced by a bigger one when it is full.
+
+use std::{
+    alloc::Layout,
+    cmp, mem,
+    ptr::{self, NonNull},
+    slice,
+};
+
+use lock_api::RawMutex;
+
+use crate::{
+    kernel::{page_size, request_memory, return_memory},
+    memalloc::MemAlloc,
+    sync,
+    utils::align,
+};
+
+/// Opaque reference to an allocation made with [`MemAlloc::allocate_handle`].
+///
+/// Handles of deallocated allocations are detected, even if their slot in the handle
+/// table is reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Handle {
+    /// Slot of the handle table.
+    index: u32,
+    /// Number of times the slot had been reused when the handle was created.
+    generation: u32,
+}
+
+/// Slot of the handle table.
+#[derive(Clone, Copy)]
+struct Entry {
+    /// Current address of the allocation, or null if the slot is free.
+    ptr: *mut u8,
+    /// Layout the allocation was made with.
+    layout: Layout,
+    /// Number of times the allocation is pinned. It can only move if this is 0.
+    pins: usize,
+    /// Incremented every time the slot is freed.
+    generation: u32,
+    /// Next free slot, if this one is free.
+    next_free: Option<u32>,
+}
+
+/// Table of every live [`Handle`] of an allocator.
+pub(crate) struct HandleTable {
+    entries: *mut Entry,
+    /// Number of slots the mapping can hold.
+    capacity: usize,
+    /// Number of slots that have ever been used.
+    len: usize,
+    /// First free slot below `len`.
+    free: Option<u32>,
+}
+
+impl HandleTable {
+    pub(crate) const fn new() -> Self {
+        Self { entries: ptr::null_mut(), capacity: 0, len: 0, free: None }
+    }
+
+    fn entries(&mut self) -> &mut [Entry] {
+        if self.entries.is_null() {
+            return &mut [];
+        }
+
+        unsafe { slice::from_raw_parts_mut(self.entries, self.len) }
+    }
+
+    /// Whether there is no live handle.
+    pub(crate) fn is_empty(&mut self) -> bool {
+        self.entries().iter().all(|entry| entry.ptr.is_null())
+    }
+
+    /// Size of the mapping of `capacity` slots.
+    fn mapping_size(capacity: usize) -> usize {
+        align(capacity * mem::size_of::<Entry>(), page_size())
+    }
+
+    /// Replaces the mapping with one twice as big. Returns `false` if it can't be mapped.
+    fn grow(&mut self) -> bool {
+        let capacity = cmp::max(self.capacity * 2, page_size() / mem::size_of::<Entry>());
+
+        unsafe {
+            let Some(entries) = request_memory(Self::mapping_size(capacity), false) else {
+                return false;
+            };
+
+            let entries = entries.as_ptr().cast::<Entry>();
+
+            if !self.entries.is_null() {
+                ptr::copy_nonoverlapping(self.entries, entries, self.len);
+                return_memory(self.entries.cast(), Self::mapping_size(self.capacity));
+            }
+
+            self.entries = entries;
+            self.capacity = capacity;
+        }
+
+        true
+    }
+
+    /// Records a new allocation and returns its handle.
+    fn insert(&mut self, ptr: *mut u8, layout: Layout) -> Option<Handle> {
+        let index = match self.free {
+            Some(index) => index,
+            None => {
+                if self.len == u32::MAX as usize || (self.len == self.capacity && !self.grow()) {
+                    return None;
+                }
+
+                unsafe {
+                    self.entries.add(self.len).write(Entry {
+                        ptr: ptr::null_mut(),
+                        layout,
+                        pins: 0,
+                        generation: 0,
+                        next_free: None,
+                    });
+                }
+
+                self.len += 1;
+                (self.len - 1) as u32
+            }
+        };
+
+        let entry = &mut self.entries()[index as usize];
+        let generation = entry.generation;
+        let next_free = entry.next_free;
+
+        *entry = Entry { ptr, layout, pins: 0, generation, next_free: None };
+
+        if self.free == Some(index) {
+            self.free = next_free;
+        }
+
+        Some(Handle { index, generation })
+    }
+
+    /// Returns the entry of a live `handle`.
+    fn get(&mut self, handle: Handle) -> Result<&mut Entry, &'static str> {
+        match self.entries().get_mut(handle.index as usize) {
+            Some(entry) if !entry.ptr.is_null() && entry.generation == handle.generation => Ok(entry),
+            _ => Err("invalid handle"),
+        }
+    }
+
+    /// Frees the slot of `handle`, which must be live.
+    fn remove(&mut self, handle: Handle) {
+        let free = self.free;
+        let entry = &mut self.entries()[handle.index as usize];
+
+        entry.ptr = ptr::null_mut();
+        entry.generation = entry.generation.wrapping_add(1);
+        entry.next_free = free;
+
+        self.free = Some(handle.index);
+    }
+}
+
+/// Addresses of the unpinned allocations sorted by address, together with their slot,
+/// kept in a scratch mapping while compacting.
+struct Movable {
+    addr: NonNull<(usize, u32)>,
+    len: usize,
+    size: usize,
+}
+
+impl Movable {
+    fn new(table: &mut HandleTable) -> Option<Self> {
+        let entries = table.entries();
+        let count = entries.iter().filter(|entry| !entry.ptr.is_null() && entry.pins == 0).count();
+        let size = align(cmp::max(count, 1) * mem::size_of::<(usize, u32)>(), page_size());
+
+        let addr = unsafe { request_memory(size, false)?.cast::<(usize, u32)>() };
+        let mut movable = Self { addr, len: count, size };
+
+        let slots = entries.iter().enumerate().filter(|(_, entry)| !entry.ptr.is_null() && entry.pins == 0);
+
+        for (slot, (index, entry)) in movable.as_mut().iter_mut().zip(slots) {
+            *slot = (entry.ptr as usize, index as u32);
+        }
+
+        movable.as_mut().sort_unstable();
+
+        Some(movable)
+    }
+
+    fn as_ref(&self) -> &[(usize, u32)] {
+        unsafe { slice::from_raw_parts(self.addr.as_ptr(), self.len) }
+    }
+
+    fn as_mut(&mut self) -> &mut [(usize, u32)] {
+        unsafe { slice::from_raw_parts_mut(self.addr.as_ptr(), self.len) }
+    }
+
+    /// Returns the slot of the unpinned allocation at `ptr`.
+    fn find(&self, ptr: *mut u8) -> Option<u32> {
+        let slots = self.as_ref();
+        let index = slots.binary_search_by_key(&(ptr as usize), |&(addr, _)| addr).ok()?;
+
+        Some(slots[index].1)
+    }
+}
+
+impl Drop for Movable {
+    fn drop(&mut self) {
+        unsafe { return_memory(self.addr.as_ptr().cast(), self.size) };
+    }
+}
+
+impl Drop for HandleTable {
+    fn drop(&mut self) {
+        if !self.entries.is_null() {
+            unsafe { return_memory(self.entries.cast(), Self::mapping_size(self.capacity)) };
+        }
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Allocates memory for `layout` and returns a handle to it, or `None` if it can't be
+    /// allocated. The memory can only be accessed with [`MemAlloc::pin`].
+    #[track_caller]
+    pub fn allocate_handle(&self, layout: Layout) -> Option<Handle> {
+        let ptr = unsafe { self.allocate(layout) };
+
+        if ptr.is_null() {
+            return None;
+        }
+
+        let handle = sync::lock(&self.allocator).handles.insert(ptr, layout);
+
+        if handle.is_none() {
+            unsafe { self.deallocate(ptr, layout) };
+        }
+
+        handle
+    }
+
+    /// Deallocates the memory of `handle`. Fails if the handle is not live or it is pinned.
+    pub fn deallocate_handle(&self, handle: Handle) -> Result<(), &'static str> {
+        let (ptr, layout) = {
+            let mut kernel = sync::lock(&self.allocator);
+            let entry = kernel.handles.get(handle)?;
+
+            if entry.pins > 0 {
+                return Err("handle is pinned");
+            }
+
+            let allocation = (entry.ptr, entry.layout);
+            kernel.handles.remove(handle);
+
+            allocation
+        };
+
+        unsafe { self.deallocate(ptr, layout) };
+
+        Ok(())
+    }
+
+    /// Returns the current address of the memory of `handle` and prevents it from moving
+    /// until [`MemAlloc::unpin`] is called as many times as this. Fails if the handle is
+    /// not live.
+    pub fn pin(&self, handle: Handle) -> Result<*mut u8, &'static str> {
+        let mut kernel = sync::lock(&self.allocator);
+        let entry = kernel.handles.get(handle)?;
+
+        entry.pins += 1;
+
+        Ok(entry.ptr)
+    }
+
+    /// Undoes a call to [`MemAlloc::pin`]. The address it returned must not be used
+    /// anymore once the handle is completely unpinned. Fails if the handle is not live
+    /// or it is not pinned.
+    pub fn unpin(&self, handle: Handle) -> Result<(), &'static str> {
+        let mut kernel = sync::lock(&self.allocator);
+        let entry = kernel.handles.get(handle)?;
+
+        if entry.pins == 0 {
+            return Err("handle is not pinned");
+        }
+
+        entry.pins -= 1;
+
+        Ok(())
+    }
+
+    /// Compacts the regions like [`MemAlloc::compact`], but only moves the allocations of
+    /// unpinned handles and updates them. Returns the number of allocations moved.
+    pub fn compact_handles(&self) -> usize {
+        let mut kernel = self.lock_draining();
+
+        // The table is taken out of the kernel so it can be updated while compacting.
+        let mut table = mem::replace(&mut kernel.handles, HandleTable::new());
+
+        let moved = match Movable::new(&mut table) {
+            Some(movable) => unsafe {
+
+                kernel.compact(
+                    &mut |ptr| movable.find(ptr).is_some(),
+                    &mut |old, new, _| {
+                        if let Some(index) = movable.find(old) {
+                            table.entries()[index as usize].ptr = new;
+                        }
+                    },
+                )
+            },
+            None => 0,
+        };
+
+        kernel.handles = table;
+
+        moved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{BLOCK_FOOTER_SIZE, BLOCK_HEADER_SIZE};
+
+    #[test]
+    fn handles_are_validated() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::new::<u64>();
+
+        let handle = allocator.allocate_handle(layout).unwrap();
+
+        assert!(allocator.unpin(handle).is_err());
+        allocator.pin(handle).unwrap();
+        assert_eq!(allocator.deallocate_handle(handle), Err("handle is pinned"));
+        allocator.unpin(handle).unwrap();
+        allocator.deallocate_handle(handle).unwrap();
+
+        // The slot is reused, but the old handle is still dead.
+        let new = allocator.allocate_handle(layout).unwrap();
+        assert_ne!(new, handle);
+        assert_eq!(allocator.pin(handle), Err("invalid handle"));
+        assert_eq!(allocator.deallocate_handle(handle), Err("invalid handle"));
+
+        allocator.deallocate_handle(new).unwrap();
+        assert!(allocator.allocator.lock().regions.is_empty());
+    }
+
+    #[test]
+    fn table_grows() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::new::<u32>();
+
+        let handles: Vec<_> = (0..2000u32)
+            .map(|i| {
+                let handle = allocator.allocate_handle(layout).unwrap();
+                unsafe { (allocator.pin(handle).unwrap() as *mut u32).write(i) };
+                allocator.unpin(handle).unwrap();
+                handle
+            })
+            .collect();
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            unsafe { assert_eq!((allocator.pin(handle).unwrap() as *mut u32).read(), i as u32) };
+            allocator.unpin(handle).unwrap();
+            allocator.deallocate_handle(handle).unwrap();
+        }
+    }
+
+    #[test]
+    fn only_unpinned_handles_move() {
+        let allocator = MemAlloc::new();
+
+        // Every block takes 256 bytes, so moving them keeps their alignment.
+        let layout = Layout::from_size_align(256 - BLOCK_HEADER_SIZE - BLOCK_FOOTER_SIZE, 8).unwrap();
+
+        unsafe {
+            let [a, b, c] = [(); 3].map(|_| allocator.allocate_handle(layout).unwrap());
+            let raw = allocator.allocate(layout);
+            let d = allocator.allocate_handle(layout).unwrap();
+
+            let addr = [a, b, c, d].map(|handle| {
+                let ptr = allocator.pin(handle).unwrap();
+                ptr.write(handle.index as u8);
+                allocator.unpin(handle).unwrap();
+                ptr
+            });
+
+            // a is freed, b moves into its place and c is pinned.
+            allocator.deallocate_handle(a).unwrap();
+            allocator.pin(c).unwrap();
+
+            assert_eq!(allocator.compact_handles(), 1);
+
+            assert_eq!(allocator.pin(b).unwrap(), addr[0]);
+            assert_eq!(allocator.pin(c).unwrap(), addr[2]);
+            assert_eq!(addr[0].read(), b.index as u8);
+
+            // Raw allocations never move, so neither can d.
+            allocator.deallocate_handle(c).unwrap_err();
+            for handle in [b, c, c] {
+                allocator.unpin(handle).unwrap();
+            }
+
+            allocator.deallocate_handle(c).unwrap();
+
+            assert_eq!(allocator.compact_handles(), 0);
+            assert_eq!(allocator.pin(d).unwrap(), addr[3]);
+            allocator.unpin(d).unwrap();
+
+            allocator.deallocate(raw, layout);
+            allocator.deallocate_handle(b).unwrap();
+            allocator.deallocate_handle(d).unwrap();
+        }
+    }
+}
@@ -0,0 +1,638 @@
+//! Hardened mode: out-of-band metadata.
+//!
+//! Regular blocks keep their header right before the payload and their footer right after
+//! it, and free blocks keep their free list node inside the payload, so a heap buffer
+//! overflow can overwrite the allocator metadata. With [`crate::Config::hardened`], the
+//! allocations are served by a separate engine whose data pages only hold user data, and
+//! every piece of metadata lives in a dedicated mapping:
+//!
+//! ```text
+//!           Metadata pages                                   Data pages
+//! +--------+--------+--------+-----+
+//! |  Span  |  Span  |  Span  | ... |      +------+------+------+------+-----+------+
+//! +--------+--------+--------+-----+      | Slot | Slot | Slot | Slot | ... | Slot |  Span
+//!     |        |        |                 +------+------+------+------+-----+------+
+//!     |        |        +-------------->  ^
+//!     |        |                          +--------------------------------+
+//!     |        +------------------------> |        Large allocation        |
+//!     |                                   +--------------------------------+
+//!     +---------------------------------> ...
+//! ```
+//!
+//! Small allocations are rounded up to a size class and placed in a span of
+//! [`SPAN_SIZE`] bytes that only holds slots of that class. The [`Span`] record keeps a
+//! bitmap of the used slots. Bigger allocations get their own mapping. Records are sorted
+//! by address, so finding the span of a pointer is a binary search that never reads
+//! anything next to the payload.
+//!
+//! Only allocating, deallocating and the ownership queries go through this engine. The
+//! features that work on blocks (sealing, compaction, the leak scanner, tags, call sites,
+//! placement strategies, the region cache, the reservation and the rest of region options)
+//! don't see hardened allocations. Executable memory keeps its own mappings.
+//!
+//! [`MemAlloc::stats_by_class`] breaks the small allocations down by size class, which
+//! shows how much memory is lost to rounding and how many slots sit free in the spans.
+//!
+//! With [`crate::Config::protect_metadata`], the metadata pages are also read-only while
+//! no allocation or deallocation is in progress, so a stray write into them crashes the
+//! program right away instead of corrupting the allocator silently.
+
+use std::{
+    alloc::Layout,
+    mem,
+    ptr::{self, NonNull},
+    slice,
+};
+
+use lock_api::RawMutex;
+
+use crate::{
+    kernel::{page_size, protect, request_aligned_memory, request_memory, return_memory, Protection},
+    memalloc::MemAlloc,
+    sync,
+    syscalls::{Syscall, SyscallCounter},
+    utils::align,
+};
+
+/// Size of the spans that hold the slots of small allocations.
+pub(crate) const SPAN_SIZE: usize = 64 * 1024;
+
+/// Sizes small allocations are rounded up to. Bigger ones get their own mapping.
+const SIZE_CLASSES: [usize; 20] = [
+    16, 32, 48, 64, 80, 96, 128, 160, 192, 256, 320, 384, 512, 768, 1024, 1536, 2048, 3072, 4096, 8192,
+];
+
+/// Words of the bitmap of used slots, enough for the smallest class.
+const BITMAP_WORDS: usize = SPAN_SIZE / SIZE_CLASSES[0] / u64::BITS as usize;
+
+/// Statistics of a size class, returned by [`MemAlloc::stats_by_class`]. Sizes are the
+/// ones requested by the layouts, like in [`crate::Stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClassStats {
+    /// Size of the slots of this class.
+    pub size: usize,
+    /// Number of allocations served from this class since the allocator was created.
+    pub allocations: usize,
+    /// Number of deallocations of this class since the allocator was created.
+    pub frees: usize,
+    /// Number of used slots.
+    pub live: usize,
+    /// Number of free slots in the spans of this class, ready to be used without mapping
+    /// more memory.
+    pub cached: usize,
+    /// Bytes requested by the live allocations.
+    pub requested: usize,
+}
+
+impl ClassStats {
+    /// Bytes lost to rounding the live allocations up to the size of the class.
+    pub fn slack(&self) -> usize {
+        self.live * self.size - self.requested
+    }
+}
+
+/// Record of a mapping of data pages, stored in the metadata pages.
+///
+/// Addresses are stored as plain integers, so the provenance of the mapping is exposed
+/// when it is recorded and pointers are rebuilt from it, which Miri accepts.
+#[derive(Clone, Copy)]
+struct Span {
+    /// Start of the mapping.
+    start: usize,
+    /// Size of the mapping.
+    len: usize,
+    /// First slot, or the address of the allocation if this is a large one.
+    base: usize,
+    /// Size of the slots, or 0 if the span holds a single large allocation.
+    class: usize,
+    /// Number of used slots.
+    used: usize,
+    /// Bit `i` is set if slot `i` is used.
+    bitmap: [u64; BITMAP_WORDS],
+}
+
+impl Span {
+    fn slots(&self) -> usize {
+        (self.start + self.len - self.base) / self.class
+    }
+}
+
+/// Allocations of the hardened mode. See the module docs.
+pub(crate) struct Hardened {
+    /// Records sorted by `start`. This is the metadata mapping.
+    spans: *mut Span,
+    /// Number of records the metadata mapping can hold.
+    capacity: usize,
+    /// Number of records.
+    len: usize,
+    /// Start of the span each class is allocating from, or 0 if there is none. It is
+    /// kept even if it is empty, so allocating and freeing in a loop doesn't map and
+    /// unmap a span every time.
+    current: [usize; SIZE_CLASSES.len()],
+    /// Statistics of every class. `cached` is computed from the spans when they are read.
+    classes: [ClassStats; SIZE_CLASSES.len()],
+    /// Whether the metadata pages are read-only between operations.
+    protect: bool,
+    /// Calls made to the OS for the spans and the metadata.
+    pub(crate) syscalls: SyscallCounter,
+}
+
+impl Hardened {
+    pub(crate) const fn new(protect: bool) -> Self {
+        let mut classes = [ClassStats { size: 0, allocations: 0, frees: 0, live: 0, cached: 0, requested: 0 }; SIZE_CLASSES.len()];
+
+        let mut class = 0;
+        while class < SIZE_CLASSES.len() {
+            classes[class].size = SIZE_CLASSES[class];
+            class += 1;
+        }
+
+        Self { spans: ptr::null_mut(), capacity: 0, len: 0, current: [0; SIZE_CLASSES.len()], classes, protect, syscalls: SyscallCounter::new() }
+    }
+
+    /// Makes the metadata pages writable for an operation that changes them, or read-only
+    /// again once it is done, if they are protected.
+    fn set_writable(&self, writable: bool) {
+        let protection = if writable { Protection::Writable } else { Protection::ReadOnly };
+
+        if let (true, Some((addr, len))) = (self.protect, self.metadata()) {
+            self.syscalls.count(Syscall::Protect);
+            unsafe { protect(addr, len, protection) };
+        }
+    }
+
+    /// Maps `len` bytes for a span or the metadata.
+    unsafe fn map(&self, len: usize) -> Option<NonNull<u8>> {
+        self.syscalls.count(Syscall::Map);
+        unsafe { request_memory(len, false) }
+    }
+
+    /// Unmaps `len` bytes starting from `addr` mapped by [`Hardened::map`].
+    unsafe fn unmap(&self, addr: *mut u8, len: usize) {
+        self.syscalls.count(Syscall::Unmap);
+        unsafe { return_memory(addr, len) };
+    }
+
+    fn spans(&self) -> &[Span] {
+        if self.spans.is_null() {
+            return &[];
+        }
+
+        unsafe { slice::from_raw_parts(self.spans, self.len) }
+    }
+
+    fn spans_mut(&mut self) -> &mut [Span] {
+        if self.spans.is_null() {
+            return &mut [];
+        }
+
+        unsafe { slice::from_raw_parts_mut(self.spans, self.len) }
+    }
+
+    /// Size of the metadata mapping of `capacity` records.
+    fn mapping_size(capacity: usize) -> usize {
+        align(capacity * mem::size_of::<Span>(), page_size())
+    }
+
+    /// Replaces the metadata mapping with one twice as big.
+    fn grow(&mut self) -> bool {
+        let capacity = (self.capacity * 2).max(page_size() / mem::size_of::<Span>()).max(1);
+
+        unsafe {
+            let Some(spans) = self.map(Self::mapping_size(capacity)) else {
+                return false;
+            };
+
+            let spans = spans.as_ptr().cast::<Span>();
+
+            if !self.spans.is_null() {
+                ptr::copy_nonoverlapping(self.spans, spans, self.len);
+                self.unmap(self.spans.cast(), Self::mapping_size(self.capacity));
+            }
+
+            self.spans = spans;
+            self.capacity = capacity;
+        }
+
+        true
+    }
+
+    /// Returns the index of the span that contains `addr`.
+    fn find(&self, addr: usize) -> Option<usize> {
+        let spans = self.spans();
+        let index = spans.partition_point(|span| span.start <= addr).checked_sub(1)?;
+
+        (addr < spans[index].start + spans[index].len).then_some(index)
+    }
+
+    /// Adds `span` keeping the records sorted. Returns its index.
+    fn insert(&mut self, span: Span) -> Option<usize> {
+        if self.len == self.capacity && !self.grow() {
+            return None;
+        }
+
+        let index = self.spans().partition_point(|other| other.start < span.start);
+
+        unsafe {
+            let slot = self.spans.add(index);
+            ptr::copy(slot, slot.add(1), self.len - index);
+            slot.write(span);
+        }
+
+        self.len += 1;
+
+        Some(index)
+    }
+
+    /// Unmaps the span at `index` and removes its record.
+    unsafe fn remove(&mut self, index: usize) {
+        let span = self.spans()[index];
+
+        unsafe {
+            self.unmap(ptr::with_exposed_provenance_mut(span.start), span.len);
+
+            let slot = self.spans.add(index);
+            ptr::copy(slot.add(1), slot, self.len - index - 1);
+        }
+
+        self.len -= 1;
+    }
+
+    /// Returns the size class for `layout`, if it is small enough to have one. Slots are
+    /// placed at multiples of their size from a page-aligned address, so the class must
+    /// be a multiple of the alignment.
+    fn class_of(layout: Layout) -> Option<usize> {
+        if layout.align() > page_size() {
+            return None;
+        }
+
+        let size = layout.size().max(1);
+
+        SIZE_CLASSES.iter().position(|&class| class >= size && class.is_multiple_of(layout.align()))
+    }
+
+    /// Allocates memory for `layout`. Returns null if it can't be mapped.
+    pub(crate) unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        self.set_writable(true);
+
+        let ptr = match Self::class_of(layout) {
+            Some(class) => self.allocate_small(class).inspect(|_| {
+                let stats = &mut self.classes[class];
+                stats.allocations += 1;
+                stats.live += 1;
+                stats.requested += layout.size();
+            }),
+            None => unsafe { self.allocate_large(layout) },
+        };
+
+        // A new metadata mapping may have been created, this protects it too.
+        self.set_writable(false);
+
+        ptr.unwrap_or(ptr::null_mut())
+    }
+
+    fn allocate_small(&mut self, class: usize) -> Option<*mut u8> {
+        let size = SIZE_CLASSES[class];
+        let index = self.span_with_room(class)?;
+        let span = &mut self.spans_mut()[index];
+
+        let (word, bits) = span.bitmap.iter_mut().enumerate().find(|(_, bits)| **bits != u64::MAX)?;
+        let bit = bits.trailing_ones() as usize;
+
+        *bits |= 1 << bit;
+        span.used += 1;
+
+        Some(ptr::with_exposed_provenance_mut(span.base + (word * u64::BITS as usize + bit) * size))
+    }
+
+    /// Returns the index of a span of `class` with free slots, mapping a new one if needed.
+    fn span_with_room(&mut self, class: usize) -> Option<usize> {
+        let has_room = |span: &Span| span.class == SIZE_CLASSES[class] && span.used < span.slots();
+
+        let current = self.find(self.current[class]).filter(|&index| has_room(&self.spans()[index]));
+        let index = match current.or_else(|| self.spans().iter().position(has_room)) {
+            Some(index) => index,
+            None => {
+                let start = unsafe { self.map(SPAN_SIZE)?.as_ptr().expose_provenance() };
+                let mut span = Span { start, len: SPAN_SIZE, base: start, class: SIZE_CLASSES[class], used: 0, bitmap: [0; BITMAP_WORDS] };
+
+                // Slots that don't exist are marked as used, so they are never given out.
+                for slot in span.slots()..BITMAP_WORDS * u64::BITS as usize {
+                    span.bitmap[slot / u64::BITS as usize] |= 1 << (slot % u64::BITS as usize);
+                }
+
+                match self.insert(span) {
+                    Some(index) => index,
+                    None => {
+                        unsafe { self.unmap(ptr::with_exposed_provenance_mut(start), SPAN_SIZE) };
+                        return None;
+                    }
+                }
+            }
+        };
+
+        self.current[class] = self.spans()[index].start;
+
+        Some(index)
+    }
+
+    /// Maps a dedicated span for `layout`.
+    unsafe fn allocate_large(&mut self, layout: Layout) -> Option<*mut u8> {
+        let page_size = page_size();
+        let len = align(layout.size().max(1), page_size);
+
+        unsafe {
+            let (start, len) = if layout.align() > page_size {
+                self.syscalls.count(Syscall::Map);
+
+                match request_aligned_memory(len, layout.align(), 0) {
+                    Some(addr) => (addr.as_ptr().expose_provenance(), len),
+                    // Over-map and use the aligned part of it.
+                    None => (self.map(len + layout.align())?.as_ptr().expose_provenance(), len + layout.align()),
+                }
+            } else {
+                (self.map(len)?.as_ptr().expose_provenance(), len)
+            };
+
+            let base = align(start, layout.align());
+            let span = Span { start, len, base, class: 0, used: 1, bitmap: [0; BITMAP_WORDS] };
+
+            if self.insert(span).is_none() {
+                self.unmap(ptr::with_exposed_provenance_mut(start), len);
+                return None;
+            }
+
+            Some(ptr::with_exposed_provenance_mut(base))
+        }
+    }
+
+    /// Deallocates `ptr`, which was allocated with `size` bytes. Returns `None` if `ptr`
+    /// is not inside any hardened span, or whether it was freed otherwise: pointers that
+    /// are not live allocations are ignored.
+    pub(crate) unsafe fn deallocate(&mut self, ptr: *mut u8, size: usize) -> Option<bool> {
+        // Reading the records doesn't need the pages to be writable.
+        self.find(ptr as usize)?;
+
+        self.set_writable(true);
+        let freed = unsafe { self.free(ptr, size) };
+        self.set_writable(false);
+
+        freed
+    }
+
+    unsafe fn free(&mut self, ptr: *mut u8, size: usize) -> Option<bool> {
+        let addr = ptr as usize;
+        let index = self.find(addr)?;
+        let span = &mut self.spans_mut()[index];
+
+        if span.class == 0 {
+            if addr != span.base {
+                return Some(false);
+            }
+
+            unsafe { self.remove(index) };
+
+            return Some(true);
+        }
+
+        let offset = addr - span.base;
+        let (slot, misaligned) = (offset / span.class, offset % span.class);
+        let (word, bit) = (slot / u64::BITS as usize, slot % u64::BITS as usize);
+
+        if misaligned != 0 || slot >= span.slots() || span.bitmap[word] & (1 << bit) == 0 {
+            return Some(false);
+        }
+
+        span.bitmap[word] &= !(1 << bit);
+        span.used -= 1;
+
+        let (used, start, class) = (span.used, span.start, span.class);
+
+        if let Some(stats) = self.classes.iter_mut().find(|stats| stats.size == class) {
+            stats.frees += 1;
+            stats.live -= 1;
+            stats.requested -= size;
+        }
+
+        if used == 0 && !self.current.contains(&start) {
+            unsafe { self.remove(index) };
+        }
+
+        Some(true)
+    }
+
+    /// Whether `addr` is inside any hardened span.
+    pub(crate) fn owns(&self, addr: usize) -> bool {
+        self.find(addr).is_some()
+    }
+
+    /// Whether `addr` is a live hardened allocation.
+    pub(crate) fn owns_allocation(&self, addr: usize) -> bool {
+        let Some(index) = self.find(addr) else {
+            return false;
+        };
+
+        let span = &self.spans()[index];
+
+        if span.class == 0 {
+            return addr == span.base;
+        }
+
+        let Some(offset) = addr.checked_sub(span.base) else {
+            return false;
+        };
+
+        let slot = offset / span.class;
+
+        offset.is_multiple_of(span.class)
+            && slot < span.slots()
+            && span.bitmap[slot / u64::BITS as usize] & (1 << (slot % u64::BITS as usize)) != 0
+    }
+
+    /// Statistics of every class, with the free slots of its spans.
+    fn class_stats(&self) -> [ClassStats; SIZE_CLASSES.len()] {
+        let mut classes = self.classes;
+
+        for span in self.spans().iter().filter(|span| span.class != 0) {
+            if let Some(stats) = classes.iter_mut().find(|stats| stats.size == span.class) {
+                stats.cached += span.slots() - span.used;
+            }
+        }
+
+        classes
+    }
+
+    /// Bytes mapped for the spans and their records.
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn mapped(&self) -> usize {
+        let metadata = self.metadata().map_or(0, |(_, len)| len);
+
+        self.spans().iter().map(|span| span.len).sum::<usize>() + metadata
+    }
+
+    /// Whether any slot or large allocation is in use.
+    pub(crate) fn has_live_allocations(&self) -> bool {
+        self.spans().iter().any(|span| span.used > 0)
+    }
+
+    /// Address and size of the metadata mapping, if there is one.
+    fn metadata(&self) -> Option<(*mut u8, usize)> {
+        (!self.spans.is_null()).then(|| (self.spans.cast(), Self::mapping_size(self.capacity)))
+    }
+}
+
+impl Drop for Hardened {
+    fn drop(&mut self) {
+        for span in self.spans() {
+            unsafe { self.unmap(ptr::with_exposed_provenance_mut(span.start), span.len) };
+        }
+
+        if let Some((addr, len)) = self.metadata() {
+            unsafe { self.unmap(addr, len) };
+        }
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Returns the statistics of every size class, from the smallest to the biggest.
+    /// Only [`crate::Config::hardened`] allocators round allocations up to size classes,
+    /// so the stats of other allocators are all zeros. See the `hardened` module.
+    pub fn stats_by_class(&self) -> impl Iterator<Item = ClassStats> {
+        sync::lock(&self.allocator).hardened.class_stats().into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, MemAlloc};
+
+    #[test]
+    fn metadata_is_not_next_to_the_payload() {
+        let allocator = MemAlloc::with_config(Config::new().hardened(true));
+        let layout = Layout::from_size_align(24, 8).unwrap();
+
+        unsafe {
+            let [a, b] = [(); 2].map(|_| allocator.allocate(layout));
+
+            // Slots are next to each other, with no header in between.
+            assert_eq!(b as usize - a as usize, 32);
+
+            // Overflowing into the next slot doesn't break the allocator.
+            ptr::write_bytes(a, 0xAB, 64);
+            assert!(allocator.owns_allocation(a) && allocator.owns_allocation(b));
+            assert!(!allocator.owns_allocation(a.add(8)));
+
+            let (metadata, len) = allocator.allocator.lock().hardened.metadata().unwrap();
+            assert!(!(metadata as usize..metadata as usize + len).contains(&(a as usize)));
+
+            allocator.deallocate(a, layout);
+            allocator.deallocate(b, layout);
+
+            // Double frees are ignored.
+            allocator.deallocate(b, layout);
+            assert!(!allocator.owns_allocation(b));
+            assert_eq!(allocator.stats().allocations, 0);
+        }
+    }
+
+    #[test]
+    fn hardened_allocations_of_every_size() {
+        let allocator = MemAlloc::with_config(Config::new().hardened(true));
+
+        unsafe {
+            let layouts = [(1, 1), (100, 64), (5000, 8), (100_000, 8), (64, 8192), (3 * 4096, 2 * 1024 * 1024)]
+                .map(|(size, align)| Layout::from_size_align(size, align).unwrap());
+
+            let ptrs: Vec<_> = (0..300).flat_map(|_| layouts.map(|layout| allocator.allocate(layout))).collect();
+
+            for (ptr, layout) in ptrs.iter().zip(layouts.iter().cycle()) {
+                assert!(!ptr.is_null());
+                assert_eq!(*ptr as usize % layout.align(), 0);
+                ptr::write_bytes(*ptr, 0xCD, layout.size());
+            }
+
+            for (ptr, layout) in ptrs.iter().zip(layouts.iter().cycle()) {
+                allocator.deallocate(*ptr, *layout);
+            }
+
+            // Only the current span of each class is kept.
+            let kernel = allocator.allocator.lock();
+            assert!(kernel.hardened.spans().iter().all(|span| span.used == 0 && span.class != 0));
+            assert!(kernel.regions.is_empty());
+        }
+    }
+
+    #[test]
+    fn stats_are_broken_down_by_class() {
+        let allocator = MemAlloc::with_config(Config::new().hardened(true));
+        let small = Layout::from_size_align(24, 8).unwrap();
+        let medium = Layout::from_size_align(100, 8).unwrap();
+
+        unsafe {
+            let smalls: Vec<_> = (0..10).map(|_| allocator.allocate(small)).collect();
+            let medium_ptr = allocator.allocate(medium);
+
+            for ptr in &smalls[..4] {
+                allocator.deallocate(*ptr, small);
+            }
+
+            let classes: Vec<_> = allocator.stats_by_class().collect();
+            assert_eq!(classes.len(), SIZE_CLASSES.len());
+
+            let class = classes.iter().find(|stats| stats.size == 32).unwrap();
+            assert_eq!((class.allocations, class.frees, class.live), (10, 4, 6));
+            assert_eq!(class.cached, SPAN_SIZE / 32 - 6);
+            assert_eq!(class.requested, 6 * 24);
+            assert_eq!(class.slack(), 6 * 8);
+
+            let class = classes.iter().find(|stats| stats.size == 128).unwrap();
+            assert_eq!((class.allocations, class.live, class.slack()), (1, 1, 28));
+
+            for ptr in &smalls[4..] {
+                allocator.deallocate(*ptr, small);
+            }
+
+            allocator.deallocate(medium_ptr, medium);
+            assert!(allocator.stats_by_class().all(|stats| stats.live == 0 && stats.requested == 0));
+        }
+
+        // Other allocators have no size classes.
+        let allocator = MemAlloc::new();
+        unsafe { allocator.deallocate(allocator.allocate(small), small) };
+        assert!(allocator.stats_by_class().all(|stats| stats.allocations == 0));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(any(miri, feature = "system-backend"), ignore = "needs OS memory")]
+    fn metadata_is_read_only_between_operations() {
+        use crate::utils::tests::permissions_of;
+
+        let allocator = MemAlloc::with_config(Config::new().hardened(true).protect_metadata(true));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        unsafe {
+            let ptrs: Vec<_> = (0..1000).map(|_| allocator.allocate(layout)).collect();
+            let (metadata, _) = allocator.allocator.lock().hardened.metadata().unwrap();
+            assert_eq!(permissions_of(metadata), "r--p");
+
+            for ptr in ptrs {
+                allocator.deallocate(ptr, layout);
+            }
+
+            let (metadata, _) = allocator.allocator.lock().hardened.metadata().unwrap();
+            assert_eq!(permissions_of(metadata), "r--p");
+            assert_eq!(allocator.stats().allocations, 0);
+        }
+
+        let allocator = MemAlloc::with_config(Config::new().hardened(true));
+
+        unsafe {
+            let ptr = allocator.allocate(layout);
+            let (metadata, _) = allocator.allocator.lock().hardened.metadata().unwrap();
+            assert_eq!(permissions_of(metadata), "rw-p");
+            allocator.deallocate(ptr, layout);
+        }
+    }
+}
@@ -0,0 +1,723 @@
+//! Named heaps with quotas and statistics.
+//!
+//! Several independent [`MemAlloc`] instances can live in the same process, for example
+//! one per subsystem, so their memory is partitioned and accounted separately. Each one
+//! keeps [`Stats`] of its live allocations, and with [`crate::Config::quota`] it refuses
+//! allocations that would go over a number of bytes. Giving it a name with
+//! [`crate::Config::name`] and registering it with [`MemAlloc::register`] makes it show
+//! up in [`heaps`]:
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::{Config, MemAlloc};
+//!
+//! static CACHE: MemAlloc = MemAlloc::with_config(Config::new().name("cache").quota(1024));
+//! static SCRATCH: MemAlloc = MemAlloc::with_config(Config::new().name("scratch"));
+//!
+//! CACHE.register().unwrap();
+//! SCRATCH.register().unwrap();
+//!
+//! unsafe {
+//!     let layout = Layout::from_size_align(1000, 8).unwrap();
+//!     let ptr = CACHE.allocate(layout);
+//!
+//!     // The cache is full.
+//!     assert!(CACHE.allocate(layout).is_null());
+//!
+//!     for (name, stats) in memalloc::heaps() {
+//!         println!("{name}: {} bytes in {} allocations", stats.allocated, stats.allocations);
+//!     }
+//!
+//!     CACHE.deallocate(ptr, layout);
+//! }
+//! ```
+//!
+//! Like the fork handlers, registered heaps are stored in a fixed-size static table since
+//! we can't use a `Vec` here, and they are stored as [`NamedHeap`] trait objects since
+//! [`MemAlloc`] is generic over its raw mutex.
+
+use std::{
+    cell::UnsafeCell,
+    fmt,
+    hint,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use lock_api::RawMutex;
+
+use crate::{
+    block::{BLOCK_FOOTER_SIZE, Block},
+    kernel::{Kernel, resident_bytes},
+    list::{List, Node},
+    memalloc::MemAlloc,
+    region::{REGION_HEADER_SIZE, Region},
+    sync::{
+        self,
+        atomic::{self, AtomicUsize},
+    },
+};
+
+/// Maximum number of heaps that can be registered with [`MemAlloc::register`].
+pub const MAX_HEAPS: usize = 16;
+
+/// Statistics of the live allocations of a heap. Sizes are the ones requested by the
+/// layouts, without the metadata and the padding of the allocator.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Bytes currently allocated.
+    pub allocated: usize,
+    /// Number of live allocations.
+    pub allocations: usize,
+    /// Maximum number of bytes that have been allocated at the same time.
+    pub peak: usize,
+    /// Number of allocations refused because of [`crate::Config::quota`].
+    pub quota_failures: usize,
+}
+
+impl Stats {
+    pub(crate) const fn new() -> Self {
+        Self { allocated: 0, allocations: 0, peak: 0, quota_failures: 0 }
+    }
+
+    // The stats of a heap are kept in `StatsCounters`, only the ones of its tags are
+    // updated here.
+    #[cfg(feature = "tagging")]
+    pub(crate) fn record_allocation(&mut self, size: usize) {
+        self.allocated += size;
+        self.allocations += 1;
+        self.peak = self.peak.max(self.allocated);
+    }
+
+    #[cfg(feature = "tagging")]
+    pub(crate) fn record_deallocation(&mut self, size: usize) {
+        self.allocated -= size;
+        self.allocations -= 1;
+    }
+}
+
+/// [`Stats`] of a heap kept in relaxed atomics, so [`MemAlloc::stats`] reads them without
+/// the lock and a thread polling them never makes allocations wait.
+///
+/// They are only written with the lock held, so there is a single writer and each
+/// counter is updated with a plain load and store. The counters are behind a seqlock so
+/// readers always get the stats of a single moment: the writer makes the sequence odd,
+/// updates the counters and makes it even again, and readers retry if the sequence was
+/// odd or changed while they read the counters.
+pub(crate) struct StatsCounters {
+    sequence: AtomicUsize,
+    allocated: AtomicUsize,
+    allocations: AtomicUsize,
+    peak: AtomicUsize,
+    quota_failures: AtomicUsize,
+}
+
+impl StatsCounters {
+    sync::const_fn! {
+        pub(crate) const fn new() -> Self {
+            Self {
+                sequence: AtomicUsize::new(0),
+                allocated: AtomicUsize::new(0),
+                allocations: AtomicUsize::new(0),
+                peak: AtomicUsize::new(0),
+                quota_failures: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    pub(crate) fn load(&self) -> Stats {
+        loop {
+            let sequence = self.sequence.load(atomic::Ordering::Acquire);
+
+            if sequence.is_multiple_of(2) {
+                let stats = Stats {
+                    allocated: self.allocated.load(atomic::Ordering::Relaxed),
+                    allocations: self.allocations.load(atomic::Ordering::Relaxed),
+                    peak: self.peak.load(atomic::Ordering::Relaxed),
+                    quota_failures: self.quota_failures.load(atomic::Ordering::Relaxed),
+                };
+
+                atomic::fence(atomic::Ordering::Acquire);
+
+                if self.sequence.load(atomic::Ordering::Relaxed) == sequence {
+                    return stats;
+                }
+            }
+
+            atomic::spin_loop();
+        }
+    }
+
+    pub(crate) fn store(&self, stats: Stats) {
+        self.write(|counters| {
+            counters.allocated.store(stats.allocated, atomic::Ordering::Relaxed);
+            counters.allocations.store(stats.allocations, atomic::Ordering::Relaxed);
+            counters.peak.store(stats.peak, atomic::Ordering::Relaxed);
+            counters.quota_failures.store(stats.quota_failures, atomic::Ordering::Relaxed);
+        })
+    }
+
+    /// Whether `size` more bytes fit in `quota`. Counts a failure if they don't.
+    fn fits(&self, size: usize, quota: Option<usize>) -> bool {
+        let fits = quota.is_none_or(|quota| self.allocated.load(atomic::Ordering::Relaxed).saturating_add(size) <= quota);
+
+        if !fits {
+            self.write(|counters| update(&counters.quota_failures, |failures| failures + 1));
+        }
+
+        fits
+    }
+
+    pub(crate) fn record_allocation(&self, size: usize) {
+        self.write(|counters| {
+            let allocated = update(&counters.allocated, |allocated| allocated + size);
+            update(&counters.allocations, |allocations| allocations + 1);
+            update(&counters.peak, |peak| peak.max(allocated));
+        })
+    }
+
+    pub(crate) fn record_deallocation(&self, size: usize) {
+        self.write(|counters| {
+            update(&counters.allocated, |allocated| allocated - size);
+            update(&counters.allocations, |allocations| allocations - 1);
+        })
+    }
+
+    /// Updates the counters with `f` between the two increments of the sequence. Must
+    /// only be called with the lock of the kernel held.
+    #[inline]
+    fn write<T>(&self, f: impl FnOnce(&Self) -> T) -> T {
+        let sequence = self.sequence.load(atomic::Ordering::Relaxed);
+        self.sequence.store(sequence + 1, atomic::Ordering::Relaxed);
+        atomic::fence(atomic::Ordering::Release);
+
+        let value = f(self);
+
+        self.sequence.store(sequence + 2, atomic::Ordering::Release);
+
+        value
+    }
+}
+
+/// Replaces the value of `counter` with `f` of it and returns the new one. Only the lock
+/// holder writes the counters, so this doesn't need a read-modify-write instruction.
+#[inline]
+fn update(counter: &AtomicUsize, f: impl FnOnce(usize) -> usize) -> usize {
+    let value = f(counter.load(atomic::Ordering::Relaxed));
+    counter.store(value, atomic::Ordering::Relaxed);
+
+    value
+}
+
+impl Kernel {
+    /// Whether an allocation of `size` bytes fits in `quota`, given the `stats` of the
+    /// heap.
+    pub(crate) fn fits_quota(&mut self, stats: &StatsCounters, size: usize, quota: Option<usize>) -> bool {
+        let fits = stats.fits(size, quota);
+
+        #[cfg(feature = "tagging")]
+        if !fits {
+            self.tag_quota_failure();
+        }
+
+        fits
+    }
+
+    /// Records the allocation `ptr` of `size` bytes in `stats`, together with the
+    /// location of the caller if the `call-sites` feature is enabled.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a live allocation of this kernel.
+    #[track_caller]
+    pub(crate) unsafe fn record_allocation(&mut self, stats: &StatsCounters, ptr: *mut u8, size: usize) {
+        unsafe { self.record_allocation_keeping(stats, ptr, size, 0) };
+    }
+
+    /// Records that the allocation `ptr` of `old_size` bytes was resized to `new_size`
+    /// bytes keeping its contents, like large allocations that are remapped.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a live allocation of this kernel.
+    #[track_caller]
+    pub(crate) unsafe fn record_reallocation(&mut self, stats: &StatsCounters, ptr: *mut u8, old_size: usize, new_size: usize) {
+        unsafe {
+            self.record_deallocation(stats, Block::from_payload(ptr), ptr, old_size);
+            self.record_allocation_keeping(stats, ptr, new_size, old_size);
+        }
+    }
+
+    /// Same as [`Kernel::record_allocation`], but the first `kept` bytes of `ptr` already
+    /// hold data.
+    #[track_caller]
+    unsafe fn record_allocation_keeping(&mut self, stats: &StatsCounters, ptr: *mut u8, size: usize, kept: usize) {
+        stats.record_allocation(size);
+
+        #[cfg(any(feature = "tagging", feature = "call-sites"))]
+        let node = unsafe { Block::from_payload(ptr) };
+
+        #[cfg(feature = "tagging")]
+        unsafe {
+            self.tag_block(node, size);
+        }
+
+        #[cfg(feature = "call-sites")]
+        unsafe {
+            (*node.as_ptr()).data.call_site = Some(std::panic::Location::caller());
+        }
+
+        #[cfg(feature = "dhat")]
+        unsafe {
+            self.profile_allocation(node, ptr, size, kept);
+        }
+
+        #[cfg(not(any(feature = "tagging", feature = "call-sites")))]
+        let _ = ptr;
+
+        #[cfg(not(feature = "dhat"))]
+        let _ = kept;
+    }
+
+    /// Removes the allocation `ptr` of `size` bytes of the used block `node` from
+    /// `stats`.
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to a valid used block header and `ptr` to its payload.
+    pub(crate) unsafe fn record_deallocation(&mut self, stats: &StatsCounters, node: NonNull<Node<Block>>, ptr: *mut u8, size: usize) {
+        stats.record_deallocation(size);
+
+        #[cfg(feature = "tagging")]
+        unsafe {
+            self.untag_block(node, size);
+        }
+
+        #[cfg(feature = "dhat")]
+        unsafe {
+            self.profile_deallocation(node, ptr, size);
+        }
+
+        #[cfg(not(feature = "tagging"))]
+        let _ = node;
+
+        #[cfg(not(feature = "dhat"))]
+        let _ = ptr;
+    }
+}
+
+/// Shape of a heap at some point: how many regions and blocks it has and how much of
+/// them is used. Returned by [`MemAlloc::summary`] and printed by the `Debug` and
+/// `Display` implementations of [`MemAlloc`].
+///
+/// Byte totals are the usable bytes of the blocks, which are at least the ones requested
+/// by their layouts. Hardened allocations are not made of blocks and are not counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapSummary {
+    /// Number of regions split into blocks.
+    pub regions: usize,
+    /// Number of regions holding a single large allocation.
+    pub large_objects: usize,
+    /// Number of empty regions kept for reuse, see [`crate::Config::cached_regions`].
+    pub cached_regions: usize,
+    /// Number of empty regions kept inaccessible, see [`crate::Config::quarantine_regions`].
+    pub quarantined_regions: usize,
+    /// Number of regions waiting for the unmapper thread, see
+    /// [`MemAlloc::start_background_unmapping`].
+    pub pending_unmaps: usize,
+    /// Number of regions holding executable code.
+    pub executable_regions: usize,
+    /// Number of blocks handed out to allocations.
+    pub used_blocks: usize,
+    /// Usable bytes of the used blocks.
+    pub used_bytes: usize,
+    /// Number of free blocks.
+    pub free_blocks: usize,
+    /// Usable bytes of the free blocks.
+    pub free_bytes: usize,
+    /// Number of blocks in the free list, which should be the same as `free_blocks`.
+    pub free_list_len: usize,
+}
+
+impl Kernel {
+    /// Walks every region to build its [`HeapSummary`].
+    fn summary(&self) -> HeapSummary {
+        let mut summary = HeapSummary {
+            regions: self.regions.len(),
+            large_objects: self.large_objects.len(),
+            cached_regions: self.cache.len(),
+            quarantined_regions: self.quarantine.len(),
+            pending_unmaps: self.unmapper.pending(),
+            executable_regions: self.executable.len(),
+            free_list_len: self.free_list.items.len(),
+            ..HeapSummary::default()
+        };
+
+        let lists: [&List<Region>; 3] = [&self.regions, &self.large_objects, &self.executable];
+
+        for block in lists.into_iter().flat_map(List::iter).flat_map(|region| region.blocks.iter()) {
+            let size = block.size() - BLOCK_FOOTER_SIZE;
+
+            if block.is_free() {
+                summary.free_blocks += 1;
+                summary.free_bytes += size;
+            } else {
+                summary.used_blocks += 1;
+                summary.used_bytes += size;
+            }
+        }
+
+        summary
+    }
+}
+
+/// How much memory a heap takes, at three levels that drift apart once memory is
+/// decommitted or cached. Returned by [`MemAlloc::memory_usage`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Bytes of address space taken by regions, including cached, quarantined and
+    /// executable ones and the ones waiting for the unmapper thread.
+    pub mapped: usize,
+    /// Usable bytes of the blocks handed out to allocations, the same as
+    /// [`HeapSummary::used_bytes`].
+    pub active: usize,
+    /// Bytes of the mapped pages that are resident in physical memory, as told by
+    /// `mincore` on Unix and `QueryWorkingSetEx` on Windows. `None` if the platform
+    /// can't tell.
+    pub resident: Option<usize>,
+}
+
+impl Kernel {
+    /// Walks every region to build its [`MemoryUsage`].
+    fn memory_usage(&self) -> MemoryUsage {
+        let mut mapped = self.quarantine.bytes() + self.unmapper.pending_bytes();
+        let mut resident = Some(0);
+
+        // Quarantined regions are decommitted, and pending ones are as good as gone.
+        for list in [&self.regions, &self.large_objects, &self.executable, &self.cache] {
+            let mut current = list.first();
+
+            while let Some(region) = current {
+                unsafe {
+                    let len = region.as_ref().data.size + REGION_HEADER_SIZE;
+
+                    mapped += len;
+                    resident = resident.zip(resident_bytes(region.as_ptr().cast(), len)).map(|(sum, bytes)| sum + bytes);
+                    current = region.as_ref().next;
+                }
+            }
+        }
+
+        MemoryUsage { mapped, active: self.summary().used_bytes, resident }
+    }
+}
+
+/// Heap that can be listed by [`heaps`].
+trait NamedHeap: Sync {
+    fn name(&self) -> Option<&'static str>;
+
+    fn stats(&self) -> Stats;
+}
+
+impl<R: RawMutex + Sync> NamedHeap for MemAlloc<R> {
+    fn name(&self) -> Option<&'static str> {
+        MemAlloc::name(self)
+    }
+
+    fn stats(&self) -> Stats {
+        MemAlloc::stats(self)
+    }
+}
+
+/// Heaps registered with [`MemAlloc::register`].
+struct Table(UnsafeCell<[Option<&'static dyn NamedHeap>; MAX_HEAPS]>);
+
+/// The table is only accessed while holding [`TABLE_LOCK`].
+unsafe impl Sync for Table {}
+
+static TABLE: Table = Table(UnsafeCell::new([None; MAX_HEAPS]));
+
+/// Spin lock that protects [`TABLE`].
+static TABLE_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// Runs `f` with the table locked.
+fn with_table<T>(f: impl FnOnce(&mut [Option<&'static dyn NamedHeap>; MAX_HEAPS]) -> T) -> T {
+    while TABLE_LOCK
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        hint::spin_loop();
+    }
+
+    let result = f(unsafe { &mut *TABLE.0.get() });
+
+    TABLE_LOCK.store(false, Ordering::Release);
+
+    result
+}
+
+/// Returns the name and the current [`Stats`] of every heap registered with
+/// [`MemAlloc::register`], in registration order.
+pub fn heaps() -> impl Iterator<Item = (&'static str, Stats)> {
+    // The stats are read after releasing the table, so reading them can't deadlock
+    // with a heap that is being registered.
+    let table = with_table(|table| *table);
+
+    table.into_iter().flatten().filter_map(|heap| Some((heap.name()?, heap.stats())))
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Name given with [`crate::Config::name`], if any.
+    pub fn name(&self) -> Option<&'static str> {
+        self.config.name
+    }
+
+    /// Returns the statistics of the live allocations of this heap. They are read
+    /// without taking the lock, so polling them never makes allocations wait, and an
+    /// operation in flight is either counted in all of them or in none.
+    pub fn stats(&self) -> Stats {
+        self.stats.load()
+    }
+
+    /// Walks the regions of this heap and counts their blocks. See [`HeapSummary`].
+    pub fn summary(&self) -> HeapSummary {
+        sync::lock(&self.allocator).summary()
+    }
+
+    /// Walks the regions of this heap and asks the OS which of their pages are resident.
+    /// See [`MemoryUsage`].
+    pub fn memory_usage(&self) -> MemoryUsage {
+        sync::lock(&self.allocator).memory_usage()
+    }
+
+    /// Adds this heap to the ones listed by [`heaps`]. Registering the same heap twice is
+    /// fine. Fails if it has no name, if another heap is registered with the same name or
+    /// if [`MAX_HEAPS`] heaps are already registered.
+    pub fn register(&'static self) -> Result<(), &'static str>
+    where
+        R: Sync,
+    {
+        let heap: &'static dyn NamedHeap = self;
+        let name = self.name().ok_or("heap has no name")?;
+
+        with_table(|table| {
+            for registered in table.iter().flatten() {
+                if ptr::addr_eq(*registered, heap) {
+                    return Ok(());
+                }
+
+                if registered.name() == Some(name) {
+                    return Err("heap name already registered");
+                }
+            }
+
+            let slot = table.iter_mut().find(|slot| slot.is_none()).ok_or("too many heaps")?;
+            *slot = Some(heap);
+
+            Ok(())
+        })
+    }
+}
+
+// The summary is taken before formatting anything, so the lock is not held while the
+// formatter writes, which may allocate with this same allocator.
+impl<R: RawMutex> fmt::Debug for MemAlloc<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let summary = self.summary();
+        let stats = self.stats();
+
+        f.debug_struct("MemAlloc")
+            .field("name", &self.name())
+            .field("regions", &summary.regions)
+            .field("large_objects", &summary.large_objects)
+            .field("cached_regions", &summary.cached_regions)
+            .field("quarantined_regions", &summary.quarantined_regions)
+            .field("pending_unmaps", &summary.pending_unmaps)
+            .field("executable_regions", &summary.executable_regions)
+            .field("used_blocks", &summary.used_blocks)
+            .field("used_bytes", &summary.used_bytes)
+            .field("free_blocks", &summary.free_blocks)
+            .field("free_bytes", &summary.free_bytes)
+            .field("free_list_len", &summary.free_list_len)
+            .field("stats", &stats)
+            .finish()
+    }
+}
+
+impl<R: RawMutex> fmt::Display for MemAlloc<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let summary = self.summary();
+
+        write!(
+            f,
+            "{}: {} regions, {} used blocks ({} bytes), {} free blocks ({} bytes)",
+            self.name().unwrap_or("memalloc"),
+            summary.regions + summary.large_objects + summary.executable_regions,
+            summary.used_blocks,
+            summary.used_bytes,
+            summary.free_blocks,
+            summary.free_bytes,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    fn quotas_limit_allocations() {
+        let allocator = MemAlloc::with_config(Config::new().quota(1000));
+        let layout = Layout::from_size_align(400, 8).unwrap();
+
+        unsafe {
+            let a = allocator.allocate(layout);
+            let b = allocator.allocate(layout);
+            assert!(!a.is_null() && !b.is_null());
+
+            assert!(allocator.allocate(layout).is_null());
+            assert_eq!(allocator.stats(), Stats { allocated: 800, allocations: 2, peak: 800, quota_failures: 1 });
+
+            allocator.deallocate(a, layout);
+            let c = allocator.allocate(layout);
+            assert!(!c.is_null());
+
+            // Large objects count too.
+            let large = Layout::from_size_align(Config::new().large_object_threshold, 8).unwrap();
+            assert!(allocator.allocate(large).is_null());
+
+            allocator.deallocate(b, layout);
+            allocator.deallocate(c, layout);
+            assert_eq!(allocator.stats(), Stats { allocated: 0, allocations: 0, peak: 800, quota_failures: 2 });
+        }
+    }
+
+    #[test]
+    fn stats_are_read_without_the_lock() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        unsafe {
+            let ptr = allocator.allocate(layout);
+
+            // Reading the stats would deadlock here if it took the lock.
+            let kernel = sync::lock(&allocator.allocator);
+            assert_eq!(allocator.stats(), Stats { allocated: 100, allocations: 1, peak: 100, quota_failures: 0 });
+            drop(kernel);
+
+            allocator.deallocate(ptr, layout);
+            assert_eq!(allocator.stats(), Stats { allocated: 0, allocations: 0, peak: 100, quota_failures: 0 });
+        }
+    }
+
+    #[test]
+    #[cfg(loom)]
+    fn loom_stats_are_never_torn() {
+        let allocated = Stats { allocated: 8, allocations: 1, peak: 8, quota_failures: 0 };
+
+        loom::model(move || {
+            let counters = loom::sync::Arc::new(StatsCounters::new());
+
+            let writer = {
+                let counters = loom::sync::Arc::clone(&counters);
+                loom::thread::spawn(move || counters.record_allocation(8))
+            };
+
+            // A read in the middle of the update sees the stats before it or after it.
+            let stats = counters.load();
+            assert!(stats == Stats::new() || stats == allocated);
+
+            writer.join().unwrap();
+            assert_eq!(counters.load(), allocated);
+        });
+    }
+
+    #[test]
+    fn summary_counts_blocks() {
+        let allocator = MemAlloc::with_config(Config::new().name("summary"));
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        unsafe {
+            let ptrs: Vec<_> = (0..3).map(|_| allocator.allocate(layout)).collect();
+            allocator.deallocate(ptrs[1], layout);
+
+            // The block in the middle and the rest of the region are free.
+            let summary = allocator.summary();
+            assert_eq!((summary.regions, summary.large_objects), (1, 0));
+            assert_eq!((summary.used_blocks, summary.free_blocks, summary.free_list_len), (2, 2, 2));
+            assert!(summary.used_bytes >= 2 * 64 && summary.free_bytes >= 64);
+
+            assert_eq!(
+                allocator.to_string(),
+                format!(
+                    "summary: 1 regions, 2 used blocks ({} bytes), 2 free blocks ({} bytes)",
+                    summary.used_bytes, summary.free_bytes,
+                ),
+            );
+
+            let debug = format!("{allocator:?}");
+            assert!(debug.starts_with("MemAlloc { name: Some(\"summary\"), regions: 1,"));
+            assert!(!debug.contains("0x"));
+
+            allocator.deallocate(ptrs[0], layout);
+            allocator.deallocate(ptrs[2], layout);
+        }
+    }
+
+    // Allocators can't be statics under loom, see the `sync` module.
+    #[test]
+    #[cfg(not(loom))]
+    fn heaps_are_listed_by_name() {
+        static SESSION: MemAlloc = MemAlloc::with_config(Config::new().name("session"));
+        static DUPLICATE: MemAlloc = MemAlloc::with_config(Config::new().name("session"));
+        static ANONYMOUS: MemAlloc = MemAlloc::new();
+
+        SESSION.register().unwrap();
+        SESSION.register().unwrap();
+        assert_eq!(DUPLICATE.register(), Err("heap name already registered"));
+        assert_eq!(ANONYMOUS.register(), Err("heap has no name"));
+
+        let layout = Layout::new::<u64>();
+
+        unsafe {
+            let ptr = SESSION.allocate(layout);
+            let session: Vec<_> = heaps().filter(|(name, _)| *name == "session").collect();
+            assert_eq!(session, [("session", SESSION.stats())]);
+            assert_eq!(session[0].1.allocated, 8);
+
+            SESSION.deallocate(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn memory_usage_tells_mapped_active_and_resident_apart() {
+        let allocator = MemAlloc::with_config(Config::new().cached_regions(1).decommit(crate::Decommit::Eager));
+        let page_size = crate::kernel::page_size();
+        let layout = Layout::from_size_align(64 * page_size, 8).unwrap();
+
+        unsafe {
+            let ptr = allocator.allocate(layout);
+            let usage = allocator.memory_usage();
+            assert!(usage.mapped >= usage.active && usage.active >= layout.size());
+
+            // Touching the pages makes them resident.
+            ptr.write_bytes(0xAB, layout.size());
+            #[cfg(not(any(miri, feature = "system-backend")))]
+            assert!(allocator.memory_usage().resident.unwrap() >= layout.size());
+
+            // The cached region stays mapped, but its pages are given back.
+            allocator.deallocate(ptr, layout);
+            let cached = allocator.memory_usage();
+            assert_eq!((cached.mapped, cached.active), (usage.mapped, 0));
+
+            #[cfg(not(any(miri, feature = "system-backend")))]
+            assert!(cached.resident.unwrap() <= 2 * page_size);
+
+            allocator.trim();
+            assert_eq!(allocator.memory_usage(), MemoryUsage { mapped: 0, active: 0, resident: Some(0) });
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Failure injection.
+//!
+//! Code that handles running out of memory is rarely tested, since making the allocator
+//! fail at the right moment is hard. [`MemAlloc::inject_failures`] makes allocations
+//! fail on purpose: they return null straight away, without touching the heap or the
+//! OS. For example, every allocation of a function can be made to fail in turn:
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::{FailureInjection, MemAlloc};
+//!
+//! /// Allocates three buffers and gives up if any of them fails.
+//! fn three_buffers(allocator: &MemAlloc) -> bool {
+//!     let layout = Layout::new::<[u8; 64]>();
+//!     let buffers = [(); 3].map(|_| unsafe { allocator.allocate(layout) });
+//!     let ok = buffers.iter().all(|ptr| !ptr.is_null());
+//!
+//!     for ptr in buffers {
+//!         unsafe { allocator.deallocate(ptr, layout) };
+//!     }
+//!
+//!     ok
+//! }
+//!
+//! let allocator = MemAlloc::new();
+//!
+//! for n in 1..=3 {
+//!     allocator.inject_failures(FailureInjection::Nth(n));
+//!     assert!(!three_buffers(&allocator));
+//! }
+//!
+//! allocator.inject_failures(FailureInjection::Never);
+//! assert!(three_buffers(&allocator));
+//! ```
+
+use std::alloc::Layout;
+
+use lock_api::RawMutex;
+
+use crate::{memalloc::MemAlloc, sync};
+
+/// Which allocations fail on purpose. See [`MemAlloc::inject_failures`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailureInjection {
+    /// No allocation fails on purpose.
+    Never,
+    /// Only the `n`th allocation made from now on fails, starting from 1.
+    Nth(usize),
+    /// Every `n`th allocation made from now on fails.
+    EveryNth(usize),
+    /// Allocations of more than `size` bytes fail with the given `probability`, from 0.0
+    /// to 1.0. The failures come from a pseudo-random generator initialized with `seed`,
+    /// so the same seed fails the same allocations on every run.
+    LargerThan { size: usize, probability: f64, seed: u64 },
+}
+
+/// State of the [`FailureInjection`] of an allocator.
+pub(crate) struct FailureInjector {
+    injection: FailureInjection,
+    /// Allocations made since the injection was set.
+    count: usize,
+    /// State of the xorshift generator used by [`FailureInjection::LargerThan`].
+    state: u64,
+}
+
+impl FailureInjector {
+    pub(crate) const fn new() -> Self {
+        Self { injection: FailureInjection::Never, count: 0, state: 0 }
+    }
+
+    fn set(&mut self, injection: FailureInjection) {
+        let state = match injection {
+            // Xorshift gets stuck at 0.
+            FailureInjection::LargerThan { seed, .. } => seed | 1,
+            _ => 0,
+        };
+
+        *self = Self { injection, count: 0, state };
+    }
+
+    /// Whether the next allocation of `layout` must fail.
+    pub(crate) fn fails(&mut self, layout: Layout) -> bool {
+        if self.injection == FailureInjection::Never {
+            return false;
+        }
+
+        self.count += 1;
+
+        match self.injection {
+            FailureInjection::Never => false,
+            FailureInjection::Nth(n) => self.count == n,
+            FailureInjection::EveryNth(n) => n > 0 && self.count.is_multiple_of(n),
+            FailureInjection::LargerThan { size, probability, .. } => {
+                layout.size() > size && self.next_random() < probability
+            }
+        }
+    }
+
+    /// Returns a pseudo-random number in `0.0..1.0`.
+    fn next_random(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+
+        // The 53 upper bits fill the mantissa of the `f64`.
+        (self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Makes the allocations chosen by `injection` fail by returning null, to test how
+    /// the program handles running out of memory. Counting starts again every time it is
+    /// called. Deallocations are never affected.
+    pub fn inject_failures(&self, injection: FailureInjection) {
+        sync::lock(&self.allocator).failures.set(injection);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn injected_failures_follow_the_policy() {
+        let small = Layout::new::<u64>();
+        let big = Layout::from_size_align(4096, 8).unwrap();
+
+        let mut injector = FailureInjector::new();
+        assert!(!(0..100).any(|_| injector.fails(small)));
+
+        injector.set(FailureInjection::EveryNth(3));
+        let failures: Vec<_> = (0..7).map(|_| injector.fails(small)).collect();
+        assert_eq!(failures, [false, false, true, false, false, true, false]);
+
+        injector.set(FailureInjection::LargerThan { size: 1024, probability: 0.5, seed: 42 });
+        let failures = (0..1000).filter(|_| injector.fails(big)).count();
+        assert!((400..600).contains(&failures));
+        assert!(!(0..100).any(|_| injector.fails(small)));
+
+        // The same seed fails the same allocations.
+        let run = |seed| {
+            let mut injector = FailureInjector::new();
+            injector.set(FailureInjection::LargerThan { size: 0, probability: 0.3, seed });
+            (0..64).map(|_| injector.fails(small)).collect::<Vec<_>>()
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn injected_failures_return_null() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::new::<u64>();
+
+        allocator.inject_failures(FailureInjection::Nth(2));
+
+        unsafe {
+            let first = allocator.allocate(layout);
+            assert!(!first.is_null());
+            assert!(allocator.allocate(layout).is_null());
+
+            let third = allocator.allocate(layout);
+            assert!(!third.is_null());
+
+            // The failed allocation is not accounted anywhere.
+            assert_eq!(allocator.stats().allocations, 2);
+
+            allocator.deallocate(first, layout);
+            allocator.deallocate(third, layout);
+        }
+    }
+}
@@ -1,5 +1,26 @@
-use std::{alloc::Layout, mem, ptr::NonNull};
-use crate::{block::{BLOCK_HEADER_SIZE, Block}, freelist::FreeList, list::{List, Node}, memalloc::MIN_BLOCK_SIZE, region::{REGION_HEADER_SIZE, Region}, utils::align};
+use std::{alloc::Layout, ffi::CStr, mem, ptr::{self, NonNull}};
+#[cfg(feature = "leak-scanner")]
+use crate::leak::LeakRoots;
+#[cfg(feature = "tagging")]
+use crate::tag::TagTable;
+#[cfg(feature = "dhat")]
+use crate::dhat::DhatTable;
+#[cfg(feature = "trace")]
+use crate::trace::Trace;
+use crate::{corruption::{Corruption, CorruptionKind}, decay::Decay, error::AllocError, handle::HandleTable, hardened::Hardened, heap::StatsCounters, inject::FailureInjector, pool::{POOL_SLOT_SIZE, Pool}, quarantine::Quarantine, syscalls::{Syscall, SyscallCounter}, unmapper::Unmapper};
+use crate::{config::{CommitCharge, Config, Decommit, FitPolicy, HUGE_PAGE_SIZE, HugePages}, block::{BLOCK_FOOTER_SIZE, BLOCK_HEADER_SIZE, Block}, freelist::FreeList, list::{List, Node}, memalloc::MIN_BLOCK_SIZE, region::{REGION_HEADER_SIZE, Region, RegionKind}, utils::align};
+
+/// Every mapping of [`Config::low_address`] ends below this address.
+const LOW_ADDRESS_LIMIT: u64 = 1 << 32;
+
+/// First address probed by [`PlatformMemory::request_low_memory`]. Lower addresses are
+/// usually reserved by the OS.
+#[cfg(not(any(miri, feature = "system-backend", target_os = "fuchsia", target_os = "uefi")))]
+const LOW_ADDRESS_START: usize = 1 << 24;
+
+/// Minimum distance between the addresses probed by [`PlatformMemory::request_low_memory`].
+#[cfg(not(any(miri, feature = "system-backend", target_os = "fuchsia", target_os = "uefi")))]
+const LOW_ADDRESS_STEP: usize = 1 << 24;
 
 /// Virtual memory page siz of the computer. This is usually 4096.
 /// This value should be a constant, but we can't do that since we 
@@ -16,6 +37,64 @@ pub(crate) struct Kernel {
     pub page_size: usize,
     /// Linked list of free blocks identified by [`Block::is_free`]
     pub free_list: FreeList,
+    /// Linked list of dedicated regions, each one holding a single large allocation.
+    /// See [`Kernel::allocate_large`].
+    pub large_objects: List<Region>,
+    /// Empty regions kept mapped for future reuse. See [`Config::cached_regions`].
+    pub cache: List<Region>,
+    /// Empty regions kept inaccessible. See [`Config::quarantine_regions`].
+    pub quarantine: Quarantine,
+    /// Regions waiting to be unmapped. See [`MemAlloc::start_background_unmapping`].
+    pub unmapper: Unmapper,
+    /// Bytes that entered the cache recently. See [`Config::decay`].
+    pub decay: Decay,
+    /// Dedicated regions holding executable code. See [`Kernel::allocate_executable`].
+    pub executable: List<Region>,
+    /// Reserved address space where regions are placed. See [`Config::reserve`].
+    pub pool: Option<Pool>,
+    /// Allocations made with [`MemAlloc::allocate_handle`].
+    pub handles: HandleTable,
+    /// Allocations that fail on purpose. See [`MemAlloc::inject_failures`].
+    pub failures: FailureInjector,
+    /// Number of allocations that failed because the OS refused to map more memory.
+    pub mapping_failures: usize,
+    /// Allocations of the hardened mode. See [`Config::hardened`].
+    pub hardened: Hardened,
+    /// First corruption found and not reported yet. See [`Kernel::found_corruption`].
+    pub corruption: Option<Corruption>,
+    /// Address where the next region is mapped, or 0 if there is no
+    /// [`Config::address_hint`].
+    pub next_address: usize,
+    /// Calls made to the OS. See [`MemAlloc::syscalls`].
+    pub syscalls: SyscallCounter,
+    /// User configuration of the allocator.
+    pub config: Config,
+    /// Roots of the leak scanner. See [`MemAlloc::find_leaks`].
+    #[cfg(feature = "leak-scanner")]
+    pub leak_roots: LeakRoots,
+    /// Statistics of every allocation tag. See [`MemAlloc::stats_by_tag`].
+    #[cfg(feature = "tagging")]
+    pub tags: TagTable,
+    /// Allocation profile of every call site. See [`MemAlloc::write_dhat`].
+    #[cfg(feature = "dhat")]
+    pub dhat: DhatTable,
+    /// Log of the events of the allocator, while a trace is running. See
+    /// [`MemAlloc::start_trace`].
+    #[cfg(feature = "trace")]
+    pub trace: Option<Trace>,
+}
+
+/// Access permissions of a range of pages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Protection {
+    /// Regular heap memory.
+    Writable,
+    /// Code that can be executed but not modified.
+    Executable,
+    /// Data that can't be modified.
+    ReadOnly,
+    /// Pages that fault on any access.
+    Inaccessible,
 }
 
 /// This trait provides an abstraction to handle low level memory operations
@@ -26,47 +105,316 @@ trait PlatformMemory {
     /// given location or None if the underlying syscall fails.
     unsafe fn request_memory(len: usize) -> Option<NonNull<u8>>;
 
+    /// Same as [`PlatformMemory::request_memory`], but the whole memory region is placed
+    /// below [`LOW_ADDRESS_LIMIT`]. See [`Config::low_address`].
+    unsafe fn request_low_memory(len: usize) -> Option<NonNull<u8>>;
+
+    /// Same as [`PlatformMemory::request_memory`], but the memory is not charged against
+    /// the commit limit of the OS. See [`CommitCharge::Lazy`]. By default, it is charged
+    /// anyway.
+    unsafe fn request_unreserved_memory(len: usize) -> Option<NonNull<u8>> {
+        unsafe { Self::request_memory(len) }
+    }
+
+    /// Same as [`PlatformMemory::request_memory`], but the returned address plus `offset`
+    /// is a multiple of `align`, which is bigger than the page size. Returns `None` if the
+    /// platform can't do it without wasting memory.
+    unsafe fn request_aligned_memory(_len: usize, _align: usize, _offset: usize) -> Option<NonNull<u8>> {
+        None
+    }
+
+    /// Same as [`PlatformMemory::request_memory`], but the memory is placed exactly at
+    /// `addr`. Returns `None` if any page of the range is already in use (it is never
+    /// replaced) or if the platform can't do it. See [`Config::address_hint`].
+    unsafe fn request_memory_at(_addr: usize, _len: usize) -> Option<NonNull<u8>> {
+        None
+    }
+
+    /// Same as [`PlatformMemory::request_memory`], but the memory is backed by huge pages
+    /// from the start. `len` is rounded up to the huge page size of the platform, so the
+    /// actual size is returned too. Returns `None` if the platform can't do it, platforms
+    /// with transparent huge pages use [`PlatformMemory::advise_huge_pages`] instead.
+    unsafe fn request_huge_memory(_len: usize) -> Option<(NonNull<u8>, usize)> {
+        None
+    }
+
     /// Returns the memory of size `len` starting from `addr` back to the kernel.
     unsafe fn return_memory(addr: *mut u8, len: usize);
 
+    /// Whether contiguous memory taken with separate requests can be given back with a
+    /// single [`PlatformMemory::return_memory`] call, so a region can absorb the mapping
+    /// that starts where it ends. See [`Kernel::extend_region`].
+    const JOINS_MAPPINGS: bool = false;
+
+    /// Resizes the mapping of size `old_len` starting from `addr` to `new_len` bytes
+    /// without copying its contents, moving it somewhere else if it can't grow in place.
+    /// Returns the new address, or `None` if the platform can't do it, in which case the
+    /// mapping is left untouched.
+    unsafe fn remap(_addr: *mut u8, _old_len: usize, _new_len: usize) -> Option<NonNull<u8>> {
+        None
+    }
+
+    /// Reserves `len` bytes of address space that can't be accessed until they are
+    /// committed with [`PlatformMemory::commit`]. Returns `None` if the platform doesn't
+    /// support it. The reservation is released with [`PlatformMemory::return_memory`].
+    unsafe fn reserve_memory(_len: usize) -> Option<NonNull<u8>> {
+        None
+    }
+
+    /// Makes the reserved pages of size `len` starting from `addr` readable and writable.
+    /// Returns `false` if the underlying syscall fails.
+    unsafe fn commit(_addr: *mut u8, _len: usize) -> bool {
+        false
+    }
+
+    /// Gives the physical memory of the committed pages of size `len` starting from `addr`
+    /// back and makes them inaccessible again, keeping them reserved.
+    unsafe fn uncommit(_addr: *mut u8, _len: usize) {}
+
     /// Returns the virtual memory page size of the computer in bytes.
     unsafe fn page_size() -> usize;
+
+    /// Releases the physical memory of the pages of size `len` starting from `addr`
+    /// while keeping them mapped. See [`Decommit`].
+    unsafe fn decommit(addr: *mut u8, len: usize, mode: Decommit);
+
+    /// Makes the pages of size `len` starting from `addr` decommitted with `mode` usable
+    /// again. Returns `false` if the underlying syscall fails. Platforms whose decommitted
+    /// pages are refaulted on access don't need to do anything.
+    unsafe fn recommit(_addr: *mut u8, _len: usize, _mode: Decommit) -> bool {
+        true
+    }
+
+    /// Changes the access permissions of the pages of size `len` starting from `addr`.
+    /// Returns `false` if the underlying syscall fails.
+    unsafe fn protect(addr: *mut u8, len: usize, protection: Protection) -> bool;
+
+    /// Returns the NUMA node of the CPU the current thread is running on, or `None`
+    /// if the platform doesn't support it.
+    unsafe fn current_node() -> Option<u32> {
+        None
+    }
+
+    /// Asks the kernel to place the physical pages of the memory of size `len`
+    /// starting from `addr` on the NUMA node `node`. This is just a hint, so it
+    /// doesn't report any error.
+    unsafe fn bind_to_node(_addr: *mut u8, _len: usize, _node: u32) {}
+
+    /// Populates the physical pages of the memory of size `len` starting from `addr`, so
+    /// touching them later doesn't take a page fault. By default, every page is written.
+    unsafe fn prefault(addr: *mut u8, len: usize) {
+        unsafe {
+            let page_size = page_size();
+
+            for offset in (0..len).step_by(page_size) {
+                // Fresh anonymous memory is zeroed, so writing a zero doesn't change it.
+                ptr::write_volatile(addr.add(offset), 0);
+            }
+        }
+    }
+
+    /// Tells the kernel whether the memory of size `len` starting from `addr` should be
+    /// backed by transparent huge pages. This is just a hint, so it doesn't report any error.
+    unsafe fn advise_huge_pages(_addr: *mut u8, _len: usize, _enabled: bool) {}
+
+    /// Labels the memory of size `len` starting from `addr` with `name`, so it can be
+    /// identified by debugging and profiling tools. This is just a hint, so it doesn't
+    /// report any error.
+    unsafe fn name_memory(_addr: *mut u8, _len: usize, _name: &CStr) {}
+
+    /// Returns how many bytes of the pages of size `len` starting from `addr` are
+    /// resident in physical memory, or `None` if the platform can't tell.
+    unsafe fn resident_bytes(_addr: *mut u8, _len: usize) -> Option<usize> {
+        None
+    }
 }
 
 
+/// Backend the allocator gets its memory from. `mmap` on Unix unless the `sbrk`
+/// feature is enabled, `VirtualAlloc` on Windows, VMOs on Fuchsia and boot services
+/// pages on UEFI with the `uefi` feature. The system allocator is used instead under
+/// Miri or with the `system-backend` feature.
+#[cfg(all(unix, not(target_os = "fuchsia"), not(any(miri, feature = "system-backend")), not(feature = "sbrk")))]
+type Platform = unix::Mmap;
+#[cfg(all(unix, not(target_os = "fuchsia"), not(any(miri, feature = "system-backend")), feature = "sbrk"))]
+type Platform = sbrk::Sbrk;
+#[cfg(all(windows, not(any(miri, feature = "system-backend"))))]
+type Platform = windows::VirtualMemory;
+#[cfg(all(target_os = "fuchsia", not(any(miri, feature = "system-backend"))))]
+type Platform = fuchsia::Vmo;
+#[cfg(all(target_os = "uefi", feature = "uefi", not(any(miri, feature = "system-backend"))))]
+type Platform = uefi::BootServicesMemory;
+#[cfg(any(miri, feature = "system-backend"))]
+type Platform = system::SystemMemory;
+
 /// Wrapper to calculate the computer's page size.
 #[inline]
 pub(crate) fn page_size() -> usize {
     unsafe {
         if PAGE_SIZE == 0 {
-            PAGE_SIZE = Kernel::page_size();
+            PAGE_SIZE = Platform::page_size();
         }
 
         PAGE_SIZE
     }
 }
 
-/// Wrapper to use [`Kernel::request_memory`] 
+/// Wrapper to use [`PlatformMemory::request_memory`] or [`PlatformMemory::request_low_memory`]
+#[inline]
+pub(crate) unsafe fn request_memory(len: usize, low_address: bool) -> Option<NonNull<u8>> {
+    unsafe {
+        if low_address {
+            Platform::request_low_memory(len)
+        } else {
+            Platform::request_memory(len)
+        }
+    }
+}
+
+/// Wrapper to use [`PlatformMemory::request_unreserved_memory`]
+#[inline]
+pub(crate) unsafe fn request_unreserved_memory(len: usize) -> Option<NonNull<u8>> {
+    unsafe { Platform::request_unreserved_memory(len) }
+}
+
+/// Wrapper to use [`PlatformMemory::request_memory_at`]
+#[inline]
+pub(crate) unsafe fn request_memory_at(addr: usize, len: usize) -> Option<NonNull<u8>> {
+    unsafe { Platform::request_memory_at(addr, len) }
+}
+
+/// Wrapper to use [`PlatformMemory::request_huge_memory`]
 #[inline]
-pub(crate) unsafe fn request_memory(len: usize) -> Option<NonNull<u8>> {
-    unsafe { Kernel::request_memory(len) }
-} 
+pub(crate) unsafe fn request_huge_memory(len: usize) -> Option<(NonNull<u8>, usize)> {
+    unsafe { Platform::request_huge_memory(len) }
+}
+
+/// Wrapper to use [`PlatformMemory::request_aligned_memory`]
+#[inline]
+pub(crate) unsafe fn request_aligned_memory(len: usize, align: usize, offset: usize) -> Option<NonNull<u8>> {
+    unsafe { Platform::request_aligned_memory(len, align, offset) }
+}
 
-/// Wrapper to use [`Kernel::return_memory`]
+/// Wrapper to use [`PlatformMemory::return_memory`]
 #[inline]
 pub(crate) unsafe fn return_memory(addr: *mut u8, len: usize) {
-    unsafe { Kernel::return_memory(addr, len); }
+    unsafe { Platform::return_memory(addr, len); }
+}
+
+/// Wrapper to use [`PlatformMemory::remap`]
+#[inline]
+pub(crate) unsafe fn remap(addr: *mut u8, old_len: usize, new_len: usize) -> Option<NonNull<u8>> {
+    unsafe { Platform::remap(addr, old_len, new_len) }
+}
+
+/// Wrapper to use [`PlatformMemory::reserve_memory`]
+#[inline]
+pub(crate) unsafe fn reserve_memory(len: usize) -> Option<NonNull<u8>> {
+    unsafe { Platform::reserve_memory(len) }
+}
+
+/// Wrapper to use [`PlatformMemory::commit`]
+#[inline]
+pub(crate) unsafe fn commit(addr: *mut u8, len: usize) -> bool {
+    unsafe { Platform::commit(addr, len) }
+}
+
+/// Wrapper to use [`PlatformMemory::uncommit`]
+#[inline]
+pub(crate) unsafe fn uncommit(addr: *mut u8, len: usize) {
+    unsafe { Platform::uncommit(addr, len); }
+}
+
+/// Wrapper to use [`PlatformMemory::decommit`]
+#[inline]
+pub(crate) unsafe fn decommit(addr: *mut u8, len: usize, mode: Decommit) {
+    unsafe { Platform::decommit(addr, len, mode); }
+}
+
+/// Wrapper to use [`PlatformMemory::recommit`]
+#[inline]
+pub(crate) unsafe fn recommit(addr: *mut u8, len: usize, mode: Decommit) -> bool {
+    unsafe { Platform::recommit(addr, len, mode) }
+}
+
+/// Wrapper to use [`PlatformMemory::protect`]
+#[inline]
+pub(crate) unsafe fn protect(addr: *mut u8, len: usize, protection: Protection) -> bool {
+    unsafe { Platform::protect(addr, len, protection) }
+}
+
+/// Wrapper to use [`PlatformMemory::current_node`]
+#[inline]
+pub(crate) fn current_node() -> Option<u32> {
+    unsafe { Platform::current_node() }
 }
 
-#[cfg(unix)]
+/// Wrapper to use [`PlatformMemory::bind_to_node`]
+#[inline]
+pub(crate) unsafe fn bind_to_node(addr: *mut u8, len: usize, node: u32) {
+    unsafe { Platform::bind_to_node(addr, len, node); }
+}
+
+/// Wrapper to use [`PlatformMemory::prefault`]
+#[inline]
+pub(crate) unsafe fn prefault(addr: *mut u8, len: usize) {
+    unsafe { Platform::prefault(addr, len); }
+}
+
+/// Wrapper to use [`PlatformMemory::advise_huge_pages`]
+#[inline]
+pub(crate) unsafe fn advise_huge_pages(addr: *mut u8, len: usize, enabled: bool) {
+    unsafe { Platform::advise_huge_pages(addr, len, enabled); }
+}
+
+/// Wrapper to use [`PlatformMemory::name_memory`]
+#[inline]
+pub(crate) unsafe fn name_memory(addr: *mut u8, len: usize, name: &CStr) {
+    unsafe { Platform::name_memory(addr, len, name); }
+}
+
+/// Wrapper to use [`PlatformMemory::resident_bytes`]
+#[inline]
+pub(crate) unsafe fn resident_bytes(addr: *mut u8, len: usize) -> Option<usize> {
+    unsafe { Platform::resident_bytes(addr, len) }
+}
+
+#[cfg(all(unix, not(target_os = "fuchsia"), not(any(miri, feature = "system-backend"))))]
 mod unix {
-    use super::{PlatformMemory, Kernel};
+    use super::{PlatformMemory, Protection, LOW_ADDRESS_LIMIT, LOW_ADDRESS_START, LOW_ADDRESS_STEP};
+    use crate::config::Decommit;
+    #[cfg(target_os = "freebsd")]
+    use crate::config::HUGE_PAGE_SIZE;
 
     use libc::{mmap, munmap, off_t, size_t};
 
     use std::{os::raw::{c_void, c_int}, ptr::{NonNull}};
 
-    impl PlatformMemory for Kernel {
+    #[cfg(target_os = "linux")]
+    use std::ffi::CStr;
+
+    /// Memory mapped with `mmap`.
+    pub(crate) struct Mmap;
+
+    impl Mmap {
+        /// Same as [`Mmap::request_memory`], with the `mmap` flags in `flags` too.
+        #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+        unsafe fn request_memory_with_flags(len: usize, flags: c_int) -> Option<NonNull<u8>> {
+            const PROT: c_int = libc::PROT_READ | libc::PROT_WRITE;
+
+            unsafe {
+                match mmap(std::ptr::null_mut(), len, PROT, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | flags, -1, 0) {
+                    libc::MAP_FAILED => None,
+                    addr => NonNull::new(addr.cast()),
+                }
+            }
+        }
+    }
+
+    impl PlatformMemory for Mmap {
+        /// `munmap` takes any range of pages, whatever mappings they belong to.
+        const JOINS_MAPPINGS: bool = true;
+
         /// Request a raw chunk of memory from the operating system using `mmap`.
         /// 
         /// This function requests a new memory mapping that is:
@@ -100,6 +448,126 @@ mod unix {
             }
         }
 
+        /// Same as [`Mmap::request_memory`], with `MAP_NORESERVE`.
+        #[cfg(target_os = "linux")]
+        unsafe fn request_unreserved_memory(len: usize) -> Option<NonNull<u8>> {
+            unsafe { Self::request_memory_with_flags(len, libc::MAP_NORESERVE) }
+        }
+
+        /// Requests memory below [`LOW_ADDRESS_LIMIT`] using `mmap`.
+        ///
+        /// On x86_64 Linux we first try `MAP_32BIT`. Otherwise, we give `mmap` increasing
+        /// address hints until it places the mapping low enough. The hint is ignored when
+        /// the address is already in use, so the mapping lands wherever the kernel wants
+        /// and we have to unmap it and try the next one.
+        unsafe fn request_low_memory(len: usize) -> Option<NonNull<u8>> {
+            const PROT: c_int = libc::PROT_READ | libc::PROT_WRITE;
+            const FLAGS: c_int = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+
+            let fits = |addr: *mut c_void| addr != libc::MAP_FAILED && (addr.addr() + len) as u64 <= LOW_ADDRESS_LIMIT;
+
+            unsafe {
+                #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+                {
+                    let addr = mmap(std::ptr::null_mut(), len, PROT, FLAGS | libc::MAP_32BIT, -1, 0);
+
+                    if fits(addr) {
+                        return NonNull::new(addr.cast());
+                    } else if addr != libc::MAP_FAILED {
+                        munmap(addr, len);
+                    }
+                }
+
+                let step = std::cmp::max(len, LOW_ADDRESS_STEP);
+                let mut hint = LOW_ADDRESS_START;
+
+                while (hint + len) as u64 <= LOW_ADDRESS_LIMIT {
+                    let addr = mmap(std::ptr::without_provenance_mut(hint), len, PROT, FLAGS, -1, 0);
+
+                    if addr == libc::MAP_FAILED {
+                        return None;
+                    }
+
+                    if fits(addr) {
+                        return NonNull::new(addr.cast());
+                    }
+
+                    munmap(addr, len);
+                    hint += step;
+                }
+
+                None
+            }
+        }
+
+        /// Over-reserves `align` extra bytes using `mmap` and unmaps the misaligned head
+        /// and tail of the mapping.
+        ///
+        /// ```text
+        /// +------------+-----------------------------------+------------+
+        /// |    Head    |             Returned              |    Tail    |
+        /// +------------+-----------------------------------+------------+
+        /// ^            ^        ^
+        /// |            |        |
+        /// mmap result  |        Multiple of `align`
+        ///              |
+        ///              +-- Multiple of `align` minus `offset`
+        /// ```
+        ///
+        /// On FreeBSD, memory whose start is aligned is mapped with `MAP_ALIGNED` instead,
+        /// which lets the kernel pick an aligned address without mapping anything extra.
+        unsafe fn request_aligned_memory(len: usize, align: usize, offset: usize) -> Option<NonNull<u8>> {
+            #[cfg(target_os = "freebsd")]
+            if offset == 0
+                && let Some(addr) = unsafe { Self::request_memory_with_flags(len, libc::MAP_ALIGNED(align.trailing_zeros() as c_int)) }
+            {
+                return Some(addr);
+            }
+
+            let total = len + align;
+
+            unsafe {
+                let raw = Self::request_memory(total)?.as_ptr();
+                let start = raw.map_addr(|addr| crate::utils::align(addr + offset, align) - offset);
+
+                let head = start.addr() - raw.addr();
+                let tail = total - head - len;
+
+                if head > 0 {
+                    munmap(raw.cast(), head);
+                }
+
+                if tail > 0 {
+                    munmap(start.add(len).cast(), tail);
+                }
+
+                NonNull::new(start)
+            }
+        }
+
+        /// Maps the memory with `MAP_FIXED_NOREPLACE` on Linux, which fails instead of
+        /// replacing existing mappings. Elsewhere (and on kernels older than 4.17, which
+        /// ignore the flag) the address is only a hint, so we have to check where the
+        /// mapping landed and unmap it if it is not `addr`.
+        unsafe fn request_memory_at(addr: usize, len: usize) -> Option<NonNull<u8>> {
+            const PROT: c_int = libc::PROT_READ | libc::PROT_WRITE;
+            #[cfg(target_os = "linux")]
+            const FLAGS: c_int = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE;
+            #[cfg(not(target_os = "linux"))]
+            const FLAGS: c_int = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+
+            unsafe {
+                match mmap(std::ptr::without_provenance_mut(addr), len, PROT, FLAGS, -1, 0) {
+                    libc::MAP_FAILED => None,
+                    mapped if mapped.addr() == addr => NonNull::new(mapped.cast()),
+                    mapped => {
+                        munmap(mapped, len);
+                        None
+                    }
+                }
+            }
+        }
+
         /// Releases a previously allocated memory segment back to the operating system.
         /// 
         /// This function wraps the `munmap` system call.
@@ -114,164 +582,1975 @@ mod unix {
             unsafe { munmap(addr as *mut c_void, len as size_t); }
         }
 
+        /// Maps the memory with `MAP_ALIGNED_SUPER`, so it starts at a superpage boundary and
+        /// the kernel can promote every fully populated superpage of it. `len` is rounded up
+        /// to a multiple of [`HUGE_PAGE_SIZE`], so the mapping ends at a boundary too.
+        #[cfg(target_os = "freebsd")]
+        unsafe fn request_huge_memory(len: usize) -> Option<(NonNull<u8>, usize)> {
+            let len = crate::utils::align(len, HUGE_PAGE_SIZE);
+
+            unsafe { Some((Self::request_memory_with_flags(len, libc::MAP_ALIGNED_SUPER)?, len)) }
+        }
+
+        /// Resizes the mapping using `mremap` with `MREMAP_MAYMOVE`, so the kernel moves
+        /// the page tables instead of copying the pages.
+        #[cfg(target_os = "linux")]
+        unsafe fn remap(addr: *mut u8, old_len: usize, new_len: usize) -> Option<NonNull<u8>> {
+            unsafe {
+                match libc::mremap(addr as *mut c_void, old_len, new_len, libc::MREMAP_MAYMOVE) {
+                    libc::MAP_FAILED => None,
+                    addr => NonNull::new(addr.cast()),
+                }
+            }
+        }
+
         /// Returns the system's virtual memory page size in bytes.
         unsafe fn page_size() -> usize {
             unsafe { libc::sysconf(libc::_SC_PAGE_SIZE) as usize }
         }
-    }
-}
 
-#[cfg(windows)]
-mod windows {
-    use std::{mem::MaybeUninit, ptr::NonNull, os::raw::c_void};
+        /// Maps the range with `PROT_NONE` and `MAP_NORESERVE`, so it doesn't count
+        /// towards the commit limit of the system.
+        unsafe fn reserve_memory(len: usize) -> Option<NonNull<u8>> {
+            const FLAGS: c_int = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE;
 
-    use crate::kernel::{Kernel, PlatformMemory};
+            unsafe {
+                match mmap(std::ptr::null_mut(), len, libc::PROT_NONE, FLAGS, -1, 0) {
+                    libc::MAP_FAILED => None,
+                    addr => NonNull::new(addr.cast()),
+                }
+            }
+        }
 
-    use windows::Win32::System::{Memory, SystemInformation};
+        /// Commits the pages using `mprotect`. The physical memory is only used once the
+        /// pages are touched.
+        unsafe fn commit(addr: *mut u8, len: usize) -> bool {
+            unsafe { Self::protect(addr, len, Protection::Writable) }
+        }
 
-    impl PlatformMemory for Kernel {
-        /// Requests memory from the Windows Operating System.
-        /// 
-        /// This implementation uses `VirtualAlloc` to reserve and commit memory
-        /// in a single step.
-        /// 
-        /// # Arguments
-        /// 
-        /// - `len` - The number of bytes to allocate.
-        unsafe fn request_memory(len: usize) -> Option<std::ptr::NonNull<u8>> {
-            // Read-Write only.
-            let protection = Memory::PAGE_READWRITE;
-            
-            // Reserve address space and commit physical storage immediately.
-            let flags = Memory::MEM_RESERVE | Memory::MEM_COMMIT;
+        /// Maps fresh `PROT_NONE` pages over the range, which drops the old ones in a
+        /// single syscall.
+        unsafe fn uncommit(addr: *mut u8, len: usize) {
+            const FLAGS: c_int = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | libc::MAP_FIXED;
+
+            unsafe { mmap(addr as *mut c_void, len, libc::PROT_NONE, FLAGS, -1, 0); }
+        }
 
+        /// Releases the given pages using `madvise`. `MADV_FREE` isn't available on
+        /// every platform nor on Linux kernels older than 4.5, so `MADV_DONTNEED` is
+        /// used when it fails.
+        unsafe fn decommit(addr: *mut u8, len: usize, mode: Decommit) {
             unsafe {
-                let addr = Memory::VirtualAlloc(None, len, flags, protection);
-                
-                NonNull::new(addr.cast())
+                #[cfg(any(target_os = "linux", target_os = "android", target_vendor = "apple", target_os = "freebsd"))]
+                if mode == Decommit::Lazy && libc::madvise(addr as *mut c_void, len, libc::MADV_FREE) == 0 {
+                    return;
+                }
+
+                if mode != Decommit::Never {
+                    libc::madvise(addr as *mut c_void, len, libc::MADV_DONTNEED);
+                }
             }
         }
 
-        /// Release a memory region previously allocated by `VirtualAlloc`.
-        /// 
-        /// This function wraps `Virtuall`.
-        /// 
-        /// # Windows Specific Behavior
-        ///
-        /// According to the Microsoft documentation for `VirtualFree` with `MEM_RELEASE`:
-        /// 
-        /// - "If the dwFreeType parameter is MEM_RELEASE, this parameter [dwSize] 
-        /// - must be 0 (zero). The function frees the entire region that is reserved 
-        /// - in the initial allocation call to VirtualAlloc."
+        /// Changes the protection of the given pages using `mprotect`.
+        unsafe fn protect(addr: *mut u8, len: usize, protection: Protection) -> bool {
+            let prot = match protection {
+                Protection::Writable => libc::PROT_READ | libc::PROT_WRITE,
+                Protection::Executable => libc::PROT_READ | libc::PROT_EXEC,
+                Protection::ReadOnly => libc::PROT_READ,
+                Protection::Inaccessible => libc::PROT_NONE,
+            };
+
+            unsafe { libc::mprotect(addr as *mut c_void, len as size_t, prot) == 0 }
+        }
+
+        /// Returns the NUMA node of the current CPU using the `getcpu` syscall.
+        #[cfg(target_os = "linux")]
+        unsafe fn current_node() -> Option<u32> {
+            let mut cpu: u32 = 0;
+            let mut node: u32 = 0;
+
+            unsafe {
+                let result = libc::syscall(
+                    libc::SYS_getcpu,
+                    &mut cpu as *mut u32,
+                    &mut node as *mut u32,
+                    std::ptr::null_mut::<c_void>(),
+                );
+
+                (result == 0).then_some(node)
+            }
+        }
+
+        /// Sets a preferred NUMA policy for the given range using `mbind`.
         /// 
-        /// Therefore, `_len` is ignored to prevent `VirtualFree` from failing.
-        ///
-        /// # Safety
+        /// We use `MPOL_PREFERRED` rather than `MPOL_BIND` so that the kernel can still
+        /// take pages from other nodes when the preferred one is out of memory.
+        #[cfg(target_os = "linux")]
+        unsafe fn bind_to_node(addr: *mut u8, len: usize, node: u32) {
+            // Not exported by `libc`, see `<linux/mempolicy.h>`
+            const MPOL_PREFERRED: c_int = 1;
+
+            // Our node mask is a single word.
+            if node >= u64::BITS {
+                return;
+            }
+
+            let nodemask: u64 = 1 << node;
+
+            unsafe {
+                // The kernel reads `maxnode - 1` bits from the mask.
+                libc::syscall(
+                    libc::SYS_mbind,
+                    addr as *mut c_void,
+                    len,
+                    MPOL_PREFERRED,
+                    &nodemask as *const u64,
+                    u64::BITS as usize + 1,
+                    0u32,
+                );
+            }
+        }
+
+        /// Populates the mapping using `MADV_POPULATE_WRITE` (Linux 5.14), falling back to
+        /// writing every page on older kernels.
         ///
-        /// Caller must ensure `addr` is a valid pointer returned by `request_memory`
-        /// and has not been freed yet.
-        unsafe fn return_memory(addr: *mut u8, _len: usize) {
-            unsafe { let _ = Memory::VirtualFree(addr as *mut c_void, 0, Memory::MEM_RELEASE); }
+        /// We don't use `MAP_POPULATE` because the pages would be placed before we have
+        /// the chance to bind the mapping to a NUMA node.
+        #[cfg(target_os = "linux")]
+        unsafe fn prefault(addr: *mut u8, len: usize) {
+            unsafe {
+                if libc::madvise(addr as *mut c_void, len, libc::MADV_POPULATE_WRITE) != 0 {
+                    for offset in (0..len).step_by(super::page_size()) {
+                        std::ptr::write_volatile(addr.add(offset), 0);
+                    }
+                }
+            }
+        }
+
+        /// Gives `MADV_HUGEPAGE` or `MADV_NOHUGEPAGE` advice for the mapping.
+        #[cfg(target_os = "linux")]
+        unsafe fn advise_huge_pages(addr: *mut u8, len: usize, enabled: bool) {
+            let advice = if enabled { libc::MADV_HUGEPAGE } else { libc::MADV_NOHUGEPAGE };
+
+            unsafe { libc::madvise(addr as *mut c_void, len, advice); }
+        }
+
+        /// Labels the mapping using `prctl(PR_SET_VMA_ANON_NAME)`, so it shows up as
+        /// `[anon:<name>]` in `/proc/self/maps`. Kernels older than 5.17 or built without
+        /// `CONFIG_ANON_VMA_NAME` reject it, in which case the mapping stays unnamed.
+        #[cfg(target_os = "linux")]
+        unsafe fn name_memory(addr: *mut u8, len: usize, name: &CStr) {
+            unsafe {
+                libc::prctl(
+                    libc::PR_SET_VMA,
+                    libc::PR_SET_VMA_ANON_NAME as libc::c_ulong,
+                    addr.addr() as libc::c_ulong,
+                    len as libc::c_ulong,
+                    name.as_ptr().addr() as libc::c_ulong,
+                );
+            }
+        }
+
+        /// Asks `mincore` which pages are resident, a chunk of pages at a time.
+        unsafe fn resident_bytes(addr: *mut u8, len: usize) -> Option<usize> {
+            let page_size = super::page_size();
+            let mut pages = [0u8; 256];
+            let mut resident = 0;
+
+            for offset in (0..len).step_by(pages.len() * page_size) {
+                let chunk = (len - offset).min(pages.len() * page_size);
+
+                unsafe {
+                    if libc::mincore(addr.add(offset) as *mut c_void, chunk, pages.as_mut_ptr().cast()) != 0 {
+                        return None;
+                    }
+                }
+
+                resident += pages[..chunk.div_ceil(page_size)].iter().filter(|&&page| page & 1 != 0).count() * page_size;
+            }
+
+            Some(resident)
+        }
+    }
+}
+
+#[cfg(all(unix, not(target_os = "fuchsia"), not(any(miri, feature = "system-backend")), feature = "sbrk"))]
+mod sbrk {
+    use super::{unix::Mmap, PlatformMemory, Protection, LOW_ADDRESS_LIMIT};
+    use crate::{config::Decommit, sync::SpinRawMutex, utils::align};
+
+    use lock_api::Mutex;
+
+    use std::{ffi::CStr, ptr::NonNull};
+
+    /// Maximum number of returned ranges [`Sbrk`] keeps track of.
+    const MAX_HOLES: usize = 64;
+
+    /// Memory taken from the program break using `sbrk`.
+    ///
+    /// The heap grows contiguously, but it can only shrink from the top: a range that is
+    /// returned while there is memory in use above it becomes a hole. Holes are decommitted,
+    /// reused by later requests and given back to the OS once everything above them is
+    /// returned too.
+    ///
+    /// ```text
+    ///                                               Program break
+    ///                                                     |
+    ///                                                     v
+    /// +----------+----------+----------+----------+----------+
+    /// |  Region  |   Hole   |  Region  |   Hole   |   Hole   |  ---> sbrk(-2 * len)
+    /// +----------+----------+----------+----------+----------+
+    /// ```
+    ///
+    /// The program break is shared with anything else in the process calling `sbrk`, such
+    /// as the main arena of glibc's `malloc`. If the break isn't where we left it, returned
+    /// memory stays as a hole until it can be reused.
+    pub(crate) struct Sbrk;
+
+    /// State of the part of the program break owned by [`Sbrk`].
+    struct Heap {
+        /// End of the last memory we took from the program break.
+        top: usize,
+        /// Returned ranges below `top`, sorted by address and never adjacent.
+        holes: [(usize, usize); MAX_HOLES],
+        /// Number of used entries of `holes`.
+        len: usize,
+    }
+
+    /// Every allocator instance shares the same program break.
+    static HEAP: Mutex<SpinRawMutex, Heap> = Mutex::new(Heap::new());
+
+    impl Heap {
+        const fn new() -> Self {
+            Self { top: 0, holes: [(0, 0); MAX_HOLES], len: 0 }
+        }
+
+        /// Takes `len` bytes from the first hole big enough.
+        fn take_hole(&mut self, len: usize) -> Option<usize> {
+            let index = self.holes[..self.len].iter().position(|&(_, size)| size >= len)?;
+            let (start, size) = self.holes[index];
+
+            if size == len {
+                self.holes.copy_within(index + 1..self.len, index);
+                self.len -= 1;
+            } else {
+                self.holes[index] = (start + len, size - len);
+            }
+
+            Some(start)
+        }
+
+        /// Records a returned range, merging it with its neighbours. Returns `false` if
+        /// there is no room for it, in which case its address space is lost.
+        fn insert_hole(&mut self, start: usize, len: usize) -> bool {
+            let index = self.holes[..self.len].partition_point(|&(hole, _)| hole < start);
+
+            let merges_prev = index > 0 && {
+                let (prev, size) = self.holes[index - 1];
+                prev + size == start
+            };
+            let merges_next = index < self.len && start + len == self.holes[index].0;
+
+            match (merges_prev, merges_next) {
+                (true, true) => {
+                    self.holes[index - 1].1 += len + self.holes[index].1;
+                    self.holes.copy_within(index + 1..self.len, index);
+                    self.len -= 1;
+                }
+                (true, false) => self.holes[index - 1].1 += len,
+                (false, true) => self.holes[index] = (start, len + self.holes[index].1),
+                (false, false) => {
+                    if self.len == MAX_HOLES {
+                        return false;
+                    }
+
+                    self.holes.copy_within(index..self.len, index + 1);
+                    self.holes[index] = (start, len);
+                    self.len += 1;
+                }
+            }
+
+            true
+        }
+
+        /// Removes the hole ending at `top`, if any. Returns the new top.
+        fn pop_top_hole(&mut self, top: usize) -> usize {
+            match self.len.checked_sub(1).map(|last| self.holes[last]) {
+                Some((start, size)) if start + size == top => {
+                    self.len -= 1;
+                    start
+                }
+                _ => top,
+            }
+        }
+    }
+
+    /// Returns the current program break.
+    unsafe fn current_break() -> usize {
+        unsafe { libc::sbrk(0).addr() }
+    }
+
+    /// Grows the program break to get `len` bytes whose address plus `offset` is a
+    /// multiple of `align`. The padding needed to align the memory is kept as a hole.
+    unsafe fn grow(heap: &mut Heap, len: usize, align: usize, offset: usize) -> Option<NonNull<u8>> {
+        unsafe {
+            let brk = current_break();
+            let base = crate::utils::align(brk, super::page_size());
+            let start = crate::utils::align(base + offset, align) - offset;
+
+            if libc::sbrk((start + len - brk) as libc::intptr_t).addr() as isize == -1 {
+                return None;
+            }
+
+            if start > base {
+                heap.insert_hole(base, start - base);
+            }
+
+            heap.top = start + len;
+
+            // The memory comes from the OS, so no pointer of ours has its provenance.
+            NonNull::new(std::ptr::with_exposed_provenance_mut(start))
+        }
+    }
+
+    impl PlatformMemory for Sbrk {
+        /// The program break grows contiguously, and any range of it can be returned.
+        const JOINS_MAPPINGS: bool = true;
+
+        /// Reuses a hole if there is one big enough, otherwise grows the program break.
+        unsafe fn request_memory(len: usize) -> Option<NonNull<u8>> {
+            let len = align(len, super::page_size());
+            let mut heap = HEAP.lock();
+
+            if let Some(start) = heap.take_hole(len) {
+                return NonNull::new(std::ptr::with_exposed_provenance_mut(start));
+            }
+
+            unsafe { grow(&mut heap, len, super::page_size(), 0) }
+        }
+
+        /// Grows the program break past the next aligned address. Holes are not reused.
+        unsafe fn request_aligned_memory(len: usize, align: usize, offset: usize) -> Option<NonNull<u8>> {
+            let len = crate::utils::align(len, super::page_size());
+
+            unsafe { grow(&mut HEAP.lock(), len, align, offset) }
+        }
+
+        /// The program break can't be moved anywhere else, so this only works if the heap
+        /// is already low enough, which is usually the case for non-PIE binaries.
+        unsafe fn request_low_memory(len: usize) -> Option<NonNull<u8>> {
+            unsafe {
+                let addr = Self::request_memory(len)?;
+
+                if (addr.as_ptr().addr() + len) as u64 <= LOW_ADDRESS_LIMIT {
+                    return Some(addr);
+                }
+
+                Self::return_memory(addr.as_ptr(), len);
+
+                None
+            }
+        }
+
+        /// Shrinks the program break if the memory is at the top of the heap, together with
+        /// any hole right below it. Otherwise, the memory is decommitted and kept as a hole.
+        unsafe fn return_memory(addr: *mut u8, len: usize) {
+            let len = align(len, super::page_size());
+            let start = addr.addr();
+            let mut heap = HEAP.lock();
+
+            unsafe {
+                if start + len == heap.top && current_break() == heap.top {
+                    let mut top = start;
+
+                    loop {
+                        let below = heap.pop_top_hole(top);
+
+                        if below == top {
+                            break;
+                        }
+
+                        top = below;
+                    }
+
+                    libc::sbrk(-((heap.top - top) as libc::intptr_t));
+                    heap.top = top;
+
+                    return;
+                }
+
+                // The memory may have been sealed or made executable, but holes are
+                // handed out again as regular memory.
+                Mmap::protect(addr, len, Protection::Writable);
+                Mmap::decommit(addr, len, Decommit::Eager);
+                heap.insert_hole(start, len);
+            }
+        }
+
+        unsafe fn page_size() -> usize {
+            unsafe { Mmap::page_size() }
+        }
+
+        unsafe fn decommit(addr: *mut u8, len: usize, mode: Decommit) {
+            unsafe { Mmap::decommit(addr, len, mode) }
+        }
+
+        unsafe fn protect(addr: *mut u8, len: usize, protection: Protection) -> bool {
+            unsafe { Mmap::protect(addr, len, protection) }
+        }
+
+        unsafe fn current_node() -> Option<u32> {
+            unsafe { Mmap::current_node() }
+        }
+
+        unsafe fn bind_to_node(addr: *mut u8, len: usize, node: u32) {
+            unsafe { Mmap::bind_to_node(addr, len, node) }
+        }
+
+        unsafe fn prefault(addr: *mut u8, len: usize) {
+            unsafe { Mmap::prefault(addr, len) }
+        }
+
+        unsafe fn advise_huge_pages(addr: *mut u8, len: usize, enabled: bool) {
+            unsafe { Mmap::advise_huge_pages(addr, len, enabled) }
+        }
+
+        unsafe fn name_memory(addr: *mut u8, len: usize, name: &CStr) {
+            unsafe { Mmap::name_memory(addr, len, name) }
+        }
+
+        unsafe fn resident_bytes(addr: *mut u8, len: usize) -> Option<usize> {
+            unsafe { Mmap::resident_bytes(addr, len) }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn holes_are_merged_and_reused() {
+            let mut heap = Heap::new();
+
+            assert!(heap.insert_hole(0x1000, 0x1000));
+            assert!(heap.insert_hole(0x5000, 0x1000));
+            assert!(heap.insert_hole(0x3000, 0x1000));
+            assert_eq!(heap.holes[..heap.len], [(0x1000, 0x1000), (0x3000, 0x1000), (0x5000, 0x1000)]);
+
+            // Fills the gap between two holes.
+            assert!(heap.insert_hole(0x4000, 0x1000));
+            assert_eq!(heap.holes[..heap.len], [(0x1000, 0x1000), (0x3000, 0x3000)]);
+
+            assert_eq!(heap.take_hole(0x2000), Some(0x3000));
+            assert_eq!(heap.take_hole(0x2000), None);
+            assert_eq!(heap.holes[..heap.len], [(0x1000, 0x1000), (0x5000, 0x1000)]);
+
+            assert_eq!(heap.pop_top_hole(0x7000), 0x7000);
+            assert_eq!(heap.pop_top_hole(0x6000), 0x5000);
+            assert_eq!(heap.pop_top_hole(0x5000), 0x5000);
+            assert_eq!(heap.len, 1);
+        }
+
+        #[test]
+        fn memory_comes_from_the_program_break() {
+            let page_size = super::super::page_size();
+
+            unsafe {
+                let a = Sbrk::request_memory(page_size).unwrap().as_ptr();
+                let b = Sbrk::request_memory(2 * page_size).unwrap().as_ptr();
+
+                for addr in [a, b] {
+                    assert_eq!(addr.addr() % page_size, 0);
+                    assert!(addr.addr() < current_break());
+                    addr.write_bytes(1, page_size);
+                }
+
+                Sbrk::return_memory(a, page_size);
+                Sbrk::return_memory(b, 2 * page_size);
+            }
+        }
+    }
+}
+
+#[cfg(all(target_os = "fuchsia", not(any(miri, feature = "system-backend"))))]
+mod fuchsia {
+    use super::{PlatformMemory, Protection};
+    use crate::config::Decommit;
+
+    use std::ptr::{self, NonNull};
+
+    type Handle = u32;
+    type Status = i32;
+
+    const ZX_OK: Status = 0;
+    const ZX_VM_PERM_READ: u32 = 1 << 0;
+    const ZX_VM_PERM_WRITE: u32 = 1 << 1;
+    const ZX_VM_ALIGN_BASE: u32 = 24;
+    const ZX_VMAR_OP_DECOMMIT: u32 = 2;
+    const ZX_PROP_NAME: u32 = 3;
+    /// Largest alignment `zx_vmar_map` can be asked for, `ZX_VM_ALIGN_4GB`.
+    const MAX_ALIGN_SHIFT: u32 = 32;
+
+    #[link(name = "zircon")]
+    unsafe extern "C" {
+        fn zx_vmar_root_self() -> Handle;
+        fn zx_system_get_page_size() -> u32;
+        fn zx_vmo_create(size: u64, options: u32, out: *mut Handle) -> Status;
+        fn zx_vmar_map(
+            vmar: Handle,
+            options: u32,
+            vmar_offset: usize,
+            vmo: Handle,
+            vmo_offset: u64,
+            len: usize,
+            mapped_addr: *mut usize,
+        ) -> Status;
+        fn zx_vmar_unmap(vmar: Handle, addr: usize, len: usize) -> Status;
+        fn zx_vmar_protect(vmar: Handle, options: u32, addr: usize, len: usize) -> Status;
+        fn zx_vmar_op_range(vmar: Handle, op: u32, addr: usize, len: usize, buffer: *mut u8, buffer_size: usize) -> Status;
+        fn zx_object_set_property(handle: Handle, property: u32, value: *const u8, size: usize) -> Status;
+        fn zx_handle_close(handle: Handle) -> Status;
+    }
+
+    /// Memory of virtual memory objects mapped into the root VMAR of the process.
+    ///
+    /// Every request creates a VMO of its size and maps the whole of it. The handle of
+    /// the VMO is closed right away, since the mapping keeps the VMO alive, so pages are
+    /// unmapped, protected and decommitted through the root VMAR instead, which works on
+    /// any part of a mapping.
+    pub(crate) struct Vmo;
+
+    impl Vmo {
+        /// Creates a VMO of `len` bytes and maps it with the extra `options` of
+        /// `zx_vmar_map`, which can ask for an aligned address.
+        unsafe fn map(len: usize, options: u32) -> Option<NonNull<u8>> {
+            let mut vmo = 0;
+            let mut addr = 0;
+
+            unsafe {
+                if zx_vmo_create(len as u64, 0, &mut vmo) != ZX_OK {
+                    return None;
+                }
+
+                // Shows up in the memory tools of Fuchsia, like `mem`.
+                let name = c"memalloc";
+                zx_object_set_property(vmo, ZX_PROP_NAME, name.as_ptr().cast(), name.count_bytes());
+
+                let options = ZX_VM_PERM_READ | ZX_VM_PERM_WRITE | options;
+                let status = zx_vmar_map(zx_vmar_root_self(), options, 0, vmo, 0, len, &mut addr);
+                zx_handle_close(vmo);
+
+                if status != ZX_OK {
+                    return None;
+                }
+
+                // The memory comes from the kernel, so no pointer of ours has its provenance.
+                NonNull::new(ptr::with_exposed_provenance_mut(addr))
+            }
+        }
+    }
+
+    impl PlatformMemory for Vmo {
+        /// `zx_vmar_unmap` takes any range of the root VMAR, like `munmap`.
+        const JOINS_MAPPINGS: bool = true;
+
+        unsafe fn request_memory(len: usize) -> Option<NonNull<u8>> {
+            unsafe { Self::map(len, 0) }
+        }
+
+        /// Zircon doesn't take address hints outside of `ZX_VM_SPECIFIC` mappings, whose
+        /// offsets are relative to the randomized base of the root VMAR, so this is not
+        /// supported.
+        unsafe fn request_low_memory(_len: usize) -> Option<NonNull<u8>> {
+            None
+        }
+
+        /// Maps the memory with `ZX_VM_ALIGN_*`, which makes the kernel pick an aligned
+        /// address. Only the start of the memory can be aligned this way, and only up to
+        /// 4 GiB.
+        unsafe fn request_aligned_memory(len: usize, align: usize, offset: usize) -> Option<NonNull<u8>> {
+            let shift = align.trailing_zeros();
+
+            if offset != 0 || !align.is_power_of_two() || shift > MAX_ALIGN_SHIFT {
+                return None;
+            }
+
+            unsafe { Self::map(len, shift << ZX_VM_ALIGN_BASE) }
+        }
+
+        unsafe fn return_memory(addr: *mut u8, len: usize) {
+            unsafe { zx_vmar_unmap(zx_vmar_root_self(), addr.addr(), len); }
+        }
+
+        unsafe fn page_size() -> usize {
+            unsafe { zx_system_get_page_size() as usize }
+        }
+
+        /// Decommits the pages with `ZX_VMAR_OP_DECOMMIT`, which frees them right away
+        /// in both modes. They are committed again, zeroed, on the next access.
+        unsafe fn decommit(addr: *mut u8, len: usize, _mode: Decommit) {
+            unsafe {
+                zx_vmar_op_range(zx_vmar_root_self(), ZX_VMAR_OP_DECOMMIT, addr.addr(), len, ptr::null_mut(), 0);
+            }
+        }
+
+        /// Changes the protection with `zx_vmar_protect`. VMOs need a resource only
+        /// privileged processes hold to be made executable, so that is not supported.
+        unsafe fn protect(addr: *mut u8, len: usize, protection: Protection) -> bool {
+            let options = match protection {
+                Protection::Writable => ZX_VM_PERM_READ | ZX_VM_PERM_WRITE,
+                Protection::ReadOnly => ZX_VM_PERM_READ,
+                Protection::Inaccessible => 0,
+                Protection::Executable => return false,
+            };
+
+            unsafe { zx_vmar_protect(zx_vmar_root_self(), options, addr.addr(), len) == ZX_OK }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn aligned_vmos_are_decommitted_to_zeroes() {
+            unsafe {
+                let page_size = Vmo::page_size();
+                let align = 64 * page_size;
+                let addr = Vmo::request_aligned_memory(4 * page_size, align, 0).unwrap().as_ptr();
+
+                assert_eq!(addr.addr() % align, 0);
+                addr.write_bytes(0xAB, 4 * page_size);
+
+                Vmo::decommit(addr, 4 * page_size, Decommit::Eager);
+                assert!(std::slice::from_raw_parts(addr, 4 * page_size).iter().all(|&byte| byte == 0));
+
+                assert!(Vmo::protect(addr, page_size, Protection::ReadOnly));
+                assert!(!Vmo::protect(addr, page_size, Protection::Executable));
+
+                Vmo::return_memory(addr, 4 * page_size);
+            }
+        }
+    }
+}
+
+#[cfg(all(target_os = "uefi", feature = "uefi", not(any(miri, feature = "system-backend"))))]
+pub(crate) mod uefi {
+    use super::{PlatformMemory, Protection, LOW_ADDRESS_LIMIT};
+    use crate::config::Decommit;
+
+    use std::{
+        ffi::c_void,
+        ptr::{self, NonNull},
+        sync::atomic::{AtomicPtr, Ordering},
+    };
+
+    type Status = usize;
+
+    const EFI_SUCCESS: Status = 0;
+    const ALLOCATE_ANY_PAGES: u32 = 0;
+    const ALLOCATE_MAX_ADDRESS: u32 = 1;
+    const ALLOCATE_ADDRESS: u32 = 2;
+    const EFI_LOADER_DATA: u32 = 2;
+
+    /// Size of the pages of `AllocatePages`, whatever the page size of the CPU is.
+    const EFI_PAGE_SIZE: usize = 4096;
+
+    /// `EFI_TABLE_HEADER`.
+    #[repr(C)]
+    struct TableHeader {
+        signature: u64,
+        revision: u32,
+        header_size: u32,
+        crc32: u32,
+        reserved: u32,
+    }
+
+    /// Start of `EFI_SYSTEM_TABLE`, up to the boot services.
+    #[repr(C)]
+    struct SystemTable {
+        hdr: TableHeader,
+        firmware_vendor: *const u16,
+        firmware_revision: u32,
+        console_in_handle: *mut c_void,
+        con_in: *mut c_void,
+        console_out_handle: *mut c_void,
+        con_out: *mut c_void,
+        standard_error_handle: *mut c_void,
+        std_err: *mut c_void,
+        runtime_services: *mut c_void,
+        boot_services: *mut BootServices,
+    }
+
+    /// Start of `EFI_BOOT_SERVICES`, up to `FreePages`.
+    #[repr(C)]
+    struct BootServices {
+        hdr: TableHeader,
+        raise_tpl: *mut c_void,
+        restore_tpl: *mut c_void,
+        allocate_pages: unsafe extern "efiapi" fn(kind: u32, memory_type: u32, pages: usize, memory: *mut u64) -> Status,
+        free_pages: unsafe extern "efiapi" fn(memory: u64, pages: usize) -> Status,
+    }
+
+    /// Boot services of the firmware, set by [`set_uefi_system_table`].
+    static BOOT_SERVICES: AtomicPtr<BootServices> = AtomicPtr::new(ptr::null_mut());
+
+    /// Gives the allocator the `EFI_SYSTEM_TABLE` the image entry point received, so it
+    /// can take memory from the boot services. Until then, and after a null pointer is
+    /// given, every request for memory fails.
+    ///
+    /// # Safety
+    ///
+    /// `system_table` must be null or point to the system table of the firmware, and a
+    /// null pointer must be given before calling `ExitBootServices`, since boot services
+    /// can't be used after that.
+    pub unsafe fn set_uefi_system_table(system_table: *mut c_void) {
+        let boot_services = match system_table.cast::<SystemTable>() {
+            table if table.is_null() => ptr::null_mut(),
+            table => unsafe { (*table).boot_services },
+        };
+
+        BOOT_SERVICES.store(boot_services, Ordering::Release);
+    }
+
+    /// Memory pages of the boot services of the firmware.
+    ///
+    /// Pages are allocated with `AllocatePages` as `EfiLoaderData`, which the OS is free
+    /// to reclaim once the firmware is gone, and freed with `FreePages`, which takes any
+    /// part of an allocation. Firmware identity maps the memory and doesn't page it, so
+    /// memory can't be decommitted or protected, and the rest of hints are ignored.
+    pub(crate) struct BootServicesMemory;
+
+    impl BootServicesMemory {
+        /// Allocates the pages needed for `len` bytes with the `AllocateType` `kind`,
+        /// where `addr` is the address that `kind` takes, if any.
+        unsafe fn allocate(kind: u32, addr: u64, len: usize) -> Option<NonNull<u8>> {
+            let boot_services = BOOT_SERVICES.load(Ordering::Acquire);
+
+            if boot_services.is_null() {
+                return None;
+            }
+
+            let mut memory = addr;
+
+            unsafe {
+                let pages = len.div_ceil(EFI_PAGE_SIZE);
+
+                if ((*boot_services).allocate_pages)(kind, EFI_LOADER_DATA, pages, &mut memory) != EFI_SUCCESS {
+                    return None;
+                }
+
+                // Unlike fresh mappings, the pages keep whatever was there before.
+                let addr = ptr::with_exposed_provenance_mut::<u8>(memory as usize);
+                addr.write_bytes(0, pages * EFI_PAGE_SIZE);
+
+                NonNull::new(addr)
+            }
+        }
+    }
+
+    impl PlatformMemory for BootServicesMemory {
+        unsafe fn request_memory(len: usize) -> Option<NonNull<u8>> {
+            unsafe { Self::allocate(ALLOCATE_ANY_PAGES, 0, len) }
+        }
+
+        /// Asks for `AllocateMaxAddress`, which places the whole memory below the address.
+        unsafe fn request_low_memory(len: usize) -> Option<NonNull<u8>> {
+            unsafe { Self::allocate(ALLOCATE_MAX_ADDRESS, LOW_ADDRESS_LIMIT - 1, len) }
+        }
+
+        unsafe fn request_memory_at(addr: usize, len: usize) -> Option<NonNull<u8>> {
+            unsafe { Self::allocate(ALLOCATE_ADDRESS, addr as u64, len) }
+        }
+
+        unsafe fn return_memory(addr: *mut u8, len: usize) {
+            let boot_services = BOOT_SERVICES.load(Ordering::Acquire);
+
+            if !boot_services.is_null() {
+                unsafe { ((*boot_services).free_pages)(addr.addr() as u64, len.div_ceil(EFI_PAGE_SIZE)) };
+            }
+        }
+
+        unsafe fn page_size() -> usize {
+            EFI_PAGE_SIZE
+        }
+
+        /// The memory stays committed.
+        unsafe fn decommit(_addr: *mut u8, _len: usize, _mode: Decommit) {}
+
+        unsafe fn protect(_addr: *mut u8, _len: usize, _protection: Protection) -> bool {
+            false
+        }
+    }
+}
+
+#[cfg(any(miri, feature = "system-backend"))]
+mod system {
+    use super::{PlatformMemory, Protection, LOW_ADDRESS_LIMIT};
+    use crate::config::Decommit;
+
+    use std::{alloc::{GlobalAlloc, Layout, System}, ptr::NonNull};
+
+    /// Page size assumed by [`SystemMemory`], since there is no OS to ask.
+    const PAGE_SIZE: usize = 4096;
+
+    /// Memory taken from the system allocator with page-aligned layouts.
+    ///
+    /// This backend lets the whole allocator (lists, splitting, merging...) run where
+    /// mapping memory isn't possible, like Miri or sandboxes where `mmap` is forbidden.
+    /// We go straight to [`System`] rather than through `std::alloc::alloc`, which
+    /// would call ourselves back when we are the global allocator.
+    ///
+    /// Everything that needs the OS is not supported: pages can't be protected, so
+    /// executable memory and sealing fail, and the rest of hints are ignored.
+    pub(crate) struct SystemMemory;
+
+    impl PlatformMemory for SystemMemory {
+        /// The memory is zeroed, just like fresh mappings.
+        unsafe fn request_memory(len: usize) -> Option<NonNull<u8>> {
+            let layout = Layout::from_size_align(len, PAGE_SIZE).ok()?;
+
+            unsafe { NonNull::new(System.alloc_zeroed(layout)) }
+        }
+
+        /// We can't choose where the system allocator places the memory, so this only
+        /// works if it happens to be low enough.
+        unsafe fn request_low_memory(len: usize) -> Option<NonNull<u8>> {
+            unsafe {
+                let addr = Self::request_memory(len)?;
+
+                if (addr.as_ptr().addr() + len) as u64 <= LOW_ADDRESS_LIMIT {
+                    return Some(addr);
+                }
+
+                Self::return_memory(addr.as_ptr(), len);
+
+                None
+            }
+        }
+
+        /// `len` must be the same size given to [`SystemMemory::request_memory`].
+        unsafe fn return_memory(addr: *mut u8, len: usize) {
+            unsafe { System.dealloc(addr, Layout::from_size_align_unchecked(len, PAGE_SIZE)) }
+        }
+
+        unsafe fn page_size() -> usize {
+            PAGE_SIZE
+        }
+
+        /// The memory stays committed.
+        unsafe fn decommit(_addr: *mut u8, _len: usize, _mode: Decommit) {}
+
+        unsafe fn protect(_addr: *mut u8, _len: usize, _protection: Protection) -> bool {
+            false
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn memory_is_zeroed_and_page_aligned() {
+            unsafe {
+                let addr = SystemMemory::request_memory(2 * PAGE_SIZE).unwrap().as_ptr();
+
+                assert_eq!(addr.addr() % PAGE_SIZE, 0);
+                assert!(std::slice::from_raw_parts(addr, 2 * PAGE_SIZE).iter().all(|&byte| byte == 0));
+
+                SystemMemory::return_memory(addr, 2 * PAGE_SIZE);
+            }
+        }
+    }
+}
+
+#[cfg(all(windows, not(any(miri, feature = "system-backend"))))]
+mod windows {
+    use std::{mem::{self, MaybeUninit}, ptr::{self, NonNull}, os::raw::c_void, sync::atomic::{AtomicUsize, Ordering}};
+
+    use crate::{config::Decommit, kernel::{PlatformMemory, Protection, LOW_ADDRESS_LIMIT, LOW_ADDRESS_START, LOW_ADDRESS_STEP}};
+
+    use windows::{
+        core::{PCSTR, PCWSTR},
+        Win32::{
+            Foundation::{CloseHandle, GetLastError, ERROR_NOT_ALL_ASSIGNED, HANDLE, LUID},
+            Security::{
+                AdjustTokenPrivileges, LookupPrivilegeValueW, LUID_AND_ATTRIBUTES, SE_LOCK_MEMORY_NAME,
+                SE_PRIVILEGE_ENABLED, TOKEN_ADJUST_PRIVILEGES, TOKEN_PRIVILEGES, TOKEN_QUERY,
+            },
+            System::{
+                LibraryLoader, Memory,
+                ProcessStatus::{PSAPI_WORKING_SET_EX_INFORMATION, QueryWorkingSetEx},
+                SystemInformation,
+                Threading::{GetCurrentProcess, OpenProcessToken},
+            },
+        },
+    };
+
+    /// Granularity of the addresses returned by `VirtualAlloc`.
+    const ALLOCATION_GRANULARITY: usize = 64 * 1024;
+
+    /// `MemExtendedParameterAddressRequirements`.
+    const ADDRESS_REQUIREMENTS: u64 = 1;
+
+    /// `MEM_ADDRESS_REQUIREMENTS`.
+    #[repr(C)]
+    struct AddressRequirements {
+        lowest_starting_address: *mut c_void,
+        highest_ending_address: *mut c_void,
+        alignment: usize,
+    }
+
+    /// `MEM_EXTENDED_PARAMETER`, with the type in the lowest 8 bits of the first word.
+    #[repr(C)]
+    struct ExtendedParameter {
+        kind: u64,
+        pointer: *mut c_void,
+    }
+
+    /// Signature of `VirtualAlloc2`.
+    type VirtualAlloc2 = unsafe extern "system" fn(
+        process: *mut c_void,
+        base_address: *const c_void,
+        size: usize,
+        allocation_type: u32,
+        page_protection: u32,
+        extended_parameters: *mut ExtendedParameter,
+        parameter_count: u32,
+    ) -> *mut c_void;
+
+    /// Address of `VirtualAlloc2` once it has been looked up. `UNRESOLVED` if it hasn't
+    /// and `MISSING` if the system doesn't have it.
+    static VIRTUAL_ALLOC2: AtomicUsize = AtomicUsize::new(UNRESOLVED);
+    const UNRESOLVED: usize = 0;
+    const MISSING: usize = 1;
+
+    /// Returns `VirtualAlloc2`, which is only available since Windows 10 version 1803.
+    /// It is looked up at runtime so the allocator still loads on older systems.
+    fn virtual_alloc2() -> Option<VirtualAlloc2> {
+        let mut address = VIRTUAL_ALLOC2.load(Ordering::Relaxed);
+
+        if address == UNRESOLVED {
+            address = unsafe {
+                LibraryLoader::GetModuleHandleA(PCSTR(c"kernelbase.dll".as_ptr().cast()))
+                    .ok()
+                    .and_then(|module| LibraryLoader::GetProcAddress(module, PCSTR(c"VirtualAlloc2".as_ptr().cast())))
+                    .map_or(MISSING, |function| function as usize)
+            };
+
+            VIRTUAL_ALLOC2.store(address, Ordering::Relaxed);
+        }
+
+        (address != MISSING).then(|| unsafe { mem::transmute::<usize, VirtualAlloc2>(address) })
+    }
+
+    /// Large page size once large pages have been set up. `UNRESOLVED` if they haven't and
+    /// `MISSING` if they can't be used.
+    static LARGE_PAGE_SIZE: AtomicUsize = AtomicUsize::new(UNRESOLVED);
+
+    /// Returns the size of the large pages, or `None` if the system doesn't support them or
+    /// the process can't use them.
+    ///
+    /// Mapping large pages needs the `SeLockMemoryPrivilege` privilege ("Lock pages in
+    /// memory"), which must be granted to the user by an administrator and then enabled in
+    /// the token of the process. The first call enables it, so without it large pages are
+    /// never requested and the regions get regular pages.
+    fn large_page_size() -> Option<usize> {
+        let mut size = LARGE_PAGE_SIZE.load(Ordering::Relaxed);
+
+        if size == UNRESOLVED {
+            size = match unsafe { Memory::GetLargePageMinimum() } {
+                0 => MISSING,
+                _ if !enable_lock_memory_privilege() => MISSING,
+                minimum => minimum,
+            };
+
+            LARGE_PAGE_SIZE.store(size, Ordering::Relaxed);
+        }
+
+        (size != MISSING).then_some(size)
+    }
+
+    /// Enables `SeLockMemoryPrivilege` in the token of the process. Returns `false` if
+    /// the user doesn't hold it.
+    fn enable_lock_memory_privilege() -> bool {
+        unsafe {
+            let mut token = HANDLE::default();
+
+            if OpenProcessToken(GetCurrentProcess(), TOKEN_ADJUST_PRIVILEGES | TOKEN_QUERY, &mut token).is_err() {
+                return false;
+            }
+
+            let mut privileges = TOKEN_PRIVILEGES {
+                PrivilegeCount: 1,
+                Privileges: [LUID_AND_ATTRIBUTES { Luid: LUID::default(), Attributes: SE_PRIVILEGE_ENABLED }],
+            };
+
+            let enabled = LookupPrivilegeValueW(PCWSTR::null(), SE_LOCK_MEMORY_NAME, &mut privileges.Privileges[0].Luid).is_ok()
+                && AdjustTokenPrivileges(token, false, Some(&privileges), 0, None, None).is_ok()
+                // It succeeds even if the privilege isn't held, in which case it says so here.
+                && GetLastError() != ERROR_NOT_ALL_ASSIGNED;
+
+            let _ = CloseHandle(token);
+
+            enabled
+        }
+    }
+
+    /// Reserves and commits `len` bytes at an address that is a multiple of `align`
+    /// using `VirtualAlloc2` with `MEM_ADDRESS_REQUIREMENTS`. `align` must be a power of
+    /// two multiple of the allocation granularity, unless it is 0.
+    unsafe fn virtual_alloc_aligned(len: usize, align: usize, flags: Memory::VIRTUAL_ALLOCATION_TYPE) -> Option<NonNull<u8>> {
+        let function = virtual_alloc2()?;
+
+        let mut requirements = AddressRequirements {
+            lowest_starting_address: ptr::null_mut(),
+            highest_ending_address: ptr::null_mut(),
+            alignment: align,
+        };
+        let mut parameter = ExtendedParameter {
+            kind: ADDRESS_REQUIREMENTS,
+            pointer: (&raw mut requirements).cast(),
+        };
+
+        unsafe {
+            let addr = function(
+                ptr::null_mut(),
+                ptr::null(),
+                len,
+                flags.0,
+                Memory::PAGE_READWRITE.0,
+                &raw mut parameter,
+                1,
+            );
+
+            NonNull::new(addr.cast())
+        }
+    }
+
+    /// Memory reserved and committed with `VirtualAlloc`.
+    pub(crate) struct VirtualMemory;
+
+    impl PlatformMemory for VirtualMemory {
+        /// Requests memory from the Windows Operating System.
+        /// 
+        /// This implementation uses `VirtualAlloc` to reserve and commit memory
+        /// in a single step.
+        /// 
+        /// # Arguments
+        /// 
+        /// - `len` - The number of bytes to allocate.
+        unsafe fn request_memory(len: usize) -> Option<std::ptr::NonNull<u8>> {
+            // Read-Write only.
+            let protection = Memory::PAGE_READWRITE;
+            
+            // Reserve address space and commit physical storage immediately.
+            let flags = Memory::MEM_RESERVE | Memory::MEM_COMMIT;
+
+            unsafe {
+                let addr = Memory::VirtualAlloc(None, len, flags, protection);
+                
+                NonNull::new(addr.cast())
+            }
+        }
+
+        /// Requests memory below [`LOW_ADDRESS_LIMIT`] by giving `VirtualAlloc` increasing
+        /// addresses until one of them is free.
+        unsafe fn request_low_memory(len: usize) -> Option<NonNull<u8>> {
+            let flags = Memory::MEM_RESERVE | Memory::MEM_COMMIT;
+
+            // Both are multiples of the allocation granularity (64 KiB).
+            let step = std::cmp::max(crate::utils::align(len, LOW_ADDRESS_STEP), LOW_ADDRESS_STEP);
+            let mut hint = LOW_ADDRESS_START;
+
+            while (hint + len) as u64 <= LOW_ADDRESS_LIMIT {
+                unsafe {
+                    let addr = Memory::VirtualAlloc(Some(hint as *const c_void), len, flags, Memory::PAGE_READWRITE);
+
+                    if let Some(addr) = NonNull::new(addr.cast()) {
+                        return Some(addr);
+                    }
+                }
+
+                hint += step;
+            }
+
+            None
+        }
+
+        /// Mappings can only start at multiples of the allocation granularity, so when
+        /// `offset` is one of them, `VirtualAlloc2` is asked for an aligned address directly.
+        ///
+        /// Otherwise, or on systems without `VirtualAlloc2`, Windows can't release part of a
+        /// mapping, so we reserve `align` extra bytes to find a suitable address, release them
+        /// and map exactly at that address. Another thread can take the address in the
+        /// meantime, so we only try a few times.
+        unsafe fn request_aligned_memory(len: usize, align: usize, offset: usize) -> Option<NonNull<u8>> {
+            const ATTEMPTS: usize = 3;
+
+            if offset.is_multiple_of(align) && align >= ALLOCATION_GRANULARITY {
+                let flags = Memory::MEM_RESERVE | Memory::MEM_COMMIT;
+
+                if let Some(addr) = unsafe { virtual_alloc_aligned(len, align, flags) } {
+                    return Some(addr);
+                }
+            }
+
+            for _ in 0..ATTEMPTS {
+                unsafe {
+                    let raw = Memory::VirtualAlloc(None, len + align, Memory::MEM_RESERVE, Memory::PAGE_NOACCESS);
+
+                    if raw.is_null() {
+                        return None;
+                    }
+
+                    let _ = Memory::VirtualFree(raw, 0, Memory::MEM_RELEASE);
+
+                    let start = crate::utils::align(raw.addr() + offset, align) - offset;
+                    let flags = Memory::MEM_RESERVE | Memory::MEM_COMMIT;
+                    let addr = Memory::VirtualAlloc(Some(std::ptr::without_provenance(start)), len, flags, Memory::PAGE_READWRITE);
+
+                    // Reserved addresses are rounded down to the allocation granularity.
+                    if addr.addr() == start {
+                        return NonNull::new(addr.cast());
+                    }
+
+                    if !addr.is_null() {
+                        let _ = Memory::VirtualFree(addr, 0, Memory::MEM_RELEASE);
+                    }
+                }
+            }
+
+            None
+        }
+
+        /// Maps large pages with `MEM_LARGE_PAGES`, aligned to the large page size using
+        /// `VirtualAlloc2`, or with `VirtualAlloc` on older systems. `len` is rounded up to
+        /// a multiple of the large page size. Large pages are never paged out. See
+        /// [`large_page_size`] for the privilege they need.
+        unsafe fn request_huge_memory(len: usize) -> Option<(NonNull<u8>, usize)> {
+            let size = large_page_size()?;
+            let len = crate::utils::align(len, size);
+            let flags = Memory::MEM_RESERVE | Memory::MEM_COMMIT | Memory::MEM_LARGE_PAGES;
+
+            let addr = unsafe {
+                virtual_alloc_aligned(len, size.max(ALLOCATION_GRANULARITY), flags)
+                    .or_else(|| NonNull::new(Memory::VirtualAlloc(None, len, flags, Memory::PAGE_READWRITE).cast()))?
+            };
+
+            Some((addr, len))
+        }
+
+        /// Release a memory region previously allocated by `VirtualAlloc`.
+        /// 
+        /// This function wraps `Virtuall`.
+        /// 
+        /// # Windows Specific Behavior
+        ///
+        /// According to the Microsoft documentation for `VirtualFree` with `MEM_RELEASE`:
+        /// 
+        /// - "If the dwFreeType parameter is MEM_RELEASE, this parameter [dwSize] 
+        /// - must be 0 (zero). The function frees the entire region that is reserved 
+        /// - in the initial allocation call to VirtualAlloc."
+        /// 
+        /// Therefore, `_len` is ignored to prevent `VirtualFree` from failing.
+        ///
+        /// # Safety
+        ///
+        /// Caller must ensure `addr` is a valid pointer returned by `request_memory`
+        /// and has not been freed yet.
+        unsafe fn return_memory(addr: *mut u8, _len: usize) {
+            unsafe { let _ = Memory::VirtualFree(addr as *mut c_void, 0, Memory::MEM_RELEASE); }
+        }
+
+        unsafe fn page_size() -> usize {
+            unsafe {
+                let mut system_info = MaybeUninit::uninit();
+                SystemInformation::GetSystemInfo(system_info.as_mut_ptr());
+                
+                system_info.assume_init().dwPageSize as usize
+            }
+        }
+
+        /// `VirtualAlloc` fails if the range is already in use.
+        unsafe fn request_memory_at(addr: usize, len: usize) -> Option<NonNull<u8>> {
+            let flags = Memory::MEM_RESERVE | Memory::MEM_COMMIT;
+
+            unsafe { NonNull::new(Memory::VirtualAlloc(Some(addr as *const c_void), len, flags, Memory::PAGE_READWRITE).cast()) }
+        }
+
+        /// Reserves the range with `MEM_RESERVE`.
+        unsafe fn reserve_memory(len: usize) -> Option<NonNull<u8>> {
+            unsafe { NonNull::new(Memory::VirtualAlloc(None, len, Memory::MEM_RESERVE, Memory::PAGE_NOACCESS).cast()) }
+        }
+
+        /// Commits the pages with `MEM_COMMIT`.
+        unsafe fn commit(addr: *mut u8, len: usize) -> bool {
+            unsafe { !Memory::VirtualAlloc(Some(addr as *const c_void), len, Memory::MEM_COMMIT, Memory::PAGE_READWRITE).is_null() }
+        }
+
+        /// Decommits the pages with `MEM_DECOMMIT`, which keeps them reserved.
+        unsafe fn uncommit(addr: *mut u8, len: usize) {
+            unsafe { let _ = Memory::VirtualFree(addr as *mut c_void, len, Memory::MEM_DECOMMIT); }
+        }
+
+        /// Releases the given pages straight away with `MEM_DECOMMIT`, which keeps them
+        /// reserved, or lazily with `MEM_RESET`: their contents are discarded and the OS
+        /// reclaims them whenever it needs to.
+        unsafe fn decommit(addr: *mut u8, len: usize, mode: Decommit) {
+            unsafe {
+                match mode {
+                    Decommit::Never => {}
+                    Decommit::Eager => Self::uncommit(addr, len),
+                    Decommit::Lazy => {
+                        Memory::VirtualAlloc(Some(addr as *const c_void), len, Memory::MEM_RESET, Memory::PAGE_READWRITE);
+                    }
+                }
+            }
+        }
+
+        /// Commits the pages again after `MEM_DECOMMIT`, or tells the OS with
+        /// `MEM_RESET_UNDO` that the pages reset with `MEM_RESET` are in use again. The
+        /// ones that were already reclaimed come back zeroed, which is fine since they
+        /// hold no data.
+        unsafe fn recommit(addr: *mut u8, len: usize, mode: Decommit) -> bool {
+            unsafe {
+                match mode {
+                    Decommit::Never => true,
+                    Decommit::Eager => Self::commit(addr, len),
+                    Decommit::Lazy => {
+                        Memory::VirtualAlloc(Some(addr as *const c_void), len, Memory::MEM_RESET_UNDO, Memory::PAGE_READWRITE);
+                        true
+                    }
+                }
+            }
+        }
+
+        /// Changes the protection of the given pages using `VirtualProtect`.
+        unsafe fn protect(addr: *mut u8, len: usize, protection: Protection) -> bool {
+            let protection = match protection {
+                Protection::Writable => Memory::PAGE_READWRITE,
+                Protection::Executable => Memory::PAGE_EXECUTE_READ,
+                Protection::ReadOnly => Memory::PAGE_READONLY,
+                Protection::Inaccessible => Memory::PAGE_NOACCESS,
+            };
+
+            let mut old = Memory::PAGE_PROTECTION_FLAGS::default();
+
+            unsafe { Memory::VirtualProtect(addr as *const c_void, len, protection, &mut old).is_ok() }
+        }
+
+        /// Asks `QueryWorkingSetEx` which pages are in the working set of the process, a
+        /// chunk of pages at a time.
+        unsafe fn resident_bytes(addr: *mut u8, len: usize) -> Option<usize> {
+            let page_size = super::page_size();
+            let mut pages = [PSAPI_WORKING_SET_EX_INFORMATION::default(); 64];
+            let mut resident = 0;
+
+            for offset in (0..len).step_by(pages.len() * page_size) {
+                let count = (len - offset).min(pages.len() * page_size).div_ceil(page_size);
+
+                for (index, page) in pages[..count].iter_mut().enumerate() {
+                    page.VirtualAddress = addr.wrapping_add(offset + index * page_size).cast();
+                }
+
+                unsafe {
+                    let size = (count * mem::size_of::<PSAPI_WORKING_SET_EX_INFORMATION>()) as u32;
+                    QueryWorkingSetEx(GetCurrentProcess(), pages.as_mut_ptr().cast(), size).ok()?;
+
+                    // The first bit of the attributes is whether the page is valid.
+                    resident += pages[..count].iter().filter(|page| page.VirtualAttributes.Flags & 1 != 0).count() * page_size;
+                }
+            }
+
+            Some(resident)
+        }
+    }
+}
+
+unsafe impl Send for Kernel {}
+unsafe impl Sync for Kernel {}
+
+impl Kernel {
+    /// Create a new instance of the allocator's `Kernel`. 
+    /// 
+    /// When created, it will calculate the computer's page size and 
+    /// initialize both the free list and the regions list to be 
+    /// new empty [`FreeList`] and [`List`] datastructures.
+    /// 
+    /// We set the page_size to 0 in order to be able to make this constructor `const`.
+    /// We will set the page_size later in [`Kernel::allocate_new_region`]
+    pub(crate) const fn new(config: Config) -> Self {
+        Self {
+            regions: List::new(),
+            page_size: 0, 
+            free_list: FreeList::new(config.placement.0, config.search_limit),
+            large_objects: List::new(),
+            cache: List::new(),
+            quarantine: Quarantine::new(),
+            unmapper: Unmapper::new(),
+            decay: Decay::new(),
+            executable: List::new(),
+            pool: None,
+            handles: HandleTable::new(),
+            failures: FailureInjector::new(),
+            mapping_failures: 0,
+            hardened: Hardened::new(config.protect_metadata),
+            corruption: None,
+            next_address: 0,
+            syscalls: SyscallCounter::new(),
+            config,
+            #[cfg(feature = "leak-scanner")]
+            leak_roots: LeakRoots::new(),
+            #[cfg(feature = "tagging")]
+            tags: TagTable::new(),
+            #[cfg(feature = "dhat")]
+            dhat: DhatTable::new(),
+            #[cfg(feature = "trace")]
+            trace: None,
+        }
+    }
+
+    /// Initializes what can't be known at compile time the first time it is needed:
+    /// the computer's page size, the [`FitPolicy::ENV_VAR`] override, the address
+    /// space reservation of [`Config::reserve`] and the [`Config::address_hint`].
+    #[inline]
+    pub(crate) fn init(&mut self) {
+        if self.page_size == 0 {
+            page_size();
+            unsafe { self.page_size = PAGE_SIZE; }
+
+            if let Some(policy) = FitPolicy::from_env() {
+                self.free_list.strategy = policy.as_strategy();
+            }
+
+            self.free_list.randomize_links();
+
+            if self.config.reserve > 0 && !self.config.low_address {
+                self.pool = Pool::reserve(self.config.reserve);
+            }
+
+            if !self.config.low_address {
+                self.next_address = align(self.config.address_hint, POOL_SLOT_SIZE);
+            }
+        }
+    }
+
+    /// Returns the NUMA node allocations of the current thread should come from, or
+    /// `None` if [`Config::numa_aware`] is disabled.
+    #[inline]
+    pub(crate) fn preferred_node(&self) -> Option<u32> {
+        if self.config.numa_aware {
+            current_node()
+        } else {
+            None
+        }
+    }
+
+    /// Maps a new region of at least `len` bytes and, if [`Config::numa_aware`] is
+    /// enabled, binds it to the NUMA node of the current thread. The region is taken from
+    /// the reservation if there is one with enough space. Returns the address, the size
+    /// and the node of the region.
+    unsafe fn map_region(&mut self, len: usize) -> Option<(NonNull<u8>, usize, Option<u32>)> {
+        unsafe {
+            let (addr, len) = match self.take_from_pool(len) {
+                Some(slice) => {
+                    self.syscalls.count(Syscall::Protect);
+                    slice
+                }
+                None => self.request_region_memory(len)?,
+            };
+            let node = self.preferred_node();
+
+            name_memory(addr.as_ptr(), len, RegionKind::Blocks.name());
+            self.apply_huge_page_advice(addr.as_ptr(), len);
+
+            // This must happen before the pages are touched for the first time.
+            if let Some(node) = node {
+                self.syscalls.count(Syscall::Advise);
+                bind_to_node(addr.as_ptr(), len, node);
+            }
+
+            if self.prefaults() {
+                prefault(addr.as_ptr(), len);
+            }
+
+            Some((addr, len, node))
+        }
+    }
+
+    /// Maps at least `len` bytes for a region: with huge pages from the start if they are
+    /// enabled and the platform maps them explicitly (see [`PlatformMemory::request_huge_memory`]),
+    /// or right after the previous one if there is an [`Config::address_hint`] and that
+    /// range is free. Returns the address and the size of the mapping.
+    unsafe fn request_region_memory(&mut self, len: usize) -> Option<(NonNull<u8>, usize)> {
+        unsafe {
+            if self.config.huge_pages == HugePages::Enabled
+                && !self.config.low_address
+                && len >= HUGE_PAGE_SIZE
+                && let Some(huge) = self.count_map(request_huge_memory(len))
+            {
+                return Some(huge);
+            }
+
+            if self.next_address != 0
+                && let Some(addr) = self.count_map(request_memory_at(self.next_address, len))
+            {
+                // Windows can only place mappings at multiples of its allocation
+                // granularity, which is the size of the pool slots.
+                self.next_address += align(len, POOL_SLOT_SIZE);
+                return Some((addr, len));
+            }
+
+            let addr = self.count_map(Self::request_charged_memory(len, self.config.low_address, self.config.commit_charge))?;
+
+            Some((addr, len))
+        }
+    }
+
+    /// Counts a call to map memory that returned `mapped`, and returns it.
+    #[inline]
+    pub(crate) fn count_map<T>(&self, mapped: T) -> T {
+        self.syscalls.count(Syscall::Map);
+        mapped
+    }
+
+    /// Maps `len` bytes charged against the commit limit as `commit_charge` says (see
+    /// [`Config::commit_charge`]), unless they have to be placed below 4 GiB.
+    unsafe fn request_charged_memory(len: usize, low_address: bool, commit_charge: CommitCharge) -> Option<NonNull<u8>> {
+        unsafe {
+            match commit_charge {
+                CommitCharge::Lazy if !low_address => request_unreserved_memory(len),
+                _ => request_memory(len, low_address),
+            }
+        }
+    }
+
+    /// Whether new regions and large objects are populated as soon as they are mapped,
+    /// see [`Config::prefault`] and [`CommitCharge::Eager`].
+    #[inline]
+    fn prefaults(&self) -> bool {
+        self.config.prefault || self.config.commit_charge == CommitCharge::Eager
+    }
+
+    /// Gives the transparent huge page advice of [`Config::huge_pages`] for the new
+    /// mapping of `len` bytes starting from `addr`.
+    unsafe fn apply_huge_page_advice(&self, addr: *mut u8, len: usize) {
+        unsafe {
+            match self.config.huge_pages {
+                HugePages::Default => {}
+                HugePages::Disabled => {
+                    self.syscalls.count(Syscall::Advise);
+                    advise_huge_pages(addr, len, false);
+                }
+                HugePages::Enabled => {
+                    // The mapping must contain at least one aligned huge page.
+                    let first_huge_page = align(addr.addr(), HUGE_PAGE_SIZE);
+
+                    if first_huge_page + HUGE_PAGE_SIZE <= addr.addr() + len {
+                        self.syscalls.count(Syscall::Advise);
+                        advise_huge_pages(addr, len, true);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tells whether an allocation of the given `layout` must be served by
+    /// [`Kernel::allocate_large`] instead of the regular blocks. This is the case for
+    /// big allocations and for allocations aligned to more than a page, since those
+    /// would waste most of a region in padding.
+    #[inline]
+    pub(crate) fn is_large(&self, layout: Layout) -> bool {
+        layout.size() >= self.config.large_object_threshold || layout.align() > page_size()
+    }
+
+    
+    /// This function returns a new memory `region` by using [`request_memory`].
+    /// 
+    /// If we don't have any free block we can use on our free list, we know for
+    /// sure there is no way we can allocate the requested size on our current
+    /// Regions. Therefor, we need to allocate a new [`Region`] using
+    /// [`libc::mmap`].
+    /// 
+    /// This implementation is platform-dependant. It only works on linux right now.
+    pub(crate) fn allocate_new_region(&mut self, layout: Layout) -> Result<(), AllocError> {
+
+        self.init();
+
+        // What we really need to allocate is the requested size (aligned)
+        // plus the overhead introduced by out allocator's data structures
+        let layout_size = align(layout.size(), std::mem::size_of::<usize>());
+
+        // In the worst case, the payload needs `layout.align()` bytes of padding
+        let padding = layout.align().saturating_sub(mem::size_of::<usize>());
+
+        // The minimun block size we can give to the user is `MIN_BLOCK_SIZE`. If we
+        // didn't do this, we wouldn't be able to store our allocator's metadata on
+        // small memory requests.
+        let needed_payload = std::cmp::max(layout_size + padding + BLOCK_FOOTER_SIZE, MIN_BLOCK_SIZE);
+
+        // The region header takes the start of the mapping too.
+        let needed = needed_payload + BLOCK_HEADER_SIZE + REGION_HEADER_SIZE;
+
+        let region_size = align(needed.max(self.config.min_region_size), self.page_size);
+
+        // Before going to the OS, we check if there is any cached region big enough.
+        if self.reuse_cached_region(needed_payload) {
+            return Ok(());
+        }
+
+        self.decay_cache();
+        self.add_region(region_size, false)
+    }
+
+    /// Maps a new region of at least `region_size` bytes with a single free block that
+    /// covers all of it, or grows the region that ends where the mapping starts. See
+    /// [`Kernel::extend_region`]. Mappings for `reserved` regions are never merged. See
+    /// [`Region::reserved`].
+    fn add_region(&mut self, region_size: usize, reserved: bool) -> Result<(), AllocError> {
+        unsafe {
+            let (addr, region_size, node) = self
+                .map_or_trim(|kernel| kernel.map_region(region_size))
+                .ok_or_else(AllocError::last_os_error)?;
+
+            if !reserved && let Some(region) = self.region_ending_at(addr, node) {
+                self.extend_region(region, addr, region_size);
+                return Ok(());
+            }
+
+            let mut region = self.regions.append(
+                Region::new(region_size - REGION_HEADER_SIZE, RegionKind::Blocks, node),
+
+                addr
+            );
+            region.as_mut().data.reserved = reserved;
+
+            // First Node<Block> right after Node<Region>
+            let block_addr = NonNull::new_unchecked(region.as_ptr().offset(1)).cast();
+
+            // Useful block size
+            let block_size = region.as_ref().data.size - BLOCK_HEADER_SIZE;
+
+            let block = region.as_mut().data.blocks.append(
+                Block::new(block_size, true, region),
+                block_addr,
+            );
+
+            // We use the payload of the free block to store the node
+            let free_node_addr = NonNull::new_unchecked(
+                block.as_ptr().cast::<u8>().add(BLOCK_HEADER_SIZE)
+            );
+
+            self.free_list.insert_free_block(block, free_node_addr);
+        }
+        
+        Ok(())
+    }
+
+    /// Maps regions for `bytes` up front, so the allocations they can serve never wait
+    /// for the OS, and keeps them mapped even when they become empty. See
+    /// [`MemAlloc::reserve`].
+    pub(crate) fn reserve_regions(&mut self, bytes: usize) -> Result<(), AllocError> {
+        self.init();
+
+        let region_size = align(bytes.max(REGION_HEADER_SIZE + BLOCK_HEADER_SIZE + MIN_BLOCK_SIZE), self.page_size);
+        self.add_region(region_size, true)
+    }
+
+    /// Returns the region that ends right where the new mapping `addr` for the NUMA `node`
+    /// starts, if there is one it can be merged with. Slices of the reservation are never
+    /// merged, since every region of the reservation must start at its own slot. See
+    /// [`crate::pool`]. Neither are reserved regions, or the memory mapped beyond what
+    /// [`MemAlloc::reserve`] reserved would be kept mapped together with them.
+    fn region_ending_at(&self, addr: NonNull<u8>, node: Option<u32>) -> Option<NonNull<Node<Region>>> {
+        let addr = addr.as_ptr().addr();
+
+        if !Platform::JOINS_MAPPINGS || self.pool.as_ref().is_some_and(|pool| pool.contains(addr)) {
+            return None;
+        }
+
+        // Mappings never overlap, so the region containing the byte before the mapping
+        // ends right there.
+        let region = Self::find_region(&self.regions, addr.checked_sub(1)?)?;
+
+        unsafe { (region.as_ref().data.node == node && !region.as_ref().data.reserved).then_some(region) }
+    }
+
+    /// Makes `region` cover the `len` bytes mapped right after it at `addr` too, instead
+    /// of making a new region of them, so the free space on both sides of the seam can
+    /// be merged:
+    ///
+    /// ```text
+    /// +-----------------------------------+-------------------+
+    /// |        | +-------+    +-------+   |                   |
+    /// | Region | | Block | -> | Free  |   |  New mapping      |
+    /// |        | +-------+    +-------+   |                   |
+    /// +-----------------------------------+-------------------+
+    ///
+    /// +-------------------------------------------------------+
+    /// |        | +-------+    +-------------------------------+|
+    /// | Region | | Block | -> | Free                          ||
+    /// |        | +-------+    +-------------------------------+|
+    /// +-------------------------------------------------------+
+    /// ```
+    ///
+    /// The new memory becomes a free block at the end of the region, merged with the
+    /// last block if that one is free, and it is appended to the free list like the
+    /// block of a new region. The whole region is still given back at once, which is why
+    /// this only happens on platforms that can do it. See
+    /// [`PlatformMemory::JOINS_MAPPINGS`].
+    ///
+    /// # Safety
+    ///
+    /// `region` must be one of [`Kernel::regions`] and end at `addr`, where `len` bytes
+    /// have just been mapped.
+    unsafe fn extend_region(&mut self, mut region: NonNull<Node<Region>>, addr: NonNull<u8>, len: usize) {
+        unsafe {
+            region.as_mut().data.size += len;
+
+            let mut block = region.as_mut().data.blocks.append(Block::new(len - BLOCK_HEADER_SIZE, false, region), addr);
+            Block::write_footer(block);
+
+            // A corrupted last block is left alone, it will be reported when it is freed.
+            let _ = region.as_mut().data.merge_with_prev(&mut block, &mut self.free_list);
+
+            let payload = NonNull::new_unchecked(block.as_ptr().cast::<u8>().add(BLOCK_HEADER_SIZE));
+            self.free_list.insert_free_block(block, payload);
+        }
+    }
+
+    /// Calls `map` and, if it fails and there are cached regions, calls it once more after
+    /// returning them to the OS with [`Kernel::trim`]. The failure may be caused by
+    /// transient address space or commit pressure, and the cache is the only memory we
+    /// keep that nobody uses. Mappings that still fail are counted in
+    /// [`Kernel::mapping_failures`].
+    pub(crate) fn map_or_trim<T>(&mut self, mut map: impl FnMut(&mut Self) -> Option<T>) -> Option<T> {
+        if let Some(mapped) = map(self) {
+            return Some(mapped);
+        }
+
+        let mapped = if self.trim() == 0 { None } else { map(self) };
+
+        if mapped.is_none() {
+            self.mapping_failures += 1;
+        }
+
+        mapped
+    }
+
+    /// Maps a dedicated region for a single large allocation.
+    ///
+    /// Huge allocations don't go through the [`FreeList`] nor [`Kernel::take_from_block`]:
+    /// the region is recorded in [`Kernel::large_objects`] and it only contains one used
+    /// block that covers the whole mapping.
+    ///
+    /// ```text
+    /// +--------------------------------------------------------------+
+    /// |        | +--------------------------------------------------+ |
+    /// | Region | | Block (large allocation)                         | |
+    /// |        | +--------------------------------------------------+ |
+    /// +--------------------------------------------------------------+
+    /// ```
+    ///
+    /// We still write a regular block header so that deallocation can find the region
+    /// the same way it does for any other block. See [`Kernel::deallocate_large`].
+    pub(crate) fn allocate_large(&mut self, layout: Layout) -> Result<*mut u8, AllocError> {
+        self.init();
+
+        unsafe {
+            let node = self.preferred_node();
+            let (region, ptr) = self
+                .map_or_trim(|kernel| {
+                    let config = kernel.config;
+                    kernel.count_map(Self::map_single(layout, kernel.page_size, RegionKind::Large, node, config.low_address, config.commit_charge))
+                })
+                .ok_or_else(AllocError::last_os_error)?;
+
+            let (start, len) = (region.as_ptr() as *mut u8, region.as_ref().data.size + REGION_HEADER_SIZE);
+
+            self.apply_huge_page_advice(start, len);
+
+            if self.prefaults() {
+                prefault(start, len);
+            }
+
+            self.large_objects.append_node(region);
+
+            Ok(ptr)
+        }
+    }
+
+    /// Maps a dedicated region for a single allocation without taking the lock and
+    /// without recording it anywhere. Returns null if the mapping fails.
+    ///
+    /// This is only used when the thread is already waiting for the allocator lock
+    /// and the lock itself needs memory. See [`crate::sync::lock`]. Since the kernel can't
+    /// be read, the [`Config::low_address`] option has to be given.
+    pub(crate) fn allocate_detached(layout: Layout, low_address: bool) -> *mut u8 {
+        unsafe {
+            match Self::map_single(layout, page_size(), RegionKind::Detached, None, low_address, CommitCharge::Default) {
+                Some((_, ptr)) => ptr,
+                None => ptr::null_mut(),
+            }
+        }
+    }
+
+    /// Returns a region created by [`Kernel::allocate_detached`] back to the OS.
+    ///
+    /// # Safety
+    ///
+    /// `region` must be a [`RegionKind::Detached`] region.
+    pub(crate) unsafe fn deallocate_detached(region: NonNull<Node<Region>>) {
+        unsafe {
+            let total_region_size = region.as_ref().data.size + REGION_HEADER_SIZE;
+            return_memory(region.as_ptr() as *mut u8, total_region_size);
+        }
+    }
+
+    /// Maps the memory of a region created by [`Kernel::map_single`]. Returns the address
+    /// and the size of the mapping.
+    ///
+    /// When the alignment is bigger than the page size, we ask for a mapping whose second
+    /// page is aligned, so the headers fill the first page and the payload starts right at
+    /// the second one. Otherwise (or if the platform can't do it) we just map enough memory
+    /// to make room for the padding.
+    unsafe fn map_single_memory(
+        layout: Layout,
+        layout_size: usize,
+        page_size: usize,
+        low_address: bool,
+        commit_charge: CommitCharge,
+    ) -> Option<(NonNull<u8>, usize)> {
+        unsafe {
+            if layout.align() > page_size && !low_address {
+                let region_size = page_size + align(layout_size + BLOCK_FOOTER_SIZE, page_size);
+
+                if let Some(addr) = request_aligned_memory(region_size, layout.align(), page_size) {
+                    return Some((addr, region_size));
+                }
+            }
+
+            let padding = layout.align().saturating_sub(mem::size_of::<usize>());
+
+            let needed = REGION_HEADER_SIZE + BLOCK_HEADER_SIZE + padding + layout_size + BLOCK_FOOTER_SIZE;
+            let region_size = align(needed, page_size);
+
+            Some((Self::request_charged_memory(region_size, low_address, commit_charge)?, region_size))
+        }
+    }
+
+    /// Maps a region of the given `kind` which only holds one used block big enough
+    /// for `layout`. Returns the region, which is not linked to any list, and the
+    /// pointer that has to be given to the user. If `node` is given, the region is
+    /// bound to that NUMA node. If `low_address` is set, it is placed below 4 GiB, and it
+    /// is charged against the commit limit as `commit_charge` says.
+    pub(crate) unsafe fn map_single(
+        layout: Layout,
+        page_size: usize,
+        kind: RegionKind,
+        node: Option<u32>,
+        low_address: bool,
+        commit_charge: CommitCharge,
+    ) -> Option<(NonNull<Node<Region>>, *mut u8)> {
+        let layout_size = align(layout.size(), mem::size_of::<usize>());
+
+        unsafe {
+            let (addr, region_size) = Self::map_single_memory(layout, layout_size, page_size, low_address, commit_charge)?;
+
+            name_memory(addr.as_ptr(), region_size, kind.name());
+
+            if let Some(node) = node {
+                bind_to_node(addr.as_ptr(), region_size, node);
+            }
+
+            let mut region = addr.cast::<Node<Region>>();
+            region.as_ptr().write(Node {
+                next: None,
+                prev: None,
+                data: Region::new(region_size - REGION_HEADER_SIZE, kind, node),
+            });
+
+            let block_addr = NonNull::new_unchecked(region.as_ptr().offset(1)).cast();
+            let block_size = region.as_ref().data.size - BLOCK_HEADER_SIZE;
+
+            let block = region.as_mut().data.blocks.append(
+                Block::new(block_size, false, region),
+                block_addr,
+            );
+            Block::write_footer(block);
+
+            let payload = (block.as_ptr() as *mut u8).add(BLOCK_HEADER_SIZE);
+            let aligned_ptr = payload.map_addr(|addr| align(addr, layout.align()));
+            Block::reflect(block, aligned_ptr);
+
+            Some((region, aligned_ptr))
         }
+    }
 
-        unsafe fn page_size() -> usize {
-            unsafe {
-                let mut system_info = MaybeUninit::uninit();
-                SystemInformation::GetSystemInfo(system_info.as_mut_ptr());
-                
-                system_info.assume_init().dwPageSize as usize
+    /// Resizes the large allocation `ptr` of the used block `block` to `new_size` bytes by
+    /// remapping its region, so growing it doesn't copy the contents. Returns the new
+    /// pointer, or `None` if the platform can't remap, in which case nothing changes.
+    ///
+    /// The region and its block keep their offsets inside the mapping, but every pointer
+    /// to them (the links of [`Kernel::large_objects`], the list of blocks of the region,
+    /// the region of the block and the reflection word) has to be written again.
+    ///
+    /// # Safety
+    ///
+    /// `block` must be the block of the [`RegionKind::Large`] region of `ptr`, which must
+    /// not be sealed nor aligned to more than the page size.
+    pub(crate) unsafe fn reallocate_large(
+        &mut self,
+        block: NonNull<Node<Block>>,
+        ptr: *mut u8,
+        new_size: usize,
+    ) -> Option<*mut u8> {
+        unsafe {
+            let region = block.as_ref().data.region;
+            let start = region.as_ptr() as *mut u8;
+            let old_len = region.as_ref().data.size + REGION_HEADER_SIZE;
+            let offset = ptr.offset_from(start) as usize;
+            let new_len = align(offset + align(new_size, mem::size_of::<usize>()) + BLOCK_FOOTER_SIZE, self.page_size);
+
+            if new_len == old_len {
+                return Some(ptr);
             }
+
+            self.large_objects.remove(region);
+
+            self.syscalls.count(Syscall::Remap);
+
+            let Some(addr) = remap(start, old_len, new_len) else {
+                self.large_objects.append_node(region);
+                return None;
+            };
+
+            let mut region = addr.cast::<Node<Region>>();
+            let block_addr = NonNull::new_unchecked(region.as_ptr().offset(1)).cast::<u8>();
+
+            // The header of the block keeps its tag and call site.
+            let mut data = ptr::read(&(*block_addr.as_ptr().cast::<Node<Block>>()).data);
+            data.region = region;
+            data.set_size(new_len - REGION_HEADER_SIZE - BLOCK_HEADER_SIZE);
+
+            region.as_mut().data.size = new_len - REGION_HEADER_SIZE;
+            region.as_mut().data.blocks = List::new();
+
+            let block = region.as_mut().data.blocks.append(data, block_addr);
+            Block::write_footer(block);
+
+            let new_ptr = addr.as_ptr().add(offset);
+            Block::reflect(block, new_ptr);
+
+            self.apply_huge_page_advice(addr.as_ptr(), new_len);
+            self.large_objects.append_node(region);
+
+            Some(new_ptr)
         }
     }
-}
 
-unsafe impl Send for Kernel {}
-unsafe impl Sync for Kernel {}
+    /// Frees the used block `block_node` of the allocation `ptr` of `size` bytes, merging
+    /// it with its free neighbours, and returns its region to the OS if it is empty.
+    /// Blocks that are already free are ignored.
+    ///
+    /// # Safety
+    ///
+    /// `block_node` must point to a valid block header of this kernel and `ptr` to its
+    /// payload. See [`MemAlloc::deallocate`].
+    pub(crate) unsafe fn free_block(&mut self, stats: &StatsCounters, block_node: NonNull<Node<Block>>, ptr: *mut u8, size: usize) {
+        unsafe {
+            if let Some(block_node) = self.release_block(stats, block_node, ptr, size) {
+                let mut region = block_node.as_ref().data.region;
 
-impl Kernel {
-    /// Create a new instance of the allocator's `Kernel`. 
-    /// 
-    /// When created, it will calculate the computer's page size and 
-    /// initialize both the free list and the regions list to be 
-    /// new empty [`FreeList`] and [`List`] datastructures.
-    /// 
-    /// We set the page_size to 0 in order to be able to make this constructor `const`.
-    /// We will set the page_size later in [`Kernel::allocate_new_region`]
-    pub(crate) const fn new() -> Self {
-        Self {
-            regions: List::new(),
-            page_size: 0, 
-            free_list: FreeList::new()
+                // Check if we need to remove and munmap the current `region`
+                self.check_region_removal(&mut region, block_node);
+            }
         }
     }
 
-    
-    /// This function returns a new memory `region` by using [`request_memory`].
-    /// 
-    /// If we don't have any free block we can use on our free list, we know for
-    /// sure there is no way we can allocate the requested size on our current
-    /// Regions. Therefor, we need to allocate a new [`Region`] using
-    /// [`libc::mmap`].
-    /// 
-    /// This implementation is platform-dependant. It only works on linux right now.
-    pub(crate) fn allocate_new_region(&mut self, layout: Layout) -> Result<(), &'static str> {
+    /// Does the work of [`Kernel::free_block`] except returning the region to the OS.
+    /// Returns the free block that resulted from merging, unless the block was already
+    /// free or it was a large allocation, whose region is gone.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`Kernel::free_block`].
+    pub(crate) unsafe fn release_block(&mut self, stats: &StatsCounters, mut block_node: NonNull<Node<Block>>, ptr: *mut u8, size: usize) -> Option<NonNull<Node<Block>>> {
+        unsafe {
+            // If it is already free, this is a double free and we don't do anything
+            if block_node.as_ref().data.is_free() {
+                self.found_corruption(CorruptionKind::DoubleFree, ptr.addr());
+                return None;
+            }
 
-        if self.page_size == 0 {
-            page_size();
-            unsafe { self.page_size = PAGE_SIZE; }
-        }
+            // Freeing with a layout bigger than the block would take more than it has off
+            // the stats, so the block is handled like the other corruption.
+            if block_node.as_ref().data.size() < size {
+                self.found_corruption(CorruptionKind::LayoutMismatch, ptr.addr());
+                return None;
+            }
 
-        // What we really need to allocate is the requested size (aligned)
-        // plus the overhead introduced by out allocator's data structures
-        let layout_size = align(layout.size(), std::mem::size_of::<usize>());
+            self.record_deallocation(stats, block_node, ptr, size);
 
-        // The minimun block size we can give to the user is `MIN_BLOCK_SIZE`. If we
-        // didn't do this, we wouldn't be able to store our allocator's metadata on
-        // small memory requests.
-        let needed_payload = std::cmp::max(layout_size, MIN_BLOCK_SIZE);
+            // The free list writes into the payload, so it must be writable again.
+            if Block::is_sealed(block_node) {
+                self.unseal_block(block_node, ptr);
+            }
 
-        let needed = needed_payload + BLOCK_HEADER_SIZE;
+            // Block data
+            let block = &mut block_node.as_mut().data;
 
-        let region_size = align(needed, self.page_size);
+            // Mark the block as free to use
+            block.set_free(true);
 
-        unsafe {    
-            // What should we do here? I assume its okay to panic if 
-            // we get None from calling `mmap`.
-            let addr = request_memory(region_size).expect("mmap syscall returned None");
+            let mut region = block.region;
 
-            let mut region = self.regions.append(
-                Region {
-                    size: region_size - REGION_HEADER_SIZE,
-                    blocks: List::new(),
-                },
+            // Large allocations own their region, so we just give it back to the OS.
+            if matches!(region.as_ref().data.kind, RegionKind::Large | RegionKind::Executable | RegionKind::Fenced) {
+                self.deallocate_large(region);
+                return None;
+            }
 
-                addr
-            );
+            // Try to merge the block with the previous one.
+            if let Err(neighbour) = region.as_mut().data.merge_with_prev(&mut block_node, &mut self.free_list) {
+                self.found_corrupted_header(neighbour);
+            }
 
-            // First Node<Block> right after Node<Region>
-            let block_addr = NonNull::new_unchecked(region.as_ptr().offset(1)).cast();
+            // Try to merge the block with the next one.
+            if let Err(neighbour) = region.as_mut().data.merge_with_next(&mut block_node, &mut self.free_list) {
+                self.found_corrupted_header(neighbour);
+            }
 
-            // Useful block size
-            let block_size = region.as_ref().data.size - BLOCK_HEADER_SIZE;
+            // We re-insert the resulting block on the free list
+            let free_node_addr = block_node.as_ptr().cast::<u8>().add(BLOCK_HEADER_SIZE);
+            self.free_list.insert_free_block(block_node, NonNull::new_unchecked(free_node_addr));
 
-            let block = region.as_mut().data.blocks.append(
-                Block {
-                    size: block_size,
-                    is_free: true,
-                    region,
-                },
-                block_addr,
-            );
+            Some(block_node)
+        }
+    }
 
-            // We use the payload of the free block to store the node
-            let free_node_addr = NonNull::new_unchecked(
-                block.as_ptr().cast::<u8>().add(BLOCK_HEADER_SIZE)
-            );
+    /// Returns the dedicated `region` of a large allocation back to the OS.
+    ///
+    /// # Safety
+    ///
+    /// `region` must be part of [`Kernel::large_objects`] or [`Kernel::executable`].
+    pub(crate) unsafe fn deallocate_large(&mut self, region: NonNull<Node<Region>>) {
+        unsafe {
+            let total_region_size = region.as_ref().data.size + REGION_HEADER_SIZE;
 
-            self.free_list.insert_free_block(block, free_node_addr);
+            match region.as_ref().data.kind {
+                RegionKind::Executable => self.executable.remove(region),
+                RegionKind::Fenced => {
+                    self.large_objects.remove(region);
+                    self.deallocate_fenced(region);
+                    return;
+                }
+                _ => self.large_objects.remove(region),
+            }
+            self.unmap_region(region.as_ptr() as *mut u8, total_region_size);
         }
-        
-        Ok(())
     }
 
     /// Checks if the given `region` needs to be returned to the OS or not.
@@ -282,17 +2561,27 @@ impl Kernel {
     /// this `block` stills free and therefor it will try to use it, causing undefined behavior.
     pub(crate) fn check_region_removal(&mut self, region: &mut NonNull<Node<Region>>, block: NonNull<Node<Block>>) {
         unsafe {
-            if region.as_mut().data.blocks.len() == 1 {
+            if region.as_mut().data.blocks.len() == 1 && !region.as_ref().data.reserved {
                 let total_region_size = region.as_ref().data.size + REGION_HEADER_SIZE;
                 
                 // Just in case the block stills in the free list, we always remove it.
                 // If it was not in the free list, `remove_free_block` will manage it
                 self.free_list.remove_free_block(block);
                 self.regions.remove(*region);
+
+                // If there is still space in the cache, we keep the region mapped.
+                if self.cache.len() < self.config.cached_regions {
+                    self.cache.append_node(*region);
+                    self.decommit_cached(*region);
+                    self.decay_cache();
+                    return;
+                }
                 
                 let region_start = region.as_ptr() as *mut u8;
 
-                return_memory(region_start, total_region_size);
+                if !self.quarantine_region(region_start, total_region_size) {
+                    self.unmap_region(region_start, total_region_size);
+                }
             } else {
                 // The current region still has other blocks so the merged block has to return to the free list.
                 
@@ -308,6 +2597,290 @@ impl Kernel {
         }
     }
 
+    /// Releases the physical memory of the cached `region` according to [`Config::decommit`].
+    /// The first page holds the headers and the last one holds the footer, so they are kept.
+    ///
+    /// # Safety
+    ///
+    /// `region` must be part of [`Kernel::cache`].
+    unsafe fn decommit_cached(&self, region: NonNull<Node<Region>>) {
+        if self.config.decommit == Decommit::Never {
+            return;
+        }
+
+        unsafe {
+            if let Some((start, len)) = self.decommitted_range(region) {
+                self.syscalls.count(Syscall::Advise);
+                decommit(start, len, self.config.decommit);
+            }
+        }
+    }
+
+    /// Makes the memory released by [`Kernel::decommit_cached`] usable again before the
+    /// cached `region` is reused. Returns `false` if it can't be.
+    ///
+    /// # Safety
+    ///
+    /// `region` must be part of [`Kernel::cache`].
+    unsafe fn recommit_cached(&self, region: NonNull<Node<Region>>) -> bool {
+        unsafe {
+            match self.decommitted_range(region) {
+                Some((start, len)) => recommit(start, len, self.config.decommit),
+                None => true,
+            }
+        }
+    }
+
+    /// Range of the cached `region` that is decommitted, see [`Kernel::decommit_cached`].
+    unsafe fn decommitted_range(&self, region: NonNull<Node<Region>>) -> Option<(*mut u8, usize)> {
+        unsafe {
+            let start = region.as_ptr().addr() + self.page_size;
+            let end = region.as_ptr().addr() + REGION_HEADER_SIZE + region.as_ref().data.size - self.page_size;
+
+            (end > start).then(|| (region.as_ptr().cast::<u8>().with_addr(start), end - start))
+        }
+    }
+
+    /// Takes a region out of the cache whose only block can hold `payload_size` bytes and
+    /// puts it back in service. Returns `false` if there is no such region.
+    fn reuse_cached_region(&mut self, payload_size: usize) -> bool {
+        let mut current = self.cache.first();
+
+        while let Some(mut region) = current {
+            unsafe {
+                current = region.as_ref().next;
+
+                // A cached region only contains one free block that covers all of it.
+                let Some(block) = region.as_mut().data.blocks.first() else {
+                    continue;
+                };
+
+                if block.as_ref().data.size() < payload_size || !self.recommit_cached(region) {
+                    continue;
+                }
+
+                self.cache.remove(region);
+                self.regions.append_node(region);
+
+                let free_node_addr = NonNull::new_unchecked(
+                    block.as_ptr().cast::<u8>().add(BLOCK_HEADER_SIZE)
+                );
+                self.free_list.insert_free_block(block, free_node_addr);
+
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Returns every cached region back to the OS. This is the equivalent of
+    /// `malloc_trim`: it gives up the memory we were keeping around for future
+    /// allocations. Returns the number of bytes released.
+    pub(crate) fn trim(&mut self) -> usize {
+        let mut released = 0;
+
+        while let Some(region) = self.cache.first() {
+            unsafe {
+                let total_region_size = region.as_ref().data.size + REGION_HEADER_SIZE;
+
+                self.cache.remove(region);
+                self.unmap_region(region.as_ptr() as *mut u8, total_region_size);
+
+                released += total_region_size;
+            }
+        }
+
+        released
+    }
+
+    /// Whether any allocation is still live: a used block in a region, a large or
+    /// executable object, or a slot of the hardened mode.
+    pub(crate) fn has_live_allocations(&self) -> bool {
+        self.regions.iter().any(|region| region.blocks.iter().any(|block| !block.is_free()))
+            || !self.large_objects.is_empty()
+            || !self.executable.is_empty()
+            || self.hardened.has_live_allocations()
+    }
+
+    /// Returns every region we have mapped back to the OS, whether it is in use, cached,
+    /// quarantined or waiting for the unmapper thread.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may use the memory of the allocator anymore.
+    unsafe fn unmap_all(&mut self) {
+        unsafe {
+            while let Some(region) = self.regions.first() {
+                self.regions.remove(region);
+                self.unmap_region(region.as_ptr().cast(), REGION_HEADER_SIZE + region.as_ref().data.size);
+            }
+
+            while let Some(region) = self.large_objects.first().or(self.executable.first()) {
+                self.deallocate_large(region);
+            }
+
+            self.trim();
+
+            while let Some((addr, len)) = self.quarantine.pop() {
+                self.unmap_region(addr, len);
+            }
+
+            self.unmapper.unmap_pending();
+        }
+    }
+
+    /// Calls `f` with the header and the contents of every used block we know about.
+    #[cfg(any(feature = "leak-scanner", feature = "massif"))]
+    pub(crate) fn for_each_used_block(&self, mut f: impl FnMut(&Block, usize, usize)) {
+        let lists: [&List<Region>; 3] = [&self.regions, &self.large_objects, &self.executable];
+
+        for list in lists {
+            let mut region = list.first();
+
+            while let Some(current) = region {
+                unsafe {
+                    let mut block = current.as_ref().data.blocks.first();
+
+                    while let Some(node) = block {
+                        let data = &node.as_ref().data;
+
+                        if !data.is_free() {
+                            let start = node.as_ptr().addr() + BLOCK_HEADER_SIZE;
+                            f(data, start, start + data.size() - BLOCK_FOOTER_SIZE);
+                        }
+
+                        block = node.as_ref().next;
+                    }
+
+                    region = current.as_ref().next;
+                }
+            }
+        }
+    }
+
+    /// Same as [`FreeList::find_free_block`], but the free list is not walked at all if no
+    /// block in it can be big enough for `layout`, which is what happens every time the
+    /// regions are full and a new one has to be mapped. Skipped searches are still counted
+    /// as misses.
+    ///
+    /// Only [`FreeList::largest`] is checked before searching, so allocations served from
+    /// the free list don't pay for looking at the regions. The hint is only lowered when a
+    /// search misses, to the [`Region::largest_free`] of the regions we have, since a new
+    /// region is about to be mapped anyway. Taking blocks out of the list leaves it as it
+    /// was, so after the largest block is taken, the next request that only it could
+    /// have held still walks the whole list once before missing.
+    pub(crate) fn find_free_block(&mut self, layout: Layout, node: Option<u32>) -> Option<NonNull<Node<Block>>> {
+        // Smallest block that can hold `layout`, if its payload doesn't need any padding.
+        let size = std::cmp::max(align(layout.size(), mem::size_of::<usize>()) + BLOCK_FOOTER_SIZE, MIN_BLOCK_SIZE);
+
+        if self.free_list.largest < size {
+            self.free_list.stats.record(0, false);
+            return None;
+        }
+
+        let block = self.free_list.find_free_block(layout, node);
+
+        if block.is_none() {
+            self.free_list.largest = self.regions.iter().map(|region| region.largest_free).max().unwrap_or(0);
+        }
+
+        block
+    }
+
+    /// Returns the region that contains `addr`, looking at every region we have
+    /// mapped: regular ones, large objects and cached ones.
+    pub(crate) fn region_of(&self, addr: usize) -> Option<NonNull<Node<Region>>> {
+        // Only block regions live in the reservation, and finding them there is O(1).
+        if let Some(pool) = &self.pool && pool.contains(addr) {
+            return pool.region_of(addr);
+        }
+
+        [&self.regions, &self.large_objects, &self.cache, &self.executable]
+            .into_iter()
+            .find_map(|list| Self::find_region(list, addr))
+    }
+
+    /// Returns the region of `list` that contains `addr`.
+    pub(crate) fn find_region(list: &List<Region>, addr: usize) -> Option<NonNull<Node<Region>>> {
+        let mut current = list.first();
+
+        while let Some(region) = current {
+            unsafe {
+                if Region::contains(region, addr) {
+                    return Some(region);
+                }
+
+                current = region.as_ref().next;
+            }
+        }
+
+        None
+    }
+
+    /// Returns the used block whose payload is `ptr`, if any.
+    ///
+    /// Unlike [`Block::from_payload`], this never trusts the header it decodes from `ptr`:
+    /// it only dereferences it after checking that it is actually one of the blocks of the
+    /// region that contains `ptr`.
+    pub(crate) fn find_used_block(&self, ptr: *const u8) -> Option<NonNull<Node<Block>>> {
+        let region = self.block_region_of(ptr.addr())?;
+
+        unsafe {
+            let block = Self::find_block(region, ptr)?;
+            let data = &block.as_ref().data;
+            let start = block.as_ptr().addr() + BLOCK_HEADER_SIZE;
+            let end = start + data.size() - BLOCK_FOOTER_SIZE;
+
+            (!data.is_free() && (start..end).contains(&ptr.addr())).then_some(block)
+        }
+    }
+
+    /// Returns the region that contains `addr` out of the ones whose blocks can be
+    /// handed out: regular ones, large objects and executable ones.
+    pub(crate) fn block_region_of(&self, addr: usize) -> Option<NonNull<Node<Region>>> {
+        match &self.pool {
+            Some(pool) if pool.contains(addr) => pool.region_of(addr),
+            _ => Self::find_region(&self.regions, addr)
+                .or_else(|| Self::find_region(&self.large_objects, addr))
+                .or_else(|| Self::find_region(&self.executable, addr)),
+        }
+    }
+
+    /// Returns the block of `region` whose header `ptr` decodes to with
+    /// [`Block::from_payload`], free or not, if there is one. `ptr` may be anywhere
+    /// inside `region`, which is walked to check the header before it is dereferenced.
+    ///
+    /// # Safety
+    ///
+    /// `region` must be a valid region that contains `ptr`.
+    pub(crate) unsafe fn find_block(region: NonNull<Node<Region>>, ptr: *const u8) -> Option<NonNull<Node<Block>>> {
+        let addr = ptr.addr();
+
+        unsafe {
+            // The payload must be after the first block header, otherwise reading
+            // the word before it would take us out of the region.
+            let first_payload = region.as_ptr().addr() + REGION_HEADER_SIZE + BLOCK_HEADER_SIZE;
+            if addr < first_payload || !addr.is_multiple_of(mem::size_of::<usize>()) {
+                return None;
+            }
+
+            let candidate = Block::from_payload(ptr as *mut u8);
+
+            let mut current = region.as_ref().data.blocks.first();
+
+            while let Some(block) = current {
+                if block == candidate {
+                    return Some(block);
+                }
+
+                current = block.as_ref().next;
+            }
+        }
+
+        None
+    }
+
     /// Splits the given `block` if possible
     /// 
     /// ```text
@@ -341,7 +2914,7 @@ impl Kernel {
             let raw_payload_ptr = (block.as_ptr() as *mut u8).add(BLOCK_HEADER_SIZE);
 
             // Aligned pointer we will return
-            let aligned_ptr  = align(raw_payload_ptr as usize, aligned_requested) as *mut u8;
+            let aligned_ptr = raw_payload_ptr.map_addr(|addr| align(addr, aligned_requested));
             
             // Calculate padding: space between header and aligned data
             let padding = aligned_ptr.offset_from(raw_payload_ptr) as usize;
@@ -349,54 +2922,223 @@ impl Kernel {
             let layout_size = align(requested_size, mem::size_of::<usize>());
 
             // For small memory requests, the requested size is going to be MIN_BLOCK_SIZE anyway.
-            let requested = std::cmp::max(layout_size + padding, MIN_BLOCK_SIZE);
+            // The footer is part of the block, so we need room for it after the content.
+            let requested = std::cmp::max(layout_size + padding + BLOCK_FOOTER_SIZE, MIN_BLOCK_SIZE);
             
-            // Calculate the offset where next header will start
-            let split_offset = align(BLOCK_HEADER_SIZE + requested, mem::size_of::<usize>());
+            // Calculate the offset where next header will start. Blocks take multiples of the
+            // minimum alignment, so the payload of the next one doesn't need padding either.
+            let split_offset = align(BLOCK_HEADER_SIZE + requested, self.config.min_align.max(mem::size_of::<usize>()));
 
             // Check if we can actualy split
-            let total = block.as_ref().data.size + BLOCK_HEADER_SIZE;
+            let total = block.as_ref().data.size() + BLOCK_HEADER_SIZE;
+
+            // The remaining space must be enough for a header + `MIN_BLOCK_SIZE`, and
+            // for as much as the configuration finds worth splitting.
+            let threshold = self.config.split_threshold.max(MIN_BLOCK_SIZE);
 
-            // The remaining space must be enough for a header + `MIN_BLOCK_SIZE`
-            if total >= split_offset + BLOCK_HEADER_SIZE + MIN_BLOCK_SIZE {
+            if total >= split_offset + BLOCK_HEADER_SIZE + threshold {
                 let remaining = total - split_offset - BLOCK_HEADER_SIZE;
 
                 // We take the block out of the Free List before modifying it
                 self.free_list.remove_free_block(block);
-                block.as_mut().data.is_free = false;
+                block.as_mut().data.set_free(false);
 
                 let new_node_addr = NonNull::new_unchecked((block.as_ptr() as *mut u8).add(split_offset));
 
                 // Adjust block size so that it ends just before the new one
-                block.as_mut().data.size = split_offset - BLOCK_HEADER_SIZE;
+                block.as_mut().data.set_size(split_offset - BLOCK_HEADER_SIZE);
+                Block::write_footer(block);
 
                 let mut region = block.as_mut().data.region;
                 let new_block = region.as_mut().data.blocks.insert_after(
                     block, 
-                    Block {
-                        size: remaining,
-                        is_free: true,
-                        region,
-                    }, 
+                    Block::new(remaining, true, region),
                     new_node_addr.cast()
                 );
 
                 let free_payload_addr = new_node_addr.add(BLOCK_HEADER_SIZE);
-                self.free_list.insert_free_block(new_block, free_payload_addr);
+
+                if self.config.move_to_front {
+                    self.free_list.insert_free_block_first(new_block, free_payload_addr);
+                } else {
+                    self.free_list.insert_free_block(new_block, free_payload_addr);
+                }
             } else {
                 // There is no space for splitting so we use the whole block
                 self.free_list.remove_free_block(block);
-                block.as_mut().data.is_free = false;
+                block.as_mut().data.set_free(false);
+                Block::write_footer(block);
             }
 
             // As we have introduced a padding, when we want to deallocate, we need to know where the
             // actual header is regardless how many padding we have. Therefor, we are going to store
             // a pointer to this header just before the address we give the user.
-            let header_ref_ptr = (aligned_ptr as *mut usize).sub(1);
-            *header_ref_ptr = block.as_ptr() as usize;
+            Block::reflect(block, aligned_ptr);
 
             // We return an aligned pointer to the payload
             aligned_ptr
         }
     }
-}
\ No newline at end of file
+}
+
+impl Drop for Kernel {
+    /// Allocators that are not global return all their memory when they go away. Their
+    /// allocations can't outlive them, so there must be none left by then, unless the
+    /// allocator is dropped while unwinding.
+    fn drop(&mut self) {
+        debug_assert!(
+            std::thread::panicking() || !self.has_live_allocations(),
+            "allocator dropped with live allocations"
+        );
+
+        unsafe { self.unmap_all() };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemAlloc;
+
+    #[test]
+    fn unmap_all_returns_every_region() {
+        let allocator = MemAlloc::with_config(Config::new().cached_regions(1).quarantine_regions(1));
+        let medium = Layout::from_size_align(600 * 1024, 8).unwrap();
+        let large = Layout::from_size_align(crate::config::LARGE_OBJECT_THRESHOLD, 8).unwrap();
+
+        unsafe {
+            // The first region is cached and the second one quarantined, if the platform
+            // can. The last two stay in use.
+            let ptrs = [medium, medium, medium, large].map(|layout| allocator.allocate(layout));
+            allocator.deallocate(ptrs[0], medium);
+            allocator.deallocate(ptrs[1], medium);
+
+            let mut kernel = allocator.allocator.lock();
+            assert!(kernel.has_live_allocations());
+
+            kernel.unmap_all();
+            assert!(!kernel.has_live_allocations());
+            assert_eq!((kernel.cache.len(), kernel.quarantine.len()), (0, 0));
+            drop(kernel);
+
+            assert_eq!(allocator.memory_usage().mapped, 0);
+        }
+    }
+
+    #[test]
+    fn failed_mappings_are_retried_after_trimming() {
+        let allocator = MemAlloc::with_config(Config::new().cached_regions(1));
+        let layout = Layout::new::<u64>();
+
+        unsafe { allocator.deallocate(allocator.allocate(layout), layout) };
+
+        let mut kernel = allocator.allocator.lock();
+        assert_eq!(kernel.cache.len(), 1);
+
+        // Mapping only works once the cache is empty.
+        let mut attempts = 0;
+        let mapped = kernel.map_or_trim(|kernel| {
+            attempts += 1;
+            kernel.cache.is_empty().then_some(())
+        });
+
+        assert_eq!((mapped, attempts), (Some(()), 2));
+        assert_eq!(kernel.mapping_failures, 0);
+
+        // Without a cache to trim there is no second attempt.
+        attempts = 0;
+        assert_eq!(kernel.map_or_trim(|_| { attempts += 1; None::<()> }), None);
+        assert_eq!(attempts, 1);
+        assert_eq!(kernel.mapping_failures, 1);
+    }
+
+    #[test]
+    fn free_bytes_of_regions_follow_splits_and_merges() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::from_size_align(1000, 8).unwrap();
+
+        let check = || {
+            let kernel = allocator.allocator.lock();
+
+            for region in kernel.regions.iter() {
+                let free: Vec<_> = region.blocks.iter().filter(|block| block.is_free()).map(Block::size).collect();
+
+                assert_eq!(region.free_bytes, free.iter().sum::<usize>());
+                assert!(region.largest_free >= free.iter().copied().max().unwrap_or(0));
+                assert!(region.largest_free <= region.free_bytes);
+            }
+        };
+
+        unsafe {
+            let ptrs: Vec<_> = (0..8).map(|_| allocator.allocate(layout)).collect();
+            check();
+
+            for &ptr in ptrs.iter().step_by(2) {
+                allocator.deallocate(ptr, layout);
+            }
+
+            check();
+
+            for &ptr in ptrs.iter().skip(1).step_by(2) {
+                allocator.deallocate(ptr, layout);
+            }
+
+            check();
+        }
+
+        // Requests bigger than every free block don't walk the free list.
+        let mut kernel = allocator.allocator.lock();
+        let huge = Layout::from_size_align(kernel.config.large_object_threshold - 1, 8).unwrap();
+
+        assert!(kernel.regions.iter().all(|region| !region.may_hold(huge.size())));
+        assert_eq!(kernel.find_free_block(huge, None), None);
+
+        // After a miss the hint is below the request, so the next one doesn't even start
+        // the search.
+        assert!(kernel.free_list.largest < huge.size());
+
+        let visited = kernel.free_list.stats.visited;
+        assert_eq!(kernel.find_free_block(huge, None), None);
+        assert_eq!(kernel.free_list.stats.visited, visited);
+    }
+
+    #[cfg(all(unix, not(feature = "sbrk")))]
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend"), ignore = "needs OS memory")]
+    fn contiguous_regions_are_merged() {
+        // Far from anything else in the address space of the test, and from the hint of
+        // `regions_follow_the_address_hint`.
+        const HINT: usize = 0x5b5b_0000_0000;
+        const REGION_SIZE: usize = 128 * 1024;
+
+        let config = Config::new().address_hint(HINT).min_region_size(REGION_SIZE);
+        let allocator = MemAlloc::with_config(config);
+        let layout = Layout::from_size_align(100 * 1024, 8).unwrap();
+
+        unsafe {
+            // The second allocation doesn't fit in the rest of the first region, but it
+            // does once the next mapping is merged with it.
+            let [a, b] = [(); 2].map(|_| allocator.allocate(layout));
+
+            let kernel = allocator.allocator.lock();
+            let region = kernel.regions.first().unwrap();
+
+            assert_eq!(kernel.regions.len(), 1);
+            assert_eq!(region.as_ptr() as usize, HINT);
+            assert_eq!(region.as_ref().data.size, 2 * REGION_SIZE - REGION_HEADER_SIZE);
+            assert!(Region::contains(region, a as usize) && Region::contains(region, b as usize));
+
+            // The rest of the first region was merged with the new block, so the second
+            // allocation starts before the seam.
+            assert_eq!(region.as_ref().data.blocks.len(), 3);
+            assert!((b as usize) < HINT + REGION_SIZE);
+            drop(kernel);
+
+            allocator.deallocate(a, layout);
+            allocator.deallocate(b, layout);
+        }
+
+        // The whole region is given back at once.
+        assert!(allocator.allocator.lock().regions.is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+//! Latency histograms.
+//!
+//! With the `profiling` feature, every [`MemAlloc::allocate`] and [`MemAlloc::deallocate`]
+//! is timed and counted in a histogram, so the tail latency caused by long free list scans
+//! and by the calls to the OS can be measured. [`MemAlloc::latency`] returns a snapshot of
+//! both histograms:
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::MemAlloc;
+//!
+//! let allocator = MemAlloc::new();
+//! let layout = Layout::from_size_align(100, 8).unwrap();
+//!
+//! unsafe {
+//!     let ptr = allocator.allocate(layout);
+//!     allocator.deallocate(ptr, layout);
+//! }
+//!
+//! let latency = allocator.latency();
+//! assert_eq!(latency.allocate.count(), 1);
+//! assert_eq!(latency.deallocate.count(), 1);
+//!
+//! let p99 = latency.allocate.percentile(99.0).unwrap();
+//! println!("99% of the allocations took less than {p99:?}");
+//! ```
+//!
+//! Buckets are powers of two of nanoseconds: bucket `i` counts the calls that took from
+//! `2^i` up to `2^(i + 1)` nanoseconds, except the first one that also counts those that
+//! took less than a nanosecond. That is precise enough to tell a free list hit from a
+//! `mmap` call, and recording is a single atomic increment outside the lock. Times are
+//! taken from [`Instant`], which doesn't allocate and is monotonic.
+//!
+//! Zero-sized allocations and the allocations the lock makes for itself (see the `sync`
+//! module) are not timed.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use lock_api::RawMutex;
+
+use crate::memalloc::MemAlloc;
+
+/// Number of buckets of a [`LatencyHistogram`], enough for any [`Duration`] that fits in
+/// a `u64` of nanoseconds.
+pub const LATENCY_BUCKETS: usize = u64::BITS as usize;
+
+/// Counts of calls by how long they took, on a log scale. See the `latency` module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS],
+}
+
+impl LatencyHistogram {
+    /// Number of calls counted.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Number of calls counted in each bucket, from the fastest to the slowest.
+    pub fn buckets(&self) -> &[u64; LATENCY_BUCKETS] {
+        &self.buckets
+    }
+
+    /// Exclusive upper bound of the times counted in the bucket `index`.
+    pub fn upper_bound(index: usize) -> Duration {
+        Duration::from_nanos(1u64.checked_shl(index as u32 + 1).unwrap_or(u64::MAX))
+    }
+
+    /// Upper bound of the bucket in which the `percentile` (between 0 and 100) of the
+    /// calls had already finished, or `None` if no calls were counted.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let count = self.count();
+
+        if count == 0 {
+            return None;
+        }
+
+        let target = ((percentile.clamp(0.0, 100.0) / 100.0) * count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+
+        for (index, &calls) in self.buckets.iter().enumerate() {
+            seen += calls;
+
+            if seen >= target {
+                return Some(Self::upper_bound(index));
+            }
+        }
+
+        Some(Self::upper_bound(LATENCY_BUCKETS - 1))
+    }
+
+    /// Upper bound of the slowest bucket with any calls, or `None` if there are none.
+    pub fn max(&self) -> Option<Duration> {
+        self.buckets.iter().rposition(|&calls| calls > 0).map(Self::upper_bound)
+    }
+}
+
+/// Latency histograms of an allocator, returned by [`MemAlloc::latency`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Latency {
+    /// Times of [`MemAlloc::allocate`].
+    pub allocate: LatencyHistogram,
+    /// Times of [`MemAlloc::deallocate`].
+    pub deallocate: LatencyHistogram,
+}
+
+/// Histogram that can be recorded without the lock.
+pub(crate) struct AtomicHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl AtomicHistogram {
+    pub(crate) const fn new() -> Self {
+        Self { buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS] }
+    }
+
+    /// Counts a call that started at `start`.
+    #[inline]
+    pub(crate) fn record(&self, start: Instant) {
+        let nanos = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        let index = (u64::BITS - 1 - (nanos | 1).leading_zeros()) as usize;
+
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        LatencyHistogram { buckets: std::array::from_fn(|index| self.buckets[index].load(Ordering::Relaxed)) }
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Returns the histograms of how long allocations and deallocations took. See the
+    /// `latency` module.
+    pub fn latency(&self) -> Latency {
+        Latency { allocate: self.allocate_latency.snapshot(), deallocate: self.deallocate_latency.snapshot() }
+    }
+
+    /// Clears the histograms of [`MemAlloc::latency`], for example to measure a single
+    /// phase of a program.
+    pub fn reset_latency(&self) {
+        self.allocate_latency.reset();
+        self.deallocate_latency.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::*;
+
+    #[test]
+    fn calls_are_counted_in_log_buckets() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        unsafe {
+            let ptrs: Vec<_> = (0..10).map(|_| allocator.allocate(layout)).collect();
+
+            for ptr in ptrs {
+                allocator.deallocate(ptr, layout);
+            }
+
+            // Not timed.
+            allocator.deallocate(allocator.allocate(Layout::new::<()>()), Layout::new::<()>());
+        }
+
+        let latency = allocator.latency();
+        assert_eq!(latency.allocate.count(), 10);
+        assert_eq!(latency.deallocate.count(), 10);
+
+        let median = latency.allocate.percentile(50.0).unwrap();
+        assert!(median <= latency.allocate.percentile(100.0).unwrap());
+        assert_eq!(latency.allocate.percentile(100.0), latency.allocate.max());
+
+        allocator.reset_latency();
+        assert_eq!(allocator.latency().allocate.count(), 0);
+        assert_eq!(allocator.latency().deallocate.max(), None);
+    }
+
+    #[test]
+    fn buckets_are_powers_of_two() {
+        let histogram = AtomicHistogram::new();
+        histogram.buckets[0].store(3, Ordering::Relaxed);
+        histogram.buckets[10].store(1, Ordering::Relaxed);
+
+        let histogram = histogram.snapshot();
+        assert_eq!(LatencyHistogram::upper_bound(0), Duration::from_nanos(2));
+        assert_eq!(LatencyHistogram::upper_bound(10), Duration::from_nanos(2048));
+        assert_eq!(LatencyHistogram::upper_bound(LATENCY_BUCKETS - 1), Duration::from_nanos(u64::MAX));
+        assert_eq!(histogram.percentile(75.0), Some(Duration::from_nanos(2)));
+        assert_eq!(histogram.percentile(76.0), Some(Duration::from_nanos(2048)));
+        assert_eq!(histogram.max(), Some(Duration::from_nanos(2048)));
+    }
+}
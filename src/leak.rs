@@ -0,0 +1,336 @@
+//! Conservative leak scanner.
+//!
+//! Finds used blocks that can't be reached from a set of roots registered by the user,
+//! which is a cheap way to look for leaks without running the whole program under an
+//! external leak checker. It works like the mark phase of a conservative garbage
+//! collector:
+//!
+//! 1. Every word-aligned value inside the roots is treated as a potential pointer. If it
+//!    points anywhere inside the contents of a used block, that block is reachable.
+//! 2. The contents of every reachable block are scanned the same way, until no new
+//!    block is found.
+//! 3. Every used block that hasn't been reached is reported as leaked.
+//!
+//! Being conservative means that any value that happens to look like a pointer keeps a
+//! block alive, so some leaks may go unnoticed, but a reported block is never referenced
+//! from the roots.
+//!
+//! We can't allocate while scanning since we are the allocator, so the bookkeeping is
+//! done in a scratch mapping requested straight from the OS.
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::MemAlloc;
+//!
+//! let allocator = MemAlloc::new();
+//! let layout = Layout::new::<u64>();
+//!
+//! unsafe {
+//!     let reachable = allocator.allocate(layout);
+//!     let leaked = allocator.allocate(layout);
+//!
+//!     // The root holds the only pointer to `reachable`.
+//!     let root = [reachable];
+//!     allocator.add_root(root.as_ptr().cast(), size_of_val(&root)).unwrap();
+//!
+//!     let mut leaks = Vec::new();
+//!     allocator.find_leaks(|leak| leaks.push(leak.ptr));
+//!     assert_eq!(leaks, [leaked]);
+//!
+//!     allocator.remove_root(root.as_ptr().cast());
+//!     allocator.deallocate(reachable, layout);
+//!     allocator.deallocate(leaked, layout);
+//! }
+//! ```
+
+use std::{mem, ptr::NonNull, slice};
+#[cfg(feature = "call-sites")]
+use std::panic::Location;
+
+use lock_api::RawMutex;
+
+use crate::{
+    error::AllocError,
+    kernel::{Kernel, request_memory, return_memory},
+    memalloc::MemAlloc,
+    sync,
+    utils::align,
+};
+
+/// Maximum number of roots that can be registered with [`MemAlloc::add_root`].
+pub const MAX_LEAK_ROOTS: usize = 32;
+
+/// A used block that is not reachable from any root. See [`MemAlloc::find_leaks`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Leak {
+    /// Start of the contents of the block. This is the pointer returned by the allocator
+    /// unless the allocation was aligned to more than a word.
+    pub ptr: *mut u8,
+    /// Usable size of the block, which may be bigger than the size requested.
+    pub size: usize,
+    /// Source location that allocated the block. See [`MemAlloc::call_site`].
+    #[cfg(feature = "call-sites")]
+    pub call_site: Option<&'static Location<'static>>,
+}
+
+/// Memory ranges registered with [`MemAlloc::add_root`].
+pub(crate) struct LeakRoots {
+    roots: [(usize, usize); MAX_LEAK_ROOTS],
+    len: usize,
+}
+
+impl LeakRoots {
+    pub(crate) const fn new() -> Self {
+        Self { roots: [(0, 0); MAX_LEAK_ROOTS], len: 0 }
+    }
+}
+
+/// Contents of a used block and whether it has been reached.
+#[derive(Clone, Copy)]
+struct Span {
+    start: usize,
+    end: usize,
+    reached: bool,
+    #[cfg(feature = "call-sites")]
+    call_site: Option<&'static Location<'static>>,
+}
+
+/// Scratch mapping used to find leaks. It holds every used block sorted by address,
+/// followed by the stack of reached blocks whose contents haven't been scanned yet.
+struct Scratch {
+    addr: NonNull<u8>,
+    len: usize,
+    spans: usize,
+}
+
+impl Scratch {
+    /// Maps enough memory for `count` blocks.
+    fn new(count: usize) -> Option<Self> {
+        let len = align(count * (mem::size_of::<Span>() + mem::size_of::<usize>()), crate::kernel::page_size());
+
+        // The mapping can't be empty.
+        let len = len.max(crate::kernel::page_size());
+        let addr = unsafe { request_memory(len, false)? };
+
+        Some(Self { addr, len, spans: count })
+    }
+
+    fn spans(&mut self) -> &mut [Span] {
+        unsafe { slice::from_raw_parts_mut(self.addr.as_ptr().cast(), self.spans) }
+    }
+
+    /// Returns the spans and the stack of pending spans.
+    fn split(&mut self) -> (&mut [Span], &mut [usize]) {
+        unsafe {
+            let spans = slice::from_raw_parts_mut(self.addr.as_ptr().cast(), self.spans);
+            let stack = self.addr.as_ptr().add(self.spans * mem::size_of::<Span>()).cast();
+
+            (spans, slice::from_raw_parts_mut(stack, self.spans))
+        }
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        unsafe { return_memory(self.addr.as_ptr(), self.len) };
+    }
+}
+
+impl Kernel {
+    /// Marks every block reachable from the roots. Returns the scratch memory where the
+    /// blocks are recorded, or `None` if it can't be mapped.
+    fn mark_reachable(&self) -> Option<Scratch> {
+        let mut count = 0;
+        self.for_each_used_block(|_, _, _| count += 1);
+
+        let mut scratch = Scratch::new(count)?;
+
+        let spans = scratch.spans();
+        let mut i = 0;
+
+        self.for_each_used_block(|_block, start, end| {
+            spans[i] = Span {
+                start,
+                end,
+                reached: false,
+                #[cfg(feature = "call-sites")]
+                call_site: _block.call_site,
+            };
+            i += 1;
+        });
+
+        // Sorting in place doesn't allocate.
+        spans.sort_unstable_by_key(|span| span.start);
+
+        let (spans, stack) = scratch.split();
+        let mut pending = 0;
+
+        let roots = &self.leak_roots;
+
+        for &(start, len) in &roots.roots[..roots.len] {
+            unsafe { scan(start, start + len, spans, stack, &mut pending) };
+        }
+
+        while pending > 0 {
+            pending -= 1;
+            let span = spans[stack[pending]];
+
+            unsafe { scan(span.start, span.end, spans, stack, &mut pending) };
+        }
+
+        Some(scratch)
+    }
+}
+
+/// Marks every block pointed to by a word in `[start, end)` and pushes it to `stack`.
+///
+/// # Safety
+///
+/// The whole range must be readable.
+unsafe fn scan(start: usize, end: usize, spans: &mut [Span], stack: &mut [usize], pending: &mut usize) {
+    let word = mem::size_of::<usize>();
+    let mut addr = align(start, word);
+
+    while addr + word <= end {
+        // This can read uninitialized memory, so we don't let the compiler reason about it.
+        let value = unsafe { (addr as *const usize).read_volatile() };
+
+        // Last span that starts before `value`.
+        let index = spans.partition_point(|span| span.start <= value);
+
+        if index > 0 {
+            let span = &mut spans[index - 1];
+
+            if value < span.end && !span.reached {
+                span.reached = true;
+                stack[*pending] = index - 1;
+                *pending += 1;
+            }
+        }
+
+        addr += word;
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Registers the memory of size `len` starting from `ptr` as a root for
+    /// [`MemAlloc::find_leaks`]. Global variables and long-lived data structures that own
+    /// allocations are good roots. Fails if [`MAX_LEAK_ROOTS`] roots are registered.
+    ///
+    /// # Safety
+    ///
+    /// The memory must stay readable until it is removed with [`MemAlloc::remove_root`].
+    pub unsafe fn add_root(&self, ptr: *const u8, len: usize) -> Result<(), AllocError> {
+        let mut kernel = sync::lock(&self.allocator);
+        let roots = &mut kernel.leak_roots;
+
+        if roots.len == MAX_LEAK_ROOTS {
+            return Err(AllocError::LimitExceeded);
+        }
+
+        roots.roots[roots.len] = (ptr as usize, len);
+        roots.len += 1;
+
+        Ok(())
+    }
+
+    /// Removes the root starting from `ptr` registered with [`MemAlloc::add_root`].
+    pub fn remove_root(&self, ptr: *const u8) {
+        let mut kernel = sync::lock(&self.allocator);
+        let roots = &mut kernel.leak_roots;
+
+        if let Some(index) = roots.roots[..roots.len].iter().position(|&(start, _)| start == ptr as usize) {
+            roots.roots.copy_within(index + 1..roots.len, index);
+            roots.len -= 1;
+        }
+    }
+
+    /// Calls `report` with every used block that can't be reached from the roots
+    /// registered with [`MemAlloc::add_root`]. Returns the number of leaked blocks.
+    ///
+    /// The lock is held while looking for leaks but not while `report` is called, so it
+    /// can allocate. Blocks freed in the meantime may still be reported.
+    pub fn find_leaks(&self, mut report: impl FnMut(Leak)) -> usize {
+        let Some(mut scratch) = self.lock_draining().mark_reachable() else {
+            return 0;
+        };
+
+        let mut leaks = 0;
+
+        for span in scratch.spans().iter().filter(|span| !span.reached) {
+            report(Leak {
+                ptr: span.start as *mut u8,
+                size: span.end - span.start,
+                #[cfg(feature = "call-sites")]
+                call_site: span.call_site,
+            });
+            leaks += 1;
+        }
+
+        leaks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{alloc::Layout, ptr};
+
+    use super::*;
+
+    #[test]
+    fn reports_unreachable_blocks() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::new::<[usize; 2]>();
+
+        unsafe {
+            // root -> a -> b, c is leaked and d is only referenced by c.
+            let [a, b, c, d] = [(); 4].map(|_| allocator.allocate(layout) as *mut usize);
+
+            a.write(b as usize);
+            c.write(d as usize);
+
+            // Interior pointers keep blocks alive too.
+            b.write(0);
+            b.add(1).write(0);
+            a.add(1).write(b.add(1) as usize);
+            d.write(0);
+            d.add(1).write(0);
+
+            let root = [a];
+            allocator.add_root(root.as_ptr().cast(), mem::size_of_val(&root)).unwrap();
+
+            let mut leaks = Vec::new();
+            let count = allocator.find_leaks(|leak| leaks.push(leak.ptr as *mut usize));
+
+            leaks.sort();
+            let mut expected = vec![c, d];
+            expected.sort();
+
+            assert_eq!(count, 2);
+            assert_eq!(leaks, expected);
+
+            allocator.remove_root(root.as_ptr().cast());
+            assert_eq!(allocator.find_leaks(|_| {}), 4);
+
+            for ptr in [a, b, c, d] {
+                allocator.deallocate(ptr.cast(), layout);
+            }
+
+            assert_eq!(allocator.find_leaks(|_| {}), 0);
+        }
+    }
+
+    #[test]
+    fn root_table_is_bounded() {
+        let allocator = MemAlloc::new();
+        let value = 0usize;
+
+        unsafe {
+            for _ in 0..MAX_LEAK_ROOTS {
+                allocator.add_root(ptr::from_ref(&value).cast(), 8).unwrap();
+            }
+
+            assert!(allocator.add_root(ptr::from_ref(&value).cast(), 8).is_err());
+        }
+    }
+}
@@ -54,17 +54,113 @@
 //! The main optimizations which are implemented are:
 //! - **Block splitting**: we split a block to avoid wasting unnecessary space
 //! - **Block merging**: we merge adjacent blocks into a bigger one
+//! - **Large objects**: huge allocations get their own mapping instead of going through the blocks
 //! 
 //! The main structure is [`MemAlloc`], you can follow the codebase from there.
 
 
+mod config;
+mod error;
 mod list;
 mod freelist;
+mod placement;
 mod block;
 mod region;
+mod pool;
 mod kernel;
 mod utils;
 mod memalloc;
+mod boxed;
+mod cacheline;
+mod batch;
+mod executable;
+mod seal;
+mod compact;
+mod handle;
+mod heap;
+mod forbid;
+mod inject;
+mod oom;
+mod hardened;
+mod virtual_vec;
+mod snapshot;
+mod deferred;
+mod quarantine;
+mod fence;
+mod corruption;
+mod pressure;
+mod unmapper;
+mod decay;
+mod syscalls;
+#[cfg(unix)]
+mod foreign;
+#[cfg(any(unix, windows))]
+mod shared;
+mod sync;
+#[cfg(all(unix, feature = "fork-safety"))]
+mod fork;
+#[cfg(feature = "leak-scanner")]
+mod leak;
+#[cfg(feature = "tagging")]
+mod tag;
+#[cfg(feature = "call-sites")]
+mod call_site;
+#[cfg(feature = "prometheus")]
+mod metrics;
+#[cfg(feature = "massif")]
+mod massif;
+#[cfg(feature = "dhat")]
+mod dhat;
+#[cfg(feature = "trace")]
+mod trace;
+#[cfg(feature = "collections")]
+mod collections;
+#[cfg(feature = "profiling")]
+mod latency;
+#[cfg(feature = "log")]
+mod diagnostics;
 
 
-pub use memalloc::MemAlloc;
\ No newline at end of file
+pub use memalloc::MemAlloc;
+pub use error::AllocError;
+pub use boxed::{AllocBox, Heap};
+pub use cacheline::{CachePadded, CACHE_LINE_SIZE};
+pub use handle::Handle;
+pub use heap::{heaps, HeapSummary, MemoryUsage, Stats, MAX_HEAPS};
+pub use forbid::ForbidAllocGuard;
+pub use inject::FailureInjection;
+pub use oom::OomAction;
+pub use hardened::ClassStats;
+pub use freelist::{SearchStats, SEARCH_BUCKETS};
+pub use virtual_vec::VirtualVec;
+pub use snapshot::Snapshot;
+pub use quarantine::MAX_QUARANTINED_REGIONS;
+pub use unmapper::MAX_PENDING_UNMAPS;
+pub use syscalls::Syscalls;
+pub use corruption::{Corruption, CorruptionKind, CorruptionPolicy, DeallocError};
+#[cfg(any(unix, windows))]
+pub use shared::SharedHeap;
+#[cfg(all(target_os = "uefi", feature = "uefi", not(any(miri, feature = "system-backend"))))]
+pub use kernel::uefi::set_uefi_system_table;
+pub use sync::{DefaultRawMutex, InterruptMask, NoInterruptMask, SpinRawMutex, StdRawMutex};
+pub use lock_api;
+pub use placement::{FreeBlock, FreeBlocks, PlacementStrategy};
+pub use config::{CommitCharge, Config, Decommit, FitPolicy, HugePages, HUGE_PAGE_SIZE, LARGE_OBJECT_THRESHOLD, MIN_ALIGN};
+#[cfg(all(unix, feature = "fork-safety"))]
+pub use fork::MAX_FORK_HANDLERS;
+#[cfg(feature = "leak-scanner")]
+pub use leak::{Leak, MAX_LEAK_ROOTS};
+#[cfg(feature = "prometheus")]
+pub use metrics::{write_metrics, Metrics};
+#[cfg(feature = "massif")]
+pub use massif::Massif;
+#[cfg(feature = "dhat")]
+pub use dhat::{DhatSite, MAX_DHAT_SITES};
+#[cfg(feature = "trace")]
+pub use trace::{Trace, TraceEvent};
+#[cfg(feature = "collections")]
+pub use collections::{AllocHashMap, AllocString, AllocVec};
+#[cfg(feature = "tagging")]
+pub use tag::{current_tag, set_tag, TagGuard, MAX_TAGS};
+#[cfg(feature = "profiling")]
+pub use latency::{Latency, LatencyHistogram, LATENCY_BUCKETS};
\ No newline at end of file
@@ -1,8 +1,13 @@
 use std::{marker::PhantomData, ptr::NonNull};
 
+use crate::utils::abort_with;
+
 /// Non-null pointer to `T`.
 pub(crate) type Link<T> = Option<NonNull<T>>;
 
+/// Node of a [`List`]. It is `repr(C)` so that `data` is always placed last, right
+/// before whatever memory follows the node (see [`crate::block::Block`]).
+#[repr(C)]
 pub(crate) struct Node<T> {
     /// Pointer to the next node of the list
     pub next: Link<Self>,
@@ -53,8 +58,7 @@ impl<T> List<T> {
 
     /// Returns the last element of the list
     #[inline]
-    #[cfg(test)]
-    fn last(&self) -> Link<Node<T>> {
+    pub fn last(&self) -> Link<Node<T>> {
         self.tail
     }
 
@@ -63,6 +67,17 @@ impl<T> List<T> {
     pub fn is_empty(&self) -> bool {
         self.len == 0
     }
+
+    /// Returns the node after `node` in a list whose links are XORed with `key`. See
+    /// [`List::append_node_keyed`].
+    ///
+    /// # Safety
+    ///
+    /// `node` must be a valid node of this list.
+    #[inline]
+    pub unsafe fn next_keyed(&self, node: NonNull<Node<T>>, key: usize) -> Link<Node<T>> {
+        unsafe { decode(node.as_ref().next, key) }
+    }
     
     /// Appends a new node to the Linked List. 
     /// 
@@ -80,21 +95,78 @@ impl<T> List<T> {
         unsafe {
             node.as_ptr().write(Node {
                 next: None,
-                prev: self.tail,
+                prev: None,
                 data,
             });
 
+            self.append_node(node);
+
+            node
+        }
+    }
+
+    /// Links an already existing `node` at the end of the list.
+    /// 
+    /// This is useful to move a node from one list to another without having
+    /// to copy its data, since the node stays at the same address.
+    /// 
+    /// # Safety
+    /// 
+    /// Caller must guarantee that `node` is valid and it is not linked to any list,
+    /// for example, because it has just been removed using [`List::remove`].
+    pub unsafe fn append_node(&mut self, node: NonNull<Node<T>>) {
+        unsafe { self.append_node_keyed(node, 0) }
+    }
+
+    /// Same as [`List::append_node`], but the `next` and `prev` links of the nodes are
+    /// stored XORed with `key`, like the safe-linking of glibc does. A link overwritten
+    /// by someone who doesn't know the key decodes to a random address, which is most
+    /// likely misaligned for a node, and reading such a link aborts the process instead
+    /// of following it. See [`crate::freelist::FreeList`].
+    ///
+    /// `key` must be odd, so no link is stored as null, or 0 to store links as they are.
+    /// Every node of the list must be linked and unlinked with the same key, and
+    /// [`List::iter`] and [`List::insert_after`] can't be used on it.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`List::append_node`].
+    pub unsafe fn append_node_keyed(&mut self, node: NonNull<Node<T>>, key: usize) {
+        unsafe {
+            (*node.as_ptr()).next = None;
+            (*node.as_ptr()).prev = encode(self.tail, key);
+
             if let Some(mut tail) = self.tail {
-                tail.as_mut().next = Some(node);
+                tail.as_mut().next = encode(Some(node), key);
             } else {
                 self.head = Some(node);
             }
+        }
 
-            self.tail = Some(node);
-            self.len += 1;
+        self.tail = Some(node);
+        self.len += 1;
+    }
 
-            node
+    /// Same as [`List::append_node_keyed`], but the node is linked at the start of the
+    /// list.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`List::append_node`].
+    pub unsafe fn prepend_node_keyed(&mut self, node: NonNull<Node<T>>, key: usize) {
+        unsafe {
+            (*node.as_ptr()).prev = None;
+            (*node.as_ptr()).next = encode(self.head, key);
+
+            if let Some(mut head) = self.head {
+                head.as_mut().prev = encode(Some(node), key);
+            } else {
+                self.tail = Some(node);
+            }
         }
+
+        self.head = Some(node);
+        self.len += 1;
     }
 
     /// Inserts a new block right after the given `node` in the list.
@@ -162,20 +234,30 @@ impl<T> List<T> {
     /// - The given `node` must be part of the current instance of `List`. If we try to remove
     ///   a node which belongs to another list we will cause UB.
     pub unsafe fn remove(&mut self, node: NonNull<Node<T>>) {
+        unsafe { self.remove_keyed(node, 0) }
+    }
+
+    /// Same as [`List::remove`] for a list whose links are XORed with `key`. See
+    /// [`List::append_node_keyed`].
+    ///
+    /// # Safety
+    ///
+    /// Same as [`List::remove`].
+    pub unsafe fn remove_keyed(&mut self, node: NonNull<Node<T>>, key: usize) {
         unsafe {
-            let prev = node.as_ref().prev;
-            let next = node.as_ref().next;
+            let prev = decode(node.as_ref().prev, key);
+            let next = decode(node.as_ref().next, key);
 
             // Link prev -> next
             if let Some(mut prev_node) = prev {
-                prev_node.as_mut().next = next;
+                prev_node.as_mut().next = encode(next, key);
             } else {
                 self.head = next;
             }
 
             // Link next -> prev
             if let Some(mut next_node) = next {
-                next_node.as_mut().prev = prev;
+                next_node.as_mut().prev = encode(prev, key);
             } else {
                 // Node was the tail
                 self.tail = prev;
@@ -194,6 +276,26 @@ impl<T> List<T> {
     }
 }
 
+/// Returns `link` as it is stored in a node of a list whose links are XORed with `key`.
+/// See [`List::append_node_keyed`].
+#[inline]
+fn encode<T>(link: Link<T>, key: usize) -> Link<T> {
+    link.and_then(|ptr| NonNull::new(ptr.as_ptr().map_addr(|addr| addr ^ key)))
+}
+
+/// Returns the link stored in a node of a list whose links are XORed with `key`,
+/// aborting the process if it has been corrupted. See [`List::append_node_keyed`].
+#[inline]
+fn decode<T>(link: Link<Node<T>>, key: usize) -> Link<Node<T>> {
+    let link = encode(link, key);
+
+    if key != 0 && link.is_some_and(|ptr| !ptr.is_aligned()) {
+        abort_with(b"memalloc: corrupted link in a free list\n");
+    }
+
+    link
+}
+
 impl<'a, T> Iterator for Iter<'a, T> {
     type Item = &'a T;
 
@@ -438,4 +540,41 @@ mod tests {
             clean_up_node(n1);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn keyed_links_are_not_stored_as_they_are() {
+        unsafe {
+            let mut list = List::<i32>::new();
+            let key = 0x5A5A_5A5B;
+
+            let nodes: Vec<_> = [10, 20, 30]
+                .into_iter()
+                .map(|data| {
+                    let node = get_memory_for_node::<i32>().cast::<Node<i32>>();
+                    node.as_ptr().write(Node { next: None, prev: None, data });
+                    list.append_node_keyed(node, key);
+                    node
+                })
+                .collect();
+
+            // The nodes only hold the encoded links.
+            assert_ne!(nodes[0].as_ref().next, Some(nodes[1]));
+            assert_eq!(list.next_keyed(nodes[0], key), Some(nodes[1]));
+            assert_eq!(list.next_keyed(nodes[2], key), None);
+
+            list.remove_keyed(nodes[1], key);
+
+            assert_eq!(list.len(), 2);
+            assert_eq!(list.next_keyed(nodes[0], key), Some(nodes[2]));
+
+            list.remove_keyed(nodes[0], key);
+
+            assert_eq!(list.first(), Some(nodes[2]));
+            assert_eq!(nodes[2].as_ref().prev, None);
+
+            for node in nodes {
+                clean_up_node(node);
+            }
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Massif-compatible heap profiles.
+//!
+//! A [`Massif`] recorder takes samples of the live allocations of an allocator, grouped
+//! by the call site that allocated them (see [`MemAlloc::call_site`]), and writes them in
+//! the output format of Valgrind's Massif tool. The file can then be read with `ms_print`
+//! or massif-visualizer to see how the heap grows over time and which lines use it:
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::{Massif, MemAlloc};
+//!
+//! let allocator = MemAlloc::new();
+//! let mut massif = Massif::new("my-program");
+//! let layout = Layout::from_size_align(1000, 8).unwrap();
+//!
+//! unsafe {
+//!     let ptrs: Vec<_> = (0..10)
+//!         .map(|_| {
+//!             massif.sample(&allocator).unwrap();
+//!             allocator.allocate(layout)
+//!         })
+//!         .collect();
+//!
+//!     let mut profile = Vec::new();
+//!     massif.write(&mut profile).unwrap();
+//!     // std::fs::write("massif.out.1", profile).unwrap();
+//!
+//!     for ptr in ptrs {
+//!         allocator.deallocate(ptr, layout);
+//!     }
+//! }
+//! ```
+//!
+//! Samples are taken when [`Massif::sample`] is called, so calling it periodically (from
+//! a timer thread, for example) gives a profile over time. Times are milliseconds since
+//! the recorder was created.
+//!
+//! The lock is held while walking the blocks, so the live blocks are recorded in a
+//! [`VirtualVec`] that is mapped from the OS. Grouping and storing the sample happens
+//! after releasing it, so the recorder can be used with the global allocator. Hardened
+//! allocations have no call site and are not recorded.
+
+use std::{
+    cmp::Reverse,
+    io::{self, Write},
+    panic::Location,
+    time::Instant,
+};
+
+use lock_api::RawMutex;
+
+use crate::{
+    block::{BLOCK_FOOTER_SIZE, BLOCK_HEADER_SIZE},
+    error::AllocError,
+    memalloc::MemAlloc,
+    sync,
+    virtual_vec::VirtualVec,
+};
+
+/// Heap sample taken by [`Massif::sample`].
+struct Sample {
+    /// Milliseconds since the recorder was created.
+    time: u128,
+    /// Usable bytes of the live blocks.
+    heap: usize,
+    /// Bytes of the headers and footers of the live blocks.
+    extra: usize,
+    /// Usable bytes of the live blocks allocated by every call site, from the biggest.
+    sites: Vec<(Option<&'static Location<'static>>, usize)>,
+}
+
+/// Recorder of heap profiles in the format of Valgrind's Massif. See the `massif` module.
+pub struct Massif {
+    /// Command shown in the profile.
+    cmd: String,
+    /// When the recorder was created.
+    start: Instant,
+    samples: Vec<Sample>,
+}
+
+impl Massif {
+    /// Creates a recorder with no samples. `cmd` is the command shown in the profile.
+    pub fn new(cmd: &str) -> Self {
+        Self { cmd: cmd.to_owned(), start: Instant::now(), samples: Vec::new() }
+    }
+
+    /// Number of samples taken.
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Whether no sample has been taken.
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Records the live allocations of `allocator`. Fails if the memory needed to walk
+    /// the blocks can't be mapped.
+    pub fn sample<R: RawMutex>(&mut self, allocator: &MemAlloc<R>) -> Result<(), AllocError> {
+        let mut blocks = {
+            let kernel = sync::lock(&allocator.allocator);
+
+            let mut count = 0;
+            kernel.for_each_used_block(|_, _, _| count += 1);
+
+            let mut blocks = VirtualVec::new(count)?;
+            kernel.for_each_used_block(|block, start, end| {
+                // It can't be full, the number of blocks didn't change.
+                let _ = blocks.push((block.call_site, end - start));
+            });
+
+            blocks
+        };
+
+        blocks.sort_unstable_by_key(|&(site, _)| site);
+
+        let mut sites: Vec<(Option<&'static Location<'static>>, usize)> = Vec::new();
+
+        for &(site, size) in blocks.iter() {
+            match sites.last_mut() {
+                Some((last, bytes)) if *last == site => *bytes += size,
+                _ => sites.push((site, size)),
+            }
+        }
+
+        sites.sort_by_key(|&(_, size)| Reverse(size));
+
+        self.samples.push(Sample {
+            time: self.start.elapsed().as_millis(),
+            heap: sites.iter().map(|&(_, size)| size).sum(),
+            extra: blocks.len() * (BLOCK_HEADER_SIZE + BLOCK_FOOTER_SIZE),
+            sites,
+        });
+
+        Ok(())
+    }
+
+    /// Writes every sample to `out` in the Massif output format. Every sample is written
+    /// as a detailed snapshot with one entry per call site, and the biggest one is marked
+    /// as the peak.
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "desc: (none)")?;
+        writeln!(out, "cmd: {}", self.cmd)?;
+        writeln!(out, "time_unit: ms")?;
+
+        let peak = (0..self.samples.len()).max_by_key(|&index| self.samples[index].heap + self.samples[index].extra);
+
+        for (index, sample) in self.samples.iter().enumerate() {
+            writeln!(out, "#-----------")?;
+            writeln!(out, "snapshot={index}")?;
+            writeln!(out, "#-----------")?;
+            writeln!(out, "time={}", sample.time)?;
+            writeln!(out, "mem_heap_B={}", sample.heap)?;
+            writeln!(out, "mem_heap_extra_B={}", sample.extra)?;
+            writeln!(out, "mem_stacks_B=0")?;
+            writeln!(out, "heap_tree={}", if peak == Some(index) { "peak" } else { "detailed" })?;
+            writeln!(
+                out,
+                "n{}: {} (heap allocation functions) malloc/new/new[], --alloc-fns, etc.",
+                sample.sites.len(),
+                sample.heap,
+            )?;
+
+            for &(site, size) in &sample.sites {
+                match site {
+                    Some(site) => writeln!(out, " n0: {size} 0x0: ??? ({}:{})", site.file(), site.line())?,
+                    None => writeln!(out, " n0: {size} 0x0: ??? (unknown call site)")?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::*;
+
+    #[test]
+    fn samples_are_grouped_by_call_site() {
+        let allocator = MemAlloc::new();
+        let mut massif = Massif::new("test");
+        let layout = Layout::from_size_align(1000, 8).unwrap();
+
+        unsafe {
+            massif.sample(&allocator).unwrap();
+
+            let (ptrs, line): (Vec<_>, _) = ((0..3).map(|_| allocator.allocate(layout)).collect(), line!());
+            let (other, other_line) = (allocator.allocate(layout), line!());
+
+            massif.sample(&allocator).unwrap();
+            assert_eq!(massif.len(), 2);
+
+            let sample = &massif.samples[1];
+            let sites: Vec<_> = sample.sites.iter().map(|&(site, size)| (site.unwrap().line(), size)).collect();
+            assert_eq!(sites[0].0, line);
+            assert_eq!(sites[1].0, other_line);
+            assert_eq!(sample.heap, sites[0].1 + sites[1].1);
+            assert!(sites[0].1 >= 3 * 1000 && sites[1].1 >= 1000);
+            assert_eq!(sample.extra, 4 * (BLOCK_HEADER_SIZE + BLOCK_FOOTER_SIZE));
+
+            let mut profile = Vec::new();
+            massif.write(&mut profile).unwrap();
+            let profile = String::from_utf8(profile).unwrap();
+
+            assert!(profile.starts_with("desc: (none)\ncmd: test\ntime_unit: ms\n"));
+            assert!(profile.contains("snapshot=0\n#-----------\ntime="));
+            assert!(profile.contains("mem_heap_B=0\n"));
+            assert!(profile.contains("heap_tree=peak\nn2: "));
+            assert!(profile.contains(&format!(" n0: {} 0x0: ??? ({}:{line})\n", sites[0].1, file!())));
+
+            for ptr in ptrs.into_iter().chain([other]) {
+                allocator.deallocate(ptr, layout);
+            }
+        }
+    }
+}
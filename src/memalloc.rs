@@ -1,9 +1,24 @@
-use std::{alloc::{GlobalAlloc, Layout, handle_alloc_error}, mem, ptr::{self, NonNull}, sync::Mutex};
+use std::{alloc::{GlobalAlloc, Layout}, mem, ptr::{self, NonNull}};
+#[cfg(target_pointer_width = "64")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "trace")]
+use std::sync::atomic::AtomicBool;
+#[cfg(feature = "profiling")]
+use crate::latency::AtomicHistogram;
+
+use lock_api::{Mutex, RawMutex};
 
 use crate::{
-    block::{BLOCK_HEADER_SIZE, Block}, 
-    kernel::Kernel, 
+    block::{BLOCK_FOOTER_SIZE, Block}, 
+    region::RegionKind,
+    config::Config,
+    kernel::{Kernel, Protection}, 
+    sync::{self, DefaultRawMutex},
     list::Node, 
+    deferred::DeferredFrees,
+    heap::StatsCounters,
+    corruption::{Corruption, CorruptionKind, DeallocError},
+    error::AllocError,
 };
 
 
@@ -11,8 +26,37 @@ use crate::{
 /// goint to split a block, and the remaining size is less than
 /// this value:
 /// - It does not make any sense to split it.
-/// - We wouldn't be able to store the [`FreeList`] block metadata
-pub(crate) const MIN_BLOCK_SIZE: usize = mem::size_of::<Node<NonNull<Node<Block>>>>(); 
+/// - We wouldn't be able to store the [`FreeList`] block metadata and
+///   the block footer (see [`Block::write_footer`])
+pub(crate) const MIN_BLOCK_SIZE: usize = mem::size_of::<Node<NonNull<Node<Block>>>>() + BLOCK_FOOTER_SIZE;
+
+/// Number of zero-sized allocations made by every allocator of the process. See
+/// [`zero_sized`].
+#[cfg(target_pointer_width = "64")]
+static ZERO_SIZED: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns a dangling pointer aligned to `align` for a zero-sized allocation, different
+/// from the ones of the other zero-sized allocations. They are taken from the upper half
+/// of the address space, which is never user memory on 64 bit targets, so they don't
+/// alias real allocations either. Addresses only repeat after `2^63 / align` of them.
+#[cfg(target_pointer_width = "64")]
+fn zero_sized(align: usize) -> NonNull<u8> {
+    let index = ZERO_SIZED.fetch_add(1, Ordering::Relaxed);
+
+    // Alignments are powers of two no bigger than the start, so every address is a
+    // multiple of `align`, even once it wraps around.
+    let addr = (1usize << (usize::BITS - 1)).wrapping_add(index.wrapping_mul(align));
+
+    unsafe { NonNull::new_unchecked(ptr::without_provenance_mut(addr.max(align))) }
+}
+
+/// On narrower targets any address may belong to a real allocation, so there is no range
+/// to take unique ones from. Every zero-sized allocation aligned to `align` gets `align`
+/// itself, like [`NonNull::dangling`] does, which is never mapped.
+#[cfg(not(target_pointer_width = "64"))]
+fn zero_sized(align: usize) -> NonNull<u8> {
+    unsafe { NonNull::new_unchecked(ptr::without_provenance_mut(align)) }
+}
 
 
 /// The main allocator's Struct. 
@@ -23,16 +67,72 @@ pub(crate) const MIN_BLOCK_SIZE: usize = mem::size_of::<Node<NonNull<Node<Block>
 /// The kernel is behind a `Mutex` in order to allow secure mutability. This is because [`GlobalAlloc`]
 /// methods take &self reference, but the internal Allocator (`Kernel`) requires mutation. By using a `Mutex`
 /// we allow safe concurrent access and satisfy the trait signature.
-pub struct MemAlloc {
-    allocator: Mutex<Kernel>,
+/// 
+/// The mutex is generic over any [`RawMutex`], defaulting to [`DefaultRawMutex`]. See
+/// [`MemAlloc::with_lock`] to use a different one.
+pub struct MemAlloc<R: RawMutex = DefaultRawMutex> {
+    pub(crate) allocator: Mutex<R, Kernel>,
+    /// Copy of the configuration of the kernel that can be read without the lock.
+    pub(crate) config: Config,
+    /// Frees waiting for the lock. See the `deferred` module.
+    pub(crate) deferred: DeferredFrees,
+    /// Stats of the live allocations, which can be read without the lock. See
+    /// [`MemAlloc::stats`].
+    pub(crate) stats: StatsCounters,
+    /// Whether a trace is running, so the lock is only taken to record events if it is.
+    /// See [`MemAlloc::start_trace`].
+    #[cfg(feature = "trace")]
+    pub(crate) tracing: AtomicBool,
+    /// Times of [`MemAlloc::allocate`]. See [`MemAlloc::latency`].
+    #[cfg(feature = "profiling")]
+    pub(crate) allocate_latency: AtomicHistogram,
+    /// Times of [`MemAlloc::deallocate`]. See [`MemAlloc::latency`].
+    #[cfg(feature = "profiling")]
+    pub(crate) deallocate_latency: AtomicHistogram,
 }
 
 impl MemAlloc {
-    /// Construct a new allocator by constructing its `Kernel`.
-    /// 
-    /// It initializes the `Kernel` inside a `Mutex` to allow safe concurrent access
-    pub const fn new() -> Self {
-        Self { allocator: Mutex::new(Kernel::new()) }
+    sync::const_fn! {
+        /// Construct a new allocator by constructing its `Kernel`.
+        /// 
+        /// It initializes the `Kernel` inside a `Mutex` to allow safe concurrent access
+        pub const fn new() -> Self {
+            Self::with_config(Config::new())
+        }
+    }
+
+    sync::const_fn! {
+        /// Construct a new allocator that uses the given `config`. See [`Config`].
+        pub const fn with_config(config: Config) -> Self {
+            Self::with_lock(config)
+        }
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    sync::const_fn! {
+        /// Construct a new allocator that uses the given `config` and the raw mutex `R`
+        /// to protect its `Kernel`.
+        /// 
+        /// ```rust
+        /// use memalloc::{Config, MemAlloc, StdRawMutex};
+        /// 
+        /// let allocator = MemAlloc::<StdRawMutex>::with_lock(Config::new());
+        /// ```
+        pub const fn with_lock(config: Config) -> Self {
+            Self {
+                allocator: Mutex::new(Kernel::new(config)),
+                config,
+                deferred: DeferredFrees::new(),
+                stats: StatsCounters::new(),
+                #[cfg(feature = "trace")]
+                tracing: AtomicBool::new(false),
+                #[cfg(feature = "profiling")]
+                allocate_latency: AtomicHistogram::new(),
+                #[cfg(feature = "profiling")]
+                deallocate_latency: AtomicHistogram::new(),
+            }
+        }
     }
 
     /// Allocates memory according to the given `layout`.
@@ -41,42 +141,156 @@ impl MemAlloc {
     /// strategy. If no block is found, it creates a new block or allocates a new `Region`
     /// if it is neccessary.
     /// 
+    /// Allocations bigger than [`Config::large_object_threshold`] or aligned to more than
+    /// the page size get their own mapping instead. See [`Kernel::allocate_large`].
+    /// 
+    /// Zero-sized allocations don't use any memory: they return a dangling pointer aligned
+    /// to the layout, and freeing it does nothing. On 64 bit targets it is a different one
+    /// every time, on narrower ones it is the same for every allocation of an alignment.
+    /// 
     /// # Safety
     /// 
     /// This function is unsafe since it deals with raw pointers and manual memory management.
     /// The returned raw pointer is guaranteed to be:
-    /// - Non-null (unless an internall failure occurs which we can't handle)
-    /// - Aligned
+    /// - Non-null, unless the memory runs out and the hook of [`MemAlloc::set_oom_hook`]
+    ///   doesn't manage to release some
+    /// - Aligned to `layout.align()` and at least to [`Config::min_align`]
     /// - Containing at leas `layout.size()` bytes of usable memory.
     #[inline]
+    #[track_caller]
     pub unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
+        unsafe { self.try_allocate(layout) }.map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    /// Same as [`MemAlloc::allocate`], but tells why the allocation failed instead of
+    /// returning null. The hook of [`MemAlloc::set_oom_hook`] is still called first, and
+    /// failures made by [`MemAlloc::inject_failures`] are reported as
+    /// [`AllocError::ExhaustedAddressSpace`].
+    ///
+    /// # Safety
+    ///
+    /// Same as [`MemAlloc::allocate`].
+    #[inline]
+    #[track_caller]
+    pub unsafe fn try_allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        // Zero-sized allocations don't need any memory, so they get a dangling pointer
+        // that is never dereferenced nor freed. See `MemAlloc::deallocate`.
+        if layout.size() == 0 {
+            return Ok(zero_sized(layout.align().max(self.config.min_align)));
+        }
+
+        // The lock itself is asking for memory while we wait for it, so we can't
+        // use the kernel. See `sync::lock`.
+        if sync::is_acquiring() {
+            // Foreign frees would take detached allocations for the C library's.
+            #[cfg(unix)]
+            if self.config.forward_foreign_frees {
+                return NonNull::new(crate::foreign::allocate(layout)).ok_or_else(AllocError::last_os_error);
+            }
 
-        // We adquire the lock. If we encounter any error, we can call the function
-        // `handle_alloc_error` which can either panic or abort the process.
-        let mut kernel = match self.allocator.lock() {
-            Ok(kernel) => kernel,
-            Err(_) => handle_alloc_error(layout),
+            return NonNull::new(Kernel::allocate_detached(layout, self.config.low_address)).ok_or_else(AllocError::last_os_error);
+        }
+
+        Self::check_forbidden(layout);
+
+        #[cfg(feature = "profiling")]
+        let start = std::time::Instant::now();
+
+        let result = loop {
+            match unsafe { self.allocate_locked(layout) } {
+                Err(AllocError::InvalidLayout) => break Err(AllocError::InvalidLayout),
+                Err(error) => {
+                    #[cfg(feature = "log")]
+                    crate::diagnostics::allocation_failed(layout, error);
+
+                    if !Self::retry_after_oom(layout) {
+                        break Err(error);
+                    }
+                }
+                // Injected failures return null without calling the hook.
+                result => break result.and_then(|ptr| NonNull::new(ptr).ok_or(AllocError::ExhaustedAddressSpace)),
+            }
         };
 
-        let mut block = kernel.free_list.find_free_block(layout);
+        #[cfg(feature = "profiling")]
+        self.allocate_latency.record(start);
 
-        if block.is_none() {
-            // There is no block aviable, so we need to allocate a new region
-            kernel.allocate_new_region(layout).unwrap();
-            block = kernel.free_list.find_free_block(layout);
-            
-            if block.is_none() {
-                // There has been an error, what should we do, panic?
-                return ptr::null_mut();
+        #[cfg(feature = "trace")]
+        self.trace_allocation(layout, result.map_or(ptr::null_mut(), NonNull::as_ptr));
+
+        result
+    }
+
+    /// Does the work of [`MemAlloc::try_allocate`] with the lock held. Fails if the memory
+    /// can't be mapped or the allocation goes over the quota, so that the hook of
+    /// [`MemAlloc::set_oom_hook`] can be called once the lock is released, and with
+    /// [`AllocError::InvalidLayout`] if the layout can't be aligned. Injected failures
+    /// just return null.
+    #[inline]
+    #[track_caller]
+    unsafe fn allocate_locked(&self, layout: Layout) -> Result<*mut u8, AllocError> {
+        // We adquire the lock and free what others deferred while it was busy.
+        let mut kernel = self.lock_draining();
+
+        if kernel.failures.fails(layout) {
+            return Ok(ptr::null_mut());
+        }
+
+        if !kernel.fits_quota(&self.stats, layout.size(), self.config.quota) {
+            return Err(AllocError::LimitExceeded);
+        }
+
+        let Ok(layout) = layout.align_to(self.config.min_align) else {
+            return Err(AllocError::InvalidLayout);
+        };
+
+        if self.config.hardened {
+            kernel.init();
+            let ptr = unsafe { kernel.hardened.allocate(layout) };
+
+            if ptr.is_null() {
+                kernel.mapping_failures += 1;
+                return Err(AllocError::last_os_error());
             }
+
+            self.stats.record_allocation(layout.size());
+
+            return Ok(ptr);
         }
 
-        // It doesn't have any sense to call this function unless `block` is not None
-        if let Some(block) = block {
-            unsafe { kernel.take_from_block(block, layout) } 
-        } else {
-            // As far as I'm concerned, this is an unrecoverable error, so the allocator should panic
-            panic!("Internal memory allocation failed");
+        if self.config.electric_fence && layout.align() <= crate::kernel::page_size() {
+            let ptr = kernel.allocate_fenced(layout)?;
+            unsafe { kernel.record_allocation(&self.stats, ptr, layout.size()) };
+
+            return Ok(ptr);
+        }
+
+        if kernel.is_large(layout) {
+            let ptr = kernel.allocate_large(layout)?;
+            unsafe { kernel.record_allocation(&self.stats, ptr, layout.size()) };
+
+            return Ok(ptr);
+        }
+
+        let node = kernel.preferred_node();
+        let mut block = kernel.find_free_block(layout, node);
+
+        if block.is_none() {
+            // There is no block aviable, so we need to allocate a new region, whose
+            // block ends up last in the free list.
+            kernel.allocate_new_region(layout)?;
+            block = kernel.free_list.find_last_free_block(layout);
+        }
+
+        // The new region always holds the layout, but if its block still can't be found
+        // the heap is out of memory all the same, and the OOM hook has to hear about it.
+        let block = block.ok_or(AllocError::ExhaustedAddressSpace)?;
+
+        unsafe {
+            let ptr = kernel.take_from_block(block, layout);
+            kernel.record_allocation(&self.stats, ptr, layout.size());
+
+            Ok(ptr)
         }
     }
     
@@ -86,8 +300,9 @@ impl MemAlloc {
     /// a dynamic amount of padding between the `Node<Block>` header and the user's `ptr`.
     /// 
     /// To solve this, `allocate` stores the address of the `Node<Block>` in the 8 bytes 
-    /// (usize) immediately preceding `ptr`. This is called "Header Reflection". This
-    /// is also the main part where the user of the allocator can experiment UB.
+    /// (usize) immediately preceding `ptr` whenever there is padding. This is called
+    /// "Header Reflection". This is also the main part where the user of the allocator
+    /// can experiment UB. See [`Block::from_payload`].
     /// 
     /// ### Memory Layout:
     /// 
@@ -103,56 +318,285 @@ impl MemAlloc {
     /// Caller must guarantee that:
     /// - `ptr` was allocated by this allocator.
     /// - `layout` is the same layout used for allocation.
+    ///
+    /// A `layout` bigger than the allocation is not freed. Like a double free, it is
+    /// handled as corruption according to [`Config::on_corruption`], which aborts the
+    /// process by default, where such a free used to be a silent no-op.
     #[inline]
+    #[track_caller]
     pub unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
-        if ptr.is_null() {
-            return;
+        #[cfg(feature = "profiling")]
+        let start = std::time::Instant::now();
+
+        // Unless the policy aborts, corrupted blocks are leaked. See `corruption`.
+        let _ = unsafe { self.free(ptr, layout) };
+
+        #[cfg(feature = "profiling")]
+        if !ptr.is_null() && layout.size() != 0 {
+            self.deallocate_latency.record(start);
+        }
+    }
+
+    /// Same as [`MemAlloc::deallocate`], but checks that `ptr` can be freed with `layout`
+    /// before touching anything, which is useful for pointers that come from across an
+    /// FFI boundary and can't be fully trusted. Returns why it can't otherwise:
+    ///
+    /// - [`DeallocError::UnknownPointer`] if `ptr` is not inside any region holding
+    ///   allocations.
+    /// - [`DeallocError::NotAnAllocation`] if it is, but it is not the start of a block
+    ///   handed out by [`MemAlloc::allocate`].
+    /// - [`DeallocError::LayoutMismatch`] if the block is smaller than `layout` or `ptr`
+    ///   isn't aligned to it.
+    /// - [`DeallocError::Corruption`] if the block was already free or its header is
+    ///   corrupted, after responding as [`crate::Config::on_corruption`] says, which
+    ///   aborts the process by default. See the `corruption` module.
+    ///
+    /// Only the first three are returned without freeing anything. Pointers are checked
+    /// by walking the blocks of their region, so this is as slow as
+    /// [`MemAlloc::owns_allocation`]. Hardened allocations are only checked to be live,
+    /// and the allocations the lock makes for itself are not recorded anywhere, so they
+    /// are unknown.
+    ///
+    /// # Safety
+    ///
+    /// Memory that isn't an allocation of this allocator can be freed safely, but it
+    /// must not be freed while someone else may be freeing it too.
+    #[track_caller]
+    pub unsafe fn checked_deallocate(&self, ptr: *mut u8, layout: Layout) -> Result<(), DeallocError> {
+        // Zero-sized allocations are dangling pointers, see `MemAlloc::allocate`.
+        if ptr.is_null() || layout.size() == 0 {
+            return Ok(());
         }
 
-        // We lock the mutex
-        let mut kernel = match self.allocator.lock() {
-            Ok(kernel) => kernel,
-            // What should we do here? This is not an allocation error
-            Err(_) => return,
+        let kernel = self.lock_draining();
+
+        let checked = if self.config.hardened {
+            match kernel.hardened.owns_allocation(ptr.addr()) {
+                true => Ok(()),
+                false if kernel.hardened.owns(ptr.addr()) => Err(DeallocError::NotAnAllocation),
+                false => Err(DeallocError::UnknownPointer),
+            }
+        } else {
+            kernel.validate_deallocation(ptr, layout)
         };
-        
+
+        drop(kernel);
+
+        match checked {
+            Ok(()) => unsafe { self.free(ptr, layout).map_err(DeallocError::Corruption) },
+            Err(DeallocError::Corruption(corruption)) => self.report_corruption(corruption).map_err(DeallocError::Corruption),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Does the work of [`MemAlloc::deallocate`].
+    #[inline]
+    #[track_caller]
+    unsafe fn free(&self, ptr: *mut u8, layout: Layout) -> Result<(), Corruption> {
+        // Zero-sized allocations are dangling pointers, see `MemAlloc::allocate`.
+        if ptr.is_null() || layout.size() == 0 {
+            return Ok(());
+        }
+
+        #[cfg(unix)]
+        if self.is_foreign(ptr) {
+            unsafe { crate::foreign::free(ptr) };
+            return Ok(());
+        }
+
+        #[cfg(feature = "trace")]
+        self.trace_deallocation(ptr, layout);
+
         unsafe {
-            // We read the pointer stored just before the payload.
-            let header_ptr = (ptr as *mut usize).sub(1).read() as *mut Node<Block>;
+            // Hardened allocations have no header. Anything else is a detached one.
+            if self.config.hardened {
+                let mut kernel = self.lock_draining();
+
+                match kernel.hardened.deallocate(ptr, layout.size()) {
+                    Some(true) => self.stats.record_deallocation(layout.size()),
+                    Some(false) => {}
+                    None => {
+                        drop(kernel);
+                        Kernel::deallocate_detached(Block::from_payload(ptr).as_ref().data.region);
+                    }
+                }
+
+                return Ok(());
+            }
 
             // We assume this is a `header`, if it isn't, this will be UB
-            let mut block_node = NonNull::new_unchecked(header_ptr);
+            let block_node = Block::from_payload(ptr);
+
+            if !Block::is_intact(block_node) {
+                return self.report_corruption(Corruption { kind: CorruptionKind::CorruptedHeader, addr: ptr.addr() });
+            }
+
+            // The header of a used block and its region kind never change, so we
+            // can read them before taking the lock.
+            let region = block_node.as_ref().data.region;
+            if region.as_ref().data.kind == RegionKind::Detached {
+                Kernel::deallocate_detached(region);
+                return Ok(());
+            }
 
-            // Block data
-            let block = &mut block_node.as_mut().data;
+            // We lock the mutex, unless someone else holds it. See `deferred`.
+            if let Some(mut kernel) = self.lock_or_defer(block_node, ptr, layout.size()) {
+                kernel.free_block(&self.stats, block_node, ptr, layout.size());
 
-            // If it is already free, we don't do anything
-            if block.is_free {
-                return;
+                if let Some(corruption) = kernel.corruption.take() {
+                    drop(kernel);
+                    return self.report_corruption(corruption);
+                }
             }
 
-            // Mark the block as free to use
-            block.is_free = true;
+            Ok(())
+        }
+    }
+
+    /// Allocates memory for executable code, for example the code cache of a JIT.
+    /// 
+    /// The returned memory is readable and writable, so the code can be copied into it.
+    /// Once it is ready, call [`MemAlloc::make_executable`] to flip it to read-execute,
+    /// and [`MemAlloc::make_writable`] to patch it again. Memory is never writable and
+    /// executable at the same time.
+    /// 
+    /// Each executable allocation gets its own mapping and the returned pointer is always
+    /// page-aligned, so changing its protection never affects any other allocation nor
+    /// the allocator metadata. Release it with [`MemAlloc::deallocate`] as usual.
+    /// 
+    /// Returns null if the memory can't be mapped.
+    /// 
+    /// # Safety
+    /// 
+    /// Same as [`MemAlloc::allocate`].
+    #[track_caller]
+    pub unsafe fn allocate_executable(&self, layout: Layout) -> *mut u8 {
+        Self::check_forbidden(layout);
+
+        let mut kernel = self.lock_draining();
+
+        if kernel.failures.fails(layout) || !kernel.fits_quota(&self.stats, layout.size(), self.config.quota) {
+            return ptr::null_mut();
+        }
+
+        let ptr = kernel.allocate_executable(layout).unwrap_or(ptr::null_mut());
+
+        if !ptr.is_null() {
+            unsafe { kernel.record_allocation(&self.stats, ptr, layout.size()) };
+        }
+
+        ptr
+    }
 
-            // I'm not sure how to use layout here. We can just check if the user is
-            // trying to deallocate more memory than the block has
-            assert!(block.size >= layout.size());
+    /// Flips the memory returned by [`MemAlloc::allocate_executable`] to read-execute.
+    /// 
+    /// On architectures without coherent instruction caches (such as ARM), the caller
+    /// still has to flush the instruction cache before running the code.
+    /// 
+    /// # Safety
+    /// 
+    /// `ptr` must have been returned by [`MemAlloc::allocate_executable`] and the memory
+    /// must not be written after this call.
+    pub unsafe fn make_executable(&self, ptr: *mut u8) -> Result<(), AllocError> {
+        sync::lock(&self.allocator).protect_executable(ptr, Protection::Executable)
+    }
 
-            let mut region = block.region;
+    /// Flips the memory returned by [`MemAlloc::allocate_executable`] back to read-write,
+    /// so the code can be modified. It can't be executed until [`MemAlloc::make_executable`]
+    /// is called again.
+    /// 
+    /// # Safety
+    /// 
+    /// `ptr` must have been returned by [`MemAlloc::allocate_executable`] and the code must
+    /// not be running on any thread.
+    pub unsafe fn make_writable(&self, ptr: *mut u8) -> Result<(), AllocError> {
+        sync::lock(&self.allocator).protect_executable(ptr, Protection::Writable)
+    }
 
-            // Try to merge the block with the previous one.
-            region.as_mut().data.merge_with_prev(&mut block_node, &mut kernel.free_list);
+    /// Makes the allocation `ptr` read-only, so data that is parsed once (for example the
+    /// configuration of the program) can't be corrupted later. Writing to it afterwards
+    /// crashes the program until [`MemAlloc::unseal`] is called. Deallocating a sealed
+    /// allocation is fine, it is unsealed automatically.
+    /// 
+    /// Protections work on whole pages and the pages of an allocation may be shared with
+    /// its neighbours, so only the pages that are fully inside the allocation are sealed.
+    /// Allocations aligned to the page size whose size is a multiple of it are sealed
+    /// entirely. Fails if the allocation doesn't cover any whole page.
+    /// 
+    /// # Safety
+    /// 
+    /// `ptr` must not be written while it is sealed.
+    pub unsafe fn seal(&self, ptr: *mut u8) -> Result<(), AllocError> {
+        sync::lock(&self.allocator).seal(ptr)
+    }
 
-            // Try to merge the block with the next one.
-            region.as_mut().data.merge_with_next(&mut block_node, &mut kernel.free_list);
+    /// Makes the allocation `ptr` sealed by [`MemAlloc::seal`] writable again.
+    pub fn unseal(&self, ptr: *mut u8) -> Result<(), AllocError> {
+        sync::lock(&self.allocator).unseal(ptr)
+    }
 
-            // We re-insert the resulting block on the free list
-            let free_node_addr = block_node.as_ptr().cast::<u8>().add(BLOCK_HEADER_SIZE);
-            kernel.free_list.insert_free_block(block_node, NonNull::new_unchecked(free_node_addr));
+    /// Returns as much cached memory as possible back to the OS, similar to
+    /// `malloc_trim`. This releases every empty region kept alive because of
+    /// [`Config::cached_regions`].
+    /// 
+    /// Returns the number of bytes given back to the OS.
+    pub fn trim(&self) -> usize {
+        self.lock_draining().trim()
+    }
 
-            // Check if we need to remove and munmap the current `region`
-            kernel.check_region_removal(&mut region, block_node);
+    /// Maps `bytes` of memory for allocations up front, as a region with a single free
+    /// block, so a latency-sensitive program can warm the allocator up during its
+    /// initialization and never wait for the OS afterwards, as long as its live
+    /// allocations fit. Calls add up.
+    ///
+    /// The reserved regions are kept mapped even when they become empty, and they are
+    /// never merged with the regions mapped later, which are returned to the OS as usual
+    /// after a burst beyond the reservation. Allocations bigger than [`Config::large_object_threshold`] still
+    /// get their own mapping. Does nothing in hardened mode, whose memory is not split
+    /// into regions. Fails if the memory can't be mapped.
+    pub fn reserve(&self, bytes: usize) -> Result<(), AllocError> {
+        if self.config.hardened || bytes == 0 {
+            return Ok(());
         }
+
+        self.lock_draining().reserve_regions(bytes)
+    }
+
+    /// Makes this allocator safe to use in the child of a `fork`.
+    /// 
+    /// It registers `pthread_atfork` handlers that hold the allocator lock while the
+    /// process forks, so that a child created while another thread was allocating
+    /// doesn't deadlock on its first allocation. See the `fork` module for more detail.
+    /// 
+    /// Returns `false` if [`crate::MAX_FORK_HANDLERS`] allocators are already registered.
+    #[cfg(all(unix, feature = "fork-safety"))]
+    pub fn register_fork_handlers(&'static self) -> bool
+    where
+        R: Sync,
+    {
+        crate::fork::register(self)
+    }
+
+    /// Tells whether `ptr` points inside any memory region mapped by this allocator.
+    /// 
+    /// This is useful to route deallocations between several allocators living in the
+    /// same process. Take into account that it doesn't tell if `ptr` is a live allocation,
+    /// use [`MemAlloc::owns_allocation`] for that.
+    pub fn owns(&self, ptr: *const u8) -> bool {
+        let kernel = sync::lock(&self.allocator);
+
+        kernel.hardened.owns(ptr as usize) || kernel.region_of(ptr as usize).is_some()
+    }
+
+    /// Tells whether `ptr` is a pointer returned by this allocator which has not been
+    /// deallocated yet.
+    /// 
+    /// This is slower than [`MemAlloc::owns`] since it has to walk the blocks of
+    /// the region that contains `ptr`.
+    pub fn owns_allocation(&self, ptr: *const u8) -> bool {
+        let kernel = sync::lock(&self.allocator);
+
+        kernel.hardened.owns_allocation(ptr as usize) || kernel.find_used_block(ptr).is_some()
     }
 
     /// Reallocates the given `ptr` to `new_size`
@@ -164,12 +608,112 @@ impl MemAlloc {
     /// 
     /// As an idea, this implementation can be improved by growing or shrinking the given block.
     /// 
+    /// Large allocations that stay large are resized by remapping their region instead
+    /// (`mremap` on Linux), so they are never copied. See [`Kernel::reallocate_large`].
+    /// 
     /// # Safety
     /// 
     /// Same safety requirements as [`MemAlloc::allocate`] and [`MemAlloc::deallocate`]
     #[inline]
+    #[track_caller]
     pub unsafe fn reallocate(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        if ptr.is_null() {
+        // The allocation and deallocation it may do are part of the same event.
+        #[cfg(feature = "trace")]
+        let untraced = crate::trace::Untraced::new();
+
+        let new_ptr = unsafe { self.resize(ptr, layout, new_size) };
+
+        #[cfg(feature = "trace")]
+        {
+            drop(untraced);
+            self.trace_reallocation(ptr, layout, new_size, new_ptr);
+        }
+
+        new_ptr
+    }
+
+    /// Grows the allocation `ptr` from `old_layout` to `new_layout`, like the unstable
+    /// `Allocator::grow`, so it can be used on stable Rust. The alignment can change too,
+    /// in which case the contents are copied to a new allocation. Otherwise it is the same
+    /// as [`MemAlloc::reallocate`], so large allocations are remapped instead.
+    ///
+    /// Returns null if the memory can't be allocated, and `ptr` is still valid then.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by this allocator with `old_layout` (it can be
+    /// dangling if its size is 0) and `new_layout` can't be smaller than `old_layout`.
+    #[track_caller]
+    pub unsafe fn grow(&self, ptr: *mut u8, old_layout: Layout, new_layout: Layout) -> *mut u8 {
+        debug_assert!(new_layout.size() >= old_layout.size(), "grow can't shrink an allocation");
+
+        unsafe { self.change_layout(ptr, old_layout, new_layout) }
+    }
+
+    /// Same as [`MemAlloc::grow`], but the bytes after the old size are zeroed.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`MemAlloc::grow`].
+    #[track_caller]
+    pub unsafe fn grow_zeroed(&self, ptr: *mut u8, old_layout: Layout, new_layout: Layout) -> *mut u8 {
+        unsafe {
+            let new_ptr = self.grow(ptr, old_layout, new_layout);
+
+            if !new_ptr.is_null() {
+                ptr::write_bytes(new_ptr.add(old_layout.size()), 0, new_layout.size() - old_layout.size());
+            }
+
+            new_ptr
+        }
+    }
+
+    /// Shrinks the allocation `ptr` from `old_layout` to `new_layout`, like the unstable
+    /// `Allocator::shrink`. See [`MemAlloc::grow`]. Shrinking to a size of 0 frees the
+    /// memory and returns a dangling pointer, like [`MemAlloc::allocate`] does for
+    /// zero-sized layouts.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by this allocator with `old_layout` (it can be
+    /// dangling if its size is 0) and `new_layout` can't be bigger than `old_layout`.
+    #[track_caller]
+    pub unsafe fn shrink(&self, ptr: *mut u8, old_layout: Layout, new_layout: Layout) -> *mut u8 {
+        debug_assert!(new_layout.size() <= old_layout.size(), "shrink can't grow an allocation");
+
+        unsafe { self.change_layout(ptr, old_layout, new_layout) }
+    }
+
+    /// Does the work of [`MemAlloc::grow`] and [`MemAlloc::shrink`].
+    #[track_caller]
+    unsafe fn change_layout(&self, ptr: *mut u8, old_layout: Layout, new_layout: Layout) -> *mut u8 {
+        unsafe {
+            if new_layout.size() == 0 {
+                self.deallocate(ptr, old_layout);
+                return self.allocate(new_layout);
+            }
+
+            if old_layout.align() == new_layout.align() {
+                return self.reallocate(ptr, old_layout, new_layout.size());
+            }
+
+            let new_ptr = self.allocate(new_layout);
+
+            if !new_ptr.is_null() {
+                ptr::copy_nonoverlapping(ptr, new_ptr, old_layout.size().min(new_layout.size()));
+                self.deallocate(ptr, old_layout);
+            }
+
+            new_ptr
+        }
+    }
+
+    /// Does the work of [`MemAlloc::reallocate`].
+    #[inline]
+    #[track_caller]
+    unsafe fn resize(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // There is nothing to copy nor to free from a zero-sized allocation.
+        if ptr.is_null() || layout.size() == 0 {
             // We check different edge cases
             if new_size == 0 {
                 return ptr::null_mut();
@@ -177,21 +721,25 @@ impl MemAlloc {
             
             unsafe {
                 let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
-                return self.alloc(new_layout);
+                return self.allocate(new_layout);
             }
         }
 
         if new_size == 0 {
             // In this case the behaviour is the same as dealloc(ptr)
             unsafe {
-                self.dealloc(ptr, layout);
+                self.deallocate(ptr, layout);
                 return ptr::null_mut();
             }
         }
+
+        if let Some(new_ptr) = unsafe { self.reallocate_large(ptr, layout, new_size) } {
+            return new_ptr;
+        }
         
         unsafe {
             let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
-            let new_ptr = self.alloc(new_layout);
+            let new_ptr = self.allocate(new_layout);
 
             if new_ptr.is_null() {
                 // There has been an error while trying to allocate
@@ -202,14 +750,79 @@ impl MemAlloc {
             ptr::copy_nonoverlapping(ptr, new_ptr, size_to_copy);
 
             // We can free the old block
-            self.dealloc(ptr, layout);
+            self.deallocate(ptr, layout);
 
             new_ptr
         }
     }
+
+    /// Resizes `ptr` by remapping its region if it is a large allocation that stays large.
+    /// Returns `None` if it isn't or the platform can't remap it, so it has to be copied.
+    ///
+    /// # Safety
+    ///
+    /// Same safety requirements as [`MemAlloc::reallocate`], with a non-null `ptr`.
+    #[track_caller]
+    unsafe fn reallocate_large(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> Option<*mut u8> {
+        // Remapped regions can be moved anywhere, and only to a page boundary.
+        if self.config.hardened || self.config.low_address || layout.align() > crate::kernel::page_size() {
+            return None;
+        }
+
+        // Foreign pointers have no header, they are copied and given back to the C library.
+        #[cfg(unix)]
+        if self.is_foreign(ptr) {
+            return None;
+        }
+
+        let new_layout = Layout::from_size_align(new_size, layout.align()).ok()?;
+
+        unsafe {
+            let block = Block::from_payload(ptr);
+
+            if block.as_ref().data.region.as_ref().data.kind != RegionKind::Large {
+                return None;
+            }
+
+            Self::check_forbidden(new_layout);
+
+            let mut kernel = self.lock_draining();
+
+            if !kernel.is_large(new_layout) || Block::is_sealed(block) {
+                return None;
+            }
+
+            let growth = new_size.saturating_sub(layout.size());
+
+            if kernel.failures.fails(new_layout) {
+                return Some(ptr::null_mut());
+            }
+
+            if !kernel.fits_quota(&self.stats, growth, self.config.quota) {
+                drop(kernel);
+
+                #[cfg(feature = "log")]
+                crate::diagnostics::allocation_failed(new_layout, AllocError::LimitExceeded);
+
+                return Some(ptr::null_mut());
+            }
+
+            let new_ptr = kernel.reallocate_large(block, ptr, new_size)?;
+
+            kernel.record_reallocation(&self.stats, new_ptr, layout.size(), new_size);
+
+            Some(new_ptr)
+        }
+    }
+}
+
+impl Default for MemAlloc {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-unsafe impl GlobalAlloc for MemAlloc {
+unsafe impl<R: RawMutex> GlobalAlloc for MemAlloc<R> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         unsafe { self.allocate(layout) }
     }
@@ -241,6 +854,8 @@ unsafe impl GlobalAlloc for MemAlloc {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::block::BLOCK_HEADER_SIZE;
+    use crate::config::{Decommit, FitPolicy, LARGE_OBJECT_THRESHOLD, MIN_ALIGN};
 
     #[test]
     fn basic_allocation_and_write() {
@@ -260,6 +875,9 @@ mod tests {
 
             // Check block1 has not been overwritten
             assert_eq!(*block1, 12415);
+
+            allocator.deallocate(block1.cast(), layout);
+            allocator.deallocate(block2.cast(), layout);
         }
     }
 
@@ -270,7 +888,7 @@ mod tests {
             let layout = Layout::new::<u64>();
 
             // Avoid munmaping the region during the test
-            allocator.allocate(Layout::new::<u64>());
+            let keep = allocator.allocate(Layout::new::<u64>());
 
             let block1 = allocator.alloc(layout);
             assert!(!block1.is_null());
@@ -288,6 +906,10 @@ mod tests {
 
             // Whe should get a different block since we haven't deallocated `block2`
             assert_ne!(block3, block2);            
+
+            for block in [keep, block2, block3] {
+                allocator.deallocate(block, layout);
+            }
         }
     }
 
@@ -308,7 +930,7 @@ mod tests {
             let layout = Layout::new::<u8>();
 
             // Avoid munmaping the region during the test
-            allocator.alloc(Layout::new::<u64>());
+            let keep = allocator.alloc(Layout::new::<u64>());
 
             let p1 = allocator.alloc(layout);
             let p2 = allocator.alloc(layout);
@@ -332,6 +954,73 @@ mod tests {
             let p5 = allocator.alloc(Layout::new::<u32>());
             assert_eq!(p3, p5);
 
+            allocator.deallocate(p5, Layout::new::<u32>());
+            allocator.deallocate(keep, Layout::new::<u64>());
+        }
+    }
+
+    #[test]
+    fn boundary_tags_locate_neighbours() {
+        unsafe {
+            let allocator = MemAlloc::new();
+            let layout = Layout::new::<u64>();
+
+            let p1 = allocator.allocate(layout);
+            let p2 = allocator.allocate(Layout::from_size_align(24, 16).unwrap());
+            let p3 = allocator.allocate(layout);
+            allocator.deallocate(p2, Layout::from_size_align(24, 16).unwrap());
+
+            {
+                let kernel = allocator.allocator.lock();
+                let region = kernel.regions.first().unwrap();
+
+                // Walking the blocks through their footers must give the same result
+                // as walking the list of blocks of the region.
+                let mut current = region.as_ref().data.blocks.first();
+                let mut prev = None;
+
+                while let Some(node) = current {
+                    assert_eq!(Block::prev_in_region(node), prev);
+                    prev = Some(node);
+                    current = node.as_ref().next;
+                    assert_eq!(Block::next_in_region(node), current);
+                }
+            }
+
+            allocator.deallocate(p1, layout);
+            allocator.deallocate(p3, layout);
+        }
+    }
+
+    #[test]
+    fn free_flag_packed_in_size() {
+        // next, prev, region and size (with the free flag), plus the tag, the call site,
+        // the allocation time and the checksum if enabled, padded to the minimum alignment.
+        let words = 4
+            + cfg!(feature = "tagging") as usize
+            + cfg!(feature = "call-sites") as usize
+            + cfg!(feature = "dhat") as usize
+            + cfg!(feature = "checksums") as usize;
+        assert_eq!(BLOCK_HEADER_SIZE, crate::utils::align(words * mem::size_of::<usize>(), MIN_ALIGN));
+
+        unsafe {
+            let allocator = MemAlloc::new();
+            let layout = Layout::new::<u64>();
+            let aligned = Layout::from_size_align(8, 64).unwrap();
+
+            let p1 = allocator.allocate(layout);
+            let p2 = allocator.allocate(aligned);
+
+            let b1 = Block::from_payload(p1);
+            let b2 = Block::from_payload(p2);
+            assert!(!b1.as_ref().data.is_free());
+            assert!(!b2.as_ref().data.is_free());
+            assert_eq!(b1.as_ref().data.size() % mem::size_of::<usize>(), 0);
+
+            allocator.deallocate(p2, aligned);
+            assert!(b2.as_ref().data.is_free());
+
+            allocator.deallocate(p1, layout);
         }
     }
 
@@ -347,7 +1036,7 @@ mod tests {
             {
                 // We need to use this inner scope because the mutex needs to be
                 // droped so that `deallocate` can take the lock.
-                let kernel = allocator.allocator.lock().unwrap();
+                let kernel = allocator.allocator.lock();
                 assert!(!kernel.regions.is_empty());
             }
             
@@ -355,7 +1044,7 @@ mod tests {
             allocator.deallocate(p2, layout);
 
             {
-                let kernel = allocator.allocator.lock().unwrap();
+                let kernel = allocator.allocator.lock();
                 assert!(kernel.regions.is_empty());
             }
 
@@ -363,36 +1052,237 @@ mod tests {
     }
 
     #[test]
-    fn multiple_regions_allocation() {
-        unsafe {
-            let allocator = MemAlloc::new();
-            // We suppose 4096 bytes pages.
-            let layout = Layout::from_size_align(3000, 8).unwrap();
-            
-            let p1 = allocator.allocate(layout);
-            // This should create a new error  
-            let p2 = allocator.allocate(layout);
-            
-            assert!(!p1.is_null());
-            assert!(!p2.is_null());
-            assert_ne!(p1, p2);
+    fn mapping_failures_return_null() {
+        // No platform can map this much memory.
+        let layout = Layout::from_size_align(isize::MAX as usize / 2, 8).unwrap();
 
-            allocator.deallocate(p1, layout);
-            allocator.deallocate(p2, layout);
+        for config in [Config::new(), Config::new().large_object_threshold(usize::MAX), Config::new().hardened(true)] {
+            let allocator = MemAlloc::with_config(config);
+
+            unsafe {
+                assert!(allocator.allocate(layout).is_null());
+                assert!(allocator.allocate_executable(layout).is_null());
+                assert_eq!(allocator.stats().allocations, 0);
+            }
         }
     }
 
     #[test]
-    fn zero_sized_type_allocation() {
+    fn cached_regions_are_reused_and_trimmed() {
         unsafe {
-            let allocator = MemAlloc::new();
-            let layout = Layout::from_size_align(0, 1).unwrap();
-            
+            let allocator = MemAlloc::with_config(Config::new().cached_regions(1));
+            let layout = Layout::new::<u64>();
+
             let p1 = allocator.allocate(layout);
-            // Generalmente devolvemos un puntero alineado mínimo o el bloque MIN_BLOCK_SIZE
-            assert!(!p1.is_null()); 
+            let region = Block::from_payload(p1).as_ref().data.region;
+            allocator.deallocate(p1, layout);
+
+            {
+                let kernel = allocator.allocator.lock();
+                assert!(kernel.regions.is_empty());
+                assert!(kernel.free_list.is_empty());
+                assert_eq!(kernel.cache.len(), 1);
+            }
+
+            // The cached region must be used again instead of mapping a new one.
+            let p2 = allocator.allocate(layout);
+            assert_eq!(Block::from_payload(p2).as_ref().data.region, region);
+            allocator.deallocate(p2, layout);
+
+            assert!(allocator.trim() > 0);
+
+            {
+                let kernel = allocator.allocator.lock();
+                assert!(kernel.cache.is_empty());
+            }
+
+            assert_eq!(allocator.trim(), 0);
+        }
+    }
+
+    #[test]
+    fn owns_pointers_inside_regions() {
+        unsafe {
+            let allocator = MemAlloc::new();
+            let other = MemAlloc::new();
+            let layout = Layout::new::<u64>();
+            let aligned = Layout::from_size_align(16, 64).unwrap();
+
+            let p1 = allocator.allocate(layout);
+            let p2 = allocator.allocate(aligned);
+            let p3 = other.allocate(layout);
+
+            assert!(allocator.owns(p1));
+            assert!(allocator.owns(p2));
+            assert!(allocator.owns(p1.add(4)));
+            assert!(!allocator.owns(p3));
+            assert!(!allocator.owns(ptr::null()));
+
+            assert!(allocator.owns_allocation(p1));
+            assert!(allocator.owns_allocation(p2));
+            assert!(!allocator.owns_allocation(p3));
+
+            allocator.deallocate(p2, aligned);
+
+            // Still mapped, but not a live allocation anymore
+            assert!(allocator.owns(p2));
+            assert!(!allocator.owns_allocation(p2));
+
+            allocator.deallocate(p1, layout);
+            other.deallocate(p3, layout);
+        }
+    }
+
+    #[test]
+    fn multiple_regions_allocation() {
+        unsafe {
+            let allocator = MemAlloc::new();
+            // We suppose 4096 bytes pages.
+            let layout = Layout::from_size_align(3000, 8).unwrap();
+            
+            let p1 = allocator.allocate(layout);
+            // This should create a new error  
+            let p2 = allocator.allocate(layout);
             
+            assert!(!p1.is_null());
+            assert!(!p2.is_null());
+            assert_ne!(p1, p2);
+
             allocator.deallocate(p1, layout);
+            allocator.deallocate(p2, layout);
+        }
+    }
+
+    #[test]
+    fn min_region_size_maps_bigger_regions() {
+        let allocator = MemAlloc::with_config(Config::new().min_region_size(64 * 1024));
+        let layout = Layout::from_size_align(3000, 8).unwrap();
+
+        unsafe {
+            // Both fit in the first region.
+            let ptrs: Vec<_> = (0..2).map(|_| allocator.allocate(layout)).collect();
+            let summary = allocator.summary();
+            assert_eq!(summary.regions, 1);
+            assert!(summary.used_bytes + summary.free_bytes >= 60 * 1024);
+
+            for ptr in ptrs {
+                allocator.deallocate(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn large_allocation_gets_own_mapping() {
+        unsafe {
+            let allocator = MemAlloc::new();
+            let layout = Layout::from_size_align(2 * LARGE_OBJECT_THRESHOLD, 4096).unwrap();
+
+            let p1 = allocator.allocate(layout);
+            assert!(!p1.is_null());
+            assert_eq!(p1 as usize % 4096, 0);
+
+            // We must be able to use the whole allocation
+            ptr::write_bytes(p1, 0xAB, layout.size());
+
+            {
+                let kernel = allocator.allocator.lock();
+                assert!(kernel.regions.is_empty());
+                assert!(kernel.free_list.is_empty());
+                assert_eq!(kernel.large_objects.len(), 1);
+            }
+
+            allocator.deallocate(p1, layout);
+
+            {
+                let kernel = allocator.allocator.lock();
+                assert!(kernel.large_objects.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn configurable_large_object_threshold() {
+        unsafe {
+            let allocator = MemAlloc::with_config(Config::new().large_object_threshold(64));
+
+            let small = Layout::new::<u64>();
+            let large = Layout::from_size_align(64, 8).unwrap();
+
+            let p1 = allocator.allocate(small);
+            let p2 = allocator.allocate(large);
+
+            {
+                let kernel = allocator.allocator.lock();
+                assert_eq!(kernel.regions.len(), 1);
+                assert_eq!(kernel.large_objects.len(), 1);
+            }
+
+            allocator.deallocate(p1, small);
+            allocator.deallocate(p2, large);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend"), ignore = "needs OS memory")]
+    fn numa_aware_regions_record_their_node() {
+        unsafe {
+            let allocator = MemAlloc::with_config(Config::new().numa_aware(true));
+            let layout = Layout::new::<u64>();
+
+            let p1 = allocator.allocate(layout);
+            *(p1 as *mut u64) = 42;
+
+            let region = Block::from_payload(p1).as_ref().data.region;
+
+            if cfg!(target_os = "linux") {
+                assert!(region.as_ref().data.node.is_some());
+            }
+
+            // Regions of allocators that are not NUMA aware don't have a node
+            let other = MemAlloc::new();
+            let p2 = other.allocate(layout);
+            assert!(Block::from_payload(p2).as_ref().data.region.as_ref().data.node.is_none());
+
+            allocator.deallocate(p1, layout);
+            other.deallocate(p2, layout);
+        }
+    }
+
+    #[test]
+    fn zero_sized_type_allocation() {
+        unsafe {
+            let allocator = MemAlloc::new();
+            let layout = Layout::from_size_align(0, 1).unwrap();
+            
+            let p1 = allocator.allocate(layout);
+            assert!(!p1.is_null()); 
+            assert_eq!(p1 as usize % MIN_ALIGN, 0);
+
+            // On 64 bit targets every one gets its own address, and no memory is mapped for
+            // them.
+            let aligned = Layout::from_size_align(0, 4096).unwrap();
+            let pointers: Vec<_> = (0..64).map(|_| allocator.allocate(aligned)).collect();
+            assert!(pointers.iter().all(|&ptr| (ptr as usize).is_multiple_of(4096)));
+
+            #[cfg(target_pointer_width = "64")]
+            {
+                assert!(pointers.iter().all(|&ptr| ptr != p1));
+                assert_eq!(pointers.iter().collect::<std::collections::HashSet<_>>().len(), pointers.len());
+                assert_ne!(MemAlloc::new().allocate(layout), allocator.allocate(layout));
+            }
+
+            assert!(allocator.allocator.lock().regions.is_empty());
+            assert_eq!(allocator.stats().allocations, 0);
+
+            for ptr in pointers {
+                allocator.deallocate(ptr, aligned);
+            }
+
+            let p2 = allocator.reallocate(p1, layout, 8);
+            assert!(allocator.owns_allocation(p2));
+
+            allocator.deallocate(p1, layout);
+            allocator.deallocate(p2, Layout::from_size_align(8, 1).unwrap());
         }
     }
 
@@ -409,6 +1299,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn small_allocations_have_the_malloc_alignment() {
+        let allocator = MemAlloc::new();
+        let word_aligned = MemAlloc::with_config(Config::new().min_align(mem::size_of::<usize>()));
+
+        unsafe {
+            let layouts = [(1, 1), (8, 8), (24, 8), (40, 4)].map(|(size, align)| Layout::from_size_align(size, align).unwrap());
+            let ptrs: Vec<_> = (0..20).flat_map(|_| layouts.map(|layout| allocator.allocate(layout))).collect();
+
+            assert!(ptrs.iter().all(|ptr| (*ptr as usize).is_multiple_of(MIN_ALIGN)));
+
+            for (ptr, layout) in ptrs.iter().zip(layouts.iter().cycle()) {
+                allocator.deallocate(*ptr, *layout);
+            }
+
+            // Blocks of this size are an odd number of words long, so without the padding
+            // every other one is only aligned to the word.
+            let layout = Layout::from_size_align(32, 8).unwrap();
+            let ptrs: Vec<_> = (0..4).map(|_| word_aligned.allocate(layout)).collect();
+            assert!(ptrs.iter().any(|ptr| !(*ptr as usize).is_multiple_of(MIN_ALIGN)));
+
+            for ptr in ptrs {
+                word_aligned.deallocate(ptr, layout);
+            }
+        }
+    }
+
     #[test]
     fn realloc_grow_preserves_data() {
         let allocator = MemAlloc::new();
@@ -505,6 +1422,42 @@ mod tests {
         }
     }
 
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend"), ignore = "needs OS memory")]
+    fn large_realloc_keeps_the_contents() {
+        let allocator = MemAlloc::new();
+        let page_size = crate::kernel::page_size();
+
+        unsafe {
+            let layout = Layout::from_size_align(2 * LARGE_OBJECT_THRESHOLD, 8).unwrap();
+            let ptr = allocator.allocate(layout);
+
+            for page in 0..layout.size() / page_size {
+                *ptr.add(page * page_size) = page as u8;
+            }
+
+            let grown = allocator.reallocate(ptr, layout, 16 * LARGE_OBJECT_THRESHOLD);
+            let layout = Layout::from_size_align(16 * LARGE_OBJECT_THRESHOLD, 8).unwrap();
+            ptr::write_bytes(grown.add(2 * LARGE_OBJECT_THRESHOLD), 0xAB, 14 * LARGE_OBJECT_THRESHOLD);
+
+            assert!(allocator.owns_allocation(grown));
+            assert_eq!(allocator.stats().allocated, layout.size());
+            assert_eq!(allocator.allocator.lock().large_objects.len(), 1);
+
+            let shrunk = allocator.reallocate(grown, layout, 2 * LARGE_OBJECT_THRESHOLD);
+            let layout = Layout::from_size_align(2 * LARGE_OBJECT_THRESHOLD, 8).unwrap();
+
+            for page in 0..layout.size() / page_size {
+                assert_eq!(*shrunk.add(page * page_size), page as u8);
+            }
+
+            assert_eq!(allocator.stats().allocated, layout.size());
+
+            allocator.deallocate(shrunk, layout);
+            assert!(allocator.allocator.lock().large_objects.is_empty());
+        }
+    }
+
     #[test]
     fn realloc_maintains_high_alignment() {
         let allocator = MemAlloc::new();
@@ -529,6 +1482,67 @@ mod tests {
         }
     }
 
+    #[test]
+    fn grow_and_shrink_change_the_layout() {
+        let allocator = MemAlloc::new();
+
+        unsafe {
+            let small = Layout::from_size_align(16, 8).unwrap();
+            let ptr = allocator.allocate(small);
+            ptr::write_bytes(ptr, 0xAB, small.size());
+
+            // The alignment goes up, so it is copied to a new allocation.
+            let big = Layout::from_size_align(256, 256).unwrap();
+            let grown = allocator.grow_zeroed(ptr, small, big);
+            assert_eq!(grown as usize % big.align(), 0);
+
+            let bytes = std::slice::from_raw_parts(grown, big.size());
+            assert!(bytes[..small.size()].iter().all(|&byte| byte == 0xAB));
+            assert!(bytes[small.size()..].iter().all(|&byte| byte == 0));
+
+            let shrunk = allocator.shrink(grown, big, small);
+            assert_eq!(*shrunk, 0xAB);
+
+            let grown = allocator.grow(shrunk, small, Layout::from_size_align(64, 8).unwrap());
+            assert_eq!(*grown.add(small.size() - 1), 0xAB);
+
+            // Shrinking to nothing frees it.
+            let empty = Layout::from_size_align(0, 8).unwrap();
+            let dangling = allocator.shrink(grown, Layout::from_size_align(64, 8).unwrap(), empty);
+            assert!(!dangling.is_null());
+            assert_eq!(allocator.stats().allocations, 0);
+
+            // And growing from nothing allocates.
+            let ptr = allocator.grow(dangling, empty, small);
+            assert!(allocator.owns_allocation(ptr));
+            allocator.deallocate(ptr, small);
+        }
+    }
+
+    #[test]
+    fn failed_allocations_tell_why() {
+        let limited = MemAlloc::with_config(Config::new().quota(4096));
+        let allocator = MemAlloc::new();
+
+        unsafe {
+            let layout = Layout::from_size_align(8192, 8).unwrap();
+            assert_eq!(limited.try_allocate(layout), Err(AllocError::LimitExceeded));
+
+            // Rounding the size up to the minimum alignment overflows.
+            let huge = Layout::from_size_align(isize::MAX as usize - 1, 1).unwrap();
+            assert_eq!(allocator.try_allocate(huge), Err(AllocError::InvalidLayout));
+
+            allocator.inject_failures(crate::FailureInjection::Nth(1));
+            let small = Layout::new::<u64>();
+            assert_eq!(allocator.try_allocate(small), Err(AllocError::ExhaustedAddressSpace));
+
+            let ptr = allocator.try_allocate(small).unwrap();
+            allocator.deallocate(ptr.as_ptr(), small);
+
+            assert!(allocator.try_allocate(Layout::new::<()>()).is_ok());
+        }
+    }
+
     #[test]
     fn realloc_null_and_zero_returns_null() {
         let allocator = MemAlloc::new();
@@ -541,4 +1555,541 @@ mod tests {
             assert!(ptr.is_null());
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend"), ignore = "needs OS memory")]
+    fn executable_allocation_runs_code() {
+        let allocator = MemAlloc::new();
+
+        unsafe {
+            let layout = Layout::from_size_align(64, 16).unwrap();
+            let ptr = allocator.allocate_executable(layout);
+
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % allocator.allocator.lock().page_size, 0);
+            assert!(allocator.owns(ptr));
+
+            // mov eax, 42; ret
+            #[cfg(target_arch = "x86_64")]
+            let code: &[u8] = &[0xB8, 42, 0, 0, 0, 0xC3];
+            // mov w0, #42; ret
+            #[cfg(target_arch = "aarch64")]
+            let code: &[u8] = &[0x40, 0x05, 0x80, 0x52, 0xC0, 0x03, 0x5F, 0xD6];
+
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            {
+                ptr::copy_nonoverlapping(code.as_ptr(), ptr, code.len());
+                allocator.make_executable(ptr).unwrap();
+
+                #[cfg(target_arch = "aarch64")]
+                unsafe extern "C" {
+                    fn __clear_cache(start: *mut u8, end: *mut u8);
+                }
+                #[cfg(target_arch = "aarch64")]
+                __clear_cache(ptr, ptr.add(code.len()));
+
+                let f: extern "C" fn() -> u32 = std::mem::transmute(ptr);
+                assert_eq!(f(), 42);
+
+                // Patch the immediate and run it again.
+                allocator.make_writable(ptr).unwrap();
+                #[cfg(target_arch = "x86_64")]
+                ptr.add(1).write(7);
+                #[cfg(target_arch = "aarch64")]
+                ptr.cast::<u32>().write(0x528000E0);
+                allocator.make_executable(ptr).unwrap();
+
+                #[cfg(target_arch = "aarch64")]
+                __clear_cache(ptr, ptr.add(code.len()));
+
+                assert_eq!(f(), 7);
+            }
+
+            assert!(allocator.make_executable(ptr.add(8)).is_err());
+            assert!(allocator.make_writable(ptr.add(8)).is_err());
+
+            allocator.deallocate(ptr, layout);
+            assert!(allocator.allocator.lock().executable.is_empty());
+        }
+    }
+
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend"), ignore = "needs OS memory")]
+    fn seal_makes_pages_read_only() {
+        let allocator = MemAlloc::new();
+
+        unsafe {
+            // Small allocations don't cover any whole page.
+            let small = Layout::new::<u64>();
+            let ptr = allocator.allocate(small);
+            assert!(allocator.seal(ptr).is_err());
+            allocator.deallocate(ptr, small);
+
+            let page_size = crate::kernel::page_size();
+            let layout = Layout::from_size_align(2 * page_size, page_size).unwrap();
+            let ptr = allocator.allocate(layout);
+            ptr::write_bytes(ptr, 7, layout.size());
+
+            // Keeps the region mapped after freeing `ptr`.
+            let neighbour = allocator.allocate(small);
+
+            allocator.seal(ptr).unwrap();
+            assert_eq!(*ptr.add(page_size), 7);
+
+            #[cfg(target_os = "linux")]
+            {
+                assert_eq!(crate::utils::tests::permissions_of(ptr), "r--p");
+                assert_eq!(crate::utils::tests::permissions_of(ptr.add(layout.size() - 1)), "r--p");
+                assert_eq!(crate::utils::tests::permissions_of(ptr.add(layout.size())), "rw-p");
+            }
+
+            allocator.unseal(ptr).unwrap();
+            ptr.write(8);
+
+            // Freeing a sealed allocation makes it writable again so it can be reused.
+            allocator.seal(ptr).unwrap();
+            allocator.deallocate(ptr, layout);
+
+            #[cfg(target_os = "linux")]
+            assert_eq!(crate::utils::tests::permissions_of(ptr), "rw-p");
+
+            let again = allocator.allocate(layout);
+            assert_eq!(again, ptr);
+            ptr::write_bytes(again, 0, layout.size());
+            allocator.deallocate(again, layout);
+            allocator.deallocate(neighbour, small);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn mappings_are_named() {
+        let allocator = MemAlloc::new();
+
+        unsafe {
+            let layout = Layout::new::<u64>();
+            let ptr = allocator.allocate(layout);
+
+            let large = Layout::array::<u8>(crate::config::LARGE_OBJECT_THRESHOLD).unwrap();
+            let large_ptr = allocator.allocate(large);
+
+            let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+
+            // Old kernels don't support naming anonymous mappings.
+            if maps.contains("[anon:") {
+                assert!(maps.contains("[anon:memalloc:region]"));
+                assert!(maps.contains("[anon:memalloc:large]"));
+            }
+
+            allocator.deallocate(large_ptr, large);
+            allocator.deallocate(ptr, layout);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend"), ignore = "needs OS memory")]
+    fn cached_regions_are_decommitted() {
+        for mode in [Decommit::Eager, Decommit::Lazy] {
+            let allocator = MemAlloc::with_config(Config::new().cached_regions(1).decommit(mode));
+
+            unsafe {
+                let page_size = crate::kernel::page_size();
+                let layout = Layout::from_size_align(8 * page_size, 8).unwrap();
+
+                let ptr = allocator.allocate(layout);
+                ptr::write_bytes(ptr, 0xAB, layout.size());
+                allocator.deallocate(ptr, layout);
+
+                // `MADV_DONTNEED` drops the contents of the pages straight away.
+                #[cfg(target_os = "linux")]
+                if mode == Decommit::Eager {
+                    assert_eq!(*ptr.add(4 * page_size), 0);
+                }
+
+                // The region must still be usable after being decommitted.
+                let again = allocator.allocate(layout);
+                assert_eq!(again, ptr);
+                ptr::write_bytes(again, 0xCD, layout.size());
+                assert_eq!(*again.add(layout.size() - 1), 0xCD);
+                allocator.deallocate(again, layout);
+
+                assert!(allocator.trim() > 0);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend"), ignore = "needs OS memory")]
+    fn huge_page_advice_is_given() {
+        use crate::{config::{HUGE_PAGE_SIZE, HugePages}, utils::tests::vm_flags_of};
+
+        // Kernels built without transparent huge pages reject the advice.
+        if !std::path::Path::new("/sys/kernel/mm/transparent_hugepage").exists() {
+            return;
+        }
+
+        unsafe {
+            let layout = Layout::from_size_align(2 * HUGE_PAGE_SIZE, 8).unwrap();
+
+            let allocator = MemAlloc::with_config(Config::new().huge_pages(HugePages::Enabled));
+            let ptr = allocator.allocate(layout);
+            assert!(vm_flags_of(ptr).contains(" hg"));
+            allocator.deallocate(ptr, layout);
+
+            let allocator = MemAlloc::with_config(Config::new().huge_pages(HugePages::Disabled));
+            let ptr = allocator.allocate(layout);
+            assert!(vm_flags_of(ptr).contains(" nh"));
+            allocator.deallocate(ptr, layout);
+        }
+    }
+
+    #[cfg(target_os = "freebsd")]
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend", feature = "sbrk"), ignore = "needs mmap")]
+    fn huge_regions_start_at_a_superpage_boundary() {
+        use crate::config::{HUGE_PAGE_SIZE, HugePages};
+
+        unsafe {
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            let config = Config::new().huge_pages(HugePages::Enabled).min_region_size(HUGE_PAGE_SIZE);
+
+            let allocator = MemAlloc::with_config(config);
+            let ptr = allocator.allocate(layout);
+
+            // The region starts at the boundary, and the block right after its header.
+            assert!(ptr.addr() % HUGE_PAGE_SIZE < crate::kernel::page_size());
+            allocator.deallocate(ptr, layout);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn prefault_populates_new_regions() {
+        /// Returns whether every page of `len` bytes starting from `addr` is resident.
+        fn is_resident(addr: *mut u8, len: usize) -> bool {
+            let page_size = crate::kernel::page_size();
+            let mut pages = vec![0u8; len.div_ceil(page_size)];
+
+            unsafe {
+                assert_eq!(libc::mincore(addr as *mut libc::c_void, len, pages.as_mut_ptr()), 0);
+            }
+
+            pages.iter().all(|page| page & 1 != 0)
+        }
+
+        unsafe {
+            let allocator = MemAlloc::with_config(Config::new().prefault(true));
+            let layout = Layout::from_size_align(16 * crate::kernel::page_size(), 4096).unwrap();
+
+            let ptr = allocator.allocate(layout);
+            assert!(is_resident(ptr, layout.size()));
+            allocator.deallocate(ptr, layout);
+
+            let large = Layout::from_size_align(LARGE_OBJECT_THRESHOLD, 4096).unwrap();
+            let ptr = allocator.allocate(large);
+            assert!(is_resident(ptr, large.size()));
+            allocator.deallocate(ptr, large);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend", feature = "sbrk"), ignore = "needs mmap")]
+    fn lazy_commit_charge_maps_without_reserving() {
+        use crate::{config::CommitCharge, utils::tests::vm_flags_of};
+
+        unsafe {
+            let small = Layout::from_size_align(64, 8).unwrap();
+            let large = Layout::from_size_align(LARGE_OBJECT_THRESHOLD, 8).unwrap();
+
+            let allocator = MemAlloc::with_config(Config::new().commit_charge(CommitCharge::Lazy));
+            for layout in [small, large] {
+                let ptr = allocator.allocate(layout);
+                assert!(vm_flags_of(ptr).contains(" nr"));
+                allocator.deallocate(ptr, layout);
+            }
+
+            let allocator = MemAlloc::new();
+            for layout in [small, large] {
+                let ptr = allocator.allocate(layout);
+                assert!(!vm_flags_of(ptr).contains(" nr"));
+                allocator.deallocate(ptr, layout);
+            }
+        }
+    }
+
+    // The program break of a PIE test binary lives above 4 GiB.
+    #[cfg(not(feature = "sbrk"))]
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend"), ignore = "needs OS memory")]
+    fn low_address_mode_stays_below_4gib() {
+        let allocator = MemAlloc::with_config(Config::new().low_address(true));
+
+        unsafe {
+            let small = Layout::new::<u64>();
+            let large = Layout::from_size_align(LARGE_OBJECT_THRESHOLD, 8).unwrap();
+
+            let ptrs = [allocator.allocate(small), allocator.allocate(large)];
+
+            for (ptr, layout) in ptrs.into_iter().zip([small, large]) {
+                assert!(!ptr.is_null());
+                assert!((ptr as u64) + (layout.size() as u64) <= 1 << 32);
+                ptr::write_bytes(ptr, 0xAB, layout.size());
+                allocator.deallocate(ptr, layout);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "sbrk"))]
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend"), ignore = "needs OS memory")]
+    fn regions_follow_the_address_hint() {
+        // Far from anything else in the address space of the test.
+        const HINT: usize = 0x5a5a_0000_0000;
+
+        let allocator = MemAlloc::with_config(Config::new().address_hint(HINT + 1));
+        let layout = Layout::from_size_align(100 * 1024, 8).unwrap();
+
+        unsafe {
+            // Each allocation needs its own region.
+            let [a, b] = [(); 2].map(|_| allocator.allocate(layout));
+
+            let kernel = allocator.allocator.lock();
+            let first = kernel.regions.first().unwrap();
+            let second = first.as_ref().next.unwrap();
+            let first_size = crate::region::REGION_HEADER_SIZE + first.as_ref().data.size;
+
+            assert_eq!(first.as_ptr() as usize, HINT + 64 * 1024);
+            assert_eq!(second.as_ptr() as usize, HINT + 64 * 1024 + crate::utils::align(first_size, 64 * 1024));
+            drop(kernel);
+
+            allocator.deallocate(a, layout);
+            allocator.deallocate(b, layout);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend"), ignore = "needs OS memory")]
+    fn over_aligned_allocations_are_trimmed() {
+        let allocator = MemAlloc::new();
+        let page_size = crate::kernel::page_size();
+        let align = 2 * 1024 * 1024;
+
+        unsafe {
+            for size in [64, 3 * page_size, 4 * align] {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = allocator.allocate(layout);
+
+                assert_eq!(ptr as usize % align, 0);
+                ptr::write_bytes(ptr, 0xAB, size);
+
+                {
+                    let kernel = allocator.allocator.lock();
+                    let region = kernel.region_of(ptr as usize).unwrap();
+                    let mapped = region.as_ref().data.size + crate::region::REGION_HEADER_SIZE;
+
+                    // Only the headers page and the pages of the payload are mapped.
+                    assert_eq!(mapped, page_size + crate::utils::align(size + BLOCK_FOOTER_SIZE, page_size));
+                }
+
+                allocator.deallocate(ptr, layout);
+                assert!(allocator.allocator.lock().large_objects.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn fit_policies_pick_different_blocks() {
+        /// Leaves two free blocks of different sizes, `big` and then `small`, after the tail
+        /// of the region, which is too small for `request`. We suppose 4096 bytes pages.
+        /// Also returns the blocks that separate them.
+        unsafe fn fragment(allocator: &MemAlloc) -> (*mut u8, *mut u8, [*mut u8; 2]) {
+            unsafe {
+                let [big, first, small, second] = [1500, 32, 1300, 32].map(|size| {
+                    allocator.allocate(Layout::from_size_align(size, 8).unwrap())
+                });
+
+                allocator.deallocate(big, Layout::from_size_align(1500, 8).unwrap());
+                allocator.deallocate(small, Layout::from_size_align(1300, 8).unwrap());
+
+                (big, small, [first, second])
+            }
+        }
+
+        /// Frees the separators of [`fragment`] and the allocations of the test.
+        unsafe fn free(allocator: &MemAlloc, separators: [*mut u8; 2], ptrs: &[(*mut u8, Layout)]) {
+            unsafe {
+                for ptr in separators {
+                    allocator.deallocate(ptr, Layout::from_size_align(32, 8).unwrap());
+                }
+
+                for &(ptr, layout) in ptrs {
+                    allocator.deallocate(ptr, layout);
+                }
+            }
+        }
+
+        let request = Layout::from_size_align(1250, 8).unwrap();
+        let tiny = Layout::from_size_align(100, 8).unwrap();
+
+        unsafe {
+            let first_fit = MemAlloc::with_config(Config::new().fit_policy(FitPolicy::FirstFit));
+            let (big, small, separators) = fragment(&first_fit);
+            let [a, b] = [request, tiny].map(|layout| first_fit.allocate(layout));
+            assert_eq!(a, big);
+            assert_ne!(b, small);
+            free(&first_fit, separators, &[(a, request), (b, tiny)]);
+
+            let best_fit = MemAlloc::with_config(Config::new().fit_policy(FitPolicy::BestFit));
+            let (_, small, separators) = fragment(&best_fit);
+            let a = best_fit.allocate(request);
+            assert_eq!(a, small);
+            free(&best_fit, separators, &[(a, request)]);
+
+            // Next fit keeps going from the block it took last time.
+            let next_fit = MemAlloc::with_config(Config::new().fit_policy(FitPolicy::NextFit));
+            let (big, small, separators) = fragment(&next_fit);
+            let [a, b] = [request, tiny].map(|layout| next_fit.allocate(layout));
+            assert_eq!(a, big);
+            assert_eq!(b, small);
+            free(&next_fit, separators, &[(a, request), (b, tiny)]);
+        }
+    }
+
+    #[test]
+    fn split_threshold_hands_out_whole_blocks() {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let hole = Layout::from_size_align(256, 8).unwrap();
+
+        unsafe {
+            for (threshold, split) in [(0, true), (512, false)] {
+                let config = Config::new().fit_policy(FitPolicy::BestFit).split_threshold(threshold);
+                let allocator = MemAlloc::with_config(config);
+
+                // A free block of 256 bytes before a used one, smaller than the tail of
+                // the region, so best fit picks it.
+                let [first, second] = [hole, layout].map(|layout| allocator.allocate(layout));
+                allocator.deallocate(first, hole);
+                let free_blocks = allocator.summary().free_blocks;
+
+                assert_eq!(allocator.allocate(layout), first);
+                assert_eq!(allocator.summary().free_blocks == free_blocks, split);
+                assert_eq!(allocator.summary().used_bytes < 256 + 64, split);
+
+                allocator.deallocate(first, layout);
+                allocator.deallocate(second, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn search_limit_maps_a_region_instead_of_walking_the_list() {
+        // Same heap as `fit_policies_pick_different_blocks`: the free list holds the tail
+        // of the region, which is too small, and then `big`.
+        let sizes = [1500, 32, 1300, 32].map(|size| Layout::from_size_align(size, 8).unwrap());
+        let fragment = |allocator: &MemAlloc| unsafe {
+            let ptrs = sizes.map(|layout| allocator.allocate(layout));
+            allocator.deallocate(ptrs[0], sizes[0]);
+
+            ptrs
+        };
+
+        let request = Layout::from_size_align(1250, 8).unwrap();
+
+        unsafe {
+            for (limit, misses) in [(1, 2), (2, 1)] {
+                let allocator = MemAlloc::with_config(Config::new().search_limit(limit));
+                let [big, rest @ ..] = fragment(&allocator);
+                let ptr = allocator.allocate(request);
+                assert_eq!(ptr == big, limit == 2);
+                assert_eq!(allocator.search_stats().misses, misses);
+
+                allocator.deallocate(ptr, request);
+
+                for (ptr, layout) in rest.into_iter().zip(&sizes[1..]) {
+                    allocator.deallocate(ptr, *layout);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reserved_memory_serves_allocations_without_mapping() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::from_size_align(1000, 8).unwrap();
+
+        allocator.reserve(1 << 20).unwrap();
+        assert_eq!(allocator.summary().regions, 1);
+
+        unsafe {
+            for _ in 0..2 {
+                let ptrs: Vec<_> = (0..500).map(|_| allocator.allocate(layout)).collect();
+
+                for ptr in ptrs {
+                    allocator.deallocate(ptr, layout);
+                }
+
+                // The empty region stays mapped for the next round.
+                assert_eq!(allocator.summary().regions, 1);
+            }
+
+            assert_eq!(allocator.search_stats().misses, 0);
+
+            // Memory beyond the reservation is given back as usual, and the reservation
+            // is kept after the burst.
+            let ptrs: Vec<_> = (0..2000).map(|_| allocator.allocate(layout)).collect();
+            assert!(allocator.summary().regions > 1);
+            assert!(allocator.search_stats().misses > 0);
+
+            for ptr in ptrs {
+                allocator.deallocate(ptr, layout);
+            }
+
+            assert_eq!(allocator.summary().regions, 1);
+
+            let misses = allocator.search_stats().misses;
+            let ptrs: Vec<_> = (0..500).map(|_| allocator.allocate(layout)).collect();
+            assert_eq!(allocator.search_stats().misses, misses);
+
+            for ptr in ptrs {
+                allocator.deallocate(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn move_to_front_keeps_remainders_first() {
+        let hole = Layout::from_size_align(64, 8).unwrap();
+        let request = Layout::from_size_align(256, 8).unwrap();
+
+        // Leaves eight holes too small for `request` behind the tail of the region, then
+        // serves two requests from the tail. Returns the nodes the second search visited.
+        let second_search = |allocator: &MemAlloc| unsafe {
+            let (holes, separators): (Vec<_>, Vec<_>) = (0..8)
+                .map(|_| (allocator.allocate(hole), allocator.allocate(hole)))
+                .unzip();
+
+            for ptr in holes {
+                allocator.deallocate(ptr, hole);
+            }
+
+            let first = allocator.allocate(request);
+            let visited = allocator.search_stats().visited;
+            let second = allocator.allocate(request);
+            let visited = allocator.search_stats().visited - visited;
+
+            for ptr in separators {
+                allocator.deallocate(ptr, hole);
+            }
+
+            allocator.deallocate(first, request);
+            allocator.deallocate(second, request);
+
+            visited
+        };
+
+        assert_eq!(second_search(&MemAlloc::new()), 9);
+        assert_eq!(second_search(&MemAlloc::with_config(Config::new().move_to_front(true))), 1);
+    }
+}
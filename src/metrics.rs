@@ -0,0 +1,215 @@
+//! Prometheus metrics.
+//!
+//! [`MemAlloc::metrics`] takes a [`Metrics`] snapshot of the counters of an allocator,
+//! and [`write_metrics`] writes snapshots in the Prometheus text exposition format, which
+//! OpenMetrics scrapers also accept. Serving them from the metrics endpoint of a service
+//! that uses [`MemAlloc`] as its global allocator is enough to get heap metrics:
+//!
+//! ```rust
+//! use memalloc::{Config, MemAlloc};
+//!
+//! static ALLOCATOR: MemAlloc = MemAlloc::with_config(Config::new().name("global"));
+//!
+//! let mut body = String::new();
+//! memalloc::write_metrics(&[ALLOCATOR.metrics()], &mut body).unwrap();
+//!
+//! assert!(body.contains("memalloc_allocated_bytes{heap=\"global\"}"));
+//! ```
+//!
+//! The snapshot is taken with the allocator lock held and doesn't allocate, so it can be
+//! taken from the global allocator. Formatting it allocates, which is why the two steps
+//! are separate.
+
+use std::fmt::{self, Write};
+
+use lock_api::RawMutex;
+
+use crate::{list::List, memalloc::MemAlloc, region::{Region, REGION_HEADER_SIZE}, sync};
+
+/// Counters of an allocator at some point, returned by [`MemAlloc::metrics`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Name given with [`crate::Config::name`], written as the `heap` label.
+    pub name: Option<&'static str>,
+    /// Bytes mapped from the OS, including the metadata and the cached regions.
+    pub mapped: usize,
+    /// Bytes currently allocated, see [`crate::Stats::allocated`].
+    pub allocated: usize,
+    /// Maximum number of bytes that have been allocated at the same time.
+    pub peak: usize,
+    /// Number of live allocations.
+    pub allocations: usize,
+    /// Number of regions split into blocks.
+    pub regions: usize,
+    /// Number of regions holding a single large allocation.
+    pub large_objects: usize,
+    /// Number of empty regions kept for reuse, see [`crate::Config::cached_regions`].
+    pub cached_regions: usize,
+    /// Number of regions holding executable code.
+    pub executable_regions: usize,
+    /// Number of allocations refused because of [`crate::Config::quota`].
+    pub quota_failures: usize,
+    /// Number of allocations that failed because the OS refused to map more memory.
+    pub mapping_failures: usize,
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Returns a snapshot of the counters of this allocator. See the `metrics` module.
+    pub fn metrics(&self) -> Metrics {
+        let kernel = sync::lock(&self.allocator);
+        let stats = self.stats.load();
+
+        let mapped = |regions: &List<Region>| regions.iter().map(|region| region.size + REGION_HEADER_SIZE).sum::<usize>();
+
+        Metrics {
+            name: self.config.name,
+            mapped: mapped(&kernel.regions)
+                + mapped(&kernel.large_objects)
+                + mapped(&kernel.cache)
+                + mapped(&kernel.executable)
+                + kernel.hardened.mapped(),
+            allocated: stats.allocated,
+            peak: stats.peak,
+            allocations: stats.allocations,
+            regions: kernel.regions.len(),
+            large_objects: kernel.large_objects.len(),
+            cached_regions: kernel.cache.len(),
+            executable_regions: kernel.executable.len(),
+            quota_failures: stats.quota_failures,
+            mapping_failures: kernel.mapping_failures,
+        }
+    }
+}
+
+/// Metric family with a single sample per heap: name, type, help and value.
+type Family = (&'static str, &'static str, &'static str, fn(&Metrics) -> usize);
+
+const FAMILIES: [Family; 6] = [
+    ("memalloc_mapped_bytes", "gauge", "Bytes mapped from the OS.", |m| m.mapped),
+    ("memalloc_allocated_bytes", "gauge", "Bytes currently allocated.", |m| m.allocated),
+    ("memalloc_peak_allocated_bytes", "gauge", "Maximum number of bytes allocated at the same time.", |m| m.peak),
+    ("memalloc_allocations", "gauge", "Number of live allocations.", |m| m.allocations),
+    ("memalloc_quota_failures_total", "counter", "Allocations refused because of the quota.", |m| m.quota_failures),
+    ("memalloc_mapping_failures_total", "counter", "Allocations that failed because the OS refused to map memory.", |m| m.mapping_failures),
+];
+
+/// Writes `heaps` to `out` in the Prometheus text exposition format, grouping the samples
+/// of every heap under the same metric families. Heaps with a name get a `heap` label,
+/// and regions get a `kind` label.
+pub fn write_metrics(heaps: &[Metrics], out: &mut impl Write) -> fmt::Result {
+    for (name, kind, help, value) in FAMILIES {
+        write_header(name, kind, help, out)?;
+
+        for metrics in heaps {
+            write_sample(name, metrics.name, None, value(metrics), out)?;
+        }
+    }
+
+    let name = "memalloc_regions";
+    write_header(name, "gauge", "Number of mapped regions.", out)?;
+
+    for metrics in heaps {
+        let regions = [
+            ("blocks", metrics.regions),
+            ("large", metrics.large_objects),
+            ("cached", metrics.cached_regions),
+            ("executable", metrics.executable_regions),
+        ];
+
+        for (kind, value) in regions {
+            write_sample(name, metrics.name, Some(kind), value, out)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_header(name: &str, kind: &str, help: &str, out: &mut impl Write) -> fmt::Result {
+    writeln!(out, "# HELP {name} {help}")?;
+    writeln!(out, "# TYPE {name} {kind}")
+}
+
+fn write_sample(name: &str, heap: Option<&str>, kind: Option<&str>, value: usize, out: &mut impl Write) -> fmt::Result {
+    out.write_str(name)?;
+
+    match (heap, kind) {
+        (None, None) => {}
+        (Some(heap), None) => write_label("heap", heap, '{', out)?,
+        (None, Some(kind)) => write_label("kind", kind, '{', out)?,
+        (Some(heap), Some(kind)) => {
+            write_label("heap", heap, '{', out)?;
+            write_label("kind", kind, ',', out)?;
+        }
+    }
+
+    if heap.is_some() || kind.is_some() {
+        out.write_char('}')?;
+    }
+
+    writeln!(out, " {value}")
+}
+
+/// Writes `separator` and the label `name` with `value`.
+fn write_label(name: &str, value: &str, separator: char, out: &mut impl Write) -> fmt::Result {
+    write!(out, "{separator}{name}=\"")?;
+    write_escaped(value, out)?;
+    out.write_char('"')
+}
+
+/// Writes a label value escaping the characters the format requires.
+fn write_escaped(value: &str, out: &mut impl Write) -> fmt::Result {
+    for c in value.chars() {
+        match c {
+            '\\' => out.write_str("\\\\")?,
+            '"' => out.write_str("\\\"")?,
+            '\n' => out.write_str("\\n")?,
+            c => out.write_char(c)?,
+        }
+    }
+
+    Ok(())
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_metrics(&[*self], f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    fn metrics_are_written_in_text_format() {
+        let allocator = MemAlloc::with_config(Config::new().name("cache").quota(1000));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        unsafe {
+            let ptr = allocator.allocate(layout);
+            assert!(allocator.allocate(Layout::from_size_align(1000, 8).unwrap()).is_null());
+
+            let metrics = allocator.metrics();
+            assert_eq!((metrics.allocated, metrics.allocations, metrics.regions), (100, 1, 1));
+            assert!(metrics.mapped >= 100 + REGION_HEADER_SIZE);
+
+            let mut text = String::new();
+            write_metrics(&[metrics, MemAlloc::new().metrics()], &mut text).unwrap();
+
+            assert!(text.contains("# TYPE memalloc_allocated_bytes gauge\n"));
+            assert!(text.contains("memalloc_allocated_bytes{heap=\"cache\"} 100\nmemalloc_allocated_bytes 0\n"));
+            assert!(text.contains("memalloc_quota_failures_total{heap=\"cache\"} 1\n"));
+            assert!(text.contains("memalloc_regions{heap=\"cache\",kind=\"blocks\"} 1\n"));
+            assert!(text.contains("memalloc_regions{kind=\"large\"} 0\n"));
+            assert_eq!(text.matches("# HELP").count(), 7);
+
+            allocator.deallocate(ptr, layout);
+        }
+
+        let quoted = Metrics { name: Some("a\"b\\c"), ..Metrics::default() };
+        assert!(quoted.to_string().contains("memalloc_allocations{heap=\"a\\\"b\\\\c\"} 0\n"));
+    }
+}
@@ -0,0 +1,128 @@
+//! Out-of-memory hook.
+//!
+//! An allocation fails when the OS refuses to map more memory or when it would go over
+//! [`crate::Config::quota`]. Before returning null, the allocator calls the hook set with
+//! [`MemAlloc::set_oom_hook`], which can free caches of the program and ask for the
+//! allocation to be tried again:
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::{MemAlloc, OomAction};
+//!
+//! fn on_oom(_layout: Layout) -> OomAction {
+//!     // Drop some caches here, then try again.
+//!     OomAction::Fail
+//! }
+//!
+//! MemAlloc::set_oom_hook(on_oom);
+//! ```
+//!
+//! The hook runs without the allocator lock held, so it can deallocate and allocate with
+//! any allocator. Allocations of the hook that run out of memory fail straight away
+//! instead of calling it again.
+
+use std::{
+    alloc::Layout,
+    cell::Cell,
+    mem,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use lock_api::RawMutex;
+
+use crate::memalloc::MemAlloc;
+
+thread_local! {
+    /// Whether the hook is running on the current thread.
+    static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Hook set with [`MemAlloc::set_oom_hook`], or null if there is none.
+static HOOK: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+
+/// What to do with an allocation that ran out of memory. Returned by the hook of
+/// [`MemAlloc::set_oom_hook`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OomAction {
+    /// Try the allocation again, because the hook released some memory.
+    Retry,
+    /// Give up and return null.
+    Fail,
+}
+
+impl MemAlloc {
+    /// Calls `hook` with the layout of every allocation that runs out of memory or goes
+    /// over the quota, with any allocator, before it fails. See the `oom` module.
+    ///
+    /// The allocation is tried again for as long as the hook returns [`OomAction::Retry`],
+    /// so it must give up at some point. It must not unwind, since the allocator may be
+    /// called by the global allocator.
+    pub fn set_oom_hook(hook: fn(Layout) -> OomAction) {
+        HOOK.store(hook as *mut (), Ordering::Release);
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Calls the hook for the allocation of `layout` that ran out of memory. Returns
+    /// whether it has to be tried again.
+    #[cold]
+    pub(crate) fn retry_after_oom(layout: Layout) -> bool {
+        let hook = HOOK.load(Ordering::Acquire);
+
+        if hook.is_null() || IN_HOOK.get() {
+            return false;
+        }
+
+        let hook = unsafe { mem::transmute::<*mut (), fn(Layout) -> OomAction>(hook) };
+
+        IN_HOOK.set(true);
+        let action = hook(layout);
+        IN_HOOK.set(false);
+
+        action == OomAction::Retry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::Config;
+
+    /// Nothing else allocates this size, so other tests are not affected by the hook.
+    const SIZE: usize = 3 * 1024 + 7;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn retry_twice(layout: Layout) -> OomAction {
+        if layout.size() != SIZE {
+            return OomAction::Fail;
+        }
+
+        match CALLS.fetch_add(1, Ordering::Relaxed) {
+            0 | 1 => OomAction::Retry,
+            _ => OomAction::Fail,
+        }
+    }
+
+    #[test]
+    fn hook_decides_whether_to_retry() {
+        MemAlloc::set_oom_hook(retry_twice);
+
+        let allocator = MemAlloc::with_config(Config::new().quota(SIZE - 1));
+        let layout = Layout::from_size_align(SIZE, 8).unwrap();
+
+        unsafe {
+            assert!(allocator.allocate(layout).is_null());
+            assert_eq!(CALLS.load(Ordering::Relaxed), 3);
+            assert_eq!(allocator.stats().quota_failures, 3);
+
+            // Smaller allocations fit, so the hook isn't called.
+            let ptr = allocator.allocate(Layout::new::<u64>());
+            assert!(!ptr.is_null());
+            assert_eq!(CALLS.load(Ordering::Relaxed), 3);
+            allocator.deallocate(ptr, Layout::new::<u64>());
+        }
+    }
+}
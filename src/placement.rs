@@ -0,0 +1,419 @@
+//! Placement strategies: how a free block is picked for an allocation.
+//!
+//! [`FreeList::find_free_block`] walks the free list and hands every block that can hold
+//! the request to a [`PlacementStrategy`], which picks one of them. The built-in
+//! [`FitPolicy`] variants are strategies too, and users can plug their own heuristics
+//! with [`crate::Config::placement_strategy`]:
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::{Config, FreeBlock, FreeBlocks, MemAlloc, PlacementStrategy};
+//!
+//! /// Takes the block with the highest address, which keeps the low part of the heap
+//! /// free for bigger allocations.
+//! struct HighestAddress;
+//!
+//! impl PlacementStrategy for HighestAddress {
+//!     fn select<'a>(&self, _request: Layout, blocks: FreeBlocks<'a>) -> Option<FreeBlock<'a>> {
+//!         blocks.max_by_key(|block| block.addr() as usize)
+//!     }
+//! }
+//!
+//! static ALLOCATOR: MemAlloc = MemAlloc::with_config(Config::new().placement_strategy(&HighestAddress));
+//! ```
+//!
+//! [`FreeList::find_free_block`]: crate::freelist::FreeList::find_free_block
+
+use std::{
+    alloc::Layout,
+    cell::Cell,
+    cmp, fmt,
+    hash::BuildHasher,
+    marker::PhantomData,
+    mem,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    block::{BLOCK_FOOTER_SIZE, BLOCK_HEADER_SIZE, Block},
+    config::FitPolicy,
+    freelist::FreeNode,
+    list::{List, Node},
+    memalloc::MIN_BLOCK_SIZE,
+    utils::align,
+};
+
+/// Picks the free block where an allocation is placed.
+///
+/// Strategies are shared by every thread using the allocator and they are called while
+/// the allocator lock is held, so they must not allocate from the same allocator.
+pub trait PlacementStrategy: Sync {
+    /// Returns one of the `blocks` for the `request`, or `None` to pick none of them, in
+    /// which case a new region is mapped. Every block given can hold the request.
+    fn select<'a>(&self, request: Layout, blocks: FreeBlocks<'a>) -> Option<FreeBlock<'a>>;
+
+    /// Whether the blocks are given starting from the one picked by the previous call
+    /// (wrapping around the free list) instead of from the start of the free list. This
+    /// is how [`FitPolicy::NextFit`] works. Defaults to `false`.
+    fn resume_search(&self) -> bool {
+        false
+    }
+}
+
+impl PlacementStrategy for FitPolicy {
+    fn select<'a>(&self, _request: Layout, mut blocks: FreeBlocks<'a>) -> Option<FreeBlock<'a>> {
+        match self {
+            Self::FirstFit | Self::NextFit => blocks.next(),
+            Self::BestFit => {
+                let mut best: Option<FreeBlock<'a>> = None;
+
+                for block in blocks {
+                    // Nothing can beat this block
+                    if block.slack() == 0 {
+                        return Some(block);
+                    }
+
+                    if best.is_none_or(|best| block.slack() < best.slack()) {
+                        best = Some(block);
+                    }
+                }
+
+                best
+            }
+            Self::Random => {
+                // Reservoir sampling: the i-th block replaces the pick with probability 1/i,
+                // so every block ends up picked with the same probability.
+                let mut picked = None;
+
+                for (seen, block) in blocks.enumerate() {
+                    if random().is_multiple_of(seen as u64 + 1) {
+                        picked = Some(block);
+                    }
+                }
+
+                picked
+            }
+        }
+    }
+
+    fn resume_search(&self) -> bool {
+        *self == Self::NextFit
+    }
+}
+
+impl FitPolicy {
+    /// Returns the policy as a strategy that can be stored in the allocator.
+    pub(crate) const fn as_strategy(self) -> &'static dyn PlacementStrategy {
+        match self {
+            Self::FirstFit => &Self::FirstFit,
+            Self::BestFit => &Self::BestFit,
+            Self::NextFit => &Self::NextFit,
+            Self::Random => &Self::Random,
+        }
+    }
+}
+
+/// State of the generator of [`random`], or 0 until it is seeded.
+static RANDOM_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Returns a pseudo-random number for [`FitPolicy::Random`] and the key of the free
+/// list (see [`List::append_node_keyed`]) using SplitMix64. The state
+/// is seeded from the random keys of [`std::hash::RandomState`], which come from the OS
+/// and don't need any allocation, so the sequence is different on every run.
+pub(crate) fn random() -> u64 {
+    const GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+    if RANDOM_STATE.load(Ordering::Relaxed) == 0 {
+        let seed = std::hash::RandomState::new().hash_one(0u64) | 1;
+        let _ = RANDOM_STATE.compare_exchange(0, seed, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    // Every call takes a different step, even if several threads race.
+    let mut z = RANDOM_STATE.fetch_add(GAMMA, Ordering::Relaxed).wrapping_add(GAMMA);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+
+    z ^ (z >> 31)
+}
+
+/// Strategy given to [`crate::Config::placement_strategy`]. Two strategies are equal if
+/// they are the same object.
+#[derive(Clone, Copy)]
+pub(crate) struct Placement(pub &'static dyn PlacementStrategy);
+
+impl PartialEq for Placement {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::addr_eq(self.0, other.0)
+    }
+}
+
+impl Eq for Placement {}
+
+impl fmt::Debug for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Placement").field(&(self.0 as *const dyn PlacementStrategy)).finish()
+    }
+}
+
+/// A free block that can hold the request given to [`PlacementStrategy::select`].
+#[derive(Clone, Copy)]
+pub struct FreeBlock<'a> {
+    pub(crate) node: FreeNode,
+    addr: *mut u8,
+    size: usize,
+    slack: usize,
+    numa_node: Option<u32>,
+    _list: PhantomData<&'a ()>,
+}
+
+impl FreeBlock<'_> {
+    /// Address where the contents of the block start.
+    pub fn addr(&self) -> *mut u8 {
+        self.addr
+    }
+
+    /// Number of bytes the block can hold.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Number of bytes that would be left in the block after placing the request,
+    /// including the padding needed to align it.
+    pub fn slack(&self) -> usize {
+        self.slack
+    }
+
+    /// NUMA node of the region of the block, if it is bound to one.
+    pub fn numa_node(&self) -> Option<u32> {
+        self.numa_node
+    }
+}
+
+impl fmt::Debug for FreeBlock<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FreeBlock")
+            .field("addr", &self.addr)
+            .field("size", &self.size)
+            .field("slack", &self.slack)
+            .field("numa_node", &self.numa_node)
+            .finish()
+    }
+}
+
+/// Which NUMA nodes [`FreeBlocks`] yields blocks from.
+#[derive(Clone, Copy)]
+pub(crate) enum NodeFilter {
+    /// Every block.
+    Any,
+    /// Only blocks of regions bound to the node.
+    Local(Option<u32>),
+    /// Only blocks of regions not bound to the node.
+    Remote(Option<u32>),
+}
+
+/// Iterator over the free blocks that can hold a request, in free list order.
+/// See [`PlacementStrategy::select`].
+pub struct FreeBlocks<'a> {
+    current: Option<FreeNode>,
+    /// Free list the nodes belong to, whose first node is where we wrap around when
+    /// resuming the search.
+    list: &'a List<NonNull<Node<Block>>>,
+    /// Key the links of the nodes are XORed with. See [`List::append_node_keyed`].
+    key: usize,
+    /// Number of nodes of the free list that haven't been visited yet.
+    remaining: usize,
+    /// Counter of the nodes visited, for [`crate::SearchStats`].
+    visited: &'a Cell<usize>,
+    layout: Layout,
+    filter: NodeFilter,
+}
+
+impl<'a> FreeBlocks<'a> {
+    /// Visits `len` nodes of `list`, whose links are XORed with `key`, starting from
+    /// `start` and wrapping around, and counts them in `visited`. The nodes stay valid
+    /// since we are borrowing the list.
+    pub(crate) fn new(
+        list: &'a List<NonNull<Node<Block>>>,
+        key: usize,
+        start: Option<FreeNode>,
+        len: usize,
+        layout: Layout,
+        filter: NodeFilter,
+        visited: &'a Cell<usize>,
+    ) -> Self {
+        Self { current: start, list, key, remaining: len, visited, layout, filter }
+    }
+}
+
+impl<'a> Iterator for FreeBlocks<'a> {
+    type Item = FreeBlock<'a>;
+
+    fn next(&mut self) -> Option<FreeBlock<'a>> {
+        // This is the size we need, including aligment
+        let layout_size = align(self.layout.size(), mem::size_of::<usize>());
+
+        while self.remaining > 0 {
+            let free_node = self.current?;
+            self.remaining -= 1;
+            self.visited.set(self.visited.get() + 1);
+
+            unsafe {
+                self.current = self.list.next_keyed(free_node, self.key).or(self.list.first());
+
+                let block = free_node.as_ref().data;
+
+                // The padding depends on where the payload of this concrete block starts
+                let payload = (block.as_ptr() as usize) + BLOCK_HEADER_SIZE;
+                let padding = align(payload, self.layout.align()) - payload;
+
+                // The minimun block size we can give to the user is `MIN_BLOCK_SIZE`. If we
+                // didn't do this, we wouldn't be able to store our allocator's metadata on
+                // small memory requests.
+                let needed_size = cmp::max(layout_size + padding + BLOCK_FOOTER_SIZE, MIN_BLOCK_SIZE);
+                let size = block.as_ref().data.size();
+
+                if size < needed_size {
+                    continue;
+                }
+
+                let numa_node = block.as_ref().data.region.as_ref().data.node;
+
+                let accepted = match self.filter {
+                    NodeFilter::Any => true,
+                    NodeFilter::Local(node) => numa_node == node,
+                    NodeFilter::Remote(node) => numa_node != node,
+                };
+
+                if accepted {
+                    return Some(FreeBlock {
+                        node: free_node,
+                        addr: block.as_ptr().cast::<u8>().with_addr(payload),
+                        size: size - BLOCK_FOOTER_SIZE,
+                        slack: size - needed_size,
+                        numa_node,
+                        _list: PhantomData,
+                    });
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{Config, MemAlloc};
+
+    /// Takes the block that leaves the most space behind and counts the candidates.
+    struct WorstFit(AtomicUsize);
+
+    impl PlacementStrategy for WorstFit {
+        fn select<'a>(&self, request: Layout, blocks: FreeBlocks<'a>) -> Option<FreeBlock<'a>> {
+            blocks
+                .inspect(|block| {
+                    assert!(block.size() >= request.size());
+                    self.0.fetch_add(1, Ordering::Relaxed);
+                })
+                .max_by_key(FreeBlock::slack)
+        }
+    }
+
+    /// Never reuses free blocks.
+    struct AlwaysMap;
+
+    impl PlacementStrategy for AlwaysMap {
+        fn select<'a>(&self, _request: Layout, _blocks: FreeBlocks<'a>) -> Option<FreeBlock<'a>> {
+            None
+        }
+    }
+
+    /// Leaves two free blocks, `small` and then `big`, with a used one in between. Also
+    /// returns the used ones, which take 32 bytes.
+    unsafe fn fragment(allocator: &MemAlloc) -> (*mut u8, *mut u8, [*mut u8; 2]) {
+        let sizes = [300, 32, 600, 32];
+
+        unsafe {
+            let [small, first, big, second] = sizes.map(|size| allocator.allocate(Layout::from_size_align(size, 8).unwrap()));
+
+            allocator.deallocate(small, Layout::from_size_align(sizes[0], 8).unwrap());
+            allocator.deallocate(big, Layout::from_size_align(sizes[2], 8).unwrap());
+
+            (small, big, [first, second])
+        }
+    }
+
+    #[test]
+    fn custom_strategies_pick_blocks() {
+        static WORST_FIT: WorstFit = WorstFit(AtomicUsize::new(0));
+        static ALWAYS_MAP: AlwaysMap = AlwaysMap;
+
+        let request = Layout::from_size_align(200, 8).unwrap();
+
+        unsafe {
+            // The tail of the region is the biggest block.
+            let worst_fit = MemAlloc::with_config(Config::new().placement_strategy(&WORST_FIT));
+            let (small, big, used) = fragment(&worst_fit);
+            WORST_FIT.0.store(0, Ordering::Relaxed);
+
+            let ptr = worst_fit.allocate(request);
+            assert!(ptr != small && ptr != big);
+            assert_eq!(WORST_FIT.0.load(Ordering::Relaxed), 3);
+
+            let always_map = MemAlloc::with_config(Config::new().placement_strategy(&ALWAYS_MAP));
+            // Even the tail of the current region is ignored, so every allocation maps
+            // more memory, which may be merged with the last region.
+            let (_, _, always_map_used) = fragment(&always_map);
+            let free_bytes = always_map.summary().free_bytes;
+            let always_map_ptr = always_map.allocate(request);
+            assert!(always_map.summary().free_bytes > free_bytes);
+
+            for (allocator, ptr, used) in [(&worst_fit, ptr, used), (&always_map, always_map_ptr, always_map_used)] {
+                allocator.deallocate(ptr, request);
+
+                for ptr in used {
+                    allocator.deallocate(ptr, Layout::from_size_align(32, 8).unwrap());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn random_fit_spreads_allocations() {
+        let allocator = MemAlloc::with_config(Config::new().fit_policy(FitPolicy::Random));
+        let hole = Layout::from_size_align(256, 8).unwrap();
+        let separator = Layout::from_size_align(16, 8).unwrap();
+
+        unsafe {
+            // Eight holes of the same size, plus the tail of the region.
+            let (holes, separators): (Vec<_>, Vec<_>) = (0..8)
+                .map(|_| (allocator.allocate(hole), allocator.allocate(separator)))
+                .unzip();
+
+            for &ptr in &holes {
+                allocator.deallocate(ptr, hole);
+            }
+
+            // Taking a hole and giving it back leaves the heap as it was, so every
+            // allocation picks among the same blocks.
+            let picks: Vec<_> = (0..64)
+                .map(|_| {
+                    let ptr = allocator.allocate(hole);
+                    allocator.deallocate(ptr, hole);
+                    ptr
+                })
+                .collect();
+
+            let distinct = picks.iter().filter(|ptr| holes.contains(ptr)).collect::<std::collections::HashSet<_>>();
+            assert!(distinct.len() > 1);
+
+            for ptr in separators {
+                allocator.deallocate(ptr, separator);
+            }
+        }
+    }
+}
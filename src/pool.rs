@@ -0,0 +1,288 @@
+//! Address space reservation for regions.
+//!
+//! With [`crate::Config::reserve`], a big contiguous range of address space is reserved
+//! up front without any access (`PROT_NONE` / `MEM_RESERVE`), which doesn't use any
+//! physical memory. New regions are then committed as slices of it, so all of them are
+//! next to each other:
+//!
+//! ```text
+//!                                  Reservation
+//! +----------+-------------------+---------+--------------------+-------------------+
+//! | Slot map |      Region       |  Free   |       Region       |     Reserved      |
+//! +----------+-------------------+---------+--------------------+-------------------+
+//! ^          ^         ^         ^
+//! |          |         |         |
+//! base       |         Slot      Slot boundary
+//!            |
+//!            +-- base + n * POOL_SLOT_SIZE
+//! ```
+//!
+//! The reservation is split in slots of [`POOL_SLOT_SIZE`] bytes and every region starts
+//! at a slot boundary and takes whole slots. The state of each slot is one byte of the
+//! slot map, stored in the first slots of the reservation itself. Finding the region of a
+//! pointer is arithmetic: the slot is `(addr - base) / POOL_SLOT_SIZE` and the region
+//! starts at the closest slot before it that starts a region.
+//!
+//! Regions that don't fit anymore are mapped the regular way.
+
+use std::ptr::{self, NonNull};
+
+use crate::{
+    kernel::{commit, reserve_memory, return_memory, uncommit, Kernel},
+    list::Node,
+    region::Region,
+    syscalls::Syscall,
+    utils::align,
+};
+
+/// Size of the slots regions are made of. This is the allocation granularity of Windows.
+pub(crate) const POOL_SLOT_SIZE: usize = 64 * 1024;
+
+/// The slot is not committed.
+const FREE: u8 = 0;
+/// The slot is the first one of a region.
+const START: u8 = 1;
+/// The slot is part of the region that starts in a previous slot.
+const CONTINUATION: u8 = 2;
+
+/// Reserved address space where regions are committed. See the module docs.
+pub(crate) struct Pool {
+    /// Start of the reservation, whose provenance is exposed. The slot map lives here.
+    base: usize,
+    /// Number of slots of the reservation, including the ones of the slot map.
+    slots: usize,
+    /// Number of slots taken by the slot map.
+    map_slots: usize,
+}
+
+impl Pool {
+    /// Reserves `len` bytes of address space. Returns `None` if the platform doesn't
+    /// support reservations or the reservation fails.
+    pub(crate) fn reserve(len: usize) -> Option<Self> {
+        let len = align(len, POOL_SLOT_SIZE);
+        let slots = len / POOL_SLOT_SIZE;
+        let map_slots = align(slots, POOL_SLOT_SIZE) / POOL_SLOT_SIZE;
+
+        // There must be room for at least one region.
+        if slots <= map_slots {
+            return None;
+        }
+
+        unsafe {
+            let base = reserve_memory(len)?.as_ptr().expose_provenance();
+
+            // Fresh memory is zeroed, so every slot starts `FREE`.
+            if !commit(ptr::with_exposed_provenance_mut(base), map_slots * POOL_SLOT_SIZE) {
+                return_memory(ptr::with_exposed_provenance_mut(base), len);
+                return None;
+            }
+
+            let mut pool = Self { base, slots, map_slots };
+            pool.map_mut()[..map_slots].fill(START);
+
+            Some(pool)
+        }
+    }
+
+    /// State of every slot.
+    fn map(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(ptr::with_exposed_provenance(self.base), self.slots) }
+    }
+
+    fn map_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(ptr::with_exposed_provenance_mut(self.base), self.slots) }
+    }
+
+    /// Whether `addr` is inside the reservation.
+    #[inline]
+    pub(crate) fn contains(&self, addr: usize) -> bool {
+        (self.base..self.base + self.slots * POOL_SLOT_SIZE).contains(&addr)
+    }
+
+    /// Commits whole slots for a region of at least `len` bytes. Returns the address and
+    /// the size of the region, or `None` if there are not enough consecutive free slots.
+    pub(crate) fn take(&mut self, len: usize) -> Option<(NonNull<u8>, usize)> {
+        // The region gets the whole of its last slot instead of wasting the rest of it.
+        let count = align(len, POOL_SLOT_SIZE) / POOL_SLOT_SIZE;
+        let first = self.find_free(count)?;
+
+        let addr = self.base + first * POOL_SLOT_SIZE;
+        let len = count * POOL_SLOT_SIZE;
+
+        unsafe {
+            if !commit(ptr::with_exposed_provenance_mut(addr), len) {
+                return None;
+            }
+        }
+
+        let map = self.map_mut();
+        map[first] = START;
+        map[first + 1..first + count].fill(CONTINUATION);
+
+        Some((NonNull::new(ptr::with_exposed_provenance_mut(addr))?, len))
+    }
+
+    /// Returns the first of `count` consecutive free slots.
+    fn find_free(&self, count: usize) -> Option<usize> {
+        let map = self.map();
+        let mut first = self.map_slots;
+
+        while first + count <= self.slots {
+            match map[first..first + count].iter().rposition(|&slot| slot != FREE) {
+                // Skip past the last used slot, no run can contain it.
+                Some(used) => first += used + 1,
+                None => return Some(first),
+            }
+        }
+
+        None
+    }
+
+    /// Decommits the region of `len` bytes starting from `addr` taken from [`Pool::take`].
+    pub(crate) fn release(&mut self, addr: *mut u8, len: usize) {
+        let first = (addr as usize - self.base) / POOL_SLOT_SIZE;
+        let count = len / POOL_SLOT_SIZE;
+
+        unsafe { uncommit(addr, len) };
+
+        self.map_mut()[first..first + count].fill(FREE);
+    }
+
+    /// Returns the region that contains `addr`, if any.
+    pub(crate) fn region_of(&self, addr: usize) -> Option<NonNull<Node<Region>>> {
+        NonNull::new(ptr::with_exposed_provenance_mut(self.region_start(addr)?))
+    }
+
+    /// Returns the start of the region that contains `addr`, if any.
+    fn region_start(&self, addr: usize) -> Option<usize> {
+        if !self.contains(addr) {
+            return None;
+        }
+
+        let map = self.map();
+        let mut slot = (addr - self.base) / POOL_SLOT_SIZE;
+
+        if slot < self.map_slots || map[slot] == FREE {
+            return None;
+        }
+
+        while map[slot] == CONTINUATION {
+            slot -= 1;
+        }
+
+        Some(self.base + slot * POOL_SLOT_SIZE)
+    }
+
+    /// Number of bytes committed for regions.
+    #[cfg(test)]
+    fn committed(&self) -> usize {
+        let used = self.map()[self.map_slots..].iter().filter(|&&slot| slot != FREE).count();
+
+        used * POOL_SLOT_SIZE
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        unsafe { return_memory(ptr::with_exposed_provenance_mut(self.base), self.slots * POOL_SLOT_SIZE) };
+    }
+}
+
+impl Kernel {
+    /// Takes at least `len` bytes for a new region from the reservation, if there is one
+    /// with enough free space. Returns the address and the actual size of the region.
+    pub(crate) fn take_from_pool(&mut self, len: usize) -> Option<(NonNull<u8>, usize)> {
+        self.pool.as_mut()?.take(len)
+    }
+
+    /// Gives the memory of size `len` starting from `addr` of a region back, either to the
+    /// reservation or to the OS, through the unmapper thread if it is running.
+    pub(crate) unsafe fn unmap_region(&mut self, addr: *mut u8, len: usize) {
+        match &mut self.pool {
+            Some(pool) if pool.contains(addr as usize) => {
+                self.syscalls.count(Syscall::Protect);
+                pool.release(addr, len);
+            }
+            _ => {
+                self.syscalls.count(Syscall::Unmap);
+
+                if !self.unmapper.queue_unmap(addr, len) {
+                    unsafe { return_memory(addr, len) };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::*;
+    use crate::{Config, MemAlloc};
+
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend", feature = "sbrk"), ignore = "needs reservations")]
+    fn regions_are_carved_from_the_reservation() {
+        let allocator = MemAlloc::with_config(Config::new().reserve(4 * POOL_SLOT_SIZE));
+        let layout = Layout::from_size_align(POOL_SLOT_SIZE / 2, 8).unwrap();
+
+        unsafe {
+            // Each allocation needs its own region.
+            let [a, b, c] = [(); 3].map(|_| allocator.allocate(layout));
+
+            let kernel = allocator.allocator.lock();
+            let pool = kernel.pool.as_ref().unwrap();
+
+            // The first slot holds the slot map.
+            assert!(pool.contains(a as usize) && pool.contains(b as usize) && pool.contains(c as usize));
+            assert_eq!(pool.region_start(a as usize), Some(pool.base + POOL_SLOT_SIZE));
+            assert_eq!(pool.region_start(b as usize), Some(pool.base + 2 * POOL_SLOT_SIZE));
+            assert_eq!(pool.committed(), 3 * POOL_SLOT_SIZE);
+            drop(kernel);
+
+            assert!(allocator.owns(b.add(100)));
+
+            // The reservation is full, so this one is mapped somewhere else.
+            let d = allocator.allocate(layout);
+            assert!(!allocator.allocator.lock().pool.as_ref().unwrap().contains(d as usize));
+            assert!(allocator.owns_allocation(d));
+
+            // Released slots are reused.
+            allocator.deallocate(b, layout);
+            assert_eq!(allocator.allocator.lock().pool.as_ref().unwrap().committed(), 2 * POOL_SLOT_SIZE);
+            assert!(!allocator.owns_allocation(b));
+
+            let e = allocator.allocate(layout);
+            assert_eq!(e, b);
+
+            for ptr in [a, c, d, e] {
+                allocator.deallocate(ptr, layout);
+            }
+
+            assert_eq!(allocator.allocator.lock().pool.as_ref().unwrap().committed(), 0);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend", feature = "sbrk"), ignore = "needs reservations")]
+    fn regions_span_several_slots() {
+        let allocator = MemAlloc::with_config(Config::new().reserve(16 * POOL_SLOT_SIZE));
+        let layout = Layout::from_size_align(3 * POOL_SLOT_SIZE, 8).unwrap();
+
+        unsafe {
+            let ptr = allocator.allocate(layout);
+            let end = ptr.add(layout.size() - 1);
+
+            let kernel = allocator.allocator.lock();
+            let pool = kernel.pool.as_ref().unwrap();
+
+            assert_eq!(pool.committed(), 4 * POOL_SLOT_SIZE);
+            assert_eq!(pool.region_start(end as usize), pool.region_start(ptr as usize));
+            drop(kernel);
+
+            assert!(allocator.owns(end));
+            allocator.deallocate(ptr, layout);
+        }
+    }
+}
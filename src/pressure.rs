@@ -0,0 +1,261 @@
+//! Memory pressure.
+//!
+//! Cached regions and the free blocks of live regions keep physical memory that the
+//! program isn't using. That is what makes allocating fast, but when the system runs
+//! low on memory it is better given back than swapped out or met by the OOM killer.
+//! [`MemAlloc::release_memory`] does it on demand: it unmaps the cached regions, like
+//! [`MemAlloc::trim`], and tells the OS that the pages inside free blocks can be
+//! reclaimed (`MADV_FREE` / `MEM_RESET`), which keeps them mapped so nothing has to be
+//! done before reusing them.
+//!
+//! [`MemAlloc::watch_memory_pressure`] starts a thread that does it whenever the OS
+//! reports memory pressure, and then calls a hook so the program can drop its own
+//! caches too:
+//!
+//! - Linux: a PSI trigger on the `memory.pressure` file of the cgroup of the process,
+//!   or on `/proc/pressure/memory`, that fires when tasks stall on memory for more than
+//!   150 ms in a 2 second window.
+//! - Windows: a low memory resource notification (`CreateMemoryResourceNotification`).
+//!
+//! Other platforms don't report memory pressure. The thread runs for as long as the
+//! process does, and responds at most once per second.
+
+use std::{mem, ptr::NonNull, thread, time::Duration};
+
+use lock_api::RawMutex;
+
+use crate::{
+    block::{BLOCK_FOOTER_SIZE, BLOCK_HEADER_SIZE, Block},
+    config::Decommit,
+    kernel::{Kernel, decommit},
+    list::Node,
+    memalloc::MemAlloc,
+    syscalls::Syscall,
+    utils::align,
+};
+
+/// Minimum time between two responses to memory pressure. Windows keeps the
+/// notification signalled for as long as the memory is low.
+const COOLDOWN: Duration = Duration::from_secs(1);
+
+/// PSI trigger written to the pressure file: some task stalled on memory for 150 ms in
+/// a window of 2 seconds, the shortest window unprivileged processes can use.
+#[cfg(target_os = "linux")]
+const PSI_TRIGGER: &[u8] = b"some 150000 2000000\0";
+
+impl Kernel {
+    /// Tells the OS that the whole pages inside the free blocks of every region can be
+    /// reclaimed. Returns the number of bytes of those pages.
+    ///
+    /// The header, the free list node and the footer of every free block are kept. The
+    /// pages stay mapped and writable, so blocks carved from them later don't need to
+    /// recommit anything, and they read as zeroes or as their old contents until they
+    /// are written.
+    pub(crate) fn decommit_free_blocks(&mut self) -> usize {
+        let mut released = 0;
+        let mut region = self.regions.first();
+
+        while let Some(current) = region {
+            unsafe {
+                // Full regions don't have any free page to release.
+                let data = &current.as_ref().data;
+                let mut block = data.blocks.first().filter(|_| data.may_hold(self.page_size));
+
+                while let Some(node) = block {
+                    let data = &node.as_ref().data;
+
+                    if data.is_free() {
+                        let payload = node.as_ptr().addr() + BLOCK_HEADER_SIZE;
+                        let start = align(payload + mem::size_of::<Node<NonNull<Node<Block>>>>(), self.page_size);
+                        let end = (payload + data.size() - BLOCK_FOOTER_SIZE) & !(self.page_size - 1);
+
+                        if end > start {
+                            self.syscalls.count(Syscall::Advise);
+                            decommit(node.as_ptr().cast::<u8>().with_addr(start), end - start, Decommit::Lazy);
+                            released += end - start;
+                        }
+                    }
+
+                    block = node.as_ref().next;
+                }
+
+                region = current.as_ref().next;
+            }
+        }
+
+        released
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Gives as much physical memory back to the OS as possible without moving any
+    /// allocation: unmaps the cached regions like [`MemAlloc::trim`] and lets the OS
+    /// reclaim the pages inside free blocks. See the `pressure` module.
+    ///
+    /// Returns the number of bytes released.
+    pub fn release_memory(&self) -> usize {
+        let mut kernel = self.lock_draining();
+
+        kernel.trim() + kernel.decommit_free_blocks()
+    }
+
+    /// Starts a thread that calls [`MemAlloc::release_memory`] and then `hook`, if any,
+    /// whenever the OS reports memory pressure. See the `pressure` module.
+    ///
+    /// The hook runs without the allocator lock held, so it can free the caches of the
+    /// program. Fails if the platform doesn't report memory pressure or the
+    /// notification can't be set up.
+    pub fn watch_memory_pressure(&'static self, hook: Option<fn()>) -> Result<(), &'static str>
+    where
+        R: Sync,
+    {
+        let source = PressureSource::open()?;
+
+        thread::Builder::new()
+            .name("memalloc-pressure".into())
+            .spawn(move || {
+                while source.wait() {
+                    self.release_memory();
+
+                    if let Some(hook) = hook {
+                        hook();
+                    }
+
+                    thread::sleep(COOLDOWN);
+                }
+            })
+            .map_err(|_| "could not spawn the memory pressure thread")?;
+
+        Ok(())
+    }
+}
+
+/// Memory pressure notifications of the OS.
+struct PressureSource {
+    /// Pressure file with a PSI trigger.
+    #[cfg(target_os = "linux")]
+    file: std::fs::File,
+    /// Low memory resource notification.
+    #[cfg(windows)]
+    handle: windows::Win32::Foundation::HANDLE,
+}
+
+// The handle is only used by the thread that waits on it.
+#[cfg(windows)]
+unsafe impl Send for PressureSource {}
+
+#[cfg(target_os = "linux")]
+impl PressureSource {
+    fn open() -> Result<Self, &'static str> {
+        use std::{fs, io::Write};
+
+        // The pressure of the cgroup is the one that matters in a container.
+        let cgroup = fs::read_to_string("/proc/self/cgroup").ok().and_then(|cgroups| {
+            let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+            Some(format!("/sys/fs/cgroup{}/memory.pressure", path.trim_end_matches('/')))
+        });
+
+        for path in cgroup.iter().map(String::as_str).chain(["/proc/pressure/memory"]) {
+            let Ok(mut file) = fs::OpenOptions::new().read(true).write(true).open(path) else {
+                continue;
+            };
+
+            if file.write(PSI_TRIGGER).is_ok() {
+                return Ok(Self { file });
+            }
+        }
+
+        Err("PSI memory pressure triggers are not available")
+    }
+
+    /// Blocks until the trigger fires. Returns `false` if it never will.
+    fn wait(&self) -> bool {
+        use std::os::fd::AsRawFd;
+
+        let mut fd = libc::pollfd { fd: self.file.as_raw_fd(), events: libc::POLLPRI, revents: 0 };
+
+        loop {
+            match unsafe { libc::poll(&mut fd, 1, -1) } {
+                1.. if fd.revents & libc::POLLERR != 0 => return false,
+                1.. => return true,
+                _ if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => {}
+                _ => return false,
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+impl PressureSource {
+    fn open() -> Result<Self, &'static str> {
+        use windows::Win32::System::Memory::{CreateMemoryResourceNotification, LowMemoryResourceNotification};
+
+        let handle = unsafe { CreateMemoryResourceNotification(LowMemoryResourceNotification) }
+            .map_err(|_| "CreateMemoryResourceNotification failed")?;
+
+        Ok(Self { handle })
+    }
+
+    /// Blocks until the memory is low. Returns `false` if it can't wait anymore.
+    fn wait(&self) -> bool {
+        use windows::Win32::{
+            Foundation::WAIT_OBJECT_0,
+            System::Threading::{INFINITE, WaitForSingleObject},
+        };
+
+        unsafe { WaitForSingleObject(self.handle, INFINITE) == WAIT_OBJECT_0 }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+impl PressureSource {
+    fn open() -> Result<Self, &'static str> {
+        Err("memory pressure notifications are not supported on this platform")
+    }
+
+    fn wait(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::*;
+    use crate::{Config, kernel::page_size};
+
+    #[test]
+    fn free_pages_and_cached_regions_are_released() {
+        let allocator = MemAlloc::with_config(Config::new().cached_regions(1));
+        let page_size = page_size();
+        let small = Layout::from_size_align(100, 8).unwrap();
+        let big = Layout::from_size_align(16 * page_size, 8).unwrap();
+
+        unsafe {
+            let neighbour = allocator.allocate(small);
+            let ptr = allocator.allocate(big);
+            ptr.write_bytes(0xAB, big.size());
+            allocator.deallocate(ptr, big);
+
+            // The pages of the free block, but not its header, node and footer.
+            let released = allocator.release_memory();
+            assert!(released >= 14 * page_size, "released {released} bytes");
+            assert_eq!(released % page_size, 0);
+
+            // Released pages can be allocated again right away.
+            let ptr = allocator.allocate(big);
+            ptr.write_bytes(0xCD, big.size());
+            assert_eq!(ptr.add(big.size() - 1).read(), 0xCD);
+
+            allocator.deallocate(ptr, big);
+            allocator.deallocate(neighbour, small);
+            assert_eq!(allocator.allocator.lock().cache.len(), 1);
+
+            // The empty region was cached, and is unmapped now.
+            assert!(allocator.release_memory() >= big.size());
+            assert!(allocator.allocator.lock().cache.is_empty());
+            assert_eq!(allocator.stats().allocations, 0);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Quarantine of empty regions.
+//!
+//! A dangling pointer into a region that has been unmapped only faults until the OS
+//! hands the same addresses out again, and from then on it silently reads or corrupts
+//! whatever got mapped there. With [`crate::Config::quarantine_regions`], empty regions
+//! are made inaccessible (`PROT_NONE` / `PAGE_NOACCESS`) instead, and kept like that
+//! until newer ones push them out:
+//!
+//! ```text
+//!  free(x): region empty      quarantine full              free(y): region empty
+//!            |                      |                                |
+//!            v                      v                                v
+//!  +------------------+------------------+------------------+------------------+
+//!  | oldest: unmapped |     PROT_NONE    |     PROT_NONE    |     PROT_NONE    |
+//!  +------------------+------------------+------------------+------------------+
+//! ```
+//!
+//! Any access through a pointer into a quarantined region faults right away, at the
+//! instruction that makes it. The pages are decommitted too, so the quarantine only
+//! holds address space, not physical memory.
+//!
+//! Region headers can't be read once their pages are inaccessible, so the quarantined
+//! regions are kept in a fixed array instead of a [`List`](crate::list::List). Regions
+//! carved from a [`crate::Config::reserve`] and the empty regions kept by
+//! [`crate::Config::cached_regions`] are not quarantined, and neither are platforms that
+//! can't change the protection of their memory, like the `system-backend` one.
+
+use std::ptr;
+
+use crate::{
+    config::Decommit,
+    kernel::{Kernel, Protection, decommit, protect},
+    syscalls::Syscall,
+};
+
+/// Maximum number of regions in quarantine, see [`crate::Config::quarantine_regions`].
+pub const MAX_QUARANTINED_REGIONS: usize = 64;
+
+/// Regions in quarantine, from the oldest to the newest. See the module docs.
+pub(crate) struct Quarantine {
+    /// Address and size of every region, in a ring that starts at `oldest`.
+    regions: [(*mut u8, usize); MAX_QUARANTINED_REGIONS],
+    /// Index of the oldest region.
+    oldest: usize,
+    /// Number of regions.
+    len: usize,
+}
+
+impl Quarantine {
+    pub(crate) const fn new() -> Self {
+        Self { regions: [(ptr::null_mut(), 0); MAX_QUARANTINED_REGIONS], oldest: 0, len: 0 }
+    }
+
+    /// Number of regions in quarantine.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Bytes of the regions in quarantine.
+    pub(crate) fn bytes(&self) -> usize {
+        (0..self.len).map(|index| self.regions[(self.oldest + index) % MAX_QUARANTINED_REGIONS].1).sum()
+    }
+
+    /// Adds the region of size `len` starting from `addr`. If there are `limit` regions
+    /// already, the oldest one leaves to make room and is returned.
+    fn push(&mut self, addr: *mut u8, len: usize, limit: usize) -> Option<(*mut u8, usize)> {
+        let evicted = if self.len == limit { self.pop() } else { None };
+
+        self.regions[(self.oldest + self.len) % MAX_QUARANTINED_REGIONS] = (addr, len);
+        self.len += 1;
+
+        evicted
+    }
+
+    /// Removes the oldest region, if there is any.
+    pub(crate) fn pop(&mut self) -> Option<(*mut u8, usize)> {
+        (self.len > 0).then(|| {
+            let oldest = self.regions[self.oldest];
+            self.oldest = (self.oldest + 1) % MAX_QUARANTINED_REGIONS;
+            self.len -= 1;
+
+            oldest
+        })
+    }
+}
+
+impl Kernel {
+    /// Puts the empty region of size `len` starting from `addr` in quarantine, unmapping
+    /// the oldest one if there is no room for it. Returns `false` if the region can't be
+    /// quarantined, in which case it has to be unmapped as usual.
+    ///
+    /// # Safety
+    ///
+    /// The region must have been removed from every list of the kernel.
+    pub(crate) unsafe fn quarantine_region(&mut self, addr: *mut u8, len: usize) -> bool {
+        let limit = match self.config.quarantine_regions {
+            0 if self.config.electric_fence => MAX_QUARANTINED_REGIONS,
+            count => count.min(MAX_QUARANTINED_REGIONS),
+        };
+
+        if limit == 0 || self.pool.as_ref().is_some_and(|pool| pool.contains(addr.addr())) {
+            return false;
+        }
+
+        unsafe {
+            self.syscalls.count(Syscall::Protect);
+
+            if !protect(addr, len, Protection::Inaccessible) {
+                return false;
+            }
+
+            // Nothing can read the pages anymore, so their contents can go.
+            self.syscalls.count(Syscall::Advise);
+            decommit(addr, len, Decommit::Eager);
+
+            if let Some((oldest, oldest_len)) = self.quarantine.push(addr, len, limit) {
+                self.unmap_region(oldest, oldest_len);
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::*;
+    use crate::{Config, MemAlloc};
+
+    #[test]
+    fn oldest_regions_leave_first() {
+        let mut quarantine = Quarantine::new();
+        let addr = |i: usize| ptr::without_provenance_mut(i * 4096);
+
+        for i in 0..3 {
+            assert_eq!(quarantine.push(addr(i), 4096, 3), None);
+        }
+
+        // The ring wraps around the end of the array.
+        for i in 3..MAX_QUARANTINED_REGIONS * 2 {
+            assert_eq!(quarantine.push(addr(i), 4096, 3), Some((addr(i - 3), 4096)));
+        }
+
+        assert_eq!(quarantine.len(), 3);
+    }
+
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend"), ignore = "needs OS memory")]
+    fn empty_regions_are_made_inaccessible() {
+        let allocator = MemAlloc::with_config(Config::new().quarantine_regions(2));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        unsafe {
+            // Each allocation empties its region when it is freed.
+            for i in 0..3 {
+                let ptr = allocator.allocate(layout);
+                allocator.deallocate(ptr, layout);
+
+                #[cfg(target_os = "linux")]
+                assert_eq!(crate::utils::tests::permissions_of(ptr), "---p");
+
+                assert_eq!(allocator.summary().quarantined_regions, (i + 1).min(2));
+            }
+
+            assert_eq!(allocator.summary().regions, 0);
+            assert_eq!(allocator.stats().allocations, 0);
+        }
+    }
+}
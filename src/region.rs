@@ -1,4 +1,4 @@
-use std::{mem, ptr::NonNull};
+use std::{ffi::CStr, mem, ptr::NonNull};
 use crate::{block::{BLOCK_HEADER_SIZE, Block}, freelist::FreeList, list::{List, Node}};
 
 
@@ -29,29 +29,133 @@ pub struct Region {
     pub size: usize,
     /// List of blocks in the region
     pub blocks: List<Block>,
+    /// What the region is used for.
+    pub kind: RegionKind,
+    /// NUMA node the region is bound to, if any. See [`crate::Config::numa_aware`].
+    pub node: Option<u32>,
+    /// Bytes of the blocks of the region that are in the free list, without their headers.
+    pub free_bytes: usize,
+    /// No block of the region in the free list is bigger than this. It is raised when a
+    /// bigger block enters the free list, but blocks leaving it only lower it down to
+    /// `free_bytes`, so it may be bigger than the actual largest block. See
+    /// [`Region::may_hold`].
+    pub largest_free: usize,
+    /// Whether the region was mapped by [`crate::MemAlloc::reserve`], so it is kept mapped
+    /// when it becomes empty and never merged with other mappings.
+    pub reserved: bool,
 }
 
+/// The different kinds of [`Region`] we map.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RegionKind {
+    /// Regular region split into blocks that are tracked by the free list.
+    Blocks,
+    /// Dedicated mapping for a single large allocation, recorded in
+    /// [`crate::kernel::Kernel::large_objects`]. See [`crate::kernel::Kernel::allocate_large`].
+    Large,
+    /// Dedicated mapping for a single allocation of executable code, recorded in
+    /// [`crate::kernel::Kernel::executable`]. See [`crate::kernel::Kernel::allocate_executable`].
+    Executable,
+    /// Dedicated mapping for a single allocation which is not recorded anywhere, so
+    /// it can be created and released without taking the lock. See
+    /// [`crate::kernel::Kernel::allocate_detached`].
+    Detached,
+    /// Dedicated mapping for a single allocation followed by a guard page, recorded in
+    /// [`crate::kernel::Kernel::large_objects`]. See [`crate::kernel::Kernel::allocate_fenced`].
+    Fenced,
+}
+
+impl RegionKind {
+    /// Name given to the mappings of this kind, see [`crate::kernel::name_memory`].
+    pub(crate) fn name(self) -> &'static CStr {
+        match self {
+            Self::Blocks => c"memalloc:region",
+            Self::Large => c"memalloc:large",
+            Self::Executable => c"memalloc:executable",
+            Self::Detached => c"memalloc:detached",
+            Self::Fenced => c"memalloc:fenced",
+        }
+    }
+}
 
 impl Region {
+    /// Creates the header of a region of `size` bytes without any block.
+    pub(crate) const fn new(size: usize, kind: RegionKind, node: Option<u32>) -> Self {
+        Self { size, blocks: List::new(), kind, node, free_bytes: 0, largest_free: 0, reserved: false }
+    }
+
+    /// Records that a free block of `size` bytes of the region entered the free list.
+    #[inline]
+    pub(crate) fn track_free(&mut self, size: usize) {
+        self.free_bytes += size;
+        self.largest_free = self.largest_free.max(size);
+    }
+
+    /// Records that a free block of `size` bytes of the region left the free list.
+    #[inline]
+    pub(crate) fn untrack_free(&mut self, size: usize) {
+        self.free_bytes -= size;
+        self.largest_free = self.largest_free.min(self.free_bytes);
+    }
+
+    /// Whether the region may have a block of at least `size` bytes in the free list.
+    /// If it doesn't, none of its blocks has to be visited to look for one.
+    #[inline]
+    pub(crate) fn may_hold(&self, size: usize) -> bool {
+        self.largest_free >= size
+    }
+
+    /// Tells whether `addr` falls inside the memory mapped for the given region `node`,
+    /// including its header.
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to a valid region header.
+    #[inline]
+    pub(crate) unsafe fn contains(node: NonNull<Node<Region>>, addr: usize) -> bool {
+        unsafe {
+            let start = node.as_ptr() as usize;
+            let end = start + REGION_HEADER_SIZE + node.as_ref().data.size;
+
+            (start..end).contains(&addr)
+        }
+    }
+
     /// Tries to merge the given block `node` with the previous one
-    /// on the list. This can be performed if that previos block is free.
-    pub(crate) fn merge_with_prev(&mut self, node: &mut NonNull<Node<Block>>, free_list: &mut FreeList) {
+    /// in memory. This can be performed if that previos block is free.
+    ///
+    /// The previous block is located through its boundary tag (see [`Block::prev_in_region`]),
+    /// so this does not need to walk the [`Region::blocks`] list.
+    ///
+    /// Returns the previous block as an error, without merging it, if its header is
+    /// corrupted. See [`Block::is_intact`].
+    pub(crate) fn merge_with_prev(
+        &mut self,
+        node: &mut NonNull<Node<Block>>,
+        free_list: &mut FreeList,
+    ) -> Result<(), NonNull<Node<Block>>> {
         unsafe {
             let block = &mut node.as_mut().data;
 
             // If the previous block is free, we can merge it with this one.
-            if let Some(mut prev_node) = node.as_ref().prev {
+            if let Some(mut prev_node) = Block::prev_in_region(*node) {
+                if !Block::is_intact(prev_node) {
+                    return Err(prev_node);
+                }
+
                 let prev_block = &mut prev_node.as_mut().data;
 
-                if prev_block.is_free {
+                if prev_block.is_free() {
                     // As prev_block is already in the `free list` we just need to increment its size
                     // and remove its adjacent block with which we are going to merge this one from the list
 
                     // We extract the previous one from the free_list temporarily, this avoids corruption problems.
-                    free_list.remove_free_block(prev_node);
+                    free_list.unlink_free_block(prev_node);
+                    self.untrack_free(prev_block.size());
 
                     // We need to cover the header and the actual content of the block
-                    prev_block.size += BLOCK_HEADER_SIZE + block.size;
+                    prev_block.set_size(prev_block.size() + BLOCK_HEADER_SIZE + block.size());
+                    Block::write_footer(prev_node);
                     
                     // We remove the block from the list since it is going to be merged
                     self.blocks.remove(*node);
@@ -61,25 +165,43 @@ impl Region {
                 }
             }
         }
+
+        Ok(())
     }
 
-    /// Tries to merge the given block `node` with the next one on the
-    /// list. This can be performed if that next block is free.
-    pub(crate) fn merge_with_next(&mut self, node: &mut NonNull<Node<Block>>, free_list: &mut FreeList) {
+    /// Tries to merge the given block `node` with the next one in
+    /// memory. This can be performed if that next block is free.
+    ///
+    /// Returns the next block as an error, without merging it, if its header is
+    /// corrupted. See [`Block::is_intact`].
+    pub(crate) fn merge_with_next(
+        &mut self,
+        node: &mut NonNull<Node<Block>>,
+        free_list: &mut FreeList,
+    ) -> Result<(), NonNull<Node<Block>>> {
         unsafe {
-            if let Some(mut next_node) = node.as_ref().next {
+            if let Some(mut next_node) = Block::next_in_region(*node) {
+                if !Block::is_intact(next_node) {
+                    return Err(next_node);
+                }
+
                 let next_block = &mut next_node.as_mut().data;
 
-                if next_block.is_free {
+                if next_block.is_free() {
                     // The current block should already be on the free_list, so we just need to absorb the next one.
-                    free_list.remove_free_block(next_node);
+                    free_list.unlink_free_block(next_node);
+                    self.untrack_free(next_block.size());
 
-                    node.as_mut().data.size += BLOCK_HEADER_SIZE + next_block.size;
+                    let size = node.as_ref().data.size() + BLOCK_HEADER_SIZE + next_block.size();
+                    node.as_mut().data.set_size(size);
+                    Block::write_footer(*node);
                     // We remove the block from the list since it is going to be merged                   
                     self.blocks.remove(next_node);
                }
             }
         }
+
+        Ok(())
     }
 
 }
\ No newline at end of file
@@ -0,0 +1,120 @@
+//! Read-only sealing of allocations.
+//!
+//! Page protections can only be changed for whole pages, and the pages of a block are
+//! usually shared with other blocks and with the headers. Writing to a sealed header
+//! would crash the allocator, so only the pages that are fully inside the content of
+//! the block are sealed:
+//!
+//! ```text
+//!          Page boundary              Page boundary              Page boundary
+//!                |                          |                          |
+//! +--------+-----+--------------------------+--------------------------+---+--------+
+//! | Header | ... |          Sealed          |          Sealed          |...| Footer |
+//! +--------+-----+--------------------------+--------------------------+---+--------+
+//!          ^                                                               ^
+//!          |                                                               |
+//!          Payload                                                         End of content
+//! ```
+//!
+//! The header, the footer and the neighbours of the block stay writable, so the block
+//! can still be merged and freed. Whether a block is sealed is recorded in its footer.
+//! See [`Block::set_sealed`].
+
+use std::ptr::NonNull;
+
+use crate::{
+    block::{BLOCK_FOOTER_SIZE, BLOCK_HEADER_SIZE, Block},
+    error::AllocError,
+    kernel::{Kernel, Protection, page_size, protect},
+    list::Node,
+    region::RegionKind,
+    syscalls::Syscall,
+    utils::align,
+};
+
+impl Kernel {
+    /// Makes the pages fully inside the allocation `ptr` read-only.
+    pub(crate) fn seal(&mut self, ptr: *mut u8) -> Result<(), AllocError> {
+        let block = self.find_used_block(ptr).ok_or(AllocError::InvalidPointer)?;
+
+        unsafe {
+            if block.as_ref().data.region.as_ref().data.kind == RegionKind::Executable {
+                return Err(AllocError::Unsupported);
+            }
+
+            if block.as_ref().data.region.as_ref().data.kind == RegionKind::Fenced {
+                return Err(AllocError::Unsupported);
+            }
+
+            if Block::is_sealed(block) {
+                return Ok(());
+            }
+
+            let (start, len) = Self::sealed_pages(block, ptr);
+
+            if len == 0 {
+                return Err(AllocError::Unsupported);
+            }
+
+            self.syscalls.count(Syscall::Protect);
+
+            if !protect(start, len, Protection::ReadOnly) {
+                return Err(AllocError::last_os_error());
+            }
+
+            Block::set_sealed(block, true);
+        }
+
+        Ok(())
+    }
+
+    /// Makes the allocation `ptr` sealed by [`Kernel::seal`] writable again.
+    pub(crate) fn unseal(&mut self, ptr: *mut u8) -> Result<(), AllocError> {
+        let block = self.find_used_block(ptr).ok_or(AllocError::InvalidPointer)?;
+
+        unsafe {
+            if Block::is_sealed(block) {
+                self.unseal_block(block, ptr);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes the pages of the sealed `block` writable again.
+    ///
+    /// # Safety
+    ///
+    /// `block` must be a used block and `ptr` its payload.
+    pub(crate) unsafe fn unseal_block(&mut self, block: NonNull<Node<Block>>, ptr: *mut u8) {
+        unsafe {
+            let (start, len) = Self::sealed_pages(block, ptr);
+
+            // The pages were already writable before, so this can't fail unless the
+            // mapping itself is gone.
+            self.syscalls.count(Syscall::Protect);
+            let restored = protect(start, len, Protection::Writable);
+            debug_assert!(restored, "mprotect syscall failed");
+
+            Block::set_sealed(block, false);
+        }
+    }
+
+    /// Returns the start and the length of the pages that are fully inside the content
+    /// of `block`, starting from its payload `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `block` must be a used block and `ptr` its payload.
+    unsafe fn sealed_pages(block: NonNull<Node<Block>>, ptr: *mut u8) -> (*mut u8, usize) {
+        unsafe {
+            let page_size = page_size();
+
+            let content_end = block.as_ptr() as usize + BLOCK_HEADER_SIZE + block.as_ref().data.size() - BLOCK_FOOTER_SIZE;
+            let start = align(ptr as usize, page_size);
+            let end = content_end & !(page_size - 1);
+
+            (ptr.with_addr(start), end.saturating_sub(start))
+        }
+    }
+}
@@ -0,0 +1,465 @@
+//! Heap in shared memory for cooperating processes.
+//!
+//! A [`SharedHeap`] lives in a single mapping of an anonymous shared memory object
+//! (`memfd_create` on Linux, `shm_open` on other Unix systems and `CreateFileMapping` on
+//! Windows) that other processes can map too, after inheriting or receiving its file
+//! descriptor or handle. Every process maps it at a different address, so allocations
+//! are identified by their offset from the start of the mapping instead of a pointer,
+//! and so is every piece of metadata:
+//!
+//! ```text
+//! +--------+-------+---------------+-------+---------------+-------+---------+
+//! | Header | Chunk |    Payload    | Chunk |    Payload    | Chunk |  Free   |
+//! +--------+-------+---------------+-------+---------------+-------+---------+
+//!     |                                                        ^
+//!     |                  Offset of the first free chunk        |
+//!     +--------------------------------------------------------+
+//! ```
+//!
+//! The blocks, regions and free list of [`crate::MemAlloc`] link each other with absolute
+//! pointers, so they can't live in shared memory. The shared heap is a much simpler
+//! first-fit allocator instead: every chunk starts with its size (whose lowest bit tells
+//! whether it is used) and free chunks also store the offset of the next free chunk, in
+//! address order so that neighbours can be merged. The header holds a [`SpinRawMutex`],
+//! which only uses atomics in the mapping and so it works across processes. A process
+//! that dies while holding it leaves the heap locked.
+
+use std::{mem, ptr::NonNull};
+
+use lock_api::RawMutex;
+
+use crate::{config::MIN_ALIGN, error::AllocError, sync::SpinRawMutex, utils::align};
+
+/// Identifies the header of a shared heap, so a mapping of something else is refused.
+const MAGIC: u64 = u64::from_le_bytes(*b"memalloc");
+
+/// Size of the [`Header`], rounded up to the alignment of the payloads.
+const HEADER_SIZE: usize = align(mem::size_of::<Header>(), MIN_ALIGN);
+
+/// Size of the metadata at the start of every chunk: its size and, while it is free, the
+/// offset of the next free chunk.
+const CHUNK_HEADER_SIZE: usize = MIN_ALIGN;
+
+/// Smallest chunk worth splitting off a free chunk.
+const MIN_CHUNK_SIZE: usize = 2 * CHUNK_HEADER_SIZE;
+
+/// Lowest bit of the size of a chunk, set while it is used.
+const USED: usize = 1;
+
+/// Start of the mapping.
+#[repr(C)]
+struct Header {
+    /// Always [`MAGIC`].
+    magic: u64,
+    /// Size of the whole mapping.
+    size: usize,
+    /// Offset of the first free chunk, or 0 if there is none.
+    free: usize,
+    /// Taken by every operation on the chunks.
+    lock: SpinRawMutex,
+}
+
+/// Allocator over memory shared with other processes. See the module docs.
+///
+/// ```rust
+/// use memalloc::SharedHeap;
+///
+/// let heap = SharedHeap::create(1 << 20).unwrap();
+/// let offset = heap.allocate(64).unwrap();
+///
+/// // Another process would map the heap with `SharedHeap::from_fd` and get the same
+/// // bytes at a different address from the same offset.
+/// unsafe {
+///     heap.ptr(offset).write_bytes(7, 64);
+///     heap.deallocate(offset);
+/// }
+/// ```
+pub struct SharedHeap {
+    /// Start of the mapping in this process.
+    base: NonNull<u8>,
+    /// Size of the mapping.
+    size: usize,
+    /// File descriptor of the shared memory object, owned by the heap.
+    #[cfg(unix)]
+    fd: std::os::fd::RawFd,
+    /// Handle of the file mapping object, owned by the heap.
+    #[cfg(windows)]
+    handle: std::os::windows::io::RawHandle,
+}
+
+unsafe impl Send for SharedHeap {}
+unsafe impl Sync for SharedHeap {}
+
+impl SharedHeap {
+    /// Creates a shared memory object of `size` bytes, rounded up to the page size, and
+    /// maps it as an empty heap.
+    pub fn create(size: usize) -> Result<Self, AllocError> {
+        let size = align(size.max(HEADER_SIZE + MIN_CHUNK_SIZE), crate::kernel::page_size());
+
+        unsafe {
+            let heap = Self::create_mapping(size)?;
+            let base = heap.base.as_ptr();
+
+            heap.base.cast::<Header>().as_ptr().write(Header {
+                magic: MAGIC,
+                size,
+                free: HEADER_SIZE,
+                lock: SpinRawMutex::INIT,
+            });
+            base.add(HEADER_SIZE).cast::<[usize; 2]>().write([size - HEADER_SIZE, 0]);
+
+            Ok(heap)
+        }
+    }
+
+    /// Size of the whole mapping, including the metadata.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Allocates `size` bytes aligned to [`MIN_ALIGN`] and returns their offset, which
+    /// is valid in every process that maps the heap. Returns `None` if there is no free
+    /// chunk big enough.
+    pub fn allocate(&self, size: usize) -> Option<usize> {
+        let needed = align(size.checked_add(CHUNK_HEADER_SIZE)?, MIN_ALIGN).max(MIN_CHUNK_SIZE);
+
+        self.header().lock.lock();
+
+        unsafe {
+            let header = &mut *self.base.cast::<Header>().as_ptr();
+            let mut link: *mut usize = &mut header.free;
+
+            while *link != 0 {
+                let chunk = *link;
+                let [chunk_size, next] = *self.chunk(chunk);
+
+                if chunk_size >= needed {
+                    if chunk_size - needed >= MIN_CHUNK_SIZE {
+                        *self.chunk(chunk + needed) = [chunk_size - needed, next];
+                        *link = chunk + needed;
+                        (*self.chunk(chunk))[0] = needed | USED;
+                    } else {
+                        *link = next;
+                        (*self.chunk(chunk))[0] = chunk_size | USED;
+                    }
+
+                    header.lock.unlock();
+                    return Some(chunk + CHUNK_HEADER_SIZE);
+                }
+
+                link = &mut (*self.chunk(chunk))[1];
+            }
+
+            header.lock.unlock();
+            None
+        }
+    }
+
+    /// Frees the allocation at `offset`, merging it with the free chunks around it.
+    ///
+    /// # Safety
+    ///
+    /// `offset` must have been returned by [`SharedHeap::allocate`] on this heap, in
+    /// any process, and not freed yet.
+    pub unsafe fn deallocate(&self, offset: usize) {
+        self.header().lock.lock();
+
+        unsafe {
+            let header = &mut *self.base.cast::<Header>().as_ptr();
+            let chunk = offset - CHUNK_HEADER_SIZE;
+            let mut size = (*self.chunk(chunk))[0] & !USED;
+
+            // Free chunks are kept in address order, find the ones around this one.
+            let mut prev = 0;
+            let mut next = header.free;
+
+            while next != 0 && next < chunk {
+                prev = next;
+                next = (*self.chunk(next))[1];
+            }
+
+            if next == chunk + size {
+                let [next_size, after] = *self.chunk(next);
+                size += next_size;
+                next = after;
+            }
+
+            if prev != 0 && prev + (*self.chunk(prev))[0] == chunk {
+                *self.chunk(prev) = [(*self.chunk(prev))[0] + size, next];
+            } else {
+                *self.chunk(chunk) = [size, next];
+
+                match prev {
+                    0 => header.free = chunk,
+                    prev => (*self.chunk(prev))[1] = chunk,
+                }
+            }
+
+            header.lock.unlock();
+        }
+    }
+
+    /// Returns the address of `offset` in this process.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` is outside of the heap.
+    pub fn ptr(&self, offset: usize) -> *mut u8 {
+        assert!(offset < self.size(), "offset outside of the shared heap");
+
+        unsafe { self.base.as_ptr().add(offset) }
+    }
+
+    /// Returns the offset of `ptr`, or `None` if it doesn't point inside the heap.
+    pub fn offset_of(&self, ptr: *const u8) -> Option<usize> {
+        let offset = (ptr as usize).checked_sub(self.base.as_ptr() as usize)?;
+
+        (offset < self.size()).then_some(offset)
+    }
+
+    #[inline]
+    fn header(&self) -> &Header {
+        unsafe { self.base.cast::<Header>().as_ref() }
+    }
+
+    /// Size and next free chunk of the chunk at `offset`.
+    #[inline]
+    unsafe fn chunk(&self, offset: usize) -> *mut [usize; 2] {
+        unsafe { self.base.as_ptr().add(offset).cast() }
+    }
+
+    /// Checks the header of a heap mapped by [`SharedHeap::from_fd`] or
+    /// [`SharedHeap::from_handle`].
+    fn validate(self) -> Result<Self, AllocError> {
+        if self.header().magic != MAGIC || self.header().size != self.size {
+            return Err(AllocError::InvalidPointer);
+        }
+
+        Ok(self)
+    }
+}
+
+#[cfg(unix)]
+impl SharedHeap {
+    /// Maps the heap whose shared memory object is `fd`, for example one inherited from
+    /// the process that created it or received through a Unix socket.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be owned by the caller, it is closed when the heap is dropped.
+    pub unsafe fn from_fd(fd: std::os::fd::RawFd) -> Result<Self, AllocError> {
+        unsafe {
+            let mut stat = mem::zeroed::<libc::stat>();
+
+            if libc::fstat(fd, &mut stat) != 0 {
+                let error = AllocError::last_os_error();
+                libc::close(fd);
+                return Err(error);
+            }
+
+            let size = stat.st_size as usize;
+            if size < HEADER_SIZE {
+                libc::close(fd);
+                return Err(AllocError::InvalidPointer);
+            }
+
+            Self::map(fd, size)?.validate()
+        }
+    }
+
+    /// File descriptor of the shared memory object, to share the heap with other
+    /// processes. It is still owned by the heap.
+    pub fn fd(&self) -> std::os::fd::RawFd {
+        self.fd
+    }
+
+    /// Creates a shared memory object of `size` bytes and maps it.
+    unsafe fn create_mapping(size: usize) -> Result<Self, AllocError> {
+        unsafe {
+            #[cfg(target_os = "linux")]
+            let fd = libc::memfd_create(c"memalloc-shared".as_ptr(), libc::MFD_CLOEXEC);
+
+            // The object only needs a name until it is opened.
+            #[cfg(not(target_os = "linux"))]
+            let fd = {
+                use std::sync::atomic::{AtomicUsize, Ordering};
+                static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+                let mut name = [0u8; 48];
+                let mut cursor = std::io::Cursor::new(&mut name[..]);
+                let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+                let _ = std::io::Write::write_fmt(&mut cursor, format_args!("/memalloc-{}-{counter}", libc::getpid()));
+
+                let fd = libc::shm_open(name.as_ptr().cast(), libc::O_RDWR | libc::O_CREAT | libc::O_EXCL, 0o600);
+                libc::shm_unlink(name.as_ptr().cast());
+                fd
+            };
+
+            if fd < 0 {
+                return Err(AllocError::last_os_error());
+            }
+
+            if libc::ftruncate(fd, size as libc::off_t) != 0 {
+                let error = AllocError::last_os_error();
+                libc::close(fd);
+                return Err(error);
+            }
+
+            Self::map(fd, size)
+        }
+    }
+
+    /// Maps `size` bytes of `fd` with `MAP_SHARED`, closing `fd` if it fails.
+    unsafe fn map(fd: std::os::fd::RawFd, size: usize) -> Result<Self, AllocError> {
+        const PROT: libc::c_int = libc::PROT_READ | libc::PROT_WRITE;
+
+        unsafe {
+            match libc::mmap(std::ptr::null_mut(), size, PROT, libc::MAP_SHARED, fd, 0) {
+                libc::MAP_FAILED => {
+                    let error = AllocError::last_os_error();
+                    libc::close(fd);
+                    Err(error)
+                }
+                addr => Ok(Self { base: NonNull::new_unchecked(addr.cast()), size, fd }),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SharedHeap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base.as_ptr().cast(), self.size);
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(windows)]
+impl SharedHeap {
+    /// Maps the heap whose file mapping object is `handle`, for example one inherited
+    /// from the process that created it or copied with `DuplicateHandle`.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be owned by the caller, it is closed when the heap is dropped.
+    pub unsafe fn from_handle(handle: std::os::windows::io::RawHandle) -> Result<Self, AllocError> {
+        unsafe {
+            let mut heap = Self::map(handle)?;
+
+            // The view covers the whole object, which is at least one page, so the
+            // header can be read to learn its size.
+            if heap.header().magic == MAGIC {
+                heap.size = heap.header().size;
+            }
+
+            heap.validate()
+        }
+    }
+
+    /// Handle of the file mapping object, to share the heap with other processes. It is
+    /// still owned by the heap.
+    pub fn handle(&self) -> std::os::windows::io::RawHandle {
+        self.handle
+    }
+
+    /// Creates a file mapping object of `size` bytes backed by the paging file and maps it.
+    unsafe fn create_mapping(size: usize) -> Result<Self, AllocError> {
+        use windows::Win32::{
+            Foundation::INVALID_HANDLE_VALUE,
+            System::Memory::{CreateFileMappingW, PAGE_READWRITE},
+        };
+
+        unsafe {
+            let size = size as u64;
+            let handle = CreateFileMappingW(
+                INVALID_HANDLE_VALUE,
+                None,
+                PAGE_READWRITE,
+                (size >> 32) as u32,
+                size as u32,
+                windows::core::PCWSTR::null(),
+            )
+            .map_err(|_| AllocError::last_os_error())?;
+
+            let mut heap = Self::map(handle.0)?;
+            heap.size = size as usize;
+
+            Ok(heap)
+        }
+    }
+
+    /// Maps a view of the whole object, closing `handle` if it fails. The size is left
+    /// for the caller to fill in.
+    unsafe fn map(handle: std::os::windows::io::RawHandle) -> Result<Self, AllocError> {
+        use windows::Win32::{
+            Foundation::{CloseHandle, HANDLE},
+            System::Memory::{FILE_MAP_ALL_ACCESS, MapViewOfFile},
+        };
+
+        unsafe {
+            let view = MapViewOfFile(HANDLE(handle), FILE_MAP_ALL_ACCESS, 0, 0, 0);
+
+            match NonNull::new(view.Value.cast::<u8>()) {
+                Some(base) => Ok(Self { base, size: 0, handle }),
+                None => {
+                    let error = AllocError::last_os_error();
+                    let _ = CloseHandle(HANDLE(handle));
+                    Err(error)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for SharedHeap {
+    fn drop(&mut self) {
+        use windows::Win32::{
+            Foundation::{CloseHandle, HANDLE},
+            System::Memory::{MEMORY_MAPPED_VIEW_ADDRESS, UnmapViewOfFile},
+        };
+
+        unsafe {
+            let _ = UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS { Value: self.base.as_ptr().cast() });
+            let _ = CloseHandle(HANDLE(self.handle));
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs shared memory")]
+    fn offsets_are_shared_between_mappings() {
+        let heap = SharedHeap::create(64 * 1024).unwrap();
+        let other = unsafe { SharedHeap::from_fd(libc::dup(heap.fd())).unwrap() };
+        assert_ne!(heap.ptr(0), other.ptr(0));
+
+        let first = heap.allocate(100).unwrap();
+        let second = other.allocate(100).unwrap();
+        assert_eq!(first % MIN_ALIGN, 0);
+        assert!(second >= first + 100);
+
+        unsafe {
+            heap.ptr(first).write_bytes(0xAB, 100);
+            assert_eq!(*other.ptr(first + 99), 0xAB);
+
+            other.deallocate(first);
+            heap.deallocate(second);
+        }
+
+        // Everything was merged back into a single free chunk.
+        let whole = heap.allocate(heap.size() - HEADER_SIZE - CHUNK_HEADER_SIZE).unwrap();
+        assert_eq!(whole, first);
+        assert_eq!(heap.allocate(1), None);
+        assert_eq!(heap.offset_of(heap.ptr(whole)), Some(whole));
+
+        let not_a_heap = unsafe { libc::memfd_create(c"other".as_ptr(), 0) };
+        unsafe { libc::ftruncate(not_a_heap, 4096) };
+        assert!(unsafe { SharedHeap::from_fd(not_a_heap) }.is_err());
+    }
+}
@@ -0,0 +1,536 @@
+//! Snapshots of the heap.
+//!
+//! [`MemAlloc::snapshot`] copies every region of blocks and every large object into a
+//! [`Snapshot`], headers, free list and payloads included, and [`MemAlloc::restore`]
+//! replaces the heap with the copy. Simulations can then checkpoint a dedicated allocator
+//! and go back to the checkpoint later, in the same process or, since the image is plain
+//! bytes, in another run of the same program:
+//!
+//! ```text
+//! +--------+--------+--------------------+--------+--------------------+-----+
+//! | Header | Record | Region (raw bytes) | Record | Region (raw bytes) | ... |
+//! +--------+--------+--------------------+--------+--------------------+-----+
+//! ```
+//!
+//! The copied headers still point into the regions they were taken from, so restoring
+//! maps every region at its old address if it is free and, otherwise, anywhere else at
+//! the same offset from a 64 KiB boundary, which keeps the alignment of the allocations.
+//! Then the links of the regions and blocks, the free list and the reflection words of
+//! padded payloads are written again (see [`Block::relocate_reflection`]). Pointers into
+//! a region that moved are translated with [`Snapshot::relocate`].
+
+use std::{mem, ptr::{self, NonNull}, slice};
+
+use lock_api::RawMutex;
+
+#[cfg(feature = "tagging")]
+use crate::tag::TagTable;
+#[cfg(feature = "dhat")]
+use crate::dhat::DhatTable;
+use crate::{
+    block::{BLOCK_HEADER_SIZE, Block},
+    error::AllocError,
+    freelist::FreeList,
+    heap::{Stats, StatsCounters},
+    kernel::{Kernel, name_memory, page_size, request_aligned_memory, request_memory, request_memory_at, return_memory},
+    list::Node,
+    memalloc::MemAlloc,
+    pool::POOL_SLOT_SIZE,
+    region::{REGION_HEADER_SIZE, Region, RegionKind},
+    utils::align,
+};
+
+/// First word of every image.
+const MAGIC: usize = 0x6d65_6d73;
+
+/// Restored regions that can't be placed at their old address keep their offset from a
+/// multiple of this, so allocations aligned to up to this many bytes stay aligned.
+const RELOCATION_ALIGN: usize = POOL_SLOT_SIZE;
+
+/// [`Record::kind`] of a region of blocks.
+const KIND_BLOCKS: usize = 0;
+
+/// [`Record::kind`] of a large object.
+const KIND_LARGE: usize = 1;
+
+/// Start of the image.
+#[repr(C)]
+struct Header {
+    /// Always [`MAGIC`].
+    magic: usize,
+    /// Size of the whole image.
+    len: usize,
+    /// [`BLOCK_HEADER_SIZE`] of the build that took the snapshot, which depends on the
+    /// enabled features.
+    block_header_size: usize,
+    /// Number of regions in the image.
+    regions: usize,
+    /// Fields of the [`Stats`] of the heap.
+    stats: [usize; 4],
+}
+
+/// Placed before the bytes of every region.
+#[repr(C)]
+struct Record {
+    /// [`KIND_BLOCKS`] or [`KIND_LARGE`].
+    kind: usize,
+    /// Address of the region when the snapshot was taken.
+    addr: usize,
+    /// Size of the region, headers included.
+    size: usize,
+    /// Address of the region after the last restore, or 0.
+    restored: usize,
+}
+
+/// Copy of a heap taken with [`MemAlloc::snapshot`]. See the module docs.
+///
+/// The image is mapped directly from the OS, so taking a snapshot of the global
+/// allocator doesn't need to allocate.
+pub struct Snapshot {
+    /// Start of the mapping that holds the image.
+    image: NonNull<u8>,
+    /// Size of the image.
+    len: usize,
+}
+
+unsafe impl Send for Snapshot {}
+unsafe impl Sync for Snapshot {}
+
+impl Snapshot {
+    /// Maps an image of `len` bytes.
+    fn map(len: usize) -> Result<Self, AllocError> {
+        unsafe {
+            let image = request_memory(align(len, page_size()), false).ok_or_else(AllocError::last_os_error)?;
+
+            Ok(Self { image, len })
+        }
+    }
+
+    /// Bytes of the image, to store it somewhere.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.image.as_ptr(), self.len) }
+    }
+
+    /// Loads an image returned by [`Snapshot::as_bytes`]. Fails if it is not one, or if
+    /// it was taken by a build whose blocks have a different layout.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        if bytes.len() < mem::size_of::<Header>() {
+            return Err("not a heap snapshot");
+        }
+
+        let snapshot = Self::map(bytes.len()).map_err(|_| "mmap syscall returned None")?;
+
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), snapshot.image.as_ptr(), bytes.len());
+        }
+
+        let header = snapshot.header();
+        if header.magic != MAGIC || header.len != bytes.len() {
+            return Err("not a heap snapshot");
+        }
+
+        if header.block_header_size != BLOCK_HEADER_SIZE {
+            return Err("snapshot taken with other block headers");
+        }
+
+        snapshot.validate()?;
+
+        Ok(snapshot)
+    }
+
+    /// Translates `ptr`, which pointed into the heap when the snapshot was taken, to
+    /// where it is after the last [`MemAlloc::restore`] of this snapshot. Returns `None`
+    /// if it didn't point into the heap or if the snapshot hasn't been restored.
+    pub fn relocate(&self, ptr: *const u8) -> Option<*mut u8> {
+        let addr = ptr as usize;
+
+        self.records()
+            .map(|(record, _)| unsafe { record.as_ref() })
+            .find(|record| record.restored != 0 && (record.addr..record.addr + record.size).contains(&addr))
+            .map(|record| ptr::with_exposed_provenance_mut(record.restored + addr - record.addr))
+    }
+
+    #[inline]
+    fn header(&self) -> &Header {
+        unsafe { self.image.cast::<Header>().as_ref() }
+    }
+
+    /// Every record of the image together with the bytes of its region.
+    fn records(&self) -> impl Iterator<Item = (NonNull<Record>, *mut u8)> {
+        let mut offset = mem::size_of::<Header>();
+
+        (0..self.header().regions).map(move |_| unsafe {
+            let record = self.image.add(offset).cast::<Record>();
+            let bytes = self.image.as_ptr().add(offset + mem::size_of::<Record>());
+            offset += mem::size_of::<Record>() + record.as_ref().size;
+
+            (record, bytes)
+        })
+    }
+
+    /// Checks that every record and block of an image that doesn't come from
+    /// [`MemAlloc::snapshot`] stays inside of it.
+    fn validate(&self) -> Result<(), &'static str> {
+        let mut offset = mem::size_of::<Header>();
+        let min_size = REGION_HEADER_SIZE + BLOCK_HEADER_SIZE;
+
+        for _ in 0..self.header().regions {
+            if offset + mem::size_of::<Record>() > self.len {
+                return Err("truncated heap snapshot");
+            }
+
+            let record = unsafe { &*self.image.as_ptr().add(offset).cast::<Record>() };
+            offset += mem::size_of::<Record>();
+
+            if record.kind > KIND_LARGE || record.size < min_size || record.size > self.len - offset {
+                return Err("corrupted heap snapshot");
+            }
+
+            let mut block = REGION_HEADER_SIZE;
+
+            while block < record.size {
+                if record.size - block < BLOCK_HEADER_SIZE {
+                    return Err("corrupted heap snapshot");
+                }
+
+                let size_word = block + BLOCK_HEADER_SIZE - mem::size_of::<usize>();
+                let size = unsafe { self.image.as_ptr().add(offset + size_word).cast::<usize>().read() } & !0b11;
+
+                if size < mem::size_of::<usize>()
+                    || !size.is_multiple_of(mem::size_of::<usize>())
+                    || size > record.size - block - BLOCK_HEADER_SIZE
+                {
+                    return Err("corrupted heap snapshot");
+                }
+
+                block += BLOCK_HEADER_SIZE + size;
+            }
+
+            offset += record.size;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        unsafe { return_memory(self.image.as_ptr(), align(self.len, page_size())) };
+    }
+}
+
+impl Kernel {
+    /// Copies every region of blocks and large object into a new [`Snapshot`], together
+    /// with the `stats` of the heap.
+    pub(crate) fn snapshot(&mut self, stats: Stats) -> Result<Snapshot, AllocError> {
+        if self.config.hardened {
+            return Err(AllocError::Unsupported);
+        }
+
+        if self.config.electric_fence {
+            return Err(AllocError::Unsupported);
+        }
+
+        if !self.handles.is_empty() {
+            return Err(AllocError::Unsupported);
+        }
+
+        let regions = || self.regions.iter().chain(self.large_objects.iter());
+        let len = mem::size_of::<Header>()
+            + regions().map(|region| mem::size_of::<Record>() + REGION_HEADER_SIZE + region.size).sum::<usize>();
+
+        let snapshot = Snapshot::map(len)?;
+
+        unsafe {
+            snapshot.image.cast::<Header>().as_ptr().write(Header {
+                magic: MAGIC,
+                len,
+                block_header_size: BLOCK_HEADER_SIZE,
+                regions: self.regions.len() + self.large_objects.len(),
+                stats: [stats.allocated, stats.allocations, stats.peak, stats.quota_failures],
+            });
+
+            let mut offset = mem::size_of::<Header>();
+
+            for list in [&self.regions, &self.large_objects] {
+                let mut current = list.first();
+
+                while let Some(region) = current {
+                    let size = REGION_HEADER_SIZE + region.as_ref().data.size;
+                    let kind = match region.as_ref().data.kind {
+                        RegionKind::Large => KIND_LARGE,
+                        _ => KIND_BLOCKS,
+                    };
+
+                    let record = snapshot.image.as_ptr().add(offset);
+                    record.cast::<Record>().write(Record { kind, addr: region.as_ptr() as usize, size, restored: 0 });
+                    ptr::copy_nonoverlapping(region.as_ptr().cast::<u8>(), record.add(mem::size_of::<Record>()), size);
+
+                    offset += mem::size_of::<Record>() + size;
+                    current = region.as_ref().next;
+                }
+            }
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Replaces every region of blocks and large object with the ones of `snapshot`, and
+    /// `stats` with the ones it holds. If a region can't be mapped, the heap is left
+    /// empty.
+    pub(crate) unsafe fn restore(&mut self, snapshot: &mut Snapshot, stats: &StatsCounters) -> Result<(), AllocError> {
+        if self.config.hardened {
+            return Err(AllocError::Unsupported);
+        }
+
+        if self.config.electric_fence {
+            return Err(AllocError::Unsupported);
+        }
+
+        if !self.handles.is_empty() {
+            return Err(AllocError::Unsupported);
+        }
+
+        self.init();
+
+        unsafe {
+            // Releasing the current regions first lets the restored ones take their
+            // addresses back, if they were taken from this heap.
+            self.release_all(stats);
+
+            for (mut record, bytes) in snapshot.records() {
+                let record = record.as_mut();
+                record.restored = 0;
+
+                let Some(addr) = self.map_restored(record.addr, record.size) else {
+                    let error = AllocError::last_os_error();
+                    self.release_all(stats);
+                    return Err(error);
+                };
+
+                ptr::copy_nonoverlapping(bytes, addr.as_ptr(), record.size);
+                self.relink_region(addr, record);
+                record.restored = addr.as_ptr().expose_provenance();
+            }
+        }
+
+        let [allocated, allocations, peak, quota_failures] = snapshot.header().stats;
+        stats.store(Stats { allocated, allocations, peak, quota_failures });
+
+        Ok(())
+    }
+
+    /// Returns every region of blocks and large object to the OS, and resets `stats`.
+    unsafe fn release_all(&mut self, stats: &StatsCounters) {
+        unsafe {
+            while let Some(region) = self.regions.first() {
+                self.regions.remove(region);
+                self.unmap_region(region.as_ptr().cast(), REGION_HEADER_SIZE + region.as_ref().data.size);
+            }
+
+            while let Some(region) = self.large_objects.first() {
+                self.deallocate_large(region);
+            }
+        }
+
+        self.free_list = FreeList::new(self.free_list.strategy, self.config.search_limit);
+        self.free_list.randomize_links();
+        stats.store(Stats::new());
+
+        #[cfg(feature = "tagging")]
+        {
+            self.tags = TagTable::new();
+        }
+
+        #[cfg(feature = "dhat")]
+        {
+            self.dhat = DhatTable::new();
+        }
+    }
+
+    /// Maps `size` bytes at `addr` or, if that range is in use, at the same offset from
+    /// a multiple of [`RELOCATION_ALIGN`].
+    unsafe fn map_restored(&self, addr: usize, size: usize) -> Option<NonNull<u8>> {
+        unsafe {
+            if let Some(addr) = self.count_map(request_memory_at(addr, size)) {
+                return Some(addr);
+            }
+
+            if RELOCATION_ALIGN > self.page_size && !self.config.low_address {
+                let offset = (RELOCATION_ALIGN - addr % RELOCATION_ALIGN) % RELOCATION_ALIGN;
+
+                if let Some(addr) = self.count_map(request_aligned_memory(size, RELOCATION_ALIGN, offset)) {
+                    return Some(addr);
+                }
+            }
+
+            self.count_map(request_memory(size, self.config.low_address))
+        }
+    }
+
+    /// Writes again every pointer of the region of `record` copied to `addr`, and links
+    /// it to [`Kernel::regions`] or [`Kernel::large_objects`]. Restored blocks have no
+    /// tag nor call site, and sealed ones are writable again.
+    unsafe fn relink_region(&mut self, addr: NonNull<u8>, record: &Record) {
+        let kind = if record.kind == KIND_LARGE { RegionKind::Large } else { RegionKind::Blocks };
+
+        unsafe {
+            let mut region = addr.cast::<Node<Region>>();
+            region.as_ptr().write(Node {
+                next: None,
+                prev: None,
+                data: Region::new(record.size - REGION_HEADER_SIZE, kind, None),
+            });
+
+            name_memory(addr.as_ptr(), record.size, kind.name());
+
+            let mut offset = REGION_HEADER_SIZE;
+
+            while offset < record.size {
+                let block = addr.add(offset).cast::<Node<Block>>();
+
+                // The call site may not exist in this run of the program, so it is
+                // replaced before the header is read as a whole.
+                ptr::addr_of_mut!((*block.as_ptr()).data.region).write(region);
+                #[cfg(feature = "tagging")]
+                ptr::addr_of_mut!((*block.as_ptr()).data.tag).write(0);
+                #[cfg(feature = "call-sites")]
+                ptr::addr_of_mut!((*block.as_ptr()).data.call_site).write(None);
+                (*block.as_ptr()).data.update_checksum();
+
+                region.as_mut().data.blocks.append_node(block);
+                Block::write_footer(block);
+
+                let data = &block.as_ref().data;
+
+                if data.is_free() {
+                    let free_node_addr = block.cast::<u8>().add(BLOCK_HEADER_SIZE);
+                    self.free_list.insert_free_block(block, free_node_addr);
+                } else if data.is_padded() {
+                    Block::relocate_reflection(block, record.addr + offset, RELOCATION_ALIGN);
+                }
+
+                offset += BLOCK_HEADER_SIZE + data.size();
+            }
+
+            match kind {
+                RegionKind::Large => self.large_objects.append_node(region),
+                _ => self.regions.append_node(region),
+            }
+        }
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Copies the whole heap, metadata and contents of the allocations, into a
+    /// [`Snapshot`] that [`MemAlloc::restore`] can bring back later. Executable memory
+    /// and cached regions are not part of it.
+    ///
+    /// Fails for [`crate::Config::hardened`] allocators, if there are live handles (see
+    /// [`MemAlloc::allocate_handle`]) or if the image can't be mapped.
+    ///
+    /// ```rust
+    /// use std::alloc::Layout;
+    /// use memalloc::MemAlloc;
+    ///
+    /// let allocator = MemAlloc::new();
+    /// let layout = Layout::new::<u64>();
+    ///
+    /// unsafe {
+    ///     let ptr = allocator.allocate(layout).cast::<u64>();
+    ///     ptr.write(1);
+    ///
+    ///     let mut snapshot = allocator.snapshot().unwrap();
+    ///     ptr.write(2);
+    ///
+    ///     allocator.restore(&mut snapshot).unwrap();
+    ///     let ptr = snapshot.relocate(ptr.cast()).unwrap().cast::<u64>();
+    ///     assert_eq!(ptr.read(), 1);
+    ///
+    ///     allocator.deallocate(ptr.cast(), layout);
+    /// }
+    /// ```
+    pub fn snapshot(&self) -> Result<Snapshot, AllocError> {
+        let mut kernel = self.lock_draining();
+
+        // The lock is held, so the stats match the regions.
+        kernel.snapshot(self.stats.load())
+    }
+
+    /// Replaces the heap with the one captured in `snapshot`, which can come from this
+    /// allocator or any other one of the same program. The statistics are restored too,
+    /// while the ones of the tags start over. Use [`Snapshot::relocate`] to find where the
+    /// allocations are now.
+    ///
+    /// Fails for [`crate::Config::hardened`] allocators or if there are live handles. If
+    /// a region can't be mapped, the heap is left empty.
+    ///
+    /// # Safety
+    ///
+    /// Every allocation of the heap is released, so nothing may use them anymore, and
+    /// pointers stored inside the restored allocations are not translated.
+    pub unsafe fn restore(&self, snapshot: &mut Snapshot) -> Result<(), AllocError> {
+        unsafe { self.lock_draining().restore(snapshot, &self.stats) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::*;
+    use crate::config::LARGE_OBJECT_THRESHOLD;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "copies whole regions")]
+    fn restored_heap_has_the_old_contents() {
+        let small = Layout::from_size_align(100, 8).unwrap();
+        let aligned = Layout::from_size_align(64, 256).unwrap();
+        let large = Layout::from_size_align(LARGE_OBJECT_THRESHOLD, 8).unwrap();
+
+        let allocator = MemAlloc::new();
+
+        unsafe {
+            let [a, b, c] = [small, aligned, large].map(|layout| allocator.allocate(layout));
+            a.write_bytes(0xA, small.size());
+            b.write_bytes(0xB, aligned.size());
+            c.write_bytes(0xC, large.size());
+
+            let hole = allocator.allocate(small);
+            allocator.deallocate(hole, small);
+
+            let stats = allocator.stats();
+            let mut snapshot = allocator.snapshot().unwrap();
+
+            a.write_bytes(0, small.size());
+            allocator.deallocate(c, large);
+            allocator.restore(&mut snapshot).unwrap();
+            assert_eq!(allocator.stats(), stats);
+
+            // Another allocator can't take the same addresses while these are alive.
+            let other = MemAlloc::new();
+            let mut copy = Snapshot::from_bytes(snapshot.as_bytes()).unwrap();
+            other.restore(&mut copy).unwrap();
+            assert_eq!(other.stats(), stats);
+
+            for (snapshot, allocator) in [(&snapshot, &allocator), (&copy, &other)] {
+                let [a, b, c] = [a, b, c].map(|ptr| snapshot.relocate(ptr).unwrap());
+                assert_eq!(b as usize % aligned.align(), 0);
+
+                // The free list works as before.
+                let reused = allocator.allocate(small);
+                assert_eq!(reused, snapshot.relocate(hole).unwrap());
+
+                for (ptr, layout, byte) in [(a, small, 0xA), (b, aligned, 0xB), (c, large, 0xC)] {
+                    assert!(slice::from_raw_parts(ptr, layout.size()).iter().all(|&x| x == byte));
+                    assert!(allocator.owns_allocation(ptr));
+                    allocator.deallocate(ptr, layout);
+                }
+
+                allocator.deallocate(reused, small);
+            }
+        }
+
+        assert!(Snapshot::from_bytes(&[0; 64]).is_err());
+    }
+}
@@ -0,0 +1,510 @@
+//! Synchronization primitives used by the allocator.
+//!
+//! [`crate::MemAlloc`] is generic over any [`lock_api::RawMutex`], so users can plug in
+//! `parking_lot`, a spinlock or the mutex of their RTOS without forking the crate:
+//!
+//! ```rust
+//! use memalloc::{Config, MemAlloc, StdRawMutex};
+//!
+//! static ALLOCATOR: MemAlloc<StdRawMutex> = MemAlloc::with_lock(Config::new());
+//! ```
+//!
+//! For kernels, firmware and interrupt contexts where blocking is not possible, we provide
+//! [`SpinRawMutex`], which can mask interrupts while the lock is held (see [`InterruptMask`]).
+//!
+//! By default we use [`StdRawMutex`], which is just [`std::sync::Mutex`] exposed through
+//! the `RawMutex` interface, or `parking_lot::RawMutex` if the `parking_lot` feature is
+//! enabled.
+//!
+//! The paths that don't take the lock, like the queue of deferred frees (see the
+//! `deferred` module) and the stats read by [`crate::MemAlloc::stats`], use the atomics
+//! of [`atomic`] instead of the standard ones. Under `cfg(loom)` those are the atomics of
+//! [`loom`](https://docs.rs/loom), so every interleaving of their tests can be checked:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --lib loom
+//! ```
+//!
+//! Loom atomics can't be created in constant expressions, so under `cfg(loom)` the
+//! constructors marked with [`const_fn`] aren't `const` and allocators can't be statics.
+//! The raw mutexes keep the standard atomics, since `RawMutex::INIT` has to be a constant.
+//!
+//! A panic while the lock is held doesn't disable the allocator. The kernel is behind a
+//! [`lock_api::Mutex`], which has no poisoning, and [`StdRawMutex`] ignores the poisoning
+//! of the standard mutex, so the lock is released while unwinding and the next operation
+//! takes it as usual. The only user code that runs with the lock held is the
+//! [`crate::PlacementStrategy`], which can't change the heap, and the callback of
+//! [`crate::MemAlloc::compact`], and compaction puts the heap back in order before a panic
+//! of its callback goes on unwinding.
+
+use std::{
+    cell::{Cell, UnsafeCell},
+    hint,
+    marker::PhantomData,
+    mem::{self, MaybeUninit},
+    sync::{
+        Mutex, MutexGuard, PoisonError, TryLockError,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use lock_api::{GuardNoSend, RawMutex};
+
+/// Atomics of the paths that don't take the lock, and the hint their spin loops give.
+/// See the module docs.
+pub(crate) mod atomic {
+    #[cfg(not(loom))]
+    pub(crate) use std::{
+        hint::spin_loop,
+        sync::atomic::{fence, AtomicPtr, AtomicUsize, Ordering},
+    };
+
+    #[cfg(loom)]
+    pub(crate) use loom::{
+        hint::spin_loop,
+        sync::atomic::{fence, AtomicPtr, AtomicUsize, Ordering},
+    };
+}
+
+/// Defines a `const fn`, except under `cfg(loom)`, where it creates [`atomic`] types that
+/// can't be created in constant expressions.
+macro_rules! const_fn {
+    ($(#[$attr:meta])* $vis:vis const fn $($rest:tt)*) => {
+        #[cfg(not(loom))]
+        $(#[$attr])* $vis const fn $($rest)*
+
+        #[cfg(loom)]
+        $(#[$attr])* $vis fn $($rest)*
+    };
+}
+
+pub(crate) use const_fn;
+
+/// Raw mutex used when no other one is specified.
+#[cfg(not(feature = "parking_lot"))]
+pub type DefaultRawMutex = StdRawMutex;
+
+/// Raw mutex used when no other one is specified. With the `parking_lot` feature
+/// enabled this is [`parking_lot::RawMutex`], which is smaller, has no poisoning
+/// and performs better under contention.
+#[cfg(feature = "parking_lot")]
+pub type DefaultRawMutex = parking_lot::RawMutex;
+
+/// [`RawMutex`] implemented on top of [`std::sync::Mutex`].
+///
+/// `RawMutex` has separate `lock` and `unlock` operations while the standard library
+/// only unlocks by dropping the guard, so we keep the guard inside the mutex itself
+/// between both calls.
+pub struct StdRawMutex {
+    /// The actual lock.
+    mutex: Mutex<()>,
+    /// Guard of `mutex` while it is locked. Its lifetime is extended to `'static`
+    /// because it borrows `mutex`, which lives in the same struct and can't move
+    /// while it is locked.
+    guard: UnsafeCell<Option<MutexGuard<'static, ()>>>,
+}
+
+/// `guard` is only accessed by the thread that holds `mutex`.
+unsafe impl Sync for StdRawMutex {}
+
+/// The mutex can only be moved while nobody holds it, so `guard` is `None`.
+unsafe impl Send for StdRawMutex {}
+
+impl StdRawMutex {
+    /// Stores the guard of the mutex we have just locked.
+    ///
+    /// # Safety
+    ///
+    /// `guard` must be the guard of `self.mutex`.
+    #[inline]
+    unsafe fn hold(&self, guard: MutexGuard<'_, ()>) {
+        unsafe {
+            *self.guard.get() = Some(mem::transmute::<MutexGuard<'_, ()>, MutexGuard<'static, ()>>(guard));
+        }
+    }
+}
+
+unsafe impl RawMutex for StdRawMutex {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        mutex: Mutex::new(()),
+        guard: UnsafeCell::new(None),
+    };
+
+    type GuardMarker = GuardNoSend;
+
+    fn lock(&self) {
+        // The mutex protects no data, so it doesn't matter if it has been poisoned.
+        let guard = self.mutex.lock().unwrap_or_else(PoisonError::into_inner);
+
+        unsafe { self.hold(guard) }
+    }
+
+    fn try_lock(&self) -> bool {
+        let guard = match self.mutex.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => return false,
+        };
+
+        unsafe { self.hold(guard) }
+
+        true
+    }
+
+    unsafe fn unlock(&self) {
+        unsafe { drop((*self.guard.get()).take()) }
+    }
+}
+
+/// Hook used by [`SpinRawMutex`] to mask interrupts while the lock is held.
+///
+/// In kernels and firmware, an interrupt handler that allocates while the interrupted
+/// code holds the allocator lock would spin forever. Masking interrupts on the current
+/// core while holding the lock prevents that.
+pub trait InterruptMask {
+    /// Whatever is needed to restore the interrupts, for example the previous
+    /// value of the flags register.
+    type State: Copy;
+
+    /// Disables interrupts on the current core and returns the previous state.
+    fn disable() -> Self::State;
+
+    /// Restores the `state` returned by [`InterruptMask::disable`].
+    fn restore(state: Self::State);
+}
+
+/// [`InterruptMask`] that doesn't do anything. Used for regular user space programs.
+pub struct NoInterruptMask;
+
+impl InterruptMask for NoInterruptMask {
+    type State = ();
+
+    #[inline]
+    fn disable() {}
+
+    #[inline]
+    fn restore(_state: ()) {}
+}
+
+/// Spin lock implementing [`RawMutex`].
+///
+/// It never blocks nor allocates, it only needs atomics, so it can be used where
+/// blocking mutexes are not available: kernels, firmware or interrupt handlers.
+/// The optional [`InterruptMask`] `M` is used to mask interrupts while the lock is held.
+///
+/// ```rust
+/// use memalloc::{Config, MemAlloc, SpinRawMutex};
+///
+/// static ALLOCATOR: MemAlloc<SpinRawMutex> = MemAlloc::with_lock(Config::new());
+/// ```
+pub struct SpinRawMutex<M: InterruptMask = NoInterruptMask> {
+    /// Whether the lock is held.
+    locked: AtomicBool,
+    /// State returned by [`InterruptMask::disable`] when the lock was acquired.
+    saved: UnsafeCell<MaybeUninit<M::State>>,
+    marker: PhantomData<M>,
+}
+
+/// `saved` is only accessed by the holder of the lock.
+unsafe impl<M: InterruptMask> Sync for SpinRawMutex<M> {}
+
+unsafe impl<M: InterruptMask> Send for SpinRawMutex<M> {}
+
+impl<M: InterruptMask> SpinRawMutex<M> {
+    #[inline]
+    fn acquire(&self) -> bool {
+        self.locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+unsafe impl<M: InterruptMask> RawMutex for SpinRawMutex<M> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        locked: AtomicBool::new(false),
+        saved: UnsafeCell::new(MaybeUninit::uninit()),
+        marker: PhantomData,
+    };
+
+    // The interrupts must be restored on the same core that masked them.
+    type GuardMarker = GuardNoSend;
+
+    fn lock(&self) {
+        loop {
+            if self.try_lock() {
+                return;
+            }
+
+            // Wait with interrupts enabled until the lock looks free.
+            while self.locked.load(Ordering::Relaxed) {
+                hint::spin_loop();
+            }
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        let state = M::disable();
+
+        if self.acquire() {
+            unsafe { (*self.saved.get()).write(state) };
+            true
+        } else {
+            M::restore(state);
+            false
+        }
+    }
+
+    unsafe fn unlock(&self) {
+        let state = unsafe { (*self.saved.get()).assume_init() };
+
+        self.locked.store(false, Ordering::Release);
+        M::restore(state);
+    }
+
+    fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+thread_local! {
+    /// Whether the current thread is waiting in the slow path of [`lock`].
+    static ACQUIRING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Locks `mutex`, even if the raw mutex allocates memory while waiting for it.
+///
+/// Some raw mutexes allocate in their slow path. For example, `parking_lot` allocates
+/// the data it needs to park a thread the first time that thread has to wait. When we
+/// are the global allocator, that allocation comes back to us while the thread is still
+/// waiting for the lock, and trying to park again from there breaks the raw mutex.
+///
+/// ```text
+/// allocate -> lock (contended) -> park -> allocate -> lock (contended) -> park -> ...
+/// ```
+///
+/// To avoid that, nested allocations don't take the lock at all (see [`is_acquiring`]) and
+/// any other nested call just spins until the lock is free instead of going through the
+/// slow path again.
+#[inline]
+pub(crate) fn lock<R: RawMutex, T>(mutex: &lock_api::Mutex<R, T>) -> lock_api::MutexGuard<'_, R, T> {
+    if let Some(guard) = mutex.try_lock() {
+        return guard;
+    }
+
+    if ACQUIRING.get() {
+        loop {
+            if let Some(guard) = mutex.try_lock() {
+                return guard;
+            }
+
+            hint::spin_loop();
+        }
+    }
+
+    ACQUIRING.set(true);
+
+    // A raw mutex that panics must not leave every later allocation of this thread
+    // detached.
+    let acquiring = Acquiring;
+    let guard = mutex.lock();
+    drop(acquiring);
+
+    guard
+}
+
+/// Clears [`ACQUIRING`] when dropped, even while unwinding.
+struct Acquiring;
+
+impl Drop for Acquiring {
+    fn drop(&mut self) {
+        ACQUIRING.set(false);
+    }
+}
+
+/// Tells whether the current thread is inside the slow path of [`lock`]. If it is, any
+/// allocation comes from the raw mutex itself and must not try to take the lock, since
+/// the raw mutex may be holding internal locks that the current lock owner needs to
+/// release it. `parking_lot`, for example, allocates while holding its bucket locks.
+#[inline]
+pub(crate) fn is_acquiring() -> bool {
+    ACQUIRING.get()
+}
+
+/// Makes [`is_acquiring`] return `acquiring` on the current thread, as if an allocation
+/// was made by the raw mutex.
+#[cfg(all(test, unix))]
+pub(crate) fn set_acquiring(acquiring: bool) {
+    ACQUIRING.set(acquiring);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn std_raw_mutex_excludes() {
+        let mutex = Arc::new(lock_api::Mutex::<StdRawMutex, usize>::new(0));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let mutex = Arc::clone(&mutex);
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        *mutex.lock() += 1;
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(*mutex.lock(), 4000);
+    }
+
+    #[test]
+    fn spin_raw_mutex_excludes() {
+        let mutex = Arc::new(lock_api::Mutex::<SpinRawMutex, usize>::new(0));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let mutex = Arc::clone(&mutex);
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        *mutex.lock() += 1;
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(*mutex.lock(), 4000);
+    }
+
+    #[test]
+    fn spin_raw_mutex_masks_interrupts() {
+        use std::sync::atomic::AtomicUsize;
+
+        static MASKED: AtomicBool = AtomicBool::new(false);
+        static RESTORED: AtomicUsize = AtomicUsize::new(0);
+
+        struct FakeInterrupts;
+
+        impl InterruptMask for FakeInterrupts {
+            type State = bool;
+
+            fn disable() -> bool {
+                !MASKED.swap(true, Ordering::SeqCst)
+            }
+
+            fn restore(was_enabled: bool) {
+                RESTORED.fetch_add(1, Ordering::SeqCst);
+                MASKED.store(!was_enabled, Ordering::SeqCst);
+            }
+        }
+
+        let allocator = crate::MemAlloc::<SpinRawMutex<FakeInterrupts>>::with_lock(crate::Config::new());
+
+        {
+            let _kernel = allocator.allocator.lock();
+            assert!(MASKED.load(Ordering::SeqCst));
+            // A failed try_lock restores the state right away
+            assert!(allocator.allocator.try_lock().is_none());
+            assert!(MASKED.load(Ordering::SeqCst));
+        }
+
+        assert!(!MASKED.load(Ordering::SeqCst));
+        assert_eq!(RESTORED.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn nested_allocations_are_detached() {
+        let allocator = crate::MemAlloc::new();
+        let layout = std::alloc::Layout::new::<[u64; 8]>();
+
+        unsafe {
+            // Simulate an allocation made by the raw mutex while waiting for the lock
+            ACQUIRING.set(true);
+            let ptr = allocator.allocate(layout);
+            ACQUIRING.set(false);
+
+            assert!(!ptr.is_null());
+            ptr.write_bytes(0xAB, layout.size());
+
+            // It is not recorded anywhere in the kernel
+            assert!(!allocator.owns(ptr));
+
+            allocator.deallocate(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn panics_with_the_lock_held_release_it() {
+        let allocator = crate::MemAlloc::new();
+        let layout = std::alloc::Layout::new::<[u64; 8]>();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _kernel = lock(&allocator.allocator);
+            panic!("panic with the lock held");
+        }));
+        assert!(result.is_err());
+
+        unsafe {
+            let ptr = allocator.allocate(layout);
+            assert!(allocator.owns(ptr));
+            allocator.deallocate(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn panicking_raw_mutexes_dont_detach_allocations() {
+        /// Raw mutex that panics instead of waiting.
+        struct Impatient(AtomicBool);
+
+        unsafe impl RawMutex for Impatient {
+            #[allow(clippy::declare_interior_mutable_const)]
+            const INIT: Self = Self(AtomicBool::new(false));
+
+            type GuardMarker = GuardNoSend;
+
+            fn lock(&self) {
+                assert!(self.try_lock(), "lock is busy");
+            }
+
+            fn try_lock(&self) -> bool {
+                !self.0.swap(true, Ordering::Acquire)
+            }
+
+            unsafe fn unlock(&self) {
+                self.0.store(false, Ordering::Release);
+            }
+        }
+
+        let mutex = lock_api::Mutex::<Impatient, ()>::new(());
+        let guard = mutex.lock();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(lock(&mutex))));
+        assert!(result.is_err());
+        assert!(!is_acquiring());
+
+        drop(guard);
+    }
+
+    #[test]
+    fn std_raw_mutex_try_lock() {
+        let mutex = lock_api::Mutex::<StdRawMutex, ()>::new(());
+
+        let guard = mutex.lock();
+        assert!(mutex.try_lock().is_none());
+
+        drop(guard);
+        assert!(mutex.try_lock().is_some());
+    }
+}
@@ -0,0 +1,158 @@
+//! Counters of the calls the allocator makes to the OS.
+//!
+//! Every mapping, unmapping, protection change and hint about pages is a system call,
+//! and the point of caching regions, reserving address space or decommitting lazily is
+//! to make fewer of them. [`MemAlloc::syscalls`] tells how many each allocator made, so
+//! the effect of those options can be measured:
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::{Config, MemAlloc};
+//!
+//! let allocator = MemAlloc::with_config(Config::new().cached_regions(1));
+//! let layout = Layout::from_size_align(100, 8).unwrap();
+//!
+//! for _ in 0..10 {
+//!     unsafe { allocator.deallocate(allocator.allocate(layout), layout) };
+//! }
+//!
+//! // The region is cached when it becomes empty, so it is only mapped once.
+//! assert_eq!(allocator.syscalls().maps, 1);
+//! assert_eq!(allocator.syscalls().unmaps, 0);
+//! ```
+//!
+//! Calls are counted by the operation the allocator asks the backend for, whatever
+//! system call implements it on each platform, and failed calls are counted too. The
+//! allocations the lock makes for itself and the tables of handles, leak roots and
+//! snapshots are not counted.
+
+use std::cell::Cell;
+
+use lock_api::RawMutex;
+
+use crate::{memalloc::MemAlloc, sync};
+
+/// Number of calls an allocator made to the OS, by operation. Returned by
+/// [`MemAlloc::syscalls`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Syscalls {
+    /// Mappings requested: `mmap`, `VirtualAlloc` or growing the program break.
+    pub maps: usize,
+    /// Mappings given back, including the ones the unmapper thread gives back.
+    pub unmaps: usize,
+    /// Mappings resized in place or moved by the OS, like with `mremap`.
+    pub remaps: usize,
+    /// Changes of the protection of pages, including committing and uncommitting the
+    /// pages of the reservation of [`crate::Config::reserve`].
+    pub protects: usize,
+    /// Hints about pages: decommitting them, backing them with huge pages or binding
+    /// them to a NUMA node.
+    pub advises: usize,
+}
+
+impl Syscalls {
+    /// Number of calls of every operation.
+    pub fn total(&self) -> usize {
+        self.maps + self.unmaps + self.remaps + self.protects + self.advises
+    }
+}
+
+/// Operations counted in [`Syscalls`].
+#[derive(Clone, Copy)]
+pub(crate) enum Syscall {
+    Map,
+    Unmap,
+    Remap,
+    Protect,
+    Advise,
+}
+
+/// [`Syscalls`] that can be counted from methods that only borrow their owner, like
+/// the ones that change the protection of pages. They are only touched with the
+/// allocator lock held.
+pub(crate) struct SyscallCounter(Cell<Syscalls>);
+
+impl SyscallCounter {
+    pub(crate) const fn new() -> Self {
+        Self(Cell::new(Syscalls { maps: 0, unmaps: 0, remaps: 0, protects: 0, advises: 0 }))
+    }
+
+    /// Counts a call of `syscall`.
+    #[inline]
+    pub(crate) fn count(&self, syscall: Syscall) {
+        let mut counts = self.0.get();
+
+        match syscall {
+            Syscall::Map => counts.maps += 1,
+            Syscall::Unmap => counts.unmaps += 1,
+            Syscall::Remap => counts.remaps += 1,
+            Syscall::Protect => counts.protects += 1,
+            Syscall::Advise => counts.advises += 1,
+        }
+
+        self.0.set(counts);
+    }
+
+    pub(crate) fn get(&self) -> Syscalls {
+        self.0.get()
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Returns how many calls this allocator made to the OS. See the `syscalls` module.
+    pub fn syscalls(&self) -> Syscalls {
+        let kernel = sync::lock(&self.allocator);
+        let (regular, hardened) = (kernel.syscalls.get(), kernel.hardened.syscalls.get());
+
+        Syscalls {
+            maps: regular.maps + hardened.maps,
+            unmaps: regular.unmaps + hardened.unmaps,
+            remaps: regular.remaps + hardened.remaps,
+            protects: regular.protects + hardened.protects,
+            advises: regular.advises + hardened.advises,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend"), ignore = "needs OS memory")]
+    fn calls_are_counted_by_operation() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::from_size_align(16 * 1024, 8).unwrap();
+
+        unsafe {
+            let ptr = allocator.allocate(layout);
+            assert_eq!(allocator.syscalls(), Syscalls { maps: 1, ..Syscalls::default() });
+
+            allocator.seal(ptr).unwrap();
+            allocator.unseal(ptr).unwrap();
+            allocator.deallocate(ptr, layout);
+
+            let syscalls = allocator.syscalls();
+            assert_eq!((syscalls.maps, syscalls.unmaps, syscalls.protects), (1, 1, 2));
+            assert_eq!(syscalls.total(), 4);
+        }
+
+        // Metadata pages of the hardened mode are protected around every operation.
+        let hardened = MemAlloc::with_config(Config::new().hardened(true).protect_metadata(true));
+
+        unsafe {
+            let small = Layout::from_size_align(100, 8).unwrap();
+            let ptr = hardened.allocate(small);
+
+            // The span and the metadata are mapped, and the metadata can only be made
+            // read-only once it exists.
+            assert_eq!(hardened.syscalls(), Syscalls { maps: 2, protects: 1, ..Syscalls::default() });
+
+            hardened.deallocate(ptr, small);
+            assert_eq!(hardened.syscalls().protects, 3);
+        }
+    }
+}
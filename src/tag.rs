@@ -0,0 +1,229 @@
+//! Allocation tags.
+//!
+//! Every thread has a current tag, set with [`set_tag`] for as long as the returned guard
+//! lives. Allocations record the tag of the thread that makes them in their block, so
+//! they are accounted to it until they are deallocated, no matter which thread does it.
+//! [`MemAlloc::stats_by_tag`] reports the [`Stats`] of every tag:
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::MemAlloc;
+//!
+//! let allocator = MemAlloc::new();
+//! let layout = Layout::new::<[u8; 100]>();
+//!
+//! unsafe {
+//!     let ptr = {
+//!         let _tag = memalloc::set_tag("parser");
+//!         allocator.allocate(layout)
+//!     };
+//!
+//!     for (tag, stats) in allocator.stats_by_tag() {
+//!         assert_eq!((tag, stats.allocated), ("parser", 100));
+//!     }
+//!
+//!     allocator.deallocate(ptr, layout);
+//! }
+//! ```
+//!
+//! Tags are only recorded with the `tagging` feature, since it takes one more word in the
+//! header of every block. Each allocator accounts for up to [`MAX_TAGS`] different tags,
+//! allocations made under any other tag are not accounted to any.
+
+use std::{cell::Cell, marker::PhantomData, ptr::NonNull};
+
+use lock_api::RawMutex;
+
+use crate::{block::Block, heap::Stats, kernel::Kernel, list::Node, memalloc::MemAlloc, sync};
+
+/// Maximum number of tags accounted by an allocator.
+pub const MAX_TAGS: usize = 64;
+
+thread_local! {
+    /// Tag of the allocations of the current thread.
+    static TAG: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+/// Sets the tag of the allocations made by the current thread until the returned guard
+/// is dropped, which restores the previous one. Guards can be nested.
+pub fn set_tag(tag: &'static str) -> TagGuard {
+    TagGuard { previous: TAG.replace(Some(tag)), _thread: PhantomData }
+}
+
+/// Returns the tag of the allocations made by the current thread, if any.
+pub fn current_tag() -> Option<&'static str> {
+    TAG.get()
+}
+
+/// Guard returned by [`set_tag`]. It must be dropped by the thread that created it.
+#[must_use = "the tag is unset as soon as the guard is dropped"]
+pub struct TagGuard {
+    previous: Option<&'static str>,
+    _thread: PhantomData<*const ()>,
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        TAG.set(self.previous);
+    }
+}
+
+/// Statistics of every tag of an allocator. Blocks store the position of their tag
+/// plus one, so `0` means untagged.
+pub(crate) struct TagTable {
+    tags: [Option<(&'static str, Stats)>; MAX_TAGS],
+}
+
+impl TagTable {
+    pub(crate) const fn new() -> Self {
+        Self { tags: [None; MAX_TAGS] }
+    }
+
+    /// Returns the index stored in the blocks of the current tag, adding it to the table
+    /// if needed, or `0` if there is none or the table is full.
+    fn current(&mut self) -> u32 {
+        let Some(tag) = current_tag() else {
+            return 0;
+        };
+
+        let mut free = None;
+
+        for (index, slot) in self.tags.iter_mut().enumerate() {
+            match slot {
+                Some((name, _)) if *name == tag => return index as u32 + 1,
+                None if free.is_none() => free = Some((index, slot)),
+                _ => {}
+            }
+        }
+
+        match free {
+            Some((index, slot)) => {
+                *slot = Some((tag, Stats::new()));
+                index as u32 + 1
+            }
+            None => 0,
+        }
+    }
+
+    fn stats(&mut self, index: u32) -> Option<&mut Stats> {
+        let (_, stats) = self.tags.get_mut((index as usize).checked_sub(1)?)?.as_mut()?;
+
+        Some(stats)
+    }
+}
+
+impl Kernel {
+    /// Accounts a refused allocation to the current tag.
+    pub(crate) fn tag_quota_failure(&mut self) {
+        let index = self.tags.current();
+
+        if let Some(stats) = self.tags.stats(index) {
+            stats.quota_failures += 1;
+        }
+    }
+
+    /// Records the current tag in the used block `node` holding `size` bytes.
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to a valid used block header.
+    pub(crate) unsafe fn tag_block(&mut self, mut node: NonNull<Node<Block>>, size: usize) {
+        let index = self.tags.current();
+
+        unsafe { node.as_mut().data.tag = index };
+
+        if let Some(stats) = self.tags.stats(index) {
+            stats.record_allocation(size);
+        }
+    }
+
+    /// Removes the `size` bytes of the used block `node` from the stats of its tag.
+    ///
+    /// # Safety
+    ///
+    /// `node` must point to a valid used block header.
+    pub(crate) unsafe fn untag_block(&mut self, node: NonNull<Node<Block>>, size: usize) {
+        let index = unsafe { node.as_ref().data.tag };
+
+        if let Some(stats) = self.tags.stats(index) {
+            stats.record_deallocation(size);
+        }
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Returns every tag that has been used with this allocator together with the
+    /// statistics of the allocations made under it. See [`set_tag`].
+    pub fn stats_by_tag(&self) -> impl Iterator<Item = (&'static str, Stats)> {
+        let tags = sync::lock(&self.allocator).tags.tags;
+
+        tags.into_iter().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{alloc::Layout, thread};
+
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    fn allocations_are_accounted_to_their_tag() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        unsafe {
+            let untagged = allocator.allocate(layout);
+
+            let (a, b, c) = {
+                let _parser = set_tag("parser");
+                let a = allocator.allocate(layout);
+
+                let b = {
+                    let _lexer = set_tag("lexer");
+                    allocator.allocate(layout)
+                };
+
+                assert_eq!(current_tag(), Some("parser"));
+                (a, b, allocator.allocate(layout))
+            };
+
+            assert_eq!(current_tag(), None);
+
+            let stats: Vec<_> = allocator.stats_by_tag().map(|(tag, stats)| (tag, stats.allocated)).collect();
+            assert_eq!(stats, [("parser", 200), ("lexer", 100)]);
+
+            // Blocks keep their tag when they are deallocated by another thread.
+            let b = b as usize;
+            thread::scope(|scope| {
+                scope.spawn(|| allocator.deallocate(b as *mut u8, layout));
+            });
+
+            allocator.deallocate(a, layout);
+            allocator.deallocate(untagged, layout);
+
+            let stats: Vec<_> = allocator.stats_by_tag().collect();
+            assert_eq!(stats[0].1, Stats { allocated: 100, allocations: 1, peak: 200, quota_failures: 0 });
+            assert_eq!(stats[1].1, Stats { allocated: 0, allocations: 0, peak: 100, quota_failures: 0 });
+
+            allocator.deallocate(c, layout);
+        }
+    }
+
+    #[test]
+    fn quota_failures_are_accounted_to_their_tag() {
+        let allocator = MemAlloc::with_config(Config::new().quota(100));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        unsafe {
+            let ptr = allocator.allocate(layout);
+
+            let _cache = set_tag("cache");
+            assert!(allocator.allocate(layout).is_null());
+            assert_eq!(allocator.stats_by_tag().next().unwrap().1.quota_failures, 1);
+
+            allocator.deallocate(ptr, layout);
+        }
+    }
+}
@@ -0,0 +1,511 @@
+//! Allocation traces.
+//!
+//! While a trace is running, every allocation, reallocation and deallocation of an
+//! allocator is appended to a compact binary log: its layout, the thread that made it,
+//! the pointer it took and the one it returned. The log can be saved and then replayed
+//! against any allocator with [`Trace::replay`], which turns the fragmentation seen in a
+//! production run into a benchmark that can be reproduced:
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use memalloc::{MemAlloc, Trace};
+//!
+//! let allocator = MemAlloc::new();
+//! let layout = Layout::from_size_align(100, 8).unwrap();
+//!
+//! allocator.start_trace(1 << 20).unwrap();
+//!
+//! unsafe {
+//!     let ptr = allocator.allocate(layout);
+//!     let ptr = allocator.reallocate(ptr, layout, 200);
+//!     allocator.deallocate(ptr, Layout::from_size_align(200, 8).unwrap());
+//! }
+//!
+//! let trace = allocator.stop_trace().unwrap();
+//! assert_eq!(trace.events().count(), 3);
+//!
+//! // The bytes can be written to a file and read back in another process.
+//! let trace = Trace::from_bytes(trace.as_bytes()).unwrap();
+//!
+//! let replayed = MemAlloc::new();
+//! let live = unsafe { trace.replay(&replayed) };
+//! assert!(live.is_empty());
+//! ```
+//!
+//! Every event is a kind byte followed by LEB128 varints, and alignments are stored as
+//! their logarithm, so most events take less than 20 bytes. The log is a [`VirtualVec`]
+//! mapped from the OS, so recording never allocates. Once it is full, the rest of the
+//! events are dropped and the trace is marked as truncated.
+//!
+//! Events are replayed in the order they were recorded, from the calling thread, so the
+//! replay is deterministic. Allocations made while the lock is being acquired (see the
+//! `sync` module) are not recorded.
+
+use std::{
+    alloc::Layout,
+    cell::Cell,
+    collections::HashMap,
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use lock_api::RawMutex;
+
+use crate::{error::AllocError, memalloc::MemAlloc, sync, virtual_vec::VirtualVec};
+
+/// First bytes of every log.
+const MAGIC: &[u8; 4] = b"MATR";
+
+/// Version of the format, the byte after [`MAGIC`].
+const VERSION: u8 = 1;
+
+/// Byte after [`VERSION`] that is set when events were dropped.
+const TRUNCATED: usize = MAGIC.len() + 1;
+
+/// Length of the header: magic, version and truncated flag.
+const HEADER_LEN: usize = TRUNCATED + 1;
+
+/// Longest encoding of an event: the kind, the thread, the alignment and four varints.
+const MAX_EVENT_LEN: usize = 1 + 5 + 1 + 4 * 10;
+
+const KIND_ALLOCATE: u8 = 0;
+const KIND_REALLOCATE: u8 = 1;
+const KIND_DEALLOCATE: u8 = 2;
+
+/// Source of [`THREAD`] ids.
+static NEXT_THREAD: AtomicU32 = AtomicU32::new(1);
+
+thread_local! {
+    /// Id of the current thread in traces, or 0 if it hasn't been given one yet.
+    static THREAD: Cell<u32> = const { Cell::new(0) };
+
+    /// Whether the events of the current thread are not recorded, see [`Untraced`].
+    static UNTRACED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Event of a [`Trace`]. Pointers are addresses of the traced process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// [`MemAlloc::allocate`] returned `result`, or 0 if it failed.
+    Allocate { thread: u32, layout: Layout, result: usize },
+    /// [`MemAlloc::reallocate`] resized `ptr` and returned `result`, or 0 if it failed.
+    Reallocate { thread: u32, ptr: usize, layout: Layout, new_size: usize, result: usize },
+    /// [`MemAlloc::deallocate`] freed `ptr`.
+    Deallocate { thread: u32, ptr: usize, layout: Layout },
+}
+
+/// Log of the events of an allocator. See the `trace` module.
+pub struct Trace {
+    log: VirtualVec<u8>,
+    /// Maximum length of the log. The reservation of `log` is rounded up to pages.
+    max_len: usize,
+}
+
+impl Trace {
+    /// Maps a log for `max_len` bytes of events.
+    fn new(max_len: usize) -> Result<Self, AllocError> {
+        let max_len = max_len.checked_add(HEADER_LEN).ok_or(AllocError::InvalidLayout)?;
+        let mut log = VirtualVec::new(max_len)?;
+
+        for &byte in MAGIC.iter().chain(&[VERSION, 0]) {
+            log.push(byte)?;
+        }
+
+        Ok(Self { log, max_len })
+    }
+
+    /// Bytes of the log, which can be saved and read back with [`Trace::from_bytes`].
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.log
+    }
+
+    /// Reads a log returned by [`Trace::as_bytes`]. Fails if it is not a valid one.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        if bytes.len() < HEADER_LEN || bytes[..MAGIC.len()] != *MAGIC || bytes[MAGIC.len()] != VERSION {
+            return Err("not an allocation trace");
+        }
+
+        let mut decoder = Decoder { bytes, pos: HEADER_LEN };
+
+        while decoder.pos < bytes.len() {
+            decoder.event().ok_or("malformed allocation trace")?;
+        }
+
+        let mut log = VirtualVec::new(bytes.len()).map_err(|_| "log can't be mapped")?;
+
+        for &byte in bytes {
+            log.push(byte).map_err(|_| "log can't be mapped")?;
+        }
+
+        Ok(Self { log, max_len: bytes.len() })
+    }
+
+    /// Whether events were dropped because the log was full.
+    pub fn is_truncated(&self) -> bool {
+        self.log[TRUNCATED] != 0
+    }
+
+    /// Returns the events in the order they were recorded.
+    pub fn events(&self) -> impl Iterator<Item = TraceEvent> + '_ {
+        let mut decoder = Decoder { bytes: &self.log, pos: HEADER_LEN };
+
+        std::iter::from_fn(move || decoder.event())
+    }
+
+    /// Runs every event against `allocator`, in order. Pointers of the log are mapped to
+    /// the ones returned by `allocator`, and events on pointers that were allocated before
+    /// the trace started are skipped. Returns the allocations that are still live at the
+    /// end, so they can be inspected and then deallocated.
+    ///
+    /// Allocations that failed when they were recorded may succeed now, or the other way
+    /// around. Either way, the pointer keeps the layout of the last allocation that
+    /// succeeded, which is the one used to deallocate it.
+    ///
+    /// # Safety
+    ///
+    /// The log must have been recorded by this crate, so it doesn't free the same pointer
+    /// twice. [`Trace::from_bytes`] only checks that it is well formed.
+    pub unsafe fn replay<R: RawMutex>(&self, allocator: &MemAlloc<R>) -> Vec<(*mut u8, Layout)> {
+        let mut live = HashMap::new();
+
+        for event in self.events() {
+            match event {
+                TraceEvent::Allocate { layout, result, .. } => {
+                    let ptr = unsafe { allocator.allocate(layout) };
+
+                    match (ptr.is_null(), result) {
+                        (true, _) => {}
+                        // Nothing refers to an allocation that failed when it was recorded.
+                        (false, 0) => unsafe { allocator.deallocate(ptr, layout) },
+                        (false, result) => {
+                            live.insert(result, (ptr, layout));
+                        }
+                    }
+                }
+                TraceEvent::Reallocate { ptr, layout, new_size, result, .. } => {
+                    // The decoder only yields sizes that fit the alignment, but an event
+                    // that doesn't is skipped rather than trusted.
+                    let Ok(new_layout) = Layout::from_size_align(new_size, layout.align()) else {
+                        continue;
+                    };
+
+                    // Zero-sized allocations are never recorded, resizing them allocates.
+                    let (old, layout) = match live.remove(&ptr) {
+                        Some(old) => old,
+                        None if layout.size() == 0 => (ptr::null_mut(), layout),
+                        None => continue,
+                    };
+
+                    let new = unsafe { allocator.reallocate(old, layout, new_size) };
+
+                    match (new.is_null(), result) {
+                        // Shrinking to zero frees the block.
+                        (true, _) if new_size == 0 => {}
+                        // A failed reallocation keeps the old block.
+                        (true, _) => {
+                            if !old.is_null() {
+                                live.insert(ptr, (old, layout));
+                            }
+                        }
+                        (false, 0) => {
+                            live.insert(ptr, (new, new_layout));
+                        }
+                        (false, result) => {
+                            live.insert(result, (new, new_layout));
+                        }
+                    }
+                }
+                TraceEvent::Deallocate { ptr, .. } => {
+                    if let Some((old, layout)) = live.remove(&ptr) {
+                        unsafe { allocator.deallocate(old, layout) };
+                    }
+                }
+            }
+        }
+
+        live.into_values().collect()
+    }
+
+    /// Appends `event`. Marks the trace as truncated if it doesn't fit.
+    fn push(&mut self, event: TraceEvent) {
+        let mut buf = [0u8; MAX_EVENT_LEN];
+        let len = encode(event, &mut buf);
+
+        if self.max_len - self.log.len() < len {
+            self.log[TRUNCATED] = 1;
+            return;
+        }
+
+        for &byte in &buf[..len] {
+            // It can't be full, there is room for the whole event.
+            let _ = self.log.push(byte);
+        }
+    }
+}
+
+/// Writes `event` to `buf`. Returns the number of bytes written.
+fn encode(event: TraceEvent, buf: &mut [u8; MAX_EVENT_LEN]) -> usize {
+    let mut len = 0;
+    let mut put = |value: usize| {
+        let mut value = value;
+
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+
+            buf[len] = byte | if value == 0 { 0 } else { 0x80 };
+            len += 1;
+
+            if value == 0 {
+                break;
+            }
+        }
+    };
+
+    match event {
+        TraceEvent::Allocate { thread, layout, result } => {
+            for value in [KIND_ALLOCATE as usize, thread as usize, layout.align().trailing_zeros() as usize, layout.size(), result] {
+                put(value);
+            }
+        }
+        TraceEvent::Reallocate { thread, ptr, layout, new_size, result } => {
+            for value in [KIND_REALLOCATE as usize, thread as usize, layout.align().trailing_zeros() as usize, layout.size(), ptr, new_size, result] {
+                put(value);
+            }
+        }
+        TraceEvent::Deallocate { thread, ptr, layout } => {
+            for value in [KIND_DEALLOCATE as usize, thread as usize, layout.align().trailing_zeros() as usize, layout.size(), ptr] {
+                put(value);
+            }
+        }
+    }
+
+    len
+}
+
+/// Reads the events of a log.
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn varint(&mut self) -> Option<usize> {
+        let mut value = 0usize;
+
+        for shift in (0..usize::BITS).step_by(7) {
+            let byte = *self.bytes.get(self.pos)?;
+            self.pos += 1;
+
+            value |= ((byte & 0x7f) as usize).checked_shl(shift)?;
+
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+
+        None
+    }
+
+    fn event(&mut self) -> Option<TraceEvent> {
+        if self.pos == self.bytes.len() {
+            return None;
+        }
+
+        let kind = self.varint()?;
+        let thread = u32::try_from(self.varint()?).ok()?;
+        let align = 1usize.checked_shl(u32::try_from(self.varint()?).ok()?)?;
+        let layout = Layout::from_size_align(self.varint()?, align).ok()?;
+
+        match u8::try_from(kind).ok()? {
+            KIND_ALLOCATE => Some(TraceEvent::Allocate { thread, layout, result: self.varint()? }),
+            KIND_REALLOCATE => {
+                let ptr = self.varint()?;
+                let new_size = self.varint()?;
+                Layout::from_size_align(new_size, align).ok()?;
+
+                Some(TraceEvent::Reallocate { thread, ptr, layout, new_size, result: self.varint()? })
+            }
+            KIND_DEALLOCATE => Some(TraceEvent::Deallocate { thread, layout, ptr: self.varint()? }),
+            _ => None,
+        }
+    }
+}
+
+/// Stops recording the events of the current thread until it is dropped. Used while an
+/// operation that is recorded as a whole calls other recorded ones.
+pub(crate) struct Untraced(bool);
+
+impl Untraced {
+    pub(crate) fn new() -> Self {
+        Self(UNTRACED.replace(true))
+    }
+}
+
+impl Drop for Untraced {
+    fn drop(&mut self) {
+        UNTRACED.set(self.0);
+    }
+}
+
+/// Id of the current thread in traces.
+fn thread_id() -> u32 {
+    match THREAD.get() {
+        0 => {
+            let id = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+            THREAD.set(id);
+            id
+        }
+        id => id,
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Starts recording every event of this allocator in a log of up to `max_len` bytes.
+    /// See the `trace` module. Fails if a trace is already running or the log can't be
+    /// mapped.
+    pub fn start_trace(&self, max_len: usize) -> Result<(), &'static str> {
+        let mut kernel = sync::lock(&self.allocator);
+
+        if kernel.trace.is_some() {
+            return Err("trace already running");
+        }
+
+        kernel.trace = Some(Trace::new(max_len).map_err(|_| "log can't be mapped")?);
+        self.tracing.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Stops recording events and returns the trace, or `None` if none was running.
+    pub fn stop_trace(&self) -> Option<Trace> {
+        let mut kernel = sync::lock(&self.allocator);
+
+        self.tracing.store(false, Ordering::Relaxed);
+        kernel.trace.take()
+    }
+
+    /// Records the allocation of `layout` that returned `result`.
+    #[inline]
+    pub(crate) fn trace_allocation(&self, layout: Layout, result: *mut u8) {
+        self.trace(|thread| TraceEvent::Allocate { thread, layout, result: result as usize });
+    }
+
+    /// Records the reallocation of `ptr` to `new_size` bytes that returned `result`.
+    #[inline]
+    pub(crate) fn trace_reallocation(&self, ptr: *mut u8, layout: Layout, new_size: usize, result: *mut u8) {
+        self.trace(|thread| TraceEvent::Reallocate { thread, ptr: ptr as usize, layout, new_size, result: result as usize });
+    }
+
+    /// Records the deallocation of `ptr`.
+    #[inline]
+    pub(crate) fn trace_deallocation(&self, ptr: *mut u8, layout: Layout) {
+        self.trace(|thread| TraceEvent::Deallocate { thread, ptr: ptr as usize, layout });
+    }
+
+    #[inline]
+    fn trace(&self, event: impl FnOnce(u32) -> TraceEvent) {
+        if !self.tracing.load(Ordering::Relaxed) || UNTRACED.get() || sync::is_acquiring() {
+            return;
+        }
+
+        let event = event(thread_id());
+
+        if let Some(trace) = sync::lock(&self.allocator).trace.as_mut() {
+            trace.push(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn traces_are_replayed() {
+        let allocator = MemAlloc::new();
+        let layouts = [(24, 8), (1000, 64), (2 * 1024 * 1024, 8)].map(|(size, align)| Layout::from_size_align(size, align).unwrap());
+
+        // Allocated before the trace, so it is skipped by the replay.
+        let before = unsafe { allocator.allocate(layouts[0]) };
+
+        allocator.start_trace(1 << 16).unwrap();
+        assert_eq!(allocator.start_trace(1 << 16), Err("trace already running"));
+
+        unsafe {
+            let ptrs = layouts.map(|layout| allocator.allocate(layout));
+            let grown = allocator.reallocate(ptrs[0], layouts[0], 500);
+            allocator.deallocate(ptrs[1], layouts[1]);
+            allocator.deallocate(before, layouts[0]);
+
+            let other = ptrs[2] as usize;
+            thread::scope(|scope| {
+                scope.spawn(|| allocator.deallocate(other as *mut u8, layouts[2]));
+            });
+
+            let trace = allocator.stop_trace().unwrap();
+            assert!(allocator.stop_trace().is_none());
+            assert!(!trace.is_truncated());
+
+            let events: Vec<_> = trace.events().collect();
+            let TraceEvent::Allocate { thread, .. } = events[0] else { panic!() };
+
+            assert_eq!(events.len(), 7);
+            assert_eq!(events[1], TraceEvent::Allocate { thread, layout: layouts[1], result: ptrs[1] as usize });
+            assert_eq!(
+                events[3],
+                TraceEvent::Reallocate { thread, ptr: ptrs[0] as usize, layout: layouts[0], new_size: 500, result: grown as usize },
+            );
+            assert_eq!(events[5], TraceEvent::Deallocate { thread, ptr: before as usize, layout: layouts[0] });
+            assert!(matches!(events[6], TraceEvent::Deallocate { thread: other, .. } if other != thread));
+
+            let trace = Trace::from_bytes(trace.as_bytes()).unwrap();
+            let replayed = MemAlloc::new();
+            let live = trace.replay(&replayed);
+
+            assert_eq!(live.len(), 1);
+            assert_eq!(live[0].1, Layout::from_size_align(500, 8).unwrap());
+            assert_eq!(replayed.stats().allocated, 500);
+
+            replayed.deallocate(live[0].0, live[0].1);
+            allocator.deallocate(grown, live[0].1);
+        }
+    }
+
+    #[test]
+    fn full_traces_are_truncated() {
+        let allocator = MemAlloc::new();
+        let layout = Layout::new::<u64>();
+
+        allocator.start_trace(16).unwrap();
+
+        unsafe {
+            let ptr = allocator.allocate(layout);
+            allocator.deallocate(ptr, layout);
+        }
+
+        let trace = allocator.stop_trace().unwrap();
+        assert!(trace.is_truncated());
+        assert_eq!(trace.events().count(), 1);
+
+        assert!(Trace::from_bytes(b"MATR").is_err());
+        assert!(Trace::from_bytes(&trace.as_bytes()[..trace.as_bytes().len() - 1]).is_err());
+    }
+
+    #[test]
+    fn reallocations_to_invalid_sizes_are_malformed() {
+        let allocator = MemAlloc::new();
+        allocator.start_trace(1024).unwrap();
+        let mut bytes = allocator.stop_trace().unwrap().as_bytes().to_vec();
+
+        // Rounded up to the alignment, the new size would overflow an `isize`.
+        let event = TraceEvent::Reallocate { thread: 0, ptr: 0x1000, layout: Layout::new::<u64>(), new_size: isize::MAX as usize, result: 0 };
+        let mut buf = [0; MAX_EVENT_LEN];
+        let len = encode(event, &mut buf);
+        bytes.extend_from_slice(&buf[..len]);
+
+        assert_eq!(Trace::from_bytes(&bytes).err(), Some("malformed allocation trace"));
+    }
+}
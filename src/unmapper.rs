@@ -0,0 +1,311 @@
+//! Background unmapping.
+//!
+//! Returning a region to the OS is a syscall (`munmap` / `VirtualFree`) that also has to
+//! shoot down the TLB entries of every thread that used it, which can take much longer
+//! than the deallocation that emptied the region.
+//! [`MemAlloc::start_background_unmapping`] starts a thread that does it instead: empty
+//! regions, large objects and regions leaving the quarantine are queued and the
+//! deallocation returns right away.
+//!
+//! ```text
+//!  Application thread                         Unmapper thread
+//!          |                                        |
+//!  deallocate(x): region empty                    park
+//!      queue region, unpark ---------------------> |
+//!          |                                 take the queue
+//!  deallocate(y): region empty                 munmap(x)
+//!      queue region                            take the queue
+//!          |                                   munmap(y)
+//!          v                                      park
+//! ```
+//!
+//! The queue lives inside the allocator and is taken under the lock, so it never
+//! allocates and the thread holds the lock only to swap it out, never during a syscall.
+//! It holds up to [`MAX_PENDING_UNMAPS`] regions, and regions that don't fit are unmapped
+//! right away. Queued regions are not used by anyone anymore, so they can be unmapped in
+//! any order.
+//!
+//! [`MemAlloc::flush_unmaps`] unmaps everything queued so far from the calling thread
+//! and waits for the batch the thread is working on, if any.
+//! [`MemAlloc::stop_background_unmapping`] flushes the queue and waits for the thread to
+//! exit, after which regions are unmapped by whoever frees them again.
+//!
+//! With [`crate::Config::decay`], the thread also wakes up once per epoch to let the
+//! region cache decay, so idle heaps shrink too (see the `decay` module).
+//!
+//! Decommits stay on the calling thread: the decommitted memory is still used by the
+//! allocator, either as a cached region or as a free block, and could be reused before
+//! the thread got to it. Regions of a [`crate::Config::reserve`] are not unmapped at all,
+//! so they don't go through the queue either.
+
+use std::{ptr, thread};
+
+use lock_api::RawMutex;
+
+use crate::{
+    decay::DECAY_EPOCHS,
+    kernel::return_memory,
+    memalloc::MemAlloc,
+};
+
+/// Maximum number of regions waiting for the unmapper thread.
+pub const MAX_PENDING_UNMAPS: usize = 64;
+
+/// Regions to unmap, as their address and size.
+#[derive(Clone, Copy)]
+struct Batch {
+    regions: [(*mut u8, usize); MAX_PENDING_UNMAPS],
+    len: usize,
+}
+
+impl Batch {
+    const fn new() -> Self {
+        Self { regions: [(ptr::null_mut(), 0); MAX_PENDING_UNMAPS], len: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns every region to the OS.
+    ///
+    /// # Safety
+    ///
+    /// Nobody may use the regions anymore.
+    unsafe fn unmap(&self) {
+        for &(addr, len) in &self.regions[..self.len] {
+            unsafe { return_memory(addr, len) };
+        }
+    }
+}
+
+/// State of the unmapper thread. See the module docs.
+pub(crate) struct Unmapper {
+    /// Regions waiting for the thread.
+    pending: Batch,
+    /// Whether the thread is running or being started.
+    running: bool,
+    /// The thread, once it has been started.
+    worker: Option<thread::JoinHandle<()>>,
+    /// Whether the thread is unmapping a batch without the lock.
+    busy: bool,
+    /// Whether the thread has to exit once the queue is empty.
+    stopping: bool,
+}
+
+impl Unmapper {
+    pub(crate) const fn new() -> Self {
+        Self { pending: Batch::new(), running: false, worker: None, busy: false, stopping: false }
+    }
+
+    /// Number of regions waiting for the thread.
+    pub(crate) fn pending(&self) -> usize {
+        self.pending.len
+    }
+
+    /// Bytes of the regions waiting for the thread.
+    pub(crate) fn pending_bytes(&self) -> usize {
+        self.pending.regions[..self.pending.len].iter().map(|&(_, len)| len).sum()
+    }
+
+    /// Queues the region of size `len` starting from `addr` for the thread, waking it up
+    /// if the queue was empty. Returns `false` if the thread isn't running or the queue is
+    /// full, in which case it must be unmapped by the caller.
+    pub(crate) fn queue_unmap(&mut self, addr: *mut u8, len: usize) -> bool {
+        let Some(worker) = &self.worker else {
+            return false;
+        };
+
+        if self.stopping || self.pending.len == MAX_PENDING_UNMAPS {
+            return false;
+        }
+
+        self.pending.regions[self.pending.len] = (addr, len);
+        self.pending.len += 1;
+
+        if self.pending.len == 1 {
+            worker.thread().unpark();
+        }
+
+        true
+    }
+
+    /// Takes every queued region, leaving the queue empty.
+    fn take(&mut self) -> Batch {
+        std::mem::replace(&mut self.pending, Batch::new())
+    }
+
+    /// Unmaps every queued region from the calling thread.
+    pub(crate) fn unmap_pending(&mut self) {
+        // Queued regions are not used by anyone anymore.
+        unsafe { self.take().unmap() };
+    }
+}
+
+impl<R: RawMutex> MemAlloc<R> {
+    /// Starts a thread that returns empty regions and large objects to the OS, so that
+    /// deallocations don't wait for the syscall. See the `unmapper` module.
+    ///
+    /// Fails if the thread is already running or can't be spawned.
+    pub fn start_background_unmapping(&'static self) -> Result<(), &'static str>
+    where
+        R: Sync,
+    {
+        let mut kernel = self.lock_draining();
+
+        if kernel.unmapper.running {
+            return Err("the unmapper thread is already running");
+        }
+
+        kernel.unmapper.running = true;
+        drop(kernel);
+
+        // Spawning allocates, so it can't be done with the lock held. Regions are unmapped
+        // as usual until the handle is stored.
+        let worker = thread::Builder::new().name("memalloc-unmapper".into()).spawn(|| self.unmap_in_background());
+        let mut kernel = self.lock_draining();
+
+        match worker {
+            Ok(worker) => {
+                kernel.unmapper.worker = Some(worker);
+                Ok(())
+            }
+            Err(_) => {
+                kernel.unmapper.running = false;
+                Err("could not spawn the unmapper thread")
+            }
+        }
+    }
+
+    /// Unmaps every queued region and stops the thread of
+    /// [`MemAlloc::start_background_unmapping`], waiting for it to exit. Does nothing if
+    /// it isn't running.
+    pub fn stop_background_unmapping(&self) {
+        let mut kernel = self.lock_draining();
+        let Some(worker) = kernel.unmapper.worker.take() else {
+            return;
+        };
+
+        kernel.unmapper.stopping = true;
+        drop(kernel);
+
+        worker.thread().unpark();
+        let _ = worker.join();
+
+        let mut kernel = self.lock_draining();
+        kernel.unmapper.running = false;
+        kernel.unmapper.stopping = false;
+    }
+
+    /// Unmaps every region queued for the unmapper thread from the calling thread, and
+    /// waits for the ones the thread has already taken. See the `unmapper` module.
+    pub fn flush_unmaps(&self) {
+        loop {
+            let mut kernel = self.lock_draining();
+            let batch = kernel.unmapper.take();
+            let busy = kernel.unmapper.busy;
+            drop(kernel);
+
+            if batch.is_empty() {
+                if !busy {
+                    return;
+                }
+
+                thread::yield_now();
+            }
+
+            unsafe { batch.unmap() };
+        }
+    }
+
+    /// Body of the unmapper thread.
+    fn unmap_in_background(&self) {
+        loop {
+            let mut kernel = self.lock_draining();
+
+            // Whatever was taken before has been unmapped by now.
+            kernel.unmapper.busy = false;
+
+            // Idle heaps only decay here, see the `decay` module.
+            kernel.decay_cache();
+
+            let batch = kernel.unmapper.take();
+            let exit = batch.is_empty() && kernel.unmapper.stopping;
+            kernel.unmapper.busy = !batch.is_empty();
+            drop(kernel);
+
+            if exit {
+                return;
+            }
+
+            if batch.is_empty() && self.config.decay.is_zero() {
+                thread::park();
+            } else if batch.is_empty() {
+                thread::park_timeout(self.config.decay / DECAY_EPOCHS as u32);
+            } else {
+                unsafe { batch.unmap() };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    fn regions_are_unmapped_by_the_thread_until_it_stops() {
+        let allocator: &'static MemAlloc = Box::leak(Box::new(MemAlloc::new()));
+        let large = Layout::from_size_align(Config::new().large_object_threshold, 8).unwrap();
+
+        assert_eq!(allocator.start_background_unmapping(), Ok(()));
+        assert!(allocator.start_background_unmapping().is_err());
+
+        unsafe {
+            let ptrs: Vec<_> = (0..8).map(|_| allocator.allocate(large)).collect();
+
+            for ptr in ptrs {
+                allocator.deallocate(ptr, large);
+            }
+
+            assert_eq!(allocator.summary().large_objects, 0);
+
+            allocator.flush_unmaps();
+            assert_eq!(allocator.summary().pending_unmaps, 0);
+            assert!(!allocator.allocator.lock().unmapper.busy);
+
+            // Stopping takes whatever is queued with it.
+            allocator.deallocate(allocator.allocate(large), large);
+            allocator.stop_background_unmapping();
+            assert_eq!(allocator.summary().pending_unmaps, 0);
+
+            // Without the thread, regions are unmapped right away.
+            allocator.deallocate(allocator.allocate(large), large);
+            assert_eq!(allocator.summary().pending_unmaps, 0);
+
+            // It can be started again.
+            assert_eq!(allocator.start_background_unmapping(), Ok(()));
+            allocator.stop_background_unmapping();
+        }
+    }
+
+    #[test]
+    fn full_queues_are_unmapped_by_the_caller() {
+        let mut unmapper = Unmapper::new();
+        assert!(!unmapper.queue_unmap(ptr::null_mut(), 4096));
+
+        // A thread that never unmaps anything, so the queue fills up.
+        unmapper.worker = Some(thread::spawn(|| {}));
+
+        for _ in 0..MAX_PENDING_UNMAPS {
+            assert!(unmapper.queue_unmap(ptr::null_mut(), 4096));
+        }
+
+        assert!(!unmapper.queue_unmap(ptr::null_mut(), 4096));
+        assert_eq!(unmapper.take().len, MAX_PENDING_UNMAPS);
+        assert_eq!(unmapper.pending(), 0);
+    }
+}
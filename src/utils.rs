@@ -1,23 +1,75 @@
 //! This file contains all the helper functions for the allocator. 
 //! This are functions that don't particularly belong to any concrete module of the program.
 
+use std::{
+    io::{self, Write},
+    process,
+};
 
 /// It aligns `to_be_aligned` using `aligment`.
 /// 
-/// This method is used to align region sizes to be a multiple of [`crate::kernel::Kernel::page_size`]
+/// This method is used to align region sizes to be a multiple of [`crate::kernel::page_size`]
 /// and pointers in blocks to be a multiple of the computer's pointer size because memory
 /// address have to be aligned.
-pub fn align(to_be_aligned: usize, aligment: usize) -> usize {
+pub const fn align(to_be_aligned: usize, aligment: usize) -> usize {
     (to_be_aligned + aligment - 1) & !(aligment - 1)
 }
 
+/// Writes `message` to the standard error and aborts the process. Used when our metadata
+/// is found corrupted, since going on would write wherever it points to, and we can't
+/// panic inside the allocator.
+#[cold]
+pub(crate) fn abort_with(message: &[u8]) -> ! {
+    let _ = io::stderr().write_all(message);
+    process::abort();
+}
+
 
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use std::mem;
 
+    /// Returns the permissions of the mapping that contains `addr`, as shown in `/proc/self/maps`.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn permissions_of(addr: *const u8) -> String {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+
+        maps.lines()
+            .find_map(|line| {
+                let (range, rest) = line.split_once(' ')?;
+                let (start, end) = range.split_once('-')?;
+                let start = usize::from_str_radix(start, 16).ok()?;
+                let end = usize::from_str_radix(end, 16).ok()?;
+
+                (start..end).contains(&(addr as usize)).then(|| rest[..4].to_string())
+            })
+            .unwrap()
+    }
+
+    /// Returns the `VmFlags` of the mapping that contains `addr`, from `/proc/self/smaps`.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn vm_flags_of(addr: *const u8) -> String {
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let mut inside = false;
+
+        for line in smaps.lines() {
+            if let Some((range, _)) = line.split_once(' ')
+                && let Some((start, end)) = range.split_once('-')
+                && let (Ok(start), Ok(end)) = (usize::from_str_radix(start, 16), usize::from_str_radix(end, 16))
+            {
+                inside = (start..end).contains(&(addr as usize));
+            }
+
+            if inside && let Some(flags) = line.strip_prefix("VmFlags:") {
+                return flags.to_string();
+            }
+        }
+
+        panic!("mapping not found");
+    }
+
     #[test]
     fn align_pointer_size() {
         let aligments = vec![(1..8, 8), (9..16, 16), (17..24, 24), (25..32, 32)];
@@ -0,0 +1,252 @@
+//! Growable buffer on top of an address space reservation.
+//!
+//! A [`VirtualVec`] reserves the address space for its maximum length once, without
+//! using any physical memory, and commits pages at the end of it as it grows:
+//!
+//! ```text
+//! +-------------------------+-------------------+-------------------------------------+
+//! |        Elements         |  Committed, free  |              Reserved               |
+//! +-------------------------+-------------------+-------------------------------------+
+//! ^                         ^                   ^                                     ^
+//! |                         |                   |                                     |
+//! ptr                       len                 committed                             reserved
+//! ```
+//!
+//! Unlike a `Vec`, growing it never moves the elements, so pointers to them stay valid
+//! for as long as the buffer lives.
+//!
+//! Where the platform doesn't support reservations, like with the `sbrk` and
+//! `system-backend` backends, the whole buffer is mapped up front instead.
+
+use std::{
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    slice,
+};
+
+use crate::{
+    error::AllocError,
+    kernel::{commit, page_size, request_memory, reserve_memory, return_memory, uncommit},
+    utils::align,
+};
+
+/// Vector whose elements never move. See the module docs.
+///
+/// ```rust
+/// use memalloc::VirtualVec;
+///
+/// let mut values = VirtualVec::new(1 << 20).unwrap();
+/// values.push(1u64).unwrap();
+///
+/// let first = &values[0] as *const u64;
+/// for value in 2..100_000 {
+///     values.push(value).unwrap();
+/// }
+///
+/// assert_eq!(first, &values[0] as *const u64);
+/// ```
+pub struct VirtualVec<T> {
+    /// Start of the reservation.
+    ptr: NonNull<T>,
+    /// Number of elements.
+    len: usize,
+    /// Bytes committed from the start of the reservation.
+    committed: usize,
+    /// Bytes of the reservation.
+    reserved: usize,
+    /// Whether the pages are committed as it grows, see the module docs.
+    lazy: bool,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for VirtualVec<T> {}
+unsafe impl<T: Sync> Sync for VirtualVec<T> {}
+
+impl<T> VirtualVec<T> {
+    /// Reserves the address space for `max_len` elements. Nothing is committed until the
+    /// first element is pushed.
+    ///
+    /// Fails for zero-sized types, types aligned to more than a page and if the address
+    /// space can't be reserved.
+    pub fn new(max_len: usize) -> Result<Self, AllocError> {
+        if mem::size_of::<T>() == 0 || mem::align_of::<T>() > page_size() {
+            return Err(AllocError::Unsupported);
+        }
+
+        let bytes = max_len.checked_mul(mem::size_of::<T>()).ok_or(AllocError::InvalidLayout)?;
+        let reserved = align(bytes.max(1), page_size());
+
+        unsafe {
+            if let Some(ptr) = reserve_memory(reserved) {
+                return Ok(Self { ptr: ptr.cast(), len: 0, committed: 0, reserved, lazy: true, _marker: PhantomData });
+            }
+
+            let ptr = request_memory(reserved, false).ok_or_else(AllocError::last_os_error)?;
+
+            Ok(Self { ptr: ptr.cast(), len: 0, committed: reserved, reserved, lazy: false, _marker: PhantomData })
+        }
+    }
+
+    /// Number of elements.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether there are no elements.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of elements that fit in the committed pages.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.committed / mem::size_of::<T>()
+    }
+
+    /// Maximum number of elements, which fit in the reservation.
+    #[inline]
+    pub fn max_len(&self) -> usize {
+        self.reserved / mem::size_of::<T>()
+    }
+
+    /// Appends `value`, committing more pages if needed. Fails with
+    /// [`AllocError::LimitExceeded`] if it already holds [`VirtualVec::max_len`] elements.
+    pub fn push(&mut self, value: T) -> Result<(), AllocError> {
+        if self.len == self.capacity() {
+            self.grow(self.len + 1)?;
+        }
+
+        unsafe { self.ptr.as_ptr().add(self.len).write(value) };
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Removes the last element and returns it, or `None` if it is empty. Pages are
+    /// kept committed, see [`VirtualVec::shrink_to_fit`].
+    pub fn pop(&mut self) -> Option<T> {
+        self.len = self.len.checked_sub(1)?;
+
+        unsafe { Some(self.ptr.as_ptr().add(self.len).read()) }
+    }
+
+    /// Drops every element, keeping the pages committed.
+    pub fn clear(&mut self) {
+        let elements = ptr::slice_from_raw_parts_mut(self.ptr.as_ptr(), self.len);
+        self.len = 0;
+
+        unsafe { ptr::drop_in_place(elements) };
+    }
+
+    /// Decommits the pages that don't hold any element, keeping them reserved.
+    pub fn shrink_to_fit(&mut self) {
+        let used = align(self.len * mem::size_of::<T>(), page_size());
+
+        if self.lazy && used < self.committed {
+            unsafe { uncommit(self.ptr.as_ptr().cast::<u8>().add(used), self.committed - used) };
+            self.committed = used;
+        }
+    }
+
+    /// Commits enough pages for `len` elements. The committed size doubles every time,
+    /// up to the end of the reservation, to keep the number of syscalls low.
+    fn grow(&mut self, len: usize) -> Result<(), AllocError> {
+        let needed = len * mem::size_of::<T>();
+
+        if needed > self.reserved {
+            return Err(AllocError::LimitExceeded);
+        }
+
+        let target = align(needed.max(self.committed * 2), page_size()).min(self.reserved);
+
+        unsafe {
+            if !commit(self.ptr.as_ptr().cast::<u8>().add(self.committed), target - self.committed) {
+                return Err(AllocError::last_os_error());
+            }
+        }
+
+        self.committed = target;
+
+        Ok(())
+    }
+}
+
+impl<T> Deref for VirtualVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> DerefMut for VirtualVec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> Drop for VirtualVec<T> {
+    fn drop(&mut self) {
+        self.clear();
+
+        unsafe { return_memory(self.ptr.as_ptr().cast(), self.reserved) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend", feature = "sbrk"), ignore = "needs reservations")]
+    fn elements_never_move() {
+        let mut values = VirtualVec::new(1 << 20).unwrap();
+        assert_eq!(values.capacity(), 0);
+
+        values.push(0u64).unwrap();
+        let first = values.as_ptr();
+        assert_eq!(values.capacity(), page_size() / 8);
+
+        for value in 1..100_000 {
+            values.push(value).unwrap();
+        }
+
+        assert_eq!(values.as_ptr(), first);
+        assert!(values.iter().enumerate().all(|(index, &value)| value == index as u64));
+        assert!(values.capacity() >= 100_000 && values.capacity() < 200_000);
+
+        while values.len() > 10 {
+            values.pop();
+        }
+
+        values.shrink_to_fit();
+        assert_eq!(values.capacity(), page_size() / 8);
+        assert_eq!(values.pop(), Some(9));
+    }
+
+    #[test]
+    #[cfg_attr(any(miri, feature = "system-backend", feature = "sbrk"), ignore = "needs reservations")]
+    fn full_vector_refuses_pushes() {
+        let counter = Rc::new(());
+        let max_len = page_size() / mem::size_of::<Rc<()>>();
+        let mut values = VirtualVec::new(max_len).unwrap();
+
+        for _ in 0..max_len {
+            values.push(counter.clone()).unwrap();
+        }
+
+        assert_eq!(values.push(counter.clone()), Err(AllocError::LimitExceeded));
+        assert_eq!(Rc::strong_count(&counter), max_len + 1);
+
+        drop(values);
+        assert_eq!(Rc::strong_count(&counter), 1);
+
+        assert!(VirtualVec::<()>::new(10).is_err());
+    }
+}